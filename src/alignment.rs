@@ -0,0 +1,107 @@
+pub const MIB: u64 = 1024 * 1024;
+
+#[derive(Clone, Copy, Debug)]
+pub struct SectorInfo {
+    pub logical_sector_size: u32,
+    pub physical_sector_size: u32,
+    pub physical_sector_offset: u32,
+    pub optimal_io_size: Option<u32>,
+}
+
+impl SectorInfo {
+    pub fn from_logical(logical_sector_size: u32) -> Self {
+        Self {
+            logical_sector_size,
+            physical_sector_size: logical_sector_size,
+            physical_sector_offset: 0,
+            optimal_io_size: None,
+        }
+    }
+
+    pub fn is_512e(&self) -> bool {
+        self.logical_sector_size == 512 && self.physical_sector_size > 512
+    }
+
+    pub fn is_4kn(&self) -> bool {
+        self.logical_sector_size == 4096
+    }
+
+    pub fn describe(&self) -> String {
+        let kind = if self.is_4kn() {
+            "4K native"
+        } else if self.is_512e() {
+            "512e"
+        } else if self.logical_sector_size == 512 {
+            "512n"
+        } else {
+            "non-standard"
+        };
+        format!("{} (logical {} B, physical {} B)", kind, self.logical_sector_size, self.physical_sector_size)
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct PartitionAlignment {
+    pub physical_sector_aligned: bool,
+    pub mib_aligned: bool,
+    pub optimal_io_aligned: Option<bool>,
+    pub suggested_start: u64,
+}
+
+impl PartitionAlignment {
+    pub fn is_misaligned(&self) -> bool {
+        !self.physical_sector_aligned || !self.mib_aligned || self.optimal_io_aligned == Some(false)
+    }
+}
+
+fn is_aligned_to(start: u64, alignment: u64, offset: u64) -> bool {
+    alignment == 0 || start.wrapping_sub(offset).is_multiple_of(alignment)
+}
+
+fn gcd(a: u64, b: u64) -> u64 {
+    if b == 0 { a } else { gcd(b, a % b) }
+}
+
+fn lcm(a: u64, b: u64) -> u64 {
+    if a == 0 || b == 0 { a.max(b) } else { a / gcd(a, b) * b }
+}
+
+// Devices that report a non-zero alignment offset (old XP-compatible 512e jumpers)
+// start their physical sectors at that offset instead of at LBA 0; no start can then
+// satisfy both boundaries, and the suggestion keeps the 1 MiB one that partitioning
+// tools expect.
+pub fn analyze_alignment(start: u64, sector_info: &SectorInfo) -> PartitionAlignment {
+    let physical = sector_info.physical_sector_size as u64;
+    let physical_offset = sector_info.physical_sector_offset as u64;
+    let optimal = sector_info.optimal_io_size.map(|size| size as u64).filter(|size| *size > 0);
+
+    let physical_sector_aligned = is_aligned_to(start, physical, physical_offset);
+    let mib_aligned = is_aligned_to(start, MIB, 0);
+    let optimal_io_aligned = optimal.map(|size| is_aligned_to(start, size, 0));
+
+    let mut step = lcm(MIB, physical);
+    if let Some(size) = optimal {
+        step = lcm(step, size);
+    }
+    // The nearest boundary, never the start of the disk where the partition table lives.
+    let below = start / step * step;
+    let above = below.saturating_add(step);
+    let suggested_start = if below == 0 || above - start <= start - below { above } else { below };
+
+    PartitionAlignment {
+        physical_sector_aligned,
+        mib_aligned,
+        optimal_io_aligned,
+        suggested_start,
+    }
+}
+
+pub fn format_offset(offset: u64) -> String {
+    if offset.is_multiple_of(MIB) {
+        format!("{} MiB", offset / MIB)
+    } else if offset.is_multiple_of(1024) {
+        format!("{} KiB", offset / 1024)
+    } else {
+        format!("{} B", offset)
+    }
+}
@@ -0,0 +1,706 @@
+use crate::disk::Disk;
+use crate::filesystem::recovery::check_destination;
+use crate::filesystem::FileSystem;
+use crate::vdisk::invalid_data;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+const SCAN_CHUNK_SIZE: u64 = 4 * 1024 * 1024;
+const READ_BLOCK_SIZE: u64 = 64 * 1024;
+const COPY_CHUNK_SIZE: u64 = 1024 * 1024;
+const MB: u64 = 1024 * 1024;
+pub const REPORT_NAME: &str = "report.txt";
+
+// How the end of a file is found once its header matched.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Structure {
+    Jpeg,
+    Png,
+    Gif,
+    Pdf,
+    Zip,
+    Mp4,
+    Sqlite,
+    Footer(Vec<u8>),
+    // No way to tell: the maximum size is carved.
+    MaxSize,
+}
+
+#[derive(Clone, Debug)]
+pub struct Signature {
+    pub name: String,
+    pub extension: String,
+    // `None` matches any byte.
+    pub header: Vec<Option<u8>>,
+    pub structure: Structure,
+    pub min_size: u64,
+    pub max_size: u64,
+}
+
+impl Signature {
+    fn builtin(name: &str, extension: &str, header: &str, structure: Structure, max_size: u64) -> Self {
+        let header = parse_pattern(header).unwrap();
+        Self {
+            name: name.to_string(),
+            extension: extension.to_string(),
+            min_size: header.len() as u64,
+            header,
+            structure,
+            max_size,
+        }
+    }
+
+    fn matches(&self, bytes: &[u8]) -> bool {
+        bytes.len() >= self.header.len() && self.header.iter().zip(bytes).all(|(expected, byte)| expected.is_none_or(|expected| expected == *byte))
+    }
+}
+
+pub fn builtin_signatures() -> Vec<Signature> {
+    vec![
+        Signature::builtin("JPEG", "jpg", "FFD8FF", Structure::Jpeg, 64 * MB),
+        Signature::builtin("PNG", "png", "89504E470D0A1A0A", Structure::Png, 64 * MB),
+        Signature::builtin("GIF", "gif", "47494638??61", Structure::Gif, 64 * MB),
+        Signature::builtin("PDF", "pdf", "255044462D", Structure::Pdf, 256 * MB),
+        Signature::builtin("ZIP", "zip", "504B0304", Structure::Zip, 1024 * MB),
+        Signature::builtin("MP4", "mp4", "????????66747970", Structure::Mp4, 4096 * MB),
+        Signature::builtin("SQLite", "sqlite", "53514C69746520666F726D6174203300", Structure::Sqlite, 1024 * MB),
+    ]
+}
+
+// Hex bytes, with "??" for a byte that may be anything; spaces are ignored.
+fn parse_pattern(text: &str) -> Option<Vec<Option<u8>>> {
+    let digits: Vec<char> = text.chars().filter(|c| !c.is_whitespace()).collect();
+    if digits.is_empty() || !digits.len().is_multiple_of(2) {
+        return None;
+    }
+    digits
+        .chunks(2)
+        .map(|pair| match pair {
+            ['?', '?'] => Some(None),
+            _ => u8::from_str_radix(&pair.iter().collect::<String>(), 16).ok().map(Some),
+        })
+        .collect()
+}
+
+// "512", "64K", "100M" or "2G".
+pub fn parse_size(text: &str) -> Option<u64> {
+    let (number, multiplier) = match text.chars().last()?.to_ascii_uppercase() {
+        'K' => (&text[..text.len() - 1], 1024),
+        'M' => (&text[..text.len() - 1], MB),
+        'G' => (&text[..text.len() - 1], 1024 * MB),
+        _ => (text, 1),
+    };
+    number.parse::<u64>().ok()?.checked_mul(multiplier)
+}
+
+// One signature per line: name, extension, header, footer or "-", maximum size and
+// optionally minimum size. Header and footer are hex with "??" for any byte; without
+// a footer the maximum size is carved. Blank lines and lines starting with # are skipped.
+pub fn parse_signatures(text: &str) -> io::Result<Vec<Signature>> {
+    let mut signatures = Vec::new();
+    for (number, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let error = |message: &str| invalid_data(format!("signature line {}: {}", number + 1, message));
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        let [name, extension, header, footer, max_size, rest @ ..] = fields.as_slice() else {
+            return Err(error("expected name, extension, header, footer or -, and maximum size"));
+        };
+        let header = parse_pattern(header).ok_or_else(|| error("invalid header"))?;
+        let structure = match *footer {
+            "-" => Structure::MaxSize,
+            footer => match parse_pattern(footer) {
+                Some(bytes) if bytes.iter().all(Option::is_some) => Structure::Footer(bytes.into_iter().flatten().collect()),
+                _ => return Err(error("invalid footer; wildcards are only allowed in headers")),
+            },
+        };
+        let max_size = parse_size(max_size).filter(|size| *size > 0).ok_or_else(|| error("invalid maximum size"))?;
+        let min_size = match rest {
+            [] => header.len() as u64,
+            [min_size] => parse_size(min_size).filter(|size| *size <= max_size).ok_or_else(|| error("invalid minimum size"))?,
+            _ => return Err(error("too many fields")),
+        };
+        signatures.push(Signature {
+            name: name.to_string(),
+            extension: extension.trim_start_matches('.').to_string(),
+            header,
+            structure,
+            min_size,
+            max_size,
+        });
+    }
+    Ok(signatures)
+}
+
+// The bytes a carved file may span, from its header to the end of its range or its
+// maximum size, read through a one-block cache. Read errors end the window.
+struct Window<'a> {
+    disk: &'a mut dyn Disk,
+    start: u64,
+    len: u64,
+    cache_offset: u64,
+    cache: Vec<u8>,
+}
+
+impl Window<'_> {
+    fn get(&mut self, position: u64, len: usize) -> Option<&[u8]> {
+        if position.checked_add(len as u64)? > self.len {
+            return None;
+        }
+        let offset = self.start + position;
+        if offset < self.cache_offset || offset + len as u64 > self.cache_offset + self.cache.len() as u64 {
+            let size = READ_BLOCK_SIZE.max(len as u64).min(self.len - position);
+            self.cache = self.disk.read_bytes(offset, size as usize).ok()?;
+            self.cache_offset = offset;
+        }
+        let index = (offset - self.cache_offset) as usize;
+        Some(&self.cache[index..index + len])
+    }
+
+    fn byte(&mut self, position: u64) -> Option<u8> {
+        self.get(position, 1).map(|bytes| bytes[0])
+    }
+
+    fn u16_be(&mut self, position: u64) -> Option<u16> {
+        self.get(position, 2).map(|bytes| u16::from_be_bytes([bytes[0], bytes[1]]))
+    }
+
+    fn u32_be(&mut self, position: u64) -> Option<u32> {
+        self.get(position, 4).map(|bytes| u32::from_be_bytes(bytes.try_into().unwrap()))
+    }
+
+    fn u64_be(&mut self, position: u64) -> Option<u64> {
+        self.get(position, 8).map(|bytes| u64::from_be_bytes(bytes.try_into().unwrap()))
+    }
+
+    fn u16_le(&mut self, position: u64) -> Option<u16> {
+        self.get(position, 2).map(|bytes| u16::from_le_bytes([bytes[0], bytes[1]]))
+    }
+
+    fn u32_le(&mut self, position: u64) -> Option<u32> {
+        self.get(position, 4).map(|bytes| u32::from_le_bytes(bytes.try_into().unwrap()))
+    }
+
+    fn u64_le(&mut self, position: u64) -> Option<u64> {
+        self.get(position, 8).map(|bytes| u64::from_le_bytes(bytes.try_into().unwrap()))
+    }
+
+    // First position of `pattern` in `from..end`.
+    fn find(&mut self, from: u64, pattern: &[u8], end: u64) -> Option<u64> {
+        self.find_any(from, &[pattern], end).map(|(position, _)| position)
+    }
+
+    // First position in `from..end` where one of the equally long `patterns` occurs,
+    // and which one it is.
+    fn find_any(&mut self, from: u64, patterns: &[&[u8]], end: u64) -> Option<(u64, usize)> {
+        let pattern_len = patterns[0].len();
+        let end = end.min(self.len);
+        let mut position = from;
+        while position + pattern_len as u64 <= end {
+            let len = READ_BLOCK_SIZE.min(end - position) as usize;
+            let block = self.get(position, len)?;
+            for (index, window) in block.windows(pattern_len).enumerate() {
+                if let Some(pattern) = patterns.iter().position(|pattern| window == *pattern) {
+                    return Some((position + index as u64, pattern));
+                }
+            }
+            // Blocks overlap so a pattern split between two is still found.
+            position += (len - pattern_len + 1) as u64;
+        }
+        None
+    }
+
+    fn crc32(&mut self, from: u64, len: u64) -> Option<u32> {
+        let mut hasher = crc32fast::Hasher::new();
+        let mut position = from;
+        while position < from + len {
+            let chunk = READ_BLOCK_SIZE.min(from + len - position);
+            hasher.update(self.get(position, chunk as usize)?);
+            position += chunk;
+        }
+        Some(hasher.finalize())
+    }
+}
+
+// Length of the file at the start of the window and, for containers, the extension
+// its content calls for; `None` when the data does not hold up as that format.
+fn measure(window: &mut Window, signature: &Signature) -> Option<(u64, Option<&'static str>)> {
+    match &signature.structure {
+        Structure::Jpeg => measure_jpeg(window).map(|len| (len, None)),
+        Structure::Png => measure_png(window).map(|len| (len, None)),
+        Structure::Gif => measure_gif(window).map(|len| (len, None)),
+        Structure::Pdf => measure_pdf(window).map(|len| (len, None)),
+        Structure::Zip => measure_zip(window),
+        Structure::Mp4 => measure_mp4(window),
+        Structure::Sqlite => measure_sqlite(window).map(|len| (len, None)),
+        Structure::Footer(footer) => {
+            let found = window.find(signature.header.len() as u64, footer, window.len)?;
+            Some((found + footer.len() as u64, None))
+        }
+        Structure::MaxSize => Some((window.len, None)),
+    }
+}
+
+// Marker segments up to each scan, then entropy-coded data, in which 0xFF is only
+// followed by a stuffed zero or a restart marker, up to the next marker.
+fn measure_jpeg(window: &mut Window) -> Option<u64> {
+    let mut position = 2;
+    let mut scanned = false;
+    loop {
+        if window.byte(position)? != 0xFF {
+            return None;
+        }
+        position += 1;
+        let mut marker = window.byte(position)?;
+        while marker == 0xFF {
+            position += 1;
+            marker = window.byte(position)?;
+        }
+        position += 1;
+        match marker {
+            0xD9 => return scanned.then_some(position),
+            0x01 | 0xD0..=0xD7 => continue,
+            0x00 | 0xD8 => return None,
+            _ => {}
+        }
+        let length = window.u16_be(position)? as u64;
+        if length < 2 {
+            return None;
+        }
+        position += length;
+        if marker == 0xDA {
+            scanned = true;
+            loop {
+                if window.byte(position)? == 0xFF {
+                    match window.byte(position + 1)? {
+                        0x00 | 0xD0..=0xD7 => position += 2,
+                        _ => break,
+                    }
+                } else {
+                    position += 1;
+                }
+            }
+        }
+    }
+}
+
+// Chunks with valid CRCs from IHDR to IEND.
+fn measure_png(window: &mut Window) -> Option<u64> {
+    let mut position = 8;
+    loop {
+        let length = window.u32_be(position)? as u64;
+        let kind: [u8; 4] = window.get(position + 4, 4)?.try_into().unwrap();
+        if length > 0x7FFF_FFFF || !kind.iter().all(u8::is_ascii_alphabetic) || (position == 8 && &kind != b"IHDR") {
+            return None;
+        }
+        let crc = window.u32_be(position + 8 + length)?;
+        if window.crc32(position + 4, length + 4)? != crc {
+            return None;
+        }
+        position += 12 + length;
+        if &kind == b"IEND" {
+            return Some(position);
+        }
+    }
+}
+
+fn skip_gif_sub_blocks(window: &mut Window, mut position: u64) -> Option<u64> {
+    loop {
+        let size = window.byte(position)? as u64;
+        position += 1 + size;
+        if size == 0 {
+            return Some(position);
+        }
+    }
+}
+
+// Extension and image blocks, each a chain of sub-blocks, up to the trailer.
+fn measure_gif(window: &mut Window) -> Option<u64> {
+    if !matches!(window.byte(4)?, b'7' | b'9') {
+        return None;
+    }
+    let flags = window.byte(10)?;
+    let mut position = 13;
+    if flags & 0x80 != 0 {
+        position += 3 << ((flags & 0x07) + 1);
+    }
+    let mut images = 0;
+    loop {
+        match window.byte(position)? {
+            0x3B => return (images > 0).then_some(position + 1),
+            0x21 => position = skip_gif_sub_blocks(window, position + 2)?,
+            0x2C => {
+                let flags = window.byte(position + 9)?;
+                position += 10;
+                if flags & 0x80 != 0 {
+                    position += 3 << ((flags & 0x07) + 1);
+                }
+                // LZW minimum code size, then the image data.
+                position = skip_gif_sub_blocks(window, position + 1)?;
+                images += 1;
+            }
+            _ => return None,
+        }
+    }
+}
+
+// Every incremental update appends its own "%%EOF", so the last one before the next
+// PDF header ends the file. Both are looked for in one pass, so a header with no
+// trailer costs a single scan of the window.
+fn measure_pdf(window: &mut Window) -> Option<u64> {
+    if !matches!(window.get(5, 2)?, b"1." | b"2.") {
+        return None;
+    }
+    let mut end = None;
+    let mut from = 5;
+    while let Some((found, pattern)) = window.find_any(from, &[b"%%EOF", b"%PDF-"], window.len) {
+        if pattern == 1 {
+            break;
+        }
+        from = found + 5;
+        end = Some(from);
+    }
+    let mut end = end?;
+    for _ in 0..2 {
+        match window.byte(end) {
+            Some(b'\r' | b'\n') => end += 1,
+            _ => break,
+        }
+    }
+    Some(end)
+}
+
+// Local file headers are followed from one to the next, so a false match is usually
+// rejected after a single header and the archive's end is found without searching
+// past it. The central directory comes right after the last entry, and the end of
+// central directory record right after that. Office documents are ZIP archives told
+// apart by their top-level folder.
+fn measure_zip(window: &mut Window) -> Option<(u64, Option<&'static str>)> {
+    let mut position = 0;
+    while window.get(position, 4)? == b"PK\x03\x04" {
+        let flags = window.u16_le(position + 6)?;
+        let method = window.u16_le(position + 8)?;
+        let name_length = window.u16_le(position + 26)? as u64;
+        if !matches!(method, 0..=20 | 93..=99) || name_length == 0 {
+            return None;
+        }
+        let data = position + 30 + name_length + window.u16_le(position + 28)? as u64;
+        position = if flags & 0x08 == 0 {
+            data + window.u32_le(position + 18)? as u64
+        } else {
+            zip_data_descriptor_end(window, data)?
+        };
+    }
+    let directory = position;
+    let mut extension = None;
+    while window.get(position, 4)? == b"PK\x01\x02" {
+        let name_length = window.u16_le(position + 28)? as u64;
+        let name = window.get(position + 46, name_length as usize)?;
+        extension = extension.or(if name.starts_with(b"word/") {
+            Some("docx")
+        } else if name.starts_with(b"xl/") {
+            Some("xlsx")
+        } else if name.starts_with(b"ppt/") {
+            Some("pptx")
+        } else {
+            None
+        });
+        position += 46 + name_length + window.u16_le(position + 30)? as u64 + window.u16_le(position + 32)? as u64;
+    }
+    if position == directory {
+        return None;
+    }
+    let directory_end = position;
+    // ZIP64 archives put their own end record and its locator in between.
+    if window.get(position, 4)? == b"PK\x06\x06" {
+        position += 12 + window.u64_le(position + 4)?;
+        if window.get(position, 4)? != b"PK\x06\x07" {
+            return None;
+        }
+        position += 20;
+    }
+    if window.get(position, 4)? != b"PK\x05\x06" {
+        return None;
+    }
+    let directory_size = window.u32_le(position + 12)?;
+    let directory_offset = window.u32_le(position + 16)?;
+    let sizes_match = (directory_size == u32::MAX || directory_size as u64 == directory_end - directory)
+        && (directory_offset == u32::MAX || directory_offset as u64 == directory);
+    let comment_length = window.u16_le(position + 20)? as u64;
+    sizes_match.then_some((position + 22 + comment_length, extension))
+}
+
+// End of an entry whose sizes follow its data: the first data descriptor whose
+// compressed size matches the distance from the data and that is followed by
+// another ZIP record.
+fn zip_data_descriptor_end(window: &mut Window, data: u64) -> Option<u64> {
+    let mut from = data;
+    loop {
+        let descriptor = window.find(from, b"PK\x07\x08", window.len)?;
+        from = descriptor + 1;
+        let compressed = descriptor - data;
+        for (size, end) in [(window.u32_le(descriptor + 8).map(u64::from), descriptor + 16), (window.u64_le(descriptor + 8), descriptor + 24)] {
+            if size == Some(compressed) && matches!(window.get(end, 4), Some(b"PK\x03\x04" | b"PK\x01\x02")) {
+                return Some(end);
+            }
+        }
+    }
+}
+
+const MP4_BOXES: &[&[u8; 4]] = &[
+    b"ftyp", b"moov", b"mdat", b"free", b"skip", b"wide", b"uuid", b"meta", b"pdin", b"moof", b"mfra", b"styp", b"sidx",
+];
+
+// Top-level boxes, as long as they are known ones; a playable file has its media data
+// and a movie (or, for HEIF images, meta) box.
+fn measure_mp4(window: &mut Window) -> Option<(u64, Option<&'static str>)> {
+    let brand: [u8; 4] = window.get(8, 4)?.try_into().unwrap();
+    let mut position = 0;
+    let (mut index, mut media) = (false, false);
+    while let Some(header) = window.get(position, 8) {
+        let kind: [u8; 4] = header[4..8].try_into().unwrap();
+        if !MP4_BOXES.contains(&&kind) {
+            break;
+        }
+        let size = match u32::from_be_bytes(header[0..4].try_into().unwrap()) {
+            1 => window.u64_be(position + 8)?,
+            size => size as u64,
+        };
+        if size < 8 || position.checked_add(size)? > window.len {
+            break;
+        }
+        index |= &kind == b"moov" || &kind == b"meta";
+        media |= &kind == b"mdat";
+        position += size;
+    }
+    let extension = match &brand {
+        b"qt  " => Some("mov"),
+        b"M4A " => Some("m4a"),
+        b"heic" | b"heix" | b"mif1" => Some("heic"),
+        brand if brand.starts_with(b"3gp") => Some("3gp"),
+        _ => None,
+    };
+    (index && media).then_some((position, extension))
+}
+
+// The page count in the header is only trusted when the version-valid-for number
+// matches the change counter.
+fn measure_sqlite(window: &mut Window) -> Option<u64> {
+    let page_size = match window.u16_be(16)? {
+        1 => 65536,
+        size if size >= 512 && size.is_power_of_two() => size as u64,
+        _ => return None,
+    };
+    let pages = window.u32_be(28)? as u64;
+    if pages == 0 || window.u32_be(24)? != window.u32_be(92)? {
+        return None;
+    }
+    Some(pages * page_size)
+}
+
+pub struct CarveOptions {
+    pub signatures: Vec<Signature>,
+    // Headers are only looked for at multiples of this many bytes; files start on
+    // sector or cluster boundaries, and 1 scans every byte.
+    pub align: u64,
+    pub destination: PathBuf,
+}
+
+pub struct CarvedFile {
+    pub signature: String,
+    pub offset: u64,
+    pub length: u64,
+    // Set when a read error cut the copy short of the measured length.
+    pub truncated: bool,
+    pub path: PathBuf,
+}
+
+#[derive(Default)]
+pub struct CarveSummary {
+    pub files: Vec<CarvedFile>,
+    pub scanned: u64,
+    pub unreadable: u64,
+    pub cancelled: bool,
+}
+
+impl CarveSummary {
+    pub fn describe(&self) -> String {
+        let bytes: u64 = self.files.iter().map(|file| file.length).sum();
+        let mut text = format!("{} file(s), {} bytes, carved from {} bytes scanned", self.files.len(), bytes, self.scanned);
+        if self.unreadable > 0 {
+            text.push_str(&format!(", {} bytes unreadable", self.unreadable));
+        }
+        let truncated = self.files.iter().filter(|file| file.truncated).count();
+        if truncated > 0 {
+            text.push_str(&format!(", {} truncated by read errors", truncated));
+        }
+        if self.cancelled {
+            text.push_str(" (cancelled)");
+        }
+        text
+    }
+}
+
+// Free clusters of a volume as byte ranges of its disk.
+pub fn unallocated_ranges(filesystem: &mut dyn FileSystem, disk: &mut dyn Disk) -> io::Result<Vec<(u64, u64)>> {
+    let cluster_size = filesystem.cluster_size();
+    Ok(filesystem
+        .cluster_bitmap(disk)?
+        .free_extents()
+        .iter()
+        .map(|extent| {
+            let start = filesystem.cluster_position(extent.start);
+            (start, start + extent.count * cluster_size)
+        })
+        .collect())
+}
+
+// Scans the byte ranges for headers and writes every file that validates to the
+// destination, named after its offset on the source, then writes a report listing
+// them. A carved file is skipped over rather than searched for embedded files such
+// as JPEG thumbnails. `progress` receives the bytes scanned and files carved so far.
+pub fn carve(
+    disk: &mut dyn Disk,
+    target: &str,
+    ranges: &[(u64, u64)],
+    options: &CarveOptions,
+    cancel: &AtomicBool,
+    progress: &mut dyn FnMut(u64, usize),
+) -> io::Result<CarveSummary> {
+    if options.signatures.is_empty() {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "no signatures to carve"));
+    }
+    check_destination(target, &options.destination)?;
+    // Carved files are never overwritten, so a folder from an earlier run is refused up front.
+    if options.destination.join(REPORT_NAME).exists() {
+        return Err(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            format!("{} already holds a carving report; choose an empty folder", options.destination.display()),
+        ));
+    }
+    fs::create_dir_all(&options.destination)?;
+    let align = options.align.max(1);
+    // Chunks overlap by all but one byte of the longest header, so a header split
+    // between two chunks is still found.
+    let overlap = options.signatures.iter().map(|signature| signature.header.len() as u64).max().unwrap_or(1) - 1;
+    let mut summary = CarveSummary::default();
+    'ranges: for &(range_start, range_end) in ranges {
+        let mut position = range_start.next_multiple_of(align);
+        while position < range_end {
+            if cancel.load(Ordering::Relaxed) {
+                summary.cancelled = true;
+                break 'ranges;
+            }
+            let chunk_len = SCAN_CHUNK_SIZE.min(range_end - position);
+            let mut next = position + chunk_len;
+            let read_len = (chunk_len + overlap).min(range_end - position);
+            let chunk = match disk.read_bytes(position, read_len as usize) {
+                Ok(chunk) => chunk,
+                Err(_) => {
+                    let (chunk, unreadable) = read_sectors(disk, position, read_len, chunk_len);
+                    summary.unreadable += unreadable;
+                    chunk
+                }
+            };
+            let mut index = 0;
+            while index < chunk_len {
+                let offset = position + index;
+                let found = options.signatures.iter().find(|signature| signature.matches(&chunk[index as usize..]));
+                let carved = match found {
+                    Some(signature) => carve_file(disk, signature, offset, range_end, &options.destination)?,
+                    None => None,
+                };
+                match carved {
+                    Some(file) => {
+                        index = (offset + file.length).next_multiple_of(align) - position;
+                        summary.files.push(file);
+                    }
+                    None => index += align,
+                }
+            }
+            next = next.max(position + index);
+            summary.scanned += next.min(range_end) - position;
+            position = next;
+            progress(summary.scanned, summary.files.len());
+        }
+    }
+    write_report(&options.destination, &summary.files)?;
+    Ok(summary)
+}
+
+// Reads `len` bytes at `offset` one sector at a time after a larger read failed, so
+// only the sectors that fail are lost. Those read back as zeros; the second value
+// counts the unreadable bytes among the first `counted`.
+fn read_sectors(disk: &mut dyn Disk, offset: u64, len: u64, counted: u64) -> (Vec<u8>, u64) {
+    let sector_size = disk.sector_size().max(1) as u64;
+    let mut data = vec![0u8; len as usize];
+    let mut unreadable = 0;
+    let mut position = 0;
+    while position < len {
+        let piece = ((offset + position) / sector_size + 1) * sector_size - offset - position;
+        let piece = piece.min(len - position);
+        let range = position as usize..(position + piece) as usize;
+        if disk.read_at(offset + position, &mut data[range]).is_err() {
+            unreadable += piece.min(counted.saturating_sub(position));
+        }
+        position += piece;
+    }
+    (data, unreadable)
+}
+
+fn carve_file(disk: &mut dyn Disk, signature: &Signature, offset: u64, range_end: u64, destination: &Path) -> io::Result<Option<CarvedFile>> {
+    let mut window = Window {
+        disk,
+        start: offset,
+        len: signature.max_size.min(range_end - offset),
+        cache_offset: 0,
+        cache: Vec::new(),
+    };
+    let Some((length, extension)) = measure(&mut window, signature) else {
+        return Ok(None);
+    };
+    // SQLite and MP4 take their size from headers that may promise more than is there.
+    if length < signature.min_size || length > window.len {
+        return Ok(None);
+    }
+    let path = destination.join(format!("{:012x}.{}", offset, extension.unwrap_or(&signature.extension)));
+    let mut out = BufWriter::new(File::options().write(true).create_new(true).open(&path)?);
+    let mut position = 0;
+    // A read error ends the file where it struck instead of the whole carve.
+    while position < length {
+        let len = COPY_CHUNK_SIZE.min(length - position);
+        let Ok(bytes) = disk.read_bytes(offset + position, len as usize) else {
+            break;
+        };
+        out.write_all(&bytes)?;
+        position += len;
+    }
+    out.flush()?;
+    if position == 0 {
+        drop(out);
+        fs::remove_file(&path)?;
+        return Ok(None);
+    }
+    Ok(Some(CarvedFile {
+        signature: signature.name.clone(),
+        offset,
+        length: position,
+        truncated: position < length,
+        path,
+    }))
+}
+
+fn write_report(destination: &Path, files: &[CarvedFile]) -> io::Result<()> {
+    let mut report = BufWriter::new(File::options().write(true).create_new(true).open(destination.join(REPORT_NAME))?);
+    writeln!(report, "{:>16} {:>14}  {:<10} File", "Offset", "Length", "Type")?;
+    for file in files {
+        let name = file.path.file_name().map_or_else(String::new, |name| name.to_string_lossy().into_owned());
+        let note = if file.truncated { " (truncated)" } else { "" };
+        writeln!(report, "{:>16} {:>14}  {:<10} {}{}", format!("{:012x}", file.offset), file.length, file.signature, name, note)?;
+    }
+    report.flush()
+}
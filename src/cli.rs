@@ -0,0 +1,1069 @@
+use crate::carving::{builtin_signatures, carve, parse_signatures, parse_size, unallocated_ranges, CarveOptions, REPORT_NAME};
+use crate::device::nvme::{NvmeController, NvmeHealth, NvmeNamespace};
+use crate::device::identify::{AtaIdentity, HiddenArea};
+use crate::device::{read_identify_pages, read_nvme_pages, read_smart_pages};
+use crate::device::smart::SmartData;
+use crate::disk::{open_disk, Disk};
+use crate::disk_usage::{format_size, ScanProgress, SizeTree};
+use crate::duplicates::{find_duplicates, quarantine, undo_quarantine, write_csv, MANIFEST_NAME};
+use crate::filesystem::fragmentation::{json_string, FragmentationReport};
+use crate::filesystem::recovery::{restore, scan_deleted};
+use crate::filesystem::{export, find_entry, format_extents, list_volumes, open_filesystem, FileSystem, SpaceUsage};
+use crate::gpt_repair::{repair_gpt, GptRepair};
+use crate::layout_check::{check_disk_layout, Severity};
+use crate::vdisk::convert::{convert_image, verify_conversion, ImageFormat};
+use crate::vdisk::ewf::verify_ewf;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::AtomicBool;
+
+const USAGE: &str = "Usage: PMTAlpha <command> [arguments]
+
+Commands:
+  check-layout <drive-index|image-path>    Validate the partition table of a physical drive or image
+  repair-gpt <drive-index|image-path> <action> [--yes]
+                                           Repair the GPT; action is one of rebuild-primary,
+                                           rebuild-backup, fix-crc or relocate-backup; asks
+                                           before writing unless --yes is given
+  verify-image <image.E01>                 Verify the acquisition MD5/SHA1 of an EWF image
+  convert-image <drive-index|image-path> <output> <format> [--verify]
+                                           Convert to raw, sparse-raw, vhd-fixed, vhd-dynamic, vhdx,
+                                           qcow2, vmdk, compressed or compressed-dedup (zstd);
+                                           --verify compares content hashes
+  list-files <drive-index|image-path> [volume#] [path]
+                                           List the volumes and their space usage, or a directory
+                                           of a FAT, exFAT, NTFS, ext2/3/4, ISO 9660 or UDF volume
+  export-files <drive-index|image-path> <volume#> <path> <destination>
+                                           Copy a file or folder out of a volume
+  file-clusters <drive-index|image-path> <volume#> <path>
+                                           Show the clusters occupied by a file
+  fragmentation <drive-index|image-path> [volume#] [--json]
+                                           Report file and free space fragmentation of one volume,
+                                           or of every volume, as text or JSON
+  deleted-files <drive-index|image-path> <volume#>
+                                           List deleted files of a FAT or NTFS volume and whether
+                                           their clusters have been reused
+  recover-files <drive-index|image-path> <volume#> <destination> <number>...|all
+                                           Restore deleted files, numbered as deleted-files lists
+                                           them, to a folder on another disk
+  carve <drive-index|image-path> <destination> [--volume <n>] [--types <list>]
+        [--signatures <file>] [--align <bytes>] [--max-size <size>]
+                                           Recover files by their signatures from the whole drive,
+                                           or from the free space of one volume; types are any of
+                                           jpeg, png, gif, pdf, zip, mp4 and sqlite
+  folder-sizes <folder> [--top <n>]        Show what takes up the space in a folder or mounted
+                                           volume: its largest subfolders, files and extensions
+  find-duplicates <folder>... [--min-size <size>] [--export <file.csv>]
+        [--quarantine <folder>]
+                                           Find files with the same content by size, then hash;
+                                           --quarantine moves all but the first of each group
+  undo-quarantine <folder>                 Move quarantined duplicates back to where they were
+  smart <drive-index> [--save <prefix>]    Show the SMART attributes and health of an ATA drive;
+                                           --save also writes the raw pages to files
+  smart <data.bin> [thresholds.bin] [--model <model>]
+                                           Decode SMART pages saved earlier
+  nvme <drive-index> [--save <prefix>]     Show the health log and identify data of an NVMe drive;
+                                           --save also writes them to files
+  nvme [--health <file>] [--controller <file>] [--namespace <file>]
+                                           Decode NVMe health and identify data saved earlier
+  identify <drive-index> [--save <prefix>] Show the IDENTIFY DEVICE data of an ATA drive and any
+                                           capacity hidden by a Host Protected Area or DCO
+  identify <identify.bin> [dco.bin] [--native <sectors>]
+                                           Decode IDENTIFY DEVICE and DCO data saved earlier
+
+Without a command the graphical interface is started.";
+
+pub fn run(args: &[String]) -> i32 {
+    match args.first().map(String::as_str) {
+        Some("check-layout") => match args.get(1) {
+            Some(target) => check_layout(target),
+            None => usage(),
+        },
+        Some("repair-gpt") => match (args.get(1), args.get(2).and_then(|name| GptRepair::from_name(name)), args.get(3).map(String::as_str)) {
+            (Some(target), Some(action), None) if args.len() == 3 => repair(target, action, false),
+            (Some(target), Some(action), Some("--yes")) if args.len() == 4 => repair(target, action, true),
+            _ => usage(),
+        },
+        Some("verify-image") => match args.get(1) {
+            Some(path) => verify_image(path),
+            None => usage(),
+        },
+        Some("convert-image") => match (args.get(1), args.get(2), args.get(3).and_then(|name| ImageFormat::from_name(name))) {
+            (Some(source), Some(output), Some(format)) => convert(source, output, format, args.get(4).is_some_and(|flag| flag == "--verify")),
+            _ => usage(),
+        },
+        Some("list-files") => match (args.get(1), args.get(2).and_then(|index| index.parse().ok())) {
+            (Some(target), Some(volume)) => list_files(target, volume, args.get(3).map_or("/", String::as_str)),
+            (Some(target), None) if args.len() == 2 => list_volume_table(target),
+            _ => usage(),
+        },
+        Some("export-files") => match (args.get(1), args.get(2).and_then(|index| index.parse().ok()), args.get(3), args.get(4)) {
+            (Some(target), Some(volume), Some(path), Some(destination)) => export_files(target, volume, path, destination),
+            _ => usage(),
+        },
+        Some("file-clusters") => match (args.get(1), args.get(2).and_then(|index| index.parse().ok()), args.get(3)) {
+            (Some(target), Some(volume), Some(path)) => file_clusters(target, volume, path),
+            _ => usage(),
+        },
+        Some("fragmentation") => {
+            let json = args.iter().any(|arg| arg == "--json");
+            let rest: Vec<&String> = args.iter().skip(1).filter(|arg| *arg != "--json").collect();
+            match (rest.first(), rest.get(1).map(|index| index.parse::<usize>())) {
+                (Some(target), None) if rest.len() == 1 => fragmentation(target, None, json),
+                (Some(target), Some(Ok(volume))) if rest.len() == 2 => fragmentation(target, Some(volume), json),
+                _ => usage(),
+            }
+        }
+        Some("deleted-files") => match (args.get(1), args.get(2).and_then(|index| index.parse().ok())) {
+            (Some(target), Some(volume)) if args.len() == 3 => deleted_files(target, volume),
+            _ => usage(),
+        },
+        Some("recover-files") => {
+            let numbers: Option<Vec<usize>> = match args.get(4..) {
+                Some([all]) if all == "all" => Some(Vec::new()),
+                Some(numbers) if !numbers.is_empty() => numbers.iter().map(|number| number.parse().ok()).collect(),
+                _ => None,
+            };
+            match (args.get(1), args.get(2).and_then(|index| index.parse().ok()), args.get(3), numbers) {
+                (Some(target), Some(volume), Some(destination), Some(numbers)) => recover_files(target, volume, destination, &numbers),
+                _ => usage(),
+            }
+        }
+        Some("carve") => match (args.get(1), args.get(2)) {
+            (Some(target), Some(destination)) if !target.starts_with("--") && !destination.starts_with("--") => {
+                carve_files(target, destination, &args[3..])
+            }
+            _ => usage(),
+        },
+        Some("folder-sizes") => match (args.get(1), args.get(2).map(String::as_str), args.get(3).map(|count| count.parse::<usize>())) {
+            (Some(folder), None, None) => folder_sizes(folder, DEFAULT_SIZES_SHOWN),
+            (Some(folder), Some("--top"), Some(Ok(count))) if args.len() == 4 => folder_sizes(folder, count),
+            _ => usage(),
+        },
+        Some("find-duplicates") => duplicates(&args[1..]),
+        Some("undo-quarantine") => match args.get(1) {
+            Some(folder) if args.len() == 2 => restore_quarantine(folder),
+            _ => usage(),
+        },
+        Some("smart") => smart_command(&args[1..]),
+        Some("nvme") => nvme_command(&args[1..]),
+        Some("identify") => identify_command(&args[1..]),
+        Some("help") | Some("--help") | Some("-h") => {
+            println!("{}", USAGE);
+            0
+        }
+        _ => usage(),
+    }
+}
+
+fn usage() -> i32 {
+    eprintln!("{}", USAGE);
+    2
+}
+
+fn check_layout(target: &str) -> i32 {
+    let mut disk = match open_disk(target, false) {
+        Ok(disk) => disk,
+        Err(err) => {
+            eprintln!("Failed to open {}: {}", target, err);
+            return 1;
+        }
+    };
+    let problems = match check_disk_layout(disk.as_mut()) {
+        Ok(problems) => problems,
+        Err(err) => {
+            eprintln!("Failed to read the partition table of {}: {}", target, err);
+            return 1;
+        }
+    };
+    if problems.is_empty() {
+        println!("No problems found in the disk layout of {}.", target);
+        return 0;
+    }
+    for problem in &problems {
+        println!("{}", problem);
+    }
+    let errors = problems.iter().filter(|problem| problem.severity == Severity::Error).count();
+    let warnings = problems.iter().filter(|problem| problem.severity == Severity::Warning).count();
+    println!("{} error(s), {} warning(s)", errors, warnings);
+    if errors > 0 { 1 } else { 0 }
+}
+
+// Like the confirmation window of the GUI, names the drive and the change before
+// anything is written.
+fn confirm_repair(target: &str, action: GptRepair) -> bool {
+    let name = match target.parse::<usize>() {
+        Ok(index) => match crate::get_drive_model_and_type(index) {
+            Some((model, _)) => format!("drive {} ({})", index, model),
+            None => format!("drive {}", index),
+        },
+        Err(_) => target.to_string(),
+    };
+    print!("{} on {}? This writes to the disk. [y/N] ", action.description(), name);
+    let _ = std::io::stdout().flush();
+    let mut answer = String::new();
+    std::io::stdin().read_line(&mut answer).is_ok() && matches!(answer.trim().to_ascii_lowercase().as_str(), "y" | "yes")
+}
+
+fn repair(target: &str, action: GptRepair, confirmed: bool) -> i32 {
+    if !confirmed && !confirm_repair(target, action) {
+        eprintln!("Cancelled; nothing was written");
+        return 1;
+    }
+    let mut disk = match open_disk(target, true) {
+        Ok(disk) => disk,
+        Err(err) => {
+            eprintln!("Failed to open {} for writing: {}", target, err);
+            return 1;
+        }
+    };
+    match repair_gpt(disk.as_mut(), action) {
+        Ok(message) => {
+            println!("{}", message);
+            0
+        }
+        Err(err) => {
+            eprintln!("{} failed: {}", action.description(), err);
+            1
+        }
+    }
+}
+
+fn verify_image(path: &str) -> i32 {
+    let checks = match verify_ewf(Path::new(path), |_, _| {}) {
+        Ok(checks) => checks,
+        Err(err) => {
+            eprintln!("Failed to verify {}: {}", path, err);
+            return 1;
+        }
+    };
+    let mut mismatches = 0;
+    for check in &checks {
+        if check.matches() {
+            println!("{} verified: {}", check.algorithm, check.computed);
+        } else {
+            println!("{} MISMATCH: stored {}, computed {}", check.algorithm, check.stored, check.computed);
+            mismatches += 1;
+        }
+    }
+    if mismatches > 0 { 1 } else { 0 }
+}
+
+fn print_progress(label: &str, done: u64, total: u64, last_percent: &mut u64) {
+    let percent = (done * 100).checked_div(total).unwrap_or(100);
+    if percent != *last_percent {
+        *last_percent = percent;
+        eprint!("\r{}... {}%", label, percent);
+        let _ = std::io::stderr().flush();
+    }
+}
+
+fn convert(source: &str, output: &str, format: ImageFormat, verify: bool) -> i32 {
+    let mut disk = match open_disk(source, false) {
+        Ok(disk) => disk,
+        Err(err) => {
+            eprintln!("Failed to open {}: {}", source, err);
+            return 1;
+        }
+    };
+    let cancel = AtomicBool::new(false);
+    let mut last_percent = u64::MAX;
+    let result = convert_image(disk.as_mut(), Path::new(output), format, &cancel, |done, total| {
+        print_progress("Converting", done, total, &mut last_percent)
+    });
+    eprintln!();
+    match result {
+        Ok(summary) => println!("{}", summary.describe()),
+        Err(err) => {
+            eprintln!("Failed to convert {} to {}: {}", source, output, err);
+            return 1;
+        }
+    }
+    if !verify {
+        return 0;
+    }
+    let mut last_percent = u64::MAX;
+    let result = verify_conversion(disk.as_mut(), Path::new(output), &cancel, |done, total| {
+        print_progress("Verifying", done, total, &mut last_percent)
+    });
+    eprintln!();
+    match result {
+        Ok(check) if check.matches() => {
+            println!("Content verified: SHA1 {}", check.converted_sha1);
+            0
+        }
+        Ok(check) => {
+            println!("Content MISMATCH: source SHA1 {}, converted SHA1 {}", check.source_sha1, check.converted_sha1);
+            1
+        }
+        Err(err) => {
+            eprintln!("Failed to verify {}: {}", output, err);
+            1
+        }
+    }
+}
+
+fn list_volume_table(target: &str) -> i32 {
+    let mut disk = match open_disk(target, false) {
+        Ok(disk) => disk,
+        Err(err) => {
+            eprintln!("Failed to open {}: {}", target, err);
+            return 1;
+        }
+    };
+    match list_volumes(disk.as_mut()) {
+        Ok(volumes) => {
+            for (index, volume) in volumes.iter().enumerate() {
+                println!("{}: {} at byte {}, {} bytes", index + 1, volume.describe(), volume.partition.start, volume.partition.size);
+                let usage = open_filesystem(disk.as_mut(), volume).and_then(|mut filesystem| {
+                    let bitmap = filesystem.cluster_bitmap(disk.as_mut())?;
+                    Ok(SpaceUsage::from_bitmap(&bitmap, filesystem.cluster_size()))
+                });
+                if let Ok(usage) = usage {
+                    let percent = (usage.used * 100).checked_div(usage.total).unwrap_or(0);
+                    println!("   {} of {} bytes used ({}%), {} bytes free", usage.used, usage.total, percent, usage.free());
+                }
+            }
+            0
+        }
+        Err(err) => {
+            eprintln!("Failed to read the volumes of {}: {}", target, err);
+            1
+        }
+    }
+}
+
+type OpenVolume = (Box<dyn Disk>, Box<dyn FileSystem>);
+
+// Volumes are numbered from 1 in the order `list-files <target>` prints them.
+fn open_volume(target: &str, volume: usize) -> Result<OpenVolume, i32> {
+    let mut disk = open_disk(target, false).map_err(|err| {
+        eprintln!("Failed to open {}: {}", target, err);
+        1
+    })?;
+    let volumes = list_volumes(disk.as_mut()).map_err(|err| {
+        eprintln!("Failed to read the volumes of {}: {}", target, err);
+        1
+    })?;
+    let Some(selected) = volume.checked_sub(1).and_then(|index| volumes.get(index)) else {
+        eprintln!("{} has no volume {} ({} found)", target, volume, volumes.len());
+        return Err(1);
+    };
+    let filesystem = open_filesystem(disk.as_mut(), selected).map_err(|err| {
+        eprintln!("Failed to open volume {} of {}: {}", volume, target, err);
+        1
+    })?;
+    Ok((disk, filesystem))
+}
+
+fn list_files(target: &str, volume: usize, path: &str) -> i32 {
+    let (mut disk, mut filesystem) = match open_volume(target, volume) {
+        Ok(opened) => opened,
+        Err(code) => return code,
+    };
+    let listing = find_entry(filesystem.as_mut(), disk.as_mut(), path).and_then(|entry| match entry.is_dir {
+        true => filesystem.read_dir(disk.as_mut(), &entry),
+        false => Ok(vec![entry]),
+    });
+    match listing {
+        Ok(entries) => {
+            for entry in entries {
+                let modified = entry.modified.as_deref().unwrap_or("-");
+                let size = if entry.is_dir { "<DIR>".to_string() } else { entry.size.to_string() };
+                match &entry.symlink {
+                    Some(target) => println!("{:<23} {:>14}  {} -> {}", modified, "<LINK>", entry.name, target),
+                    None => println!("{:<23} {:>14}  {}", modified, size, entry.name),
+                }
+            }
+            0
+        }
+        Err(err) => {
+            eprintln!("Failed to list {}: {}", path, err);
+            1
+        }
+    }
+}
+
+fn export_files(target: &str, volume: usize, path: &str, destination: &str) -> i32 {
+    let (mut disk, mut filesystem) = match open_volume(target, volume) {
+        Ok(opened) => opened,
+        Err(code) => return code,
+    };
+    let result = find_entry(filesystem.as_mut(), disk.as_mut(), path).and_then(|entry| {
+        export(filesystem.as_mut(), disk.as_mut(), &entry, Path::new(destination), &mut |path, _| eprintln!("{}", path))
+    });
+    match result {
+        Ok(summary) => {
+            println!("Exported {} to {}", summary.describe(), destination);
+            0
+        }
+        Err(err) => {
+            eprintln!("Failed to export {}: {}", path, err);
+            1
+        }
+    }
+}
+
+fn file_clusters(target: &str, volume: usize, path: &str) -> i32 {
+    let (mut disk, mut filesystem) = match open_volume(target, volume) {
+        Ok(opened) => opened,
+        Err(code) => return code,
+    };
+    let result = find_entry(filesystem.as_mut(), disk.as_mut(), path)
+        .and_then(|entry| filesystem.allocation(disk.as_mut(), &entry));
+    match result {
+        Ok(extents) => {
+            let clusters: u64 = extents.iter().map(|extent| extent.count).sum();
+            println!("{} cluster(s) of {} bytes in {} fragment(s)", clusters, filesystem.cluster_size(), extents.len());
+            println!("{}", format_extents(&extents));
+            0
+        }
+        Err(err) => {
+            eprintln!("Failed to read the clusters of {}: {}", path, err);
+            1
+        }
+    }
+}
+
+fn deleted_files(target: &str, volume: usize) -> i32 {
+    let (mut disk, mut filesystem) = match open_volume(target, volume) {
+        Ok(opened) => opened,
+        Err(code) => return code,
+    };
+    match scan_deleted(filesystem.as_mut(), disk.as_mut()) {
+        Ok(files) => {
+            for (number, file) in files.iter().enumerate() {
+                let modified = file.entry.modified.as_deref().unwrap_or("-");
+                println!("{:>6}  {:<23} {:>14}  {:<34} {}", number + 1, modified, file.entry.size, file.describe_recoverability(), file.entry.path);
+                if let Some(error) = &file.error {
+                    println!("        {}", error);
+                }
+            }
+            println!("{} deleted file(s) found", files.len());
+            0
+        }
+        Err(err) => {
+            eprintln!("Failed to scan volume {} of {} for deleted files: {}", volume, target, err);
+            1
+        }
+    }
+}
+
+// An empty list of numbers restores every deleted file found.
+fn recover_files(target: &str, volume: usize, destination: &str, numbers: &[usize]) -> i32 {
+    let (mut disk, mut filesystem) = match open_volume(target, volume) {
+        Ok(opened) => opened,
+        Err(code) => return code,
+    };
+    let files = match scan_deleted(filesystem.as_mut(), disk.as_mut()) {
+        Ok(files) => files,
+        Err(err) => {
+            eprintln!("Failed to scan volume {} of {} for deleted files: {}", volume, target, err);
+            return 1;
+        }
+    };
+    let mut selected = Vec::new();
+    for number in numbers {
+        match number.checked_sub(1).and_then(|index| files.get(index)) {
+            Some(file) => selected.push(file.entry.clone()),
+            None => {
+                eprintln!("There is no deleted file {} ({} found)", number, files.len());
+                return 1;
+            }
+        }
+    }
+    if numbers.is_empty() {
+        selected = files.into_iter().map(|file| file.entry).collect();
+    }
+    let result = restore(filesystem.as_mut(), disk.as_mut(), target, &selected, Path::new(destination), &mut |path| eprintln!("{}", path));
+    match result {
+        Ok(summary) => {
+            for (path, error) in &summary.failed {
+                eprintln!("Failed to restore {}: {}", path, error);
+            }
+            println!("Restored {} to {}", summary.describe(), destination);
+            if summary.failed.is_empty() { 0 } else { 1 }
+        }
+        Err(err) => {
+            eprintln!("Failed to restore to {}: {}", destination, err);
+            1
+        }
+    }
+}
+
+const DEFAULT_CARVE_ALIGN: u64 = 512;
+
+fn carve_files(target: &str, destination: &str, flags: &[String]) -> i32 {
+    let mut volume = None;
+    let mut types: Option<Vec<String>> = None;
+    let mut signature_file = None;
+    let mut align = DEFAULT_CARVE_ALIGN;
+    let mut max_size = None;
+    for pair in flags.chunks(2) {
+        let [flag, value] = pair else {
+            return usage();
+        };
+        match flag.as_str() {
+            "--volume" => match value.parse::<usize>() {
+                Ok(index) => volume = Some(index),
+                Err(_) => return usage(),
+            },
+            "--types" => types = Some(value.split(',').map(|name| name.trim().to_ascii_lowercase()).collect()),
+            "--signatures" => signature_file = Some(value),
+            "--align" => match value.parse::<u64>() {
+                Ok(bytes) if bytes > 0 => align = bytes,
+                _ => return usage(),
+            },
+            "--max-size" => match parse_size(value) {
+                Some(size) if size > 0 => max_size = Some(size),
+                _ => return usage(),
+            },
+            _ => return usage(),
+        }
+    }
+
+    let mut signatures = builtin_signatures();
+    if let Some(types) = &types {
+        if let Some(unknown) = types.iter().find(|name| !signatures.iter().any(|signature| signature.name.eq_ignore_ascii_case(name))) {
+            eprintln!("Unknown file type {}", unknown);
+            return 1;
+        }
+        signatures.retain(|signature| types.iter().any(|name| signature.name.eq_ignore_ascii_case(name)));
+    }
+    if let Some(path) = signature_file {
+        match std::fs::read_to_string(path).and_then(|text| parse_signatures(&text)) {
+            Ok(custom) => signatures.extend(custom),
+            Err(err) => {
+                eprintln!("Failed to read signatures from {}: {}", path, err);
+                return 1;
+            }
+        }
+    }
+    if let Some(max_size) = max_size {
+        for signature in &mut signatures {
+            signature.max_size = signature.max_size.min(max_size);
+        }
+    }
+
+    let (mut disk, ranges) = match volume {
+        Some(volume) => {
+            let (mut disk, mut filesystem) = match open_volume(target, volume) {
+                Ok(opened) => opened,
+                Err(code) => return code,
+            };
+            match unallocated_ranges(filesystem.as_mut(), disk.as_mut()) {
+                Ok(ranges) => (disk, ranges),
+                Err(err) => {
+                    eprintln!("Failed to read the free space of volume {}: {}", volume, err);
+                    return 1;
+                }
+            }
+        }
+        None => match open_disk(target, false) {
+            Ok(disk) => {
+                let size = disk.size();
+                (disk, vec![(0, size)])
+            }
+            Err(err) => {
+                eprintln!("Failed to open {}: {}", target, err);
+                return 1;
+            }
+        },
+    };
+    let options = CarveOptions {
+        signatures,
+        align,
+        destination: Path::new(destination).to_path_buf(),
+    };
+    let total: u64 = ranges.iter().map(|(start, end)| end - start).sum();
+    let mut last_percent = u64::MAX;
+    let result = carve(disk.as_mut(), target, &ranges, &options, &AtomicBool::new(false), &mut |scanned, _| {
+        print_progress("Scanning", scanned, total, &mut last_percent)
+    });
+    eprintln!();
+    match result {
+        Ok(summary) => {
+            for file in &summary.files {
+                let note = if file.truncated { " (truncated)" } else { "" };
+                println!("{:>16} {:>14}  {:<8} {}{}", format!("{:012x}", file.offset), file.length, file.signature, file.path.display(), note);
+            }
+            println!("Carved {}; list in {}", summary.describe(), options.destination.join(REPORT_NAME).display());
+            0
+        }
+        Err(err) => {
+            eprintln!("Carving failed: {}", err);
+            1
+        }
+    }
+}
+
+const MOST_FRAGMENTED_SHOWN: usize = 20;
+
+// Without a volume number every volume is analysed, and those that cannot be are noted
+// rather than failing the run.
+fn fragmentation(target: &str, volume: Option<usize>, json: bool) -> i32 {
+    let mut disk = match open_disk(target, false) {
+        Ok(disk) => disk,
+        Err(err) => {
+            eprintln!("Failed to open {}: {}", target, err);
+            return 1;
+        }
+    };
+    let volumes = match list_volumes(disk.as_mut()) {
+        Ok(volumes) => volumes,
+        Err(err) => {
+            eprintln!("Failed to read the volumes of {}: {}", target, err);
+            return 1;
+        }
+    };
+    let selected: Vec<usize> = match volume {
+        Some(volume) if (1..=volumes.len()).contains(&volume) => vec![volume - 1],
+        Some(volume) => {
+            eprintln!("{} has no volume {} ({} found)", target, volume, volumes.len());
+            return 1;
+        }
+        None => (0..volumes.len()).collect(),
+    };
+    let mut failed = false;
+    let mut json_reports = Vec::new();
+    for index in selected {
+        let description = volumes[index].describe();
+        let result = open_filesystem(disk.as_mut(), &volumes[index]).and_then(|mut filesystem| {
+            FragmentationReport::analyze(filesystem.as_mut(), disk.as_mut(), MOST_FRAGMENTED_SHOWN, &mut |_| {})
+        });
+        if result.is_err() && volume.is_some() {
+            failed = true;
+        }
+        match (json, result) {
+            (true, Ok(report)) => json_reports.push(format!(
+                "{{\"volume\": {}, \"description\": {}, \"report\": {}}}",
+                index + 1,
+                json_string(&description),
+                report.to_json()
+            )),
+            (true, Err(err)) => json_reports.push(format!(
+                "{{\"volume\": {}, \"description\": {}, \"error\": {}}}",
+                index + 1,
+                json_string(&description),
+                json_string(&err.to_string())
+            )),
+            (false, Ok(report)) => print!("{}: {}\n{}\n", index + 1, description, report.describe()),
+            (false, Err(err)) => println!("{}: {}\nNot analysed: {}\n", index + 1, description, err),
+        }
+    }
+    if json {
+        println!("[{}]", json_reports.join(",\n "));
+    }
+    if failed { 1 } else { 0 }
+}
+
+const DEFAULT_SIZES_SHOWN: usize = 20;
+
+fn folder_sizes(folder: &str, count: usize) -> i32 {
+    let progress = ScanProgress::default();
+    let tree = match SizeTree::scan(Path::new(folder), &AtomicBool::new(false), &progress) {
+        Ok(tree) => tree,
+        Err(err) => {
+            eprintln!("Failed to scan {}: {}", folder, err);
+            return 1;
+        }
+    };
+    let root = &tree.nodes[0];
+    println!("{}: {} in {} file(s)", folder, format_size(root.size), root.files);
+    if tree.unreadable > 0 || tree.skipped_links > 0 {
+        println!("{} folder(s) could not be read; {} link(s) not followed", tree.unreadable, tree.skipped_links);
+    }
+    let share = |size: u64| if root.size == 0 { 0.0 } else { size as f64 * 100.0 / root.size as f64 };
+
+    println!("\nLargest entries:");
+    for child in root.children.iter().take(count) {
+        let node = &tree.nodes[*child];
+        let name = if node.directory { format!("{}/", node.name) } else { node.name.clone() };
+        println!("{:>12} {:>6.1}% {:>10}  {}", format_size(node.size), share(node.size), node.files, name);
+    }
+    println!("\nLargest files:");
+    for file in tree.largest_files(0, count) {
+        println!("{:>12}  {}", format_size(tree.nodes[file].size), tree.path(file).display());
+    }
+    println!("\nExtensions:");
+    for extension in tree.extensions(0).iter().take(count) {
+        println!("{:>12} {:>6.1}% {:>10}  {}", format_size(extension.bytes), share(extension.bytes), extension.files, extension.extension);
+    }
+    0
+}
+
+fn duplicates(args: &[String]) -> i32 {
+    let mut roots = Vec::new();
+    let mut min_size = 1;
+    let mut export_path = None;
+    let mut quarantine_path = None;
+    let mut index = 0;
+    while index < args.len() {
+        let arg = &args[index];
+        if !arg.starts_with("--") {
+            roots.push(PathBuf::from(arg));
+            index += 1;
+            continue;
+        }
+        let Some(value) = args.get(index + 1) else {
+            return usage();
+        };
+        match arg.as_str() {
+            "--min-size" => match parse_size(value) {
+                Some(size) => min_size = size,
+                None => return usage(),
+            },
+            "--export" => export_path = Some(value),
+            "--quarantine" => quarantine_path = Some(value),
+            _ => return usage(),
+        }
+        index += 2;
+    }
+    if roots.is_empty() {
+        return usage();
+    }
+
+    let cancel = AtomicBool::new(false);
+    let mut last_stage = None;
+    let mut last_percent = u64::MAX;
+    let report = find_duplicates(&roots, min_size, &cancel, &mut |stage, done, total| {
+        if last_stage != Some(stage) {
+            if last_stage.is_some() {
+                eprintln!();
+            }
+            last_stage = Some(stage);
+            last_percent = u64::MAX;
+        }
+        print_progress(stage.name(), done, total, &mut last_percent);
+    });
+    if last_stage.is_some() {
+        eprintln!();
+    }
+    let report = match report {
+        Ok(report) => report,
+        Err(err) => {
+            eprintln!("Failed to search for duplicates: {}", err);
+            return 1;
+        }
+    };
+    for (number, group) in report.groups.iter().enumerate() {
+        println!("Group {}: {} copies of {}, {} reclaimable", number + 1, group.files.len(), format_size(group.size), format_size(group.reclaimable()));
+        for file in &group.files {
+            println!("    {}", file.display());
+        }
+    }
+    println!("{}", report.describe());
+
+    if let Some(export_path) = export_path {
+        if let Err(err) = write_csv(&report, Path::new(export_path)) {
+            eprintln!("Failed to export the list to {}: {}", export_path, err);
+            return 1;
+        }
+        println!("List exported to {}", export_path);
+    }
+    let Some(quarantine_path) = quarantine_path else {
+        return 0;
+    };
+    let keep = vec![0; report.groups.len()];
+    match quarantine(&report.groups, &keep, Path::new(quarantine_path), &mut |_| {}) {
+        Ok(summary) => {
+            for (path, error) in &summary.failed {
+                eprintln!("Failed to move {}: {}", path.display(), error);
+            }
+            println!("Moved {} to {}; {} lists them for undo-quarantine", summary.describe(), quarantine_path, MANIFEST_NAME);
+            if summary.failed.is_empty() { 0 } else { 1 }
+        }
+        Err(err) => {
+            eprintln!("Failed to quarantine to {}: {}", quarantine_path, err);
+            1
+        }
+    }
+}
+
+fn restore_quarantine(folder: &str) -> i32 {
+    match undo_quarantine(Path::new(folder), &mut |_| {}) {
+        Ok(summary) => {
+            for (path, error) in &summary.failed {
+                eprintln!("Failed to move back {}: {}", path.display(), error);
+            }
+            println!("Moved back {}", summary.describe());
+            if summary.failed.is_empty() { 0 } else { 1 }
+        }
+        Err(err) => {
+            eprintln!("Failed to undo the quarantine in {}: {}", folder, err);
+            1
+        }
+    }
+}
+
+fn smart_command(args: &[String]) -> i32 {
+    let mut files = Vec::new();
+    let mut save = None;
+    let mut model = None;
+    let mut index = 0;
+    while index < args.len() {
+        let arg = &args[index];
+        if !arg.starts_with("--") {
+            files.push(arg.as_str());
+            index += 1;
+            continue;
+        }
+        match (arg.as_str(), args.get(index + 1)) {
+            ("--save", Some(value)) => save = Some(value.as_str()),
+            ("--model", Some(value)) => model = Some(value.as_str()),
+            _ => return usage(),
+        }
+        index += 2;
+    }
+    match (files.as_slice(), save, model) {
+        ([drive], save, None) if drive.parse::<usize>().is_ok() => smart(drive.parse().unwrap(), save),
+        ([data], None, model) => smart_files(data, None, model.unwrap_or_default()),
+        ([data, thresholds], None, model) => smart_files(data, Some(thresholds), model.unwrap_or_default()),
+        _ => usage(),
+    }
+}
+
+// Writes each page to `<prefix>-<suffix>.bin`, never over an existing file.
+fn save_pages(prefix: &str, pages: &[(&str, &[u8])]) -> bool {
+    for (suffix, page) in pages {
+        let path = format!("{}-{}.bin", prefix, suffix);
+        let result = std::fs::OpenOptions::new().write(true).create_new(true).open(&path).and_then(|mut file| file.write_all(page));
+        if let Err(err) = result {
+            eprintln!("Failed to save {}: {}", path, err);
+            return false;
+        }
+        println!("Saved {}", path);
+    }
+    true
+}
+
+// Live pages can be saved to decode later, or elsewhere, with `smart <data.bin>`.
+fn smart(index: usize, save: Option<&str>) -> i32 {
+    let (data, thresholds) = match read_smart_pages(index) {
+        Ok(pages) => pages,
+        Err(err) => {
+            eprintln!("Failed to read SMART data from drive {}: {}", index, err);
+            return 1;
+        }
+    };
+    if let Some(prefix) = save {
+        if !save_pages(prefix, &[("data", &data), ("thresholds", &thresholds)]) {
+            return 1;
+        }
+    }
+    let model = crate::get_drive_model_and_type(index).map(|(model, _)| model).unwrap_or_default();
+    print_smart(&data, Some(&thresholds), &model)
+}
+
+fn smart_files(data: &str, thresholds: Option<&str>, model: &str) -> i32 {
+    let read = |path: &str| std::fs::read(path).map_err(|err| eprintln!("Failed to read {}: {}", path, err));
+    let Ok(data) = read(data) else {
+        return 1;
+    };
+    let thresholds = match thresholds.map(read).transpose() {
+        Ok(thresholds) => thresholds,
+        Err(()) => return 1,
+    };
+    print_smart(&data, thresholds.as_deref(), model)
+}
+
+fn print_smart(data: &[u8], thresholds: Option<&[u8]>, model: &str) -> i32 {
+    let smart = match SmartData::parse(data, thresholds, model) {
+        Ok(smart) => smart,
+        Err(err) => {
+            eprintln!("Failed to decode the SMART data: {}", err);
+            return 1;
+        }
+    };
+    println!("SMART data revision {}", smart.revision);
+    println!(" ID  {:<34} {:<20} {:>5} {:>5} {:>6}  Raw", "Attribute", "Type", "Value", "Worst", "Thresh");
+    for attribute in &smart.attributes {
+        let threshold = attribute.threshold.map_or("-".to_string(), |threshold| threshold.to_string());
+        let marker = if attribute.failing_now() { "  FAILING" } else { "" };
+        println!(
+            "{:>3}  {:<34} {:<20} {:>5} {:>5} {:>6}  {}{}",
+            attribute.id, attribute.name, attribute.kind(), attribute.current, attribute.worst, threshold, attribute.raw_text, marker
+        );
+    }
+    println!("{}", smart.describe_offline_collection());
+    println!("{}", smart.describe_self_test());
+    let (verdict, reasons) = smart.assess();
+    println!("Health: {}", verdict.name());
+    for reason in &reasons {
+        println!("    {}", reason);
+    }
+    0
+}
+
+fn nvme_command(args: &[String]) -> i32 {
+    match args {
+        [drive] => match drive.parse() {
+            Ok(index) => return nvme(index, None),
+            Err(_) => return usage(),
+        },
+        [drive, flag, prefix] if flag == "--save" => match drive.parse() {
+            Ok(index) => return nvme(index, Some(prefix)),
+            Err(_) => return usage(),
+        },
+        _ => {}
+    }
+    let mut files = [None, None, None];
+    for pair in args.chunks(2) {
+        let [flag, path] = pair else {
+            return usage();
+        };
+        let slot = match flag.as_str() {
+            "--health" => 0,
+            "--controller" => 1,
+            "--namespace" => 2,
+            _ => return usage(),
+        };
+        match std::fs::read(path) {
+            Ok(data) => files[slot] = Some(data),
+            Err(err) => {
+                eprintln!("Failed to read {}: {}", path, err);
+                return 1;
+            }
+        }
+    }
+    if files.iter().all(Option::is_none) {
+        return usage();
+    }
+    let [health, controller, namespace] = files;
+    print_nvme(health.as_deref(), controller.as_deref(), namespace.as_deref())
+}
+
+fn nvme(index: usize, save: Option<&str>) -> i32 {
+    let pages = match read_nvme_pages(index) {
+        Ok(pages) => pages,
+        Err(err) => {
+            eprintln!("Failed to read NVMe data from drive {}: {}", index, err);
+            return 1;
+        }
+    };
+    if let Some(prefix) = save {
+        if !save_pages(prefix, &[("health", &pages.health), ("controller", &pages.controller), ("namespace", &pages.namespace)]) {
+            return 1;
+        }
+    }
+    print_nvme(Some(&pages.health), Some(&pages.controller), Some(&pages.namespace))
+}
+
+fn print_nvme(health: Option<&[u8]>, controller: Option<&[u8]>, namespace: Option<&[u8]>) -> i32 {
+    let mut failed = false;
+    let mut print_details = |title: &str, details: std::io::Result<Vec<(String, String)>>| match details {
+        Ok(details) => {
+            println!("{}:", title);
+            for (key, value) in details {
+                println!("    {}: {}", key, value);
+            }
+        }
+        Err(err) => {
+            eprintln!("Failed to decode the {}: {}", title.to_lowercase(), err);
+            failed = true;
+        }
+    };
+    if let Some(controller) = controller {
+        print_details("Controller", NvmeController::parse(controller).map(|controller| controller.details()));
+    }
+    if let Some(namespace) = namespace {
+        print_details("Namespace 1", NvmeNamespace::parse(namespace).map(|namespace| namespace.details()));
+    }
+    if let Some(health) = health {
+        let health = NvmeHealth::parse(health);
+        let assessment = health.as_ref().ok().map(NvmeHealth::assess);
+        print_details("Health log", health.map(|health| health.details()));
+        if let Some((verdict, reasons)) = assessment {
+            println!("Health: {}", verdict.name());
+            for reason in &reasons {
+                println!("    {}", reason);
+            }
+        }
+    }
+    if failed { 1 } else { 0 }
+}
+
+fn identify_command(args: &[String]) -> i32 {
+    let mut files = Vec::new();
+    let mut save = None;
+    let mut native = None;
+    let mut index = 0;
+    while index < args.len() {
+        let arg = &args[index];
+        if !arg.starts_with("--") {
+            files.push(arg.as_str());
+            index += 1;
+            continue;
+        }
+        match (arg.as_str(), args.get(index + 1)) {
+            ("--save", Some(value)) => save = Some(value.as_str()),
+            ("--native", Some(value)) => match value.parse::<u64>() {
+                Ok(sectors) => native = Some(sectors),
+                Err(_) => return usage(),
+            },
+            _ => return usage(),
+        }
+        index += 2;
+    }
+    match (files.as_slice(), save, native) {
+        ([drive], save, None) if drive.parse::<usize>().is_ok() => identify(drive.parse().unwrap(), save),
+        ([identify], None, native) => identify_files(identify, None, native),
+        ([identify, dco], None, native) => identify_files(identify, Some(dco), native),
+        _ => usage(),
+    }
+}
+
+// The native size is not a page of its own; `--native` takes it from an earlier run.
+fn identify(index: usize, save: Option<&str>) -> i32 {
+    let pages = match read_identify_pages(index) {
+        Ok(pages) => pages,
+        Err(err) => {
+            eprintln!("Failed to read IDENTIFY data from drive {}: {}", index, err);
+            return 1;
+        }
+    };
+    if let Some(prefix) = save {
+        let mut saved = vec![("identify", &pages.identify[..])];
+        saved.extend(pages.dco.as_deref().map(|dco| ("dco", dco)));
+        if !save_pages(prefix, &saved) {
+            return 1;
+        }
+    }
+    print_identify(&pages.identify, pages.native_sectors, pages.dco.as_deref())
+}
+
+fn identify_files(identify: &str, dco: Option<&str>, native: Option<u64>) -> i32 {
+    let read = |path: &str| std::fs::read(path).map_err(|err| eprintln!("Failed to read {}: {}", path, err));
+    let Ok(identify) = read(identify) else {
+        return 1;
+    };
+    let dco = match dco.map(read).transpose() {
+        Ok(dco) => dco,
+        Err(()) => return 1,
+    };
+    print_identify(&identify, native, dco.as_deref())
+}
+
+fn print_identify(identify: &[u8], native: Option<u64>, dco: Option<&[u8]>) -> i32 {
+    let identity = match AtaIdentity::parse(identify) {
+        Ok(identity) => identity,
+        Err(err) => {
+            eprintln!("Failed to decode the IDENTIFY data: {}", err);
+            return 1;
+        }
+    };
+    for (key, value) in identity.details() {
+        println!("{}: {}", key, value);
+    }
+    let hidden = HiddenArea::new(&identity, native, dco);
+    for (key, value) in hidden.details() {
+        println!("{}: {}", key, value);
+    }
+    if hidden.hidden_sectors() != 0 {
+        println!(
+            "{} at the end of the drive is hidden from the operating system",
+            format_size(hidden.hidden_sectors().saturating_mul(identity.logical_sector_size))
+        );
+    }
+    0
+}
@@ -0,0 +1,501 @@
+use crate::disk_usage::format_size;
+use crate::vdisk::invalid_data;
+use std::io;
+
+pub const IDENTIFY_DEVICE_SIZE: usize = 512;
+// IDENTIFY DEVICE and DEVICE CONFIGURATION IDENTIFY end with this signature when the
+// last byte holds a checksum.
+const CHECKSUM_SIGNATURE: u8 = 0xA5;
+const DCO_REVISION: u16 = 0x0002;
+
+// (word, bit, name) of supported features in words 82 to 84 and 76.
+const SUPPORTED_FEATURES: [(usize, u16, &str); 12] = [
+    (82, 0x0001, "SMART"),
+    (82, 0x0002, "Security"),
+    (82, 0x0020, "Write cache"),
+    (82, 0x0040, "Read look-ahead"),
+    (82, 0x0400, "Host Protected Area"),
+    (83, 0x0008, "Advanced Power Management"),
+    (83, 0x0200, "Automatic Acoustic Management"),
+    (83, 0x0400, "48-bit LBA"),
+    (83, 0x0800, "Device Configuration Overlay"),
+    (84, 0x0002, "SMART self-test"),
+    (84, 0x0020, "General Purpose Logging"),
+    (76, 0x0100, "Native Command Queuing"),
+];
+
+fn word(data: &[u8], index: usize) -> u16 {
+    u16::from_le_bytes([data[index * 2], data[index * 2 + 1]])
+}
+
+fn words_u64(data: &[u8], first: usize, count: usize) -> u64 {
+    (0..count).rev().fold(0u64, |value, index| value << 16 | u64::from(word(data, first + index)))
+}
+
+// ATA strings store two characters per word, first character in the high byte.
+fn ata_string(data: &[u8], first: usize, last: usize) -> String {
+    let bytes: Vec<u8> = (first..=last).flat_map(|index| word(data, index).to_be_bytes()).collect();
+    String::from_utf8_lossy(&bytes).trim_matches(|c: char| c == '\0' || c.is_whitespace()).to_string()
+}
+
+fn checksum_valid(data: &[u8]) -> Option<bool> {
+    (data[510] == CHECKSUM_SIGNATURE).then(|| data.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte)) == 0)
+}
+
+fn check_size(structure: &str, data: &[u8]) -> io::Result<()> {
+    if data.len() != IDENTIFY_DEVICE_SIZE {
+        return Err(invalid_data(format!("{} is {} bytes, expected {}", structure, data.len(), IDENTIFY_DEVICE_SIZE)));
+    }
+    Ok(())
+}
+
+// IDENTIFY DEVICE data (command ECh) of an ATA drive.
+pub struct AtaIdentity {
+    pub model: String,
+    pub serial: String,
+    pub firmware: String,
+    pub sectors: u64,
+    pub logical_sector_size: u64,
+    pub physical_sector_size: u64,
+    pub alignment_offset: u16,
+    pub rotation_rate: u16,
+    pub major_version: u16,
+    pub sata_speed: u16,
+    pub command_sets: [u16; 3],
+    pub security: u16,
+    pub erase_minutes: u16,
+    pub enhanced_erase_minutes: u16,
+    pub trim: bool,
+    pub trim_zeroes: bool,
+    pub checksum_valid: Option<bool>,
+}
+
+impl AtaIdentity {
+    pub fn parse(data: &[u8]) -> io::Result<Self> {
+        check_size("IDENTIFY DEVICE data", data)?;
+        // Word 0 bit 15 is set by ATAPI devices, which answer IDENTIFY PACKET DEVICE instead.
+        if word(data, 0) & 0x8000 != 0 {
+            return Err(invalid_data("the IDENTIFY data belongs to an ATAPI device, not a disk"));
+        }
+        let command_sets = [word(data, 82), word(data, 83), word(data, 84)];
+        let lba48 = command_sets[1] & 0x0400 != 0;
+        let sectors = match lba48 {
+            true => words_u64(data, 100, 4),
+            false => words_u64(data, 60, 2),
+        };
+        // Word 106 is only meaningful when bit 14 is set and bit 15 clear.
+        let sector_info = word(data, 106);
+        let sector_info = if sector_info & 0xC000 == 0x4000 { sector_info } else { 0 };
+        let logical_sector_size = match sector_info & 0x1000 {
+            0 => 512,
+            _ => words_u64(data, 117, 2) * 2,
+        };
+        let physical_sector_size = match sector_info & 0x2000 {
+            0 => logical_sector_size,
+            _ => logical_sector_size << (sector_info & 0x0F),
+        };
+        let alignment = word(data, 209);
+        Ok(Self {
+            model: ata_string(data, 27, 46),
+            serial: ata_string(data, 10, 19),
+            firmware: ata_string(data, 23, 26),
+            sectors,
+            logical_sector_size,
+            physical_sector_size,
+            alignment_offset: if alignment & 0xC000 == 0x4000 { alignment & 0x3FFF } else { 0 },
+            rotation_rate: word(data, 217),
+            major_version: word(data, 80),
+            sata_speed: word(data, 76),
+            command_sets,
+            security: word(data, 128),
+            erase_minutes: word(data, 89),
+            enhanced_erase_minutes: word(data, 90),
+            trim: word(data, 169) & 0x0001 != 0,
+            trim_zeroes: word(data, 69) & 0x4020 == 0x4020,
+            checksum_valid: checksum_valid(data),
+        })
+    }
+
+    pub fn lba48(&self) -> bool {
+        self.command_sets[1] & 0x0400 != 0
+    }
+
+    pub fn hpa_supported(&self) -> bool {
+        self.command_sets[0] & 0x0400 != 0
+    }
+
+    pub fn dco_supported(&self) -> bool {
+        self.command_sets[1] & 0x0800 != 0
+    }
+
+    pub fn features(&self) -> Vec<&'static str> {
+        let mut features: Vec<&str> = SUPPORTED_FEATURES
+            .iter()
+            .filter(|(word, bit, _)| match word {
+                76 => self.sata_speed != 0xFFFF && self.sata_speed & bit != 0,
+                _ => self.command_sets[word - 82] & bit != 0,
+            })
+            .map(|(_, _, name)| *name)
+            .collect();
+        if self.trim {
+            features.push("TRIM");
+        }
+        features
+    }
+
+    fn describe_rotation(&self) -> String {
+        match self.rotation_rate {
+            0 => "Not reported".to_string(),
+            1 => "Solid state (non-rotating)".to_string(),
+            rate @ 0x0401..=0xFFFE => format!("{} rpm", rate),
+            rate => format!("Reserved value {:#06x}", rate),
+        }
+    }
+
+    // The highest ATA/ATAPI standard the drive claims, from the bits of word 80.
+    fn describe_version(&self) -> String {
+        const VERSIONS: [&str; 12] = ["ATA-1", "ATA-2", "ATA-3", "ATA/ATAPI-4", "ATA/ATAPI-5", "ATA/ATAPI-6", "ATA/ATAPI-7", "ATA8-ACS", "ACS-2", "ACS-3", "ACS-4", "ACS-5"];
+        match self.major_version {
+            0 | 0xFFFF => "Not reported".to_string(),
+            bits => match (1..=12).rev().find(|bit| bits & (1 << bit) != 0) {
+                Some(bit) => VERSIONS[bit - 1].to_string(),
+                None => format!("{:#06x}", bits),
+            },
+        }
+    }
+
+    fn describe_sata_speed(&self) -> Option<&'static str> {
+        match self.sata_speed {
+            0 | 0xFFFF => None,
+            speed if speed & 0x0008 != 0 => Some("SATA 6.0 Gb/s"),
+            speed if speed & 0x0004 != 0 => Some("SATA 3.0 Gb/s"),
+            speed if speed & 0x0002 != 0 => Some("SATA 1.5 Gb/s"),
+            _ => None,
+        }
+    }
+
+    pub fn describe_security(&self) -> String {
+        if self.security & 0x0001 == 0 {
+            return "Not supported".to_string();
+        }
+        let mut states = vec![if self.security & 0x0002 != 0 { "enabled (password set)" } else { "disabled" }];
+        if self.security & 0x0004 != 0 {
+            states.push("locked");
+        }
+        if self.security & 0x0008 != 0 {
+            states.push("frozen");
+        }
+        if self.security & 0x0010 != 0 {
+            states.push("password attempts exceeded");
+        }
+        if self.security & 0x0020 != 0 {
+            states.push("enhanced erase supported");
+        }
+        states.join(", ")
+    }
+
+    // Erase times are in units of two minutes, in bits 7:0 or, when bit 15 is set, in the
+    // extended format of bits 14:0; the largest value of either means longer than that.
+    fn describe_erase_time(value: u16) -> Option<String> {
+        let (units, longest) = match value & 0x8000 {
+            0 => (value & 0x00FF, 0x00FF),
+            _ => (value & 0x7FFF, 0x7FFF),
+        };
+        match units {
+            0 => None,
+            units if units == longest => Some(format!("more than {} minutes", (u32::from(units) - 1) * 2)),
+            units => Some(format!("{} minutes", u32::from(units) * 2)),
+        }
+    }
+
+    pub fn details(&self) -> Vec<(String, String)> {
+        let mut details = vec![
+            ("Model".to_string(), self.model.clone()),
+            ("Serial number".to_string(), self.serial.clone()),
+            ("Firmware".to_string(), self.firmware.clone()),
+            ("Standard".to_string(), self.describe_version()),
+            (
+                "Capacity".to_string(),
+                format!("{} sectors ({})", self.sectors, format_size(self.sectors.saturating_mul(self.logical_sector_size))),
+            ),
+            ("Addressing".to_string(), if self.lba48() { "48-bit LBA" } else { "28-bit LBA" }.to_string()),
+        ];
+        let sectors = match self.physical_sector_size == self.logical_sector_size {
+            true => format!("{} bytes", self.logical_sector_size),
+            false => format!("{} bytes logical, {} bytes physical", self.logical_sector_size, self.physical_sector_size),
+        };
+        details.push(("Sector size".to_string(), sectors));
+        if self.alignment_offset != 0 {
+            details.push(("Logical sector alignment".to_string(), format!("sector {} of each physical sector", self.alignment_offset)));
+        }
+        details.push(("Rotation rate".to_string(), self.describe_rotation()));
+        if let Some(speed) = self.describe_sata_speed() {
+            details.push(("Interface speed".to_string(), speed.to_string()));
+        }
+        let features = self.features();
+        details.push(("Supported features".to_string(), if features.is_empty() { "None".to_string() } else { features.join(", ") }));
+        let trim = match (self.trim, self.trim_zeroes) {
+            (false, _) => "Not supported",
+            (true, false) => "Supported",
+            (true, true) => "Supported, trimmed sectors read as zeroes",
+        };
+        details.push(("TRIM".to_string(), trim.to_string()));
+        details.push(("Security".to_string(), self.describe_security()));
+        if self.security & 0x0001 != 0 {
+            if let Some(time) = Self::describe_erase_time(self.erase_minutes) {
+                details.push(("Security erase time".to_string(), time));
+            }
+            if let Some(time) = Self::describe_erase_time(self.enhanced_erase_minutes).filter(|_| self.security & 0x0020 != 0) {
+                details.push(("Enhanced security erase time".to_string(), time));
+            }
+        }
+        if self.checksum_valid == Some(false) {
+            details.push(("Checksum".to_string(), "Does not match; the data may be unreliable".to_string()));
+        }
+        details
+    }
+}
+
+// Returns the number of sectors the drive was built with, from DEVICE CONFIGURATION
+// IDENTIFY data (command B1h, feature C2h).
+pub fn parse_dco_sectors(data: &[u8]) -> io::Result<u64> {
+    check_size("DEVICE CONFIGURATION IDENTIFY data", data)?;
+    if word(data, 0) != DCO_REVISION {
+        return Err(invalid_data(format!("unknown DEVICE CONFIGURATION IDENTIFY revision {:#06x}", word(data, 0))));
+    }
+    if checksum_valid(data) == Some(false) {
+        return Err(invalid_data("the DEVICE CONFIGURATION IDENTIFY checksum does not match"));
+    }
+    words_u64(data, 3, 4)
+        .checked_add(1)
+        .ok_or_else(|| invalid_data("the DEVICE CONFIGURATION IDENTIFY maximum LBA is out of range"))
+}
+
+// Sizes in sectors, from smallest to largest: what the host can address now, what
+// READ NATIVE MAX ADDRESS reports once a Host Protected Area is lifted, and what the
+// drive had before a Device Configuration Overlay shrank it.
+pub struct HiddenArea {
+    pub current: u64,
+    pub native: Option<u64>,
+    pub factory: Option<u64>,
+    // Why the DCO page the drive returned could not be used.
+    pub factory_error: Option<String>,
+    pub sector_size: u64,
+}
+
+impl HiddenArea {
+    // A DCO page that does not decode leaves the factory size unknown, as a refused
+    // command does, rather than hiding the rest.
+    pub fn new(identity: &AtaIdentity, native: Option<u64>, dco: Option<&[u8]>) -> Self {
+        let (factory, factory_error) = match dco.map(parse_dco_sectors) {
+            Some(Ok(sectors)) => (Some(sectors), None),
+            Some(Err(err)) => (None, Some(err.to_string())),
+            None => (None, None),
+        };
+        Self {
+            current: identity.sectors,
+            native,
+            factory,
+            factory_error,
+            sector_size: identity.logical_sector_size,
+        }
+    }
+
+    pub fn hpa_sectors(&self) -> Option<u64> {
+        self.native.map(|native| native.saturating_sub(self.current))
+    }
+
+    // Without the native size, anything beyond the current capacity may be either.
+    pub fn dco_sectors(&self) -> Option<u64> {
+        self.factory.map(|factory| factory.saturating_sub(self.native.unwrap_or(self.current)))
+    }
+
+    pub fn hidden_sectors(&self) -> u64 {
+        self.factory.or(self.native).map_or(0, |size| size.saturating_sub(self.current))
+    }
+
+    pub fn details(&self) -> Vec<(String, String)> {
+        let sectors = |count: u64| format!("{} sectors ({})", count, format_size(count.saturating_mul(self.sector_size)));
+        let unknown = |count: Option<u64>| count.map_or("Unknown".to_string(), sectors);
+        let mut details = vec![
+            ("Addressable capacity".to_string(), sectors(self.current)),
+            ("Native capacity".to_string(), unknown(self.native)),
+            (
+                "Factory capacity (DCO)".to_string(),
+                match &self.factory_error {
+                    Some(err) => format!("Unknown; {}", err),
+                    None => unknown(self.factory),
+                },
+            ),
+        ];
+        match (self.hpa_sectors(), self.dco_sectors()) {
+            (Some(hpa), Some(dco)) => {
+                details.push(("Hidden by HPA".to_string(), sectors(hpa)));
+                details.push(("Hidden by DCO".to_string(), sectors(dco)));
+            }
+            (Some(hpa), None) => details.push(("Hidden by HPA".to_string(), sectors(hpa))),
+            (None, Some(hidden)) => details.push(("Hidden by HPA or DCO".to_string(), sectors(hidden))),
+            (None, None) => {}
+        }
+        details
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const IDENTIFY: &[u8; IDENTIFY_DEVICE_SIZE] = include_bytes!("fixtures/ata-wd10ezex-identify.bin");
+    const DCO: &[u8; IDENTIFY_DEVICE_SIZE] = include_bytes!("fixtures/ata-wd10ezex-dco.bin");
+    const SECTORS: u64 = 1_953_525_168;
+
+    // Sets word `index` and fixes up the checksum after it.
+    fn edit(page: &[u8], index: usize, value: u16) -> Vec<u8> {
+        let mut data = page.to_vec();
+        data[index * 2..index * 2 + 2].copy_from_slice(&value.to_le_bytes());
+        data[511] = 0;
+        data[511] = data.iter().fold(0u8, |sum, byte| sum.wrapping_sub(*byte));
+        data
+    }
+
+    fn detail(details: &[(String, String)], name: &str) -> Option<String> {
+        details.iter().find(|(key, _)| key == name).map(|(_, value)| value.clone())
+    }
+
+    fn hidden(current: u64, native: Option<u64>, factory: Option<u64>) -> HiddenArea {
+        HiddenArea { current, native, factory, factory_error: None, sector_size: 512 }
+    }
+
+    #[test]
+    fn decodes_identify_device() {
+        let identity = AtaIdentity::parse(IDENTIFY).unwrap();
+        assert_eq!(identity.model, "WDC WD10EZEX-08WN4A0");
+        assert_eq!(identity.serial, "WD-WCC6Y1KZ3A7N");
+        assert_eq!(identity.firmware, "01.01A01");
+        assert_eq!(identity.sectors, SECTORS);
+        assert!(identity.lba48());
+        assert_eq!((identity.logical_sector_size, identity.physical_sector_size), (512, 4096));
+        assert_eq!(identity.alignment_offset, 0);
+        assert_eq!(identity.rotation_rate, 7200);
+        assert!(identity.hpa_supported());
+        assert!(identity.dco_supported());
+        assert!(!identity.trim);
+        assert_eq!(identity.checksum_valid, Some(true));
+        assert_eq!(
+            identity.features(),
+            [
+                "SMART",
+                "Security",
+                "Write cache",
+                "Read look-ahead",
+                "Host Protected Area",
+                "Automatic Acoustic Management",
+                "48-bit LBA",
+                "Device Configuration Overlay",
+                "SMART self-test",
+                "General Purpose Logging",
+                "Native Command Queuing",
+            ]
+        );
+
+        let details = identity.details();
+        assert_eq!(detail(&details, "Standard").as_deref(), Some("ACS-3"));
+        assert_eq!(detail(&details, "Sector size").as_deref(), Some("512 bytes logical, 4096 bytes physical"));
+        assert_eq!(detail(&details, "Rotation rate").as_deref(), Some("7200 rpm"));
+        assert_eq!(detail(&details, "Interface speed").as_deref(), Some("SATA 6.0 Gb/s"));
+        assert_eq!(detail(&details, "Security").as_deref(), Some("disabled, frozen, enhanced erase supported"));
+        assert_eq!(detail(&details, "Security erase time").as_deref(), Some("320 minutes"));
+        assert_eq!(detail(&details, "Checksum"), None);
+    }
+
+    #[test]
+    fn twenty_eight_bit_drive_uses_words_60_and_61() {
+        let data = edit(IDENTIFY, 83, 0x7F61 & !0x0400);
+        let identity = AtaIdentity::parse(&data).unwrap();
+        assert!(!identity.lba48());
+        assert_eq!(identity.sectors, 0x0FFF_FFFF);
+    }
+
+    #[test]
+    fn decodes_both_erase_time_formats() {
+        assert_eq!(AtaIdentity::describe_erase_time(0x0000), None);
+        assert_eq!(AtaIdentity::describe_erase_time(0x00A0).as_deref(), Some("320 minutes"));
+        assert_eq!(AtaIdentity::describe_erase_time(0x00FF).as_deref(), Some("more than 508 minutes"));
+        assert_eq!(AtaIdentity::describe_erase_time(0x8000), None);
+        assert_eq!(AtaIdentity::describe_erase_time(0x8400).as_deref(), Some("2048 minutes"));
+        assert_eq!(AtaIdentity::describe_erase_time(0xFFFF).as_deref(), Some("more than 65532 minutes"));
+
+        let data = edit(IDENTIFY, 90, 0x8400);
+        let details = AtaIdentity::parse(&data).unwrap().details();
+        assert_eq!(detail(&details, "Enhanced security erase time").as_deref(), Some("2048 minutes"));
+    }
+
+    #[test]
+    fn rejects_atapi_and_truncated_pages() {
+        assert!(AtaIdentity::parse(&edit(IDENTIFY, 0, 0x85C0)).is_err());
+        assert!(AtaIdentity::parse(&IDENTIFY[..IDENTIFY_DEVICE_SIZE - 2]).is_err());
+    }
+
+    #[test]
+    fn bad_checksum_is_reported() {
+        let mut data = IDENTIFY.to_vec();
+        data[511] ^= 1;
+        let identity = AtaIdentity::parse(&data).unwrap();
+        assert_eq!(identity.checksum_valid, Some(false));
+        assert!(detail(&identity.details(), "Checksum").is_some());
+    }
+
+    #[test]
+    fn decodes_dco_sectors() {
+        assert_eq!(parse_dco_sectors(DCO).unwrap(), SECTORS);
+        assert!(parse_dco_sectors(&edit(DCO, 0, 0x0001)).is_err());
+        let mut corrupt = DCO.to_vec();
+        corrupt[511] ^= 1;
+        assert!(parse_dco_sectors(&corrupt).is_err());
+    }
+
+    #[test]
+    fn dco_maximum_lba_overflow_is_an_error() {
+        let data = (3..7).fold(DCO.to_vec(), |data, index| edit(&data, index, 0xFFFF));
+        assert!(parse_dco_sectors(&data).is_err());
+    }
+
+    #[test]
+    fn splits_hidden_sectors_between_hpa_and_dco() {
+        let both = hidden(1_000_000, Some(1_200_000), Some(1_500_000));
+        assert_eq!(both.hpa_sectors(), Some(200_000));
+        assert_eq!(both.dco_sectors(), Some(300_000));
+        assert_eq!(both.hidden_sectors(), 500_000);
+
+        let hpa_only = hidden(1_000_000, Some(1_200_000), None);
+        assert_eq!(hpa_only.hpa_sectors(), Some(200_000));
+        assert_eq!(hpa_only.dco_sectors(), None);
+        assert_eq!(hpa_only.hidden_sectors(), 200_000);
+
+        // Without the native size the gap cannot be split, and is all put down to the DCO.
+        let unknown_native = hidden(1_000_000, None, Some(1_500_000));
+        assert_eq!(unknown_native.hpa_sectors(), None);
+        assert_eq!(unknown_native.dco_sectors(), Some(500_000));
+        assert_eq!(unknown_native.hidden_sectors(), 500_000);
+        assert_eq!(detail(&unknown_native.details(), "Hidden by HPA or DCO").as_deref(), Some("500000 sectors (244.1 MB)"));
+
+        let nothing = hidden(SECTORS, Some(SECTORS), Some(SECTORS));
+        assert_eq!((nothing.hpa_sectors(), nothing.dco_sectors(), nothing.hidden_sectors()), (Some(0), Some(0), 0));
+        assert_eq!(hidden(SECTORS, None, None).hidden_sectors(), 0);
+    }
+
+    #[test]
+    fn unreadable_dco_leaves_factory_size_unknown() {
+        let identity = AtaIdentity::parse(IDENTIFY).unwrap();
+        let area = HiddenArea::new(&identity, Some(SECTORS), Some(DCO));
+        assert_eq!((area.factory, area.factory_error.as_deref()), (Some(SECTORS), None));
+
+        let area = HiddenArea::new(&identity, Some(SECTORS), Some(&edit(DCO, 0, 0x0001)));
+        assert_eq!(area.factory, None);
+        assert_eq!(area.hpa_sectors(), Some(0));
+        assert_eq!(
+            detail(&area.details(), "Factory capacity (DCO)").as_deref(),
+            Some("Unknown; unknown DEVICE CONFIGURATION IDENTIFY revision 0x0001")
+        );
+    }
+}
@@ -0,0 +1,291 @@
+use std::io;
+use std::mem::{offset_of, size_of};
+use std::ptr::null_mut;
+use widestring::U16CString;
+use winapi::um::fileapi::{CreateFileW, OPEN_EXISTING};
+use winapi::um::handleapi::{CloseHandle, INVALID_HANDLE_VALUE};
+use winapi::um::ioapiset::DeviceIoControl;
+use winapi::um::winnt::{FILE_ATTRIBUTE_NORMAL, FILE_SHARE_READ, FILE_SHARE_WRITE, GENERIC_READ, GENERIC_WRITE, HANDLE};
+
+pub mod identify;
+pub mod nvme;
+pub mod smart;
+
+use identify::AtaIdentity;
+use nvme::{HEALTH_LOG_SIZE, IDENTIFY_SIZE};
+use smart::SMART_PAGE_SIZE;
+
+// Shared by the ATA and NVMe health assessments, from best to worst.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Verdict {
+    Passed,
+    Warning,
+    Failing,
+}
+
+impl Verdict {
+    pub fn name(self) -> &'static str {
+        match self {
+            Verdict::Passed => "Passed",
+            Verdict::Warning => "Warning",
+            Verdict::Failing => "Failing",
+        }
+    }
+}
+
+const IOCTL_ATA_PASS_THROUGH: u32 = 0x0004_D02C;
+const ATA_FLAGS_DRDY_REQUIRED: u16 = 0x01;
+const ATA_FLAGS_DATA_IN: u16 = 0x02;
+const ATA_FLAGS_48BIT_COMMAND: u16 = 0x08;
+const ATA_TIMEOUT_SECONDS: u32 = 10;
+const ATA_STATUS_ERROR: u8 = 0x01;
+
+const ATA_SMART: u8 = 0xB0;
+const SMART_READ_DATA: u8 = 0xD0;
+const SMART_READ_THRESHOLDS: u8 = 0xD1;
+// SMART commands only run with this signature in the LBA mid and high registers.
+const SMART_LBA_MID: u8 = 0x4F;
+const SMART_LBA_HIGH: u8 = 0xC2;
+
+const ATA_IDENTIFY_DEVICE: u8 = 0xEC;
+const ATA_READ_NATIVE_MAX_ADDRESS: u8 = 0xF8;
+const ATA_READ_NATIVE_MAX_ADDRESS_EXT: u8 = 0x27;
+const ATA_DEVICE_CONFIGURATION: u8 = 0xB1;
+const DCO_IDENTIFY: u8 = 0xC2;
+const ATA_DEVICE_LBA: u8 = 0x40;
+
+// ATA_PASS_THROUGH_EX followed by the buffer it transfers, so a single call carries both.
+#[repr(C)]
+struct AtaPassThrough {
+    length: u16,
+    ata_flags: u16,
+    path_id: u8,
+    target_id: u8,
+    lun: u8,
+    reserved_as_uchar: u8,
+    data_transfer_length: u32,
+    time_out_value: u32,
+    reserved_as_ulong: u32,
+    data_buffer_offset: usize,
+    previous_task_file: [u8; 8],
+    current_task_file: [u8; 8],
+    data: [u8; SMART_PAGE_SIZE],
+}
+
+struct DeviceHandle(HANDLE);
+
+impl Drop for DeviceHandle {
+    fn drop(&mut self) {
+        unsafe { CloseHandle(self.0) };
+    }
+}
+
+// Pass-through commands need write access to the device even when they only read.
+fn open_device(index: usize) -> io::Result<DeviceHandle> {
+    let device_path = U16CString::from_str(format!("\\\\.\\PHYSICALDRIVE{}", index))
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "invalid device path"))?;
+    let handle = unsafe {
+        CreateFileW(
+            device_path.as_ptr(),
+            GENERIC_READ | GENERIC_WRITE,
+            FILE_SHARE_READ | FILE_SHARE_WRITE,
+            null_mut(),
+            OPEN_EXISTING,
+            FILE_ATTRIBUTE_NORMAL,
+            null_mut(),
+        )
+    };
+    if handle == INVALID_HANDLE_VALUE {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(DeviceHandle(handle))
+}
+
+// Sends one ATA command with `task_file` as features, sector count, LBA low, mid, high,
+// device and command, and returns the request with the registers the drive answered with.
+fn ata_pass_through(device: &DeviceHandle, task_file: [u8; 7], ata_flags: u16, data_in: bool) -> io::Result<AtaPassThrough> {
+    let mut request = AtaPassThrough {
+        length: offset_of!(AtaPassThrough, data) as u16,
+        ata_flags: ATA_FLAGS_DRDY_REQUIRED | ata_flags | if data_in { ATA_FLAGS_DATA_IN } else { 0 },
+        path_id: 0,
+        target_id: 0,
+        lun: 0,
+        reserved_as_uchar: 0,
+        data_transfer_length: if data_in { SMART_PAGE_SIZE as u32 } else { 0 },
+        time_out_value: ATA_TIMEOUT_SECONDS,
+        reserved_as_ulong: 0,
+        data_buffer_offset: offset_of!(AtaPassThrough, data),
+        previous_task_file: [0; 8],
+        current_task_file: [task_file[0], task_file[1], task_file[2], task_file[3], task_file[4], task_file[5], task_file[6], 0],
+        data: [0; SMART_PAGE_SIZE],
+    };
+    let mut bytes_returned = 0u32;
+    let result = unsafe {
+        DeviceIoControl(
+            device.0,
+            IOCTL_ATA_PASS_THROUGH,
+            &mut request as *mut _ as *mut _,
+            size_of::<AtaPassThrough>() as u32,
+            &mut request as *mut _ as *mut _,
+            size_of::<AtaPassThrough>() as u32,
+            &mut bytes_returned,
+            null_mut(),
+        )
+    };
+    if result == 0 {
+        let err = io::Error::last_os_error();
+        return Err(io::Error::new(
+            err.kind(),
+            format!("ATA pass-through failed ({}); drives behind USB bridges or NVMe controllers may not accept ATA commands", err),
+        ));
+    }
+    // On return the task file holds the status and error registers.
+    if request.current_task_file[6] & ATA_STATUS_ERROR != 0 {
+        return Err(io::Error::other(format!(
+            "the drive rejected command {:#04x} (error register {:#04x})",
+            task_file[6], request.current_task_file[0]
+        )));
+    }
+    Ok(request)
+}
+
+// Runs a PIO data-in ATA command that returns one 512-byte block.
+fn ata_read_block(device: &DeviceHandle, task_file: [u8; 7]) -> io::Result<Vec<u8>> {
+    Ok(ata_pass_through(device, task_file, 0, true)?.data.to_vec())
+}
+
+// Returns the SMART READ DATA and READ THRESHOLDS pages of an ATA drive.
+pub fn read_smart_pages(index: usize) -> io::Result<(Vec<u8>, Vec<u8>)> {
+    let device = open_device(index)?;
+    let smart_hint = |err: io::Error| match err.kind() {
+        io::ErrorKind::Other => io::Error::other(format!("{}; SMART may be disabled", err)),
+        _ => err,
+    };
+    let data = ata_read_block(&device, [SMART_READ_DATA, 1, 0, SMART_LBA_MID, SMART_LBA_HIGH, 0, ATA_SMART]).map_err(smart_hint)?;
+    let thresholds = ata_read_block(&device, [SMART_READ_THRESHOLDS, 1, 0, SMART_LBA_MID, SMART_LBA_HIGH, 0, ATA_SMART]).map_err(smart_hint)?;
+    Ok((data, thresholds))
+}
+
+pub struct IdentifyPages {
+    pub identify: Vec<u8>,
+    // Sectors reported by READ NATIVE MAX ADDRESS, when the drive answers it.
+    pub native_sectors: Option<u64>,
+    pub dco: Option<Vec<u8>>,
+}
+
+// READ NATIVE MAX ADDRESS returns the last sector in the LBA registers; for the 48-bit
+// form the high bytes come back in the previous task file.
+fn read_native_sectors(device: &DeviceHandle, lba48: bool) -> io::Result<u64> {
+    let max_address = match lba48 {
+        true => {
+            let request = ata_pass_through(device, [0, 0, 0, 0, 0, ATA_DEVICE_LBA, ATA_READ_NATIVE_MAX_ADDRESS_EXT], ATA_FLAGS_48BIT_COMMAND, false)?;
+            let (low, high) = (request.current_task_file, request.previous_task_file);
+            [low[2], low[3], low[4], high[2], high[3], high[4]].iter().rev().fold(0u64, |value, byte| value << 8 | u64::from(*byte))
+        }
+        false => {
+            let request = ata_pass_through(device, [0, 0, 0, 0, 0, ATA_DEVICE_LBA, ATA_READ_NATIVE_MAX_ADDRESS], 0, false)?;
+            let registers = request.current_task_file;
+            [registers[2], registers[3], registers[4], registers[5] & 0x0F].iter().rev().fold(0u64, |value, byte| value << 8 | u64::from(*byte))
+        }
+    };
+    Ok(max_address + 1)
+}
+
+// Reads IDENTIFY DEVICE and, where the drive supports them, the native and factory
+// sizes. Those two are left out when the drive refuses them, which it does once the
+// BIOS has frozen the configuration.
+pub fn read_identify_pages(index: usize) -> io::Result<IdentifyPages> {
+    let device = open_device(index)?;
+    let identify = ata_read_block(&device, [0, 1, 0, 0, 0, 0, ATA_IDENTIFY_DEVICE])?;
+    let identity = AtaIdentity::parse(&identify)?;
+    let native_sectors = match identity.hpa_supported() {
+        true => read_native_sectors(&device, identity.lba48()).ok(),
+        false => None,
+    };
+    let dco = match identity.dco_supported() {
+        true => ata_read_block(&device, [DCO_IDENTIFY, 0, 0, 0, 0, 0, ATA_DEVICE_CONFIGURATION]).ok(),
+        false => None,
+    };
+    Ok(IdentifyPages { identify, native_sectors, dco })
+}
+
+const IOCTL_STORAGE_QUERY_PROPERTY: u32 = 0x002D_1400;
+const STORAGE_ADAPTER_PROTOCOL_SPECIFIC_PROPERTY: u32 = 49;
+const STORAGE_DEVICE_PROTOCOL_SPECIFIC_PROPERTY: u32 = 50;
+const PROTOCOL_TYPE_NVME: u32 = 3;
+const NVME_DATA_TYPE_IDENTIFY: u32 = 1;
+const NVME_DATA_TYPE_LOG_PAGE: u32 = 2;
+const NVME_IDENTIFY_NAMESPACE: u32 = 0;
+const NVME_IDENTIFY_CONTROLLER: u32 = 1;
+const NVME_LOG_HEALTH_INFORMATION: u32 = 2;
+const NVME_FIRST_NAMESPACE: u32 = 1;
+// STORAGE_PROPERTY_QUERY up to its parameters, and STORAGE_PROTOCOL_SPECIFIC_DATA.
+const PROPERTY_QUERY_HEADER: usize = 8;
+const PROTOCOL_DATA_HEADER: usize = 40;
+
+// The Windows inbox NVMe driver hands out identify data and log pages through
+// IOCTL_STORAGE_QUERY_PROPERTY with a STORAGE_PROTOCOL_SPECIFIC_DATA request; the same
+// buffer returns a STORAGE_PROTOCOL_DATA_DESCRIPTOR with the data after it.
+fn nvme_query(device: &DeviceHandle, property: u32, data_type: u32, value: u32, sub_value: u32, len: usize) -> io::Result<Vec<u8>> {
+    let mut buffer = vec![0u8; PROPERTY_QUERY_HEADER + PROTOCOL_DATA_HEADER + len];
+    let fields = [
+        property,
+        0,
+        PROTOCOL_TYPE_NVME,
+        data_type,
+        value,
+        sub_value,
+        PROTOCOL_DATA_HEADER as u32,
+        len as u32,
+    ];
+    for (index, field) in fields.iter().enumerate() {
+        buffer[index * 4..index * 4 + 4].copy_from_slice(&field.to_le_bytes());
+    }
+    let mut bytes_returned = 0u32;
+    let result = unsafe {
+        DeviceIoControl(
+            device.0,
+            IOCTL_STORAGE_QUERY_PROPERTY,
+            buffer.as_mut_ptr() as *mut _,
+            buffer.len() as u32,
+            buffer.as_mut_ptr() as *mut _,
+            buffer.len() as u32,
+            &mut bytes_returned,
+            null_mut(),
+        )
+    };
+    if result == 0 {
+        let err = io::Error::last_os_error();
+        return Err(io::Error::new(err.kind(), format!("NVMe query failed ({}); the drive may not be NVMe or its driver may not pass the request on", err)));
+    }
+    let field = |offset: usize| u32::from_le_bytes(buffer[offset..offset + 4].try_into().unwrap()) as usize;
+    // Offsets in the descriptor count from its protocol data, 8 bytes in.
+    let offset = PROPERTY_QUERY_HEADER + field(PROPERTY_QUERY_HEADER + 16);
+    let returned = field(PROPERTY_QUERY_HEADER + 20);
+    if returned < len || offset + len > buffer.len() {
+        return Err(io::Error::other(format!("the driver returned {} bytes of NVMe data, expected {}", returned, len)));
+    }
+    Ok(buffer[offset..offset + len].to_vec())
+}
+
+pub struct NvmePages {
+    pub health: Vec<u8>,
+    pub controller: Vec<u8>,
+    pub namespace: Vec<u8>,
+}
+
+pub fn read_nvme_pages(index: usize) -> io::Result<NvmePages> {
+    let device = open_device(index)?;
+    Ok(NvmePages {
+        health: nvme_query(&device, STORAGE_DEVICE_PROTOCOL_SPECIFIC_PROPERTY, NVME_DATA_TYPE_LOG_PAGE, NVME_LOG_HEALTH_INFORMATION, 0, HEALTH_LOG_SIZE)?,
+        controller: nvme_query(&device, STORAGE_ADAPTER_PROTOCOL_SPECIFIC_PROPERTY, NVME_DATA_TYPE_IDENTIFY, NVME_IDENTIFY_CONTROLLER, 0, IDENTIFY_SIZE)?,
+        namespace: nvme_query(
+            &device,
+            STORAGE_DEVICE_PROTOCOL_SPECIFIC_PROPERTY,
+            NVME_DATA_TYPE_IDENTIFY,
+            NVME_IDENTIFY_NAMESPACE,
+            NVME_FIRST_NAMESPACE,
+            IDENTIFY_SIZE,
+        )?,
+    })
+}
@@ -0,0 +1,436 @@
+use crate::device::Verdict;
+use crate::disk_usage::format_size;
+use crate::vdisk::invalid_data;
+use std::io;
+
+pub const HEALTH_LOG_SIZE: usize = 512;
+pub const IDENTIFY_SIZE: usize = 4096;
+// Data units are thousands of 512-byte blocks.
+const DATA_UNIT_BYTES: u128 = 512 * 1000;
+const KELVIN: i64 = 273;
+
+const CRITICAL_WARNINGS: [(u8, &str, Verdict); 6] = [
+    (0x01, "Available spare is below its threshold", Verdict::Failing),
+    (0x02, "Temperature is outside its allowed range", Verdict::Warning),
+    (0x04, "Reliability is degraded by media errors", Verdict::Failing),
+    (0x08, "Media has been placed in read-only mode", Verdict::Failing),
+    (0x10, "Volatile memory backup has failed", Verdict::Failing),
+    (0x20, "Persistent memory region has become read-only", Verdict::Failing),
+];
+
+fn check_size(structure: &str, data: &[u8], size: usize) -> io::Result<()> {
+    if data.len() < size {
+        return Err(invalid_data(format!("{} is {} bytes, expected {}", structure, data.len(), size)));
+    }
+    Ok(())
+}
+
+fn u16_at(data: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes(data[offset..offset + 2].try_into().unwrap())
+}
+
+fn u32_at(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap())
+}
+
+fn u64_at(data: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(data[offset..offset + 8].try_into().unwrap())
+}
+
+fn u128_at(data: &[u8], offset: usize) -> u128 {
+    u128::from_le_bytes(data[offset..offset + 16].try_into().unwrap())
+}
+
+fn ascii_at(data: &[u8], offset: usize, len: usize) -> String {
+    String::from_utf8_lossy(&data[offset..offset + len]).trim_matches(|c: char| c == '\0' || c.is_whitespace()).to_string()
+}
+
+fn celsius(kelvin: u16) -> String {
+    format!("{} °C", i64::from(kelvin) - KELVIN)
+}
+
+// Counters wider than u64 are shown as they are once they no longer fit a byte count.
+fn format_data_units(units: u128) -> String {
+    match units.checked_mul(DATA_UNIT_BYTES).and_then(|bytes| u64::try_from(bytes).ok()) {
+        Some(bytes) => format!("{} ({})", units, format_size(bytes)),
+        None => units.to_string(),
+    }
+}
+
+// SMART / Health Information log page (log identifier 02h).
+pub struct NvmeHealth {
+    pub critical_warning: u8,
+    pub temperature: u16,
+    pub available_spare: u8,
+    pub spare_threshold: u8,
+    pub percentage_used: u8,
+    pub data_units_read: u128,
+    pub data_units_written: u128,
+    pub host_reads: u128,
+    pub host_writes: u128,
+    pub busy_minutes: u128,
+    pub power_cycles: u128,
+    pub power_on_hours: u128,
+    pub unsafe_shutdowns: u128,
+    pub media_errors: u128,
+    pub error_log_entries: u128,
+    pub warning_temperature_minutes: u32,
+    pub critical_temperature_minutes: u32,
+    pub sensors: Vec<u16>,
+}
+
+impl NvmeHealth {
+    pub fn parse(data: &[u8]) -> io::Result<Self> {
+        check_size("The health log", data, HEALTH_LOG_SIZE)?;
+        Ok(Self {
+            critical_warning: data[0],
+            temperature: u16_at(data, 1),
+            available_spare: data[3],
+            spare_threshold: data[4],
+            percentage_used: data[5],
+            data_units_read: u128_at(data, 32),
+            data_units_written: u128_at(data, 48),
+            host_reads: u128_at(data, 64),
+            host_writes: u128_at(data, 80),
+            busy_minutes: u128_at(data, 96),
+            power_cycles: u128_at(data, 112),
+            power_on_hours: u128_at(data, 128),
+            unsafe_shutdowns: u128_at(data, 144),
+            media_errors: u128_at(data, 160),
+            error_log_entries: u128_at(data, 176),
+            warning_temperature_minutes: u32_at(data, 192),
+            critical_temperature_minutes: u32_at(data, 196),
+            // Sensors that are not implemented report zero.
+            sensors: (0..8).map(|sensor| u16_at(data, 200 + sensor * 2)).collect(),
+        })
+    }
+
+    pub fn details(&self) -> Vec<(String, String)> {
+        let mut details = vec![
+            ("Critical warning".to_string(), format!("{:#04x}", self.critical_warning)),
+            ("Composite temperature".to_string(), celsius(self.temperature)),
+            ("Available spare".to_string(), format!("{}% (threshold {}%)", self.available_spare, self.spare_threshold)),
+            ("Percentage used".to_string(), format!("{}%", self.percentage_used)),
+            ("Data units read".to_string(), format_data_units(self.data_units_read)),
+            ("Data units written".to_string(), format_data_units(self.data_units_written)),
+            ("Host read commands".to_string(), self.host_reads.to_string()),
+            ("Host write commands".to_string(), self.host_writes.to_string()),
+            ("Controller busy time".to_string(), format!("{} minutes", self.busy_minutes)),
+            ("Power cycles".to_string(), self.power_cycles.to_string()),
+            ("Power-on hours".to_string(), self.power_on_hours.to_string()),
+            ("Unsafe shutdowns".to_string(), self.unsafe_shutdowns.to_string()),
+            ("Media and data integrity errors".to_string(), self.media_errors.to_string()),
+            ("Error log entries".to_string(), self.error_log_entries.to_string()),
+            ("Time above warning temperature".to_string(), format!("{} minutes", self.warning_temperature_minutes)),
+            ("Time above critical temperature".to_string(), format!("{} minutes", self.critical_temperature_minutes)),
+        ];
+        for (number, sensor) in self.sensors.iter().enumerate().filter(|(_, sensor)| **sensor != 0) {
+            details.push((format!("Temperature sensor {}", number + 1), celsius(*sensor)));
+        }
+        details
+    }
+
+    // Critical warning bits are the controller's own verdict; wear and media errors are
+    // early signs it has not flagged yet.
+    pub fn assess(&self) -> (Verdict, Vec<String>) {
+        let mut verdict = Verdict::Passed;
+        let mut reasons = Vec::new();
+        for (bit, reason, level) in CRITICAL_WARNINGS {
+            if self.critical_warning & bit != 0 {
+                verdict = verdict.max(level);
+                reasons.push(reason.to_string());
+            }
+        }
+        if self.percentage_used >= 100 {
+            verdict = verdict.max(Verdict::Warning);
+            reasons.push(format!("{}% of the rated endurance has been used", self.percentage_used));
+        }
+        if self.media_errors > 0 {
+            verdict = verdict.max(Verdict::Warning);
+            reasons.push(format!("{} media and data integrity error(s)", self.media_errors));
+        }
+        (verdict, reasons)
+    }
+}
+
+const ONCS_FEATURES: [(u16, &str); 7] = [
+    (0x01, "Compare"),
+    (0x02, "Write Uncorrectable"),
+    (0x04, "Dataset Management (TRIM)"),
+    (0x08, "Write Zeroes"),
+    (0x10, "Save/Select in features"),
+    (0x20, "Reservations"),
+    (0x40, "Timestamp"),
+];
+
+const OACS_FEATURES: [(u16, &str); 6] = [
+    (0x01, "Security Send/Receive"),
+    (0x02, "Format NVM"),
+    (0x04, "Firmware download"),
+    (0x08, "Namespace management"),
+    (0x10, "Device self-test"),
+    (0x20, "Directives"),
+];
+
+// Identify Controller data structure (CNS 01h).
+pub struct NvmeController {
+    pub vendor_id: u16,
+    pub serial: String,
+    pub model: String,
+    pub firmware: String,
+    pub version: u32,
+    pub warning_temperature: u16,
+    pub critical_temperature: u16,
+    pub total_capacity: u128,
+    pub unallocated_capacity: u128,
+    pub namespaces: u32,
+    pub admin_commands: u16,
+    pub optional_commands: u16,
+}
+
+impl NvmeController {
+    pub fn parse(data: &[u8]) -> io::Result<Self> {
+        check_size("The Identify Controller data", data, IDENTIFY_SIZE)?;
+        Ok(Self {
+            vendor_id: u16_at(data, 0),
+            serial: ascii_at(data, 4, 20),
+            model: ascii_at(data, 24, 40),
+            firmware: ascii_at(data, 64, 8),
+            version: u32_at(data, 80),
+            admin_commands: u16_at(data, 256),
+            warning_temperature: u16_at(data, 266),
+            critical_temperature: u16_at(data, 268),
+            total_capacity: u128_at(data, 280),
+            unallocated_capacity: u128_at(data, 296),
+            namespaces: u32_at(data, 516),
+            optional_commands: u16_at(data, 520),
+        })
+    }
+
+    pub fn details(&self) -> Vec<(String, String)> {
+        let features = |table: &[(u16, &str)], bits: u16| {
+            let names: Vec<&str> = table.iter().filter(|(bit, _)| bits & bit != 0).map(|(_, name)| *name).collect();
+            if names.is_empty() { "None".to_string() } else { names.join(", ") }
+        };
+        // Controllers older than NVMe 1.2 leave the version and capacities at zero.
+        let version = match self.version {
+            0 => "1.0 or 1.1".to_string(),
+            version => format!("{}.{}.{}", version >> 16, (version >> 8) & 0xFF, version & 0xFF),
+        };
+        let capacity = |bytes: u128| u64::try_from(bytes).map_or(bytes.to_string(), format_size);
+        let mut details = vec![
+            ("Model".to_string(), self.model.clone()),
+            ("Serial number".to_string(), self.serial.clone()),
+            ("Firmware".to_string(), self.firmware.clone()),
+            ("PCI vendor ID".to_string(), format!("{:04x}", self.vendor_id)),
+            ("NVMe version".to_string(), version),
+            ("Namespaces".to_string(), self.namespaces.to_string()),
+        ];
+        if self.total_capacity != 0 {
+            details.push(("Total capacity".to_string(), capacity(self.total_capacity)));
+            details.push(("Unallocated capacity".to_string(), capacity(self.unallocated_capacity)));
+        }
+        if self.warning_temperature != 0 {
+            details.push(("Warning temperature".to_string(), celsius(self.warning_temperature)));
+        }
+        if self.critical_temperature != 0 {
+            details.push(("Critical temperature".to_string(), celsius(self.critical_temperature)));
+        }
+        details.push(("Admin commands".to_string(), features(&OACS_FEATURES, self.admin_commands)));
+        details.push(("Optional NVM commands".to_string(), features(&ONCS_FEATURES, self.optional_commands)));
+        details
+    }
+}
+
+pub struct LbaFormat {
+    pub sector_size: u64,
+    pub metadata_size: u16,
+    pub relative_performance: u8,
+}
+
+// Identify Namespace data structure (CNS 00h).
+pub struct NvmeNamespace {
+    pub size: u64,
+    pub capacity: u64,
+    pub utilization: u64,
+    pub formats: Vec<LbaFormat>,
+    pub current_format: usize,
+}
+
+impl NvmeNamespace {
+    pub fn parse(data: &[u8]) -> io::Result<Self> {
+        check_size("The Identify Namespace data", data, IDENTIFY_SIZE)?;
+        let format_count = data[25] as usize + 1;
+        // The format index is split into low and, since NVMe 2.0, high bits.
+        let current_format = (data[26] & 0x0F) as usize | ((data[26] as usize >> 5) & 0x03) << 4;
+        let formats: Vec<LbaFormat> = (0..format_count.min(64))
+            .map(|index| {
+                let entry = u32_at(data, 128 + index * 4);
+                LbaFormat {
+                    metadata_size: entry as u16,
+                    sector_size: 1u64.checked_shl((entry >> 16) & 0xFF).unwrap_or(0),
+                    relative_performance: ((entry >> 24) & 0x03) as u8,
+                }
+            })
+            .collect();
+        if current_format >= formats.len() {
+            return Err(invalid_data(format!("namespace uses LBA format {} of {}", current_format, formats.len())));
+        }
+        Ok(Self {
+            size: u64_at(data, 0),
+            capacity: u64_at(data, 8),
+            utilization: u64_at(data, 16),
+            formats,
+            current_format,
+        })
+    }
+
+    pub fn sector_size(&self) -> u64 {
+        self.formats[self.current_format].sector_size
+    }
+
+    pub fn details(&self) -> Vec<(String, String)> {
+        let blocks = |count: u64| format!("{} blocks ({})", count, format_size(count.saturating_mul(self.sector_size())));
+        let mut details = vec![
+            ("Namespace size".to_string(), blocks(self.size)),
+            ("Namespace capacity".to_string(), blocks(self.capacity)),
+            ("Namespace utilization".to_string(), blocks(self.utilization)),
+        ];
+        for (index, format) in self.formats.iter().enumerate() {
+            let performance = ["best", "better", "good", "degraded"][format.relative_performance as usize];
+            let current = if index == self.current_format { ", in use" } else { "" };
+            details.push((
+                format!("LBA format {}", index),
+                format!("{} bytes + {} metadata, {} performance{}", format.sector_size, format.metadata_size, performance, current),
+            ));
+        }
+        details
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HEALTH: &[u8; HEALTH_LOG_SIZE] = include_bytes!("fixtures/nvme-970evoplus-health.bin");
+    const CONTROLLER: &[u8; IDENTIFY_SIZE] = include_bytes!("fixtures/nvme-970evoplus-identify-controller.bin");
+    const NAMESPACE: &[u8; IDENTIFY_SIZE] = include_bytes!("fixtures/nvme-970evoplus-identify-namespace.bin");
+
+    #[test]
+    fn decodes_health_log() {
+        let health = NvmeHealth::parse(HEALTH).unwrap();
+        assert_eq!(health.critical_warning, 0);
+        assert_eq!(health.temperature, 312);
+        assert_eq!((health.available_spare, health.spare_threshold, health.percentage_used), (100, 10, 2));
+        assert_eq!((health.data_units_read, health.data_units_written), (27_581_242, 39_211_865));
+        assert_eq!((health.host_reads, health.host_writes), (512_345_678, 987_654_321));
+        assert_eq!(health.busy_minutes, 1520);
+        assert_eq!(health.power_cycles, 845);
+        assert_eq!(health.power_on_hours, 6125);
+        assert_eq!(health.unsafe_shutdowns, 37);
+        assert_eq!(health.media_errors, 0);
+        assert_eq!(health.error_log_entries, 1204);
+        assert_eq!((health.warning_temperature_minutes, health.critical_temperature_minutes), (0, 0));
+        assert_eq!(health.sensors, [312, 318, 0, 0, 0, 0, 0, 0]);
+
+        let details = health.details();
+        assert!(details.contains(&("Composite temperature".to_string(), "39 °C".to_string())));
+        assert!(details.contains(&("Temperature sensor 2".to_string(), "45 °C".to_string())));
+        assert!(!details.iter().any(|(name, _)| name == "Temperature sensor 3"));
+        assert_eq!(health.assess(), (Verdict::Passed, Vec::new()));
+    }
+
+    #[test]
+    fn critical_warning_bits_map_to_verdicts() {
+        for (bit, reason, level) in CRITICAL_WARNINGS {
+            let mut data = HEALTH.to_vec();
+            data[0] = bit;
+            assert_eq!(NvmeHealth::parse(&data).unwrap().assess(), (level, vec![reason.to_string()]));
+        }
+        assert_eq!(CRITICAL_WARNINGS.iter().filter(|(_, _, level)| *level == Verdict::Warning).count(), 1);
+
+        // The worst bit decides when several are set.
+        let mut data = HEALTH.to_vec();
+        data[0] = 0x02 | 0x04;
+        let (verdict, reasons) = NvmeHealth::parse(&data).unwrap().assess();
+        assert_eq!(verdict, Verdict::Failing);
+        assert_eq!(reasons.len(), 2);
+    }
+
+    #[test]
+    fn wear_and_media_errors_warn() {
+        let mut data = HEALTH.to_vec();
+        data[5] = 100;
+        data[160] = 3;
+        let (verdict, reasons) = NvmeHealth::parse(&data).unwrap().assess();
+        assert_eq!(verdict, Verdict::Warning);
+        assert_eq!(reasons, ["100% of the rated endurance has been used", "3 media and data integrity error(s)"]);
+    }
+
+    #[test]
+    fn decodes_identify_controller() {
+        let controller = NvmeController::parse(CONTROLLER).unwrap();
+        assert_eq!(controller.vendor_id, 0x144d);
+        assert_eq!(controller.serial, "S4EWNX0R123456A");
+        assert_eq!(controller.model, "Samsung SSD 970 EVO Plus 1TB");
+        assert_eq!(controller.firmware, "2B2QEXM7");
+        assert_eq!(controller.version, 0x0001_0300);
+        assert_eq!((controller.warning_temperature, controller.critical_temperature), (358, 358));
+        assert_eq!((controller.total_capacity, controller.unallocated_capacity), (1_000_204_886_016, 0));
+        assert_eq!(controller.namespaces, 1);
+        assert_eq!(controller.admin_commands, 0x0017);
+        assert_eq!(controller.optional_commands, 0x005f);
+
+        let details = controller.details();
+        assert!(details.contains(&("NVMe version".to_string(), "1.3.0".to_string())));
+        assert!(details.contains(&("Warning temperature".to_string(), "85 °C".to_string())));
+        assert!(details.contains(&(
+            "Admin commands".to_string(),
+            "Security Send/Receive, Format NVM, Firmware download, Device self-test".to_string()
+        )));
+    }
+
+    #[test]
+    fn decodes_identify_namespace() {
+        let namespace = NvmeNamespace::parse(NAMESPACE).unwrap();
+        assert_eq!((namespace.size, namespace.capacity, namespace.utilization), (1_953_525_168, 1_953_525_168, 412_345_678));
+        assert_eq!(namespace.formats.len(), 1);
+        assert_eq!(namespace.current_format, 0);
+        assert_eq!(namespace.sector_size(), 512);
+        assert_eq!((namespace.formats[0].metadata_size, namespace.formats[0].relative_performance), (0, 0));
+    }
+
+    #[test]
+    fn current_format_includes_high_bits() {
+        let mut data = NAMESPACE.to_vec();
+        // 18 formats, the last of them 4 KiB with 8 bytes of metadata and "better" performance.
+        data[25] = 17;
+        data[128 + 17 * 4..128 + 18 * 4].copy_from_slice(&(1u32 << 24 | 12 << 16 | 8).to_le_bytes());
+        // FLBAS bits 3:0 hold 1 and bits 6:5 hold 1, for format 0x11.
+        data[26] = 0x01 | 0x01 << 5 | 0x10;
+        let namespace = NvmeNamespace::parse(&data).unwrap();
+        assert_eq!(namespace.formats.len(), 18);
+        assert_eq!(namespace.current_format, 17);
+        assert_eq!(namespace.sector_size(), 4096);
+        assert_eq!(namespace.formats[17].metadata_size, 8);
+        assert_eq!(namespace.formats[17].relative_performance, 1);
+    }
+
+    #[test]
+    fn current_format_past_the_list_is_rejected() {
+        let mut data = NAMESPACE.to_vec();
+        data[26] = 0x01;
+        let error = NvmeNamespace::parse(&data).err().unwrap();
+        assert_eq!(error.to_string(), "namespace uses LBA format 1 of 1");
+        // Bit 4 is the metadata setting, not part of the index.
+        data[26] = 0x10;
+        assert_eq!(NvmeNamespace::parse(&data).unwrap().current_format, 0);
+    }
+
+    #[test]
+    fn short_pages_are_rejected() {
+        assert!(NvmeHealth::parse(&HEALTH[..HEALTH_LOG_SIZE - 1]).is_err());
+        assert!(NvmeController::parse(&CONTROLLER[..512]).is_err());
+        assert!(NvmeNamespace::parse(&NAMESPACE[..IDENTIFY_SIZE - 1]).is_err());
+    }
+}
@@ -0,0 +1,413 @@
+use crate::device::Verdict;
+use crate::vdisk::invalid_data;
+use std::io;
+
+pub const SMART_PAGE_SIZE: usize = 512;
+const ATTRIBUTE_COUNT: usize = 30;
+const ENTRY_SIZE: usize = 12;
+const FIRST_ENTRY: usize = 2;
+const OFFLINE_STATUS: usize = 362;
+const SELF_TEST_STATUS: usize = 363;
+
+const FLAG_PREFAILURE: u16 = 0x01;
+const FLAG_ONLINE: u16 = 0x02;
+
+// Attributes whose raw value counts sectors the drive has given up on; any at all is a
+// warning sign long before the normalized value reaches its threshold.
+const SECTOR_PROBLEM_ATTRIBUTES: [u8; 3] = [5, 197, 198];
+
+fn attribute_name(id: u8) -> &'static str {
+    match id {
+        1 => "Raw Read Error Rate",
+        2 => "Throughput Performance",
+        3 => "Spin-Up Time",
+        4 => "Start/Stop Count",
+        5 => "Reallocated Sectors Count",
+        6 => "Read Channel Margin",
+        7 => "Seek Error Rate",
+        8 => "Seek Time Performance",
+        9 => "Power-On Hours",
+        10 => "Spin Retry Count",
+        11 => "Calibration Retry Count",
+        12 => "Power Cycle Count",
+        13 => "Soft Read Error Rate",
+        22 => "Current Helium Level",
+        170 => "Available Reserved Space",
+        171 => "Program Fail Count",
+        172 => "Erase Fail Count",
+        173 => "Wear Leveling Count",
+        174 => "Unexpected Power Loss Count",
+        175 => "Power Loss Protection Failure",
+        177 => "Wear Range Delta",
+        179 => "Used Reserved Block Count",
+        180 => "Unused Reserved Block Count",
+        181 => "Program Fail Count Total",
+        182 => "Erase Fail Count Total",
+        183 => "SATA Downshift Error Count",
+        184 => "End-to-End Error",
+        187 => "Reported Uncorrectable Errors",
+        188 => "Command Timeout",
+        189 => "High Fly Writes",
+        190 => "Airflow Temperature",
+        191 => "G-Sense Error Rate",
+        192 => "Power-Off Retract Count",
+        193 => "Load Cycle Count",
+        194 => "Temperature",
+        195 => "Hardware ECC Recovered",
+        196 => "Reallocation Event Count",
+        197 => "Current Pending Sector Count",
+        198 => "Offline Uncorrectable Sector Count",
+        199 => "UltraDMA CRC Error Count",
+        200 => "Multi-Zone Error Rate",
+        201 => "Soft Read Error Rate",
+        202 => "Data Address Mark Errors",
+        206 => "Flying Height",
+        220 => "Disk Shift",
+        222 => "Loaded Hours",
+        223 => "Load/Unload Retry Count",
+        224 => "Load Friction",
+        225 => "Load/Unload Cycle Count",
+        226 => "Load-In Time",
+        231 => "Life Left",
+        232 => "Endurance Remaining",
+        233 => "Media Wearout Indicator",
+        235 => "Good Block Count",
+        240 => "Head Flying Hours",
+        241 => "Total LBAs Written",
+        242 => "Total LBAs Read",
+        254 => "Free Fall Protection",
+        _ => "Vendor Specific",
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Vendor {
+    Seagate,
+    Intel,
+    Other,
+}
+
+impl Vendor {
+    fn from_model(model: &str) -> Self {
+        let model = model.trim().to_ascii_uppercase();
+        if model.starts_with("ST") || model.starts_with("SEAGATE") {
+            Vendor::Seagate
+        } else if model.starts_with("INTEL") {
+            Vendor::Intel
+        } else {
+            Vendor::Other
+        }
+    }
+}
+
+// The raw value is six vendor-defined bytes; these are the common layouts, and anything
+// else is shown as one 48-bit number.
+fn describe_raw(id: u8, raw: &[u8; 6], vendor: Vendor) -> String {
+    let word = |index: usize| u16::from_le_bytes([raw[index * 2], raw[index * 2 + 1]]);
+    let value = raw.iter().rev().fold(0u64, |value, byte| value << 8 | u64::from(*byte));
+    let low32 = value & 0xFFFF_FFFF;
+    match (id, vendor) {
+        (190 | 194, _) => {
+            let (current, minimum, maximum) = (raw[0], raw[2], raw[4]);
+            if minimum != 0 && maximum != 0 && minimum <= current && current <= maximum {
+                format!("{} °C (min {}, max {})", current, minimum, maximum)
+            } else {
+                format!("{} °C", current)
+            }
+        }
+        (9, _) => format!("{} hours", low32),
+        (3, _) => format!("{} ms", word(0)),
+        // Seagate packs the error count above the number of operations it was out of.
+        (1 | 7 | 195, Vendor::Seagate) => format!("{} errors in {} operations", word(2), low32),
+        (188, Vendor::Seagate) => format!("{}/{}/{}", word(0), word(1), word(2)),
+        (241 | 242, Vendor::Intel) => format!("{} ({:.1} GB)", value, value as f64 * 32.0 * 1048576.0 / 1e9),
+        (241 | 242, _) => format!("{} ({:.1} GB)", value, value as f64 * 512.0 / 1e9),
+        _ => value.to_string(),
+    }
+}
+
+pub struct SmartAttribute {
+    pub id: u8,
+    pub name: &'static str,
+    pub flags: u16,
+    pub current: u8,
+    pub worst: u8,
+    pub threshold: Option<u8>,
+    pub raw: u64,
+    pub raw_text: String,
+}
+
+impl SmartAttribute {
+    pub fn prefailure(&self) -> bool {
+        self.flags & FLAG_PREFAILURE != 0
+    }
+
+    pub fn kind(&self) -> &'static str {
+        match (self.prefailure(), self.flags & FLAG_ONLINE != 0) {
+            (true, true) => "Pre-failure, online",
+            (true, false) => "Pre-failure",
+            (false, true) => "Old age, online",
+            (false, false) => "Old age",
+        }
+    }
+
+    // A threshold of zero means the attribute can never fail.
+    pub fn failing_now(&self) -> bool {
+        self.threshold.is_some_and(|threshold| threshold != 0 && self.current <= threshold)
+    }
+
+    pub fn failed_in_past(&self) -> bool {
+        self.threshold.is_some_and(|threshold| threshold != 0 && self.worst <= threshold)
+    }
+}
+
+pub struct SmartData {
+    pub revision: u16,
+    pub attributes: Vec<SmartAttribute>,
+    pub offline_status: u8,
+    pub self_test_status: u8,
+    pub checksum_valid: bool,
+    pub thresholds_checksum_valid: Option<bool>,
+}
+
+fn checksum_valid(page: &[u8]) -> bool {
+    page.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte)) == 0
+}
+
+impl SmartData {
+    // Decodes SMART READ DATA and, when available, READ THRESHOLDS pages, however they
+    // were obtained. `model` picks the vendor's raw value layouts.
+    pub fn parse(data: &[u8], thresholds: Option<&[u8]>, model: &str) -> io::Result<Self> {
+        if data.len() != SMART_PAGE_SIZE {
+            return Err(invalid_data(format!("SMART data is {} bytes, expected {}", data.len(), SMART_PAGE_SIZE)));
+        }
+        if let Some(thresholds) = thresholds.filter(|thresholds| thresholds.len() != SMART_PAGE_SIZE) {
+            return Err(invalid_data(format!("SMART thresholds are {} bytes, expected {}", thresholds.len(), SMART_PAGE_SIZE)));
+        }
+        let vendor = Vendor::from_model(model);
+        let entries = |page: &[u8]| {
+            page[FIRST_ENTRY..FIRST_ENTRY + ATTRIBUTE_COUNT * ENTRY_SIZE]
+                .chunks_exact(ENTRY_SIZE)
+                .filter(|entry| entry[0] != 0)
+                .map(|entry| entry.to_vec())
+                .collect::<Vec<_>>()
+        };
+        let threshold_entries = thresholds.map(entries).unwrap_or_default();
+        let attributes = entries(data)
+            .into_iter()
+            .map(|entry| {
+                let id = entry[0];
+                let raw: [u8; 6] = entry[5..11].try_into().unwrap();
+                SmartAttribute {
+                    id,
+                    name: attribute_name(id),
+                    flags: u16::from_le_bytes([entry[1], entry[2]]),
+                    current: entry[3],
+                    worst: entry[4],
+                    threshold: threshold_entries.iter().find(|threshold| threshold[0] == id).map(|threshold| threshold[1]),
+                    raw: raw.iter().rev().fold(0u64, |value, byte| value << 8 | u64::from(*byte)),
+                    raw_text: describe_raw(id, &raw, vendor),
+                }
+            })
+            .collect();
+        Ok(Self {
+            revision: u16::from_le_bytes([data[0], data[1]]),
+            attributes,
+            offline_status: data[OFFLINE_STATUS],
+            self_test_status: data[SELF_TEST_STATUS],
+            checksum_valid: checksum_valid(data),
+            thresholds_checksum_valid: thresholds.map(checksum_valid),
+        })
+    }
+
+    pub fn describe_offline_collection(&self) -> String {
+        let state = match self.offline_status & 0x7F {
+            0x00 => "never started",
+            0x02 => "completed without error",
+            0x03 => "in progress",
+            0x04 => "suspended",
+            0x05 => "aborted by the host",
+            0x06 => "aborted by a device error",
+            _ => "in a vendor-specific state",
+        };
+        let automatic = if self.offline_status & 0x80 != 0 { ", automatic collection enabled" } else { "" };
+        format!("Offline data collection {}{}", state, automatic)
+    }
+
+    pub fn describe_self_test(&self) -> String {
+        let remaining = (self.self_test_status & 0x0F) as u32 * 10;
+        match self.self_test_status >> 4 {
+            0 => "Last self-test completed without error".to_string(),
+            1 => "Last self-test was aborted by the host".to_string(),
+            2 => "Last self-test was interrupted by a reset".to_string(),
+            3 => "Last self-test could not complete because of a fatal error".to_string(),
+            4 => "Last self-test failed on an unknown element".to_string(),
+            5 => "Last self-test failed on its electrical element".to_string(),
+            6 => "Last self-test failed on its servo or seek element".to_string(),
+            7 => "Last self-test failed on its read element".to_string(),
+            8 => "Last self-test failed, probably from handling damage".to_string(),
+            15 => format!("Self-test in progress, {}% remaining", remaining),
+            status => format!("Self-test status {:#x}", status),
+        }
+    }
+
+    // Like the drive's own SMART RETURN STATUS, a pre-failure attribute at or below its
+    // threshold means the drive predicts its own failure; the rest are early warnings.
+    pub fn assess(&self) -> (Verdict, Vec<String>) {
+        let mut verdict = Verdict::Passed;
+        let mut reasons = Vec::new();
+        let mut report = |level: Verdict, reason: String| {
+            verdict = verdict.max(level);
+            reasons.push(reason);
+        };
+        if !self.checksum_valid || self.thresholds_checksum_valid == Some(false) {
+            report(Verdict::Warning, "The SMART data checksum does not match; the values may be unreliable".to_string());
+        }
+        for attribute in &self.attributes {
+            if attribute.failing_now() {
+                match attribute.prefailure() {
+                    true => report(Verdict::Failing, format!("{} ({}) is at or below its threshold", attribute.name, attribute.id)),
+                    false => report(
+                        Verdict::Warning,
+                        format!("{} ({}) is past its threshold; the drive is beyond its rated life", attribute.name, attribute.id),
+                    ),
+                }
+            } else if attribute.failed_in_past() {
+                report(Verdict::Warning, format!("{} ({}) was at or below its threshold in the past", attribute.name, attribute.id));
+            }
+            if SECTOR_PROBLEM_ATTRIBUTES.contains(&attribute.id) && attribute.raw & 0xFFFF_FFFF != 0 {
+                report(Verdict::Warning, format!("{} ({}): {} sector(s)", attribute.name, attribute.id, attribute.raw & 0xFFFF_FFFF));
+            }
+        }
+        if matches!(self.self_test_status >> 4, 3..=8) {
+            report(Verdict::Warning, self.describe_self_test());
+        }
+        (verdict, reasons)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DATA: &[u8; SMART_PAGE_SIZE] = include_bytes!("fixtures/smart-st2000dm008-data.bin");
+    const THRESHOLDS: &[u8; SMART_PAGE_SIZE] = include_bytes!("fixtures/smart-st2000dm008-thresholds.bin");
+    const MODEL: &str = "ST2000DM008-2FR102";
+
+    fn parse(data: &[u8]) -> SmartData {
+        SmartData::parse(data, Some(THRESHOLDS), MODEL).unwrap()
+    }
+
+    fn attribute(smart: &SmartData, id: u8) -> &SmartAttribute {
+        smart.attributes.iter().find(|attribute| attribute.id == id).unwrap()
+    }
+
+    // Changes the entry of attribute `id` and fixes up the page checksum after it.
+    fn edit(id: u8, change: impl Fn(&mut [u8])) -> Vec<u8> {
+        let mut data = DATA.to_vec();
+        let entry = data[FIRST_ENTRY..FIRST_ENTRY + ATTRIBUTE_COUNT * ENTRY_SIZE]
+            .chunks_exact_mut(ENTRY_SIZE)
+            .find(|entry| entry[0] == id)
+            .unwrap();
+        change(entry);
+        data[SMART_PAGE_SIZE - 1] = 0;
+        data[SMART_PAGE_SIZE - 1] = data.iter().fold(0u8, |sum, byte| sum.wrapping_sub(*byte));
+        data
+    }
+
+    #[test]
+    fn decodes_attributes() {
+        let smart = parse(DATA);
+        assert_eq!(smart.revision, 10);
+        assert_eq!(smart.attributes.len(), 25);
+        assert!(smart.checksum_valid);
+        assert_eq!(smart.thresholds_checksum_valid, Some(true));
+
+        let reallocated = attribute(&smart, 5);
+        assert_eq!(reallocated.name, "Reallocated Sectors Count");
+        assert_eq!(reallocated.flags, 0x0033);
+        assert!(reallocated.prefailure());
+        assert_eq!(reallocated.kind(), "Pre-failure, online");
+        assert_eq!((reallocated.current, reallocated.worst, reallocated.threshold), (100, 100, Some(10)));
+
+        let seek_errors = attribute(&smart, 7);
+        assert_eq!((seek_errors.current, seek_errors.worst, seek_errors.threshold), (87, 60, Some(45)));
+
+        let power_cycles = attribute(&smart, 12);
+        assert_eq!(power_cycles.name, "Power Cycle Count");
+        assert_eq!(power_cycles.kind(), "Old age, online");
+        assert_eq!(power_cycles.raw, 1240);
+
+        assert_eq!(attribute(&smart, 183).name, "SATA Downshift Error Count");
+        assert_eq!(attribute(&smart, 240).kind(), "Old age");
+    }
+
+    #[test]
+    fn missing_thresholds_leave_them_unknown() {
+        let smart = SmartData::parse(DATA, None, MODEL).unwrap();
+        assert!(smart.attributes.iter().all(|attribute| attribute.threshold.is_none()));
+        assert_eq!(smart.thresholds_checksum_valid, None);
+    }
+
+    #[test]
+    fn interprets_vendor_raw_values() {
+        let smart = parse(DATA);
+        assert_eq!(attribute(&smart, 1).raw_text, "0 errors in 102456232 operations");
+        assert_eq!(attribute(&smart, 7).raw_text, "3 errors in 512365012 operations");
+        assert_eq!(attribute(&smart, 9).raw_text, "16321 hours");
+        assert_eq!(attribute(&smart, 3).raw_text, "0 ms");
+        assert_eq!(attribute(&smart, 188).raw_text, "0/1/2");
+        assert_eq!(attribute(&smart, 190).raw_text, "34 °C (min 25, max 41)");
+        assert_eq!(attribute(&smart, 194).raw_text, "34 °C (min 21, max 48)");
+        assert_eq!(attribute(&smart, 241).raw_text, "23456789012 (12009.9 GB)");
+
+        // Other vendors get the plain 48-bit number, and Intel counts 32 MiB units.
+        let other = SmartData::parse(DATA, Some(THRESHOLDS), "WDC WD20EZRZ-00Z5HB0").unwrap();
+        assert_eq!(attribute(&other, 7).raw_text, ((3u64 << 32) | 512365012).to_string());
+        let intel = SmartData::parse(DATA, Some(THRESHOLDS), "INTEL SSDSC2KB480G8").unwrap();
+        assert_eq!(attribute(&intel, 241).raw_text, "23456789012 (787079231.8 GB)");
+    }
+
+    #[test]
+    fn healthy_drive_passes() {
+        let (verdict, reasons) = parse(DATA).assess();
+        assert_eq!(verdict, Verdict::Passed);
+        assert!(reasons.is_empty());
+    }
+
+    #[test]
+    fn reallocated_sectors_warn() {
+        let data = edit(5, |entry| entry[5] = 8);
+        let (verdict, reasons) = parse(&data).assess();
+        assert_eq!(verdict, Verdict::Warning);
+        assert_eq!(reasons, ["Reallocated Sectors Count (5): 8 sector(s)"]);
+    }
+
+    #[test]
+    fn prefailure_attribute_at_threshold_fails() {
+        let data = edit(5, |entry| {
+            entry[3] = 10;
+            entry[4] = 10;
+        });
+        let smart = parse(&data);
+        assert!(attribute(&smart, 5).failing_now());
+        let (verdict, reasons) = smart.assess();
+        assert_eq!(verdict, Verdict::Failing);
+        assert_eq!(reasons, ["Reallocated Sectors Count (5) is at or below its threshold"]);
+    }
+
+    #[test]
+    fn bad_checksum_warns() {
+        let mut data = DATA.to_vec();
+        data[SMART_PAGE_SIZE - 1] ^= 1;
+        let smart = parse(&data);
+        assert!(!smart.checksum_valid);
+        assert_eq!(smart.assess().0, Verdict::Warning);
+    }
+
+    #[test]
+    fn truncated_page_is_rejected() {
+        assert!(SmartData::parse(&DATA[..SMART_PAGE_SIZE - 1], Some(THRESHOLDS), MODEL).is_err());
+        assert!(SmartData::parse(DATA, Some(&THRESHOLDS[..256]), MODEL).is_err());
+    }
+}
@@ -0,0 +1,333 @@
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::os::windows::io::AsRawHandle;
+use std::path::Path;
+use std::ptr::null_mut;
+use widestring::U16CString;
+use winapi::um::fileapi::{
+    CreateFileW, FlushFileBuffers, GetVolumeNameForVolumeMountPointW, GetVolumePathNameW, ReadFile, SetFilePointerEx, WriteFile,
+    OPEN_EXISTING,
+};
+use winapi::um::ioapiset::DeviceIoControl;
+use winapi::um::handleapi::{CloseHandle, INVALID_HANDLE_VALUE};
+use winapi::um::winbase::FILE_BEGIN;
+use winapi::um::winioctl::{FSCTL_SET_SPARSE, IOCTL_DISK_UPDATE_PROPERTIES, IOCTL_VOLUME_GET_VOLUME_DISK_EXTENTS};
+use winapi::um::winnt::{FILE_ATTRIBUTE_NORMAL, FILE_SHARE_READ, FILE_SHARE_WRITE, GENERIC_READ, GENERIC_WRITE, HANDLE, LARGE_INTEGER};
+
+pub trait Disk: Send {
+    fn size(&self) -> u64;
+
+    fn sector_size(&self) -> u32 {
+        512
+    }
+
+    fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> io::Result<()>;
+
+    fn write_at(&mut self, _offset: u64, _buf: &[u8]) -> io::Result<()> {
+        Err(io::Error::new(io::ErrorKind::PermissionDenied, "disk is opened read-only"))
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn format_name(&self) -> &'static str {
+        "Raw"
+    }
+
+    fn details(&self) -> Vec<(String, String)> {
+        Vec::new()
+    }
+
+    fn read_bytes(&mut self, offset: u64, len: usize) -> io::Result<Vec<u8>> {
+        let mut buf = vec![0u8; len];
+        self.read_at(offset, &mut buf)?;
+        Ok(buf)
+    }
+}
+
+pub struct PhysicalDrive {
+    handle: HANDLE,
+    size: u64,
+    sector_size: u32,
+}
+
+unsafe impl Send for PhysicalDrive {}
+
+impl PhysicalDrive {
+    pub fn open(index: usize, writable: bool) -> io::Result<Self> {
+        let device_path = format!("\\\\.\\PHYSICALDRIVE{}", index);
+        let device_path_utf16 = U16CString::from_str(&device_path)
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "invalid device path"))?;
+        let access = if writable { GENERIC_READ | GENERIC_WRITE } else { GENERIC_READ };
+        let handle = unsafe {
+            CreateFileW(
+                device_path_utf16.as_ptr(),
+                access,
+                FILE_SHARE_READ | FILE_SHARE_WRITE,
+                null_mut(),
+                OPEN_EXISTING,
+                FILE_ATTRIBUTE_NORMAL,
+                null_mut(),
+            )
+        };
+        if handle == INVALID_HANDLE_VALUE {
+            return Err(io::Error::last_os_error());
+        }
+        let drive = Self { handle, size: 0, sector_size: 512 };
+        let geometry = crate::get_drive_geometry(handle)
+            .ok_or_else(|| io::Error::other("failed to get disk geometry"))?;
+        Ok(Self {
+            size: unsafe { *geometry.DiskSize.QuadPart() } as u64,
+            sector_size: geometry.Geometry.BytesPerSector.max(512),
+            ..drive
+        })
+    }
+
+    fn seek(&mut self, offset: u64) -> io::Result<()> {
+        let mut distance: LARGE_INTEGER = unsafe { std::mem::zeroed() };
+        unsafe { *distance.QuadPart_mut() = offset as i64 };
+        if unsafe { SetFilePointerEx(self.handle, distance, null_mut(), FILE_BEGIN) } == 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    fn read_exact(&mut self, buf: &mut [u8]) -> io::Result<()> {
+        let mut bytes_read: u32 = 0;
+        let result = unsafe { ReadFile(self.handle, buf.as_mut_ptr() as *mut _, buf.len() as u32, &mut bytes_read, null_mut()) };
+        if result == 0 {
+            Err(io::Error::last_os_error())
+        } else if bytes_read as usize != buf.len() {
+            Err(io::Error::new(io::ErrorKind::UnexpectedEof, "short read from physical drive"))
+        } else {
+            Ok(())
+        }
+    }
+
+    fn write_all(&mut self, buf: &[u8]) -> io::Result<()> {
+        let mut bytes_written: u32 = 0;
+        let result = unsafe { WriteFile(self.handle, buf.as_ptr() as *const _, buf.len() as u32, &mut bytes_written, null_mut()) };
+        if result == 0 {
+            Err(io::Error::last_os_error())
+        } else if bytes_written as usize != buf.len() {
+            Err(io::Error::new(io::ErrorKind::WriteZero, "short write to physical drive"))
+        } else {
+            Ok(())
+        }
+    }
+
+    fn aligned_range(&self, offset: u64, len: usize) -> io::Result<(u64, usize)> {
+        let sector_size = self.sector_size as u64;
+        let start = offset / sector_size * sector_size;
+        let end = offset
+            .checked_add(len as u64)
+            .and_then(|end| end.div_ceil(sector_size).checked_mul(sector_size))
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "offset and length overflow the addressable range"))?;
+        Ok((start, (end - start) as usize))
+    }
+}
+
+// Raw device handles only accept whole-sector transfers, so unaligned requests
+// go through a sector-aligned bounce buffer.
+impl Disk for PhysicalDrive {
+    fn size(&self) -> u64 {
+        self.size
+    }
+
+    fn sector_size(&self) -> u32 {
+        self.sector_size
+    }
+
+    fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> io::Result<()> {
+        let (start, len) = self.aligned_range(offset, buf.len())?;
+        let mut bounce = vec![0u8; len];
+        self.seek(start)?;
+        self.read_exact(&mut bounce)?;
+        let skip = (offset - start) as usize;
+        buf.copy_from_slice(&bounce[skip..skip + buf.len()]);
+        Ok(())
+    }
+
+    fn write_at(&mut self, offset: u64, buf: &[u8]) -> io::Result<()> {
+        let (start, len) = self.aligned_range(offset, buf.len())?;
+        let mut bounce = vec![0u8; len];
+        if start != offset || len != buf.len() {
+            self.seek(start)?;
+            self.read_exact(&mut bounce)?;
+        }
+        let skip = (offset - start) as usize;
+        bounce[skip..skip + buf.len()].copy_from_slice(buf);
+        self.seek(start)?;
+        self.write_all(&bounce)
+    }
+
+    fn format_name(&self) -> &'static str {
+        "Physical drive"
+    }
+
+    // Windows caches the partition layout, so ask it to re-read the table after writing.
+    fn flush(&mut self) -> io::Result<()> {
+        if unsafe { FlushFileBuffers(self.handle) } == 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let mut bytes_returned: u32 = 0;
+        unsafe {
+            DeviceIoControl(
+                self.handle,
+                IOCTL_DISK_UPDATE_PROPERTIES,
+                null_mut(),
+                0,
+                null_mut(),
+                0,
+                &mut bytes_returned,
+                null_mut(),
+            )
+        };
+        Ok(())
+    }
+}
+
+impl Drop for PhysicalDrive {
+    fn drop(&mut self) {
+        unsafe { CloseHandle(self.handle) };
+    }
+}
+
+const RAW_IMAGE: &str = "Raw image";
+
+pub struct ImageFile {
+    file: File,
+    size: u64,
+}
+
+impl ImageFile {
+    pub fn open(path: &str, writable: bool) -> io::Result<Self> {
+        let file = OpenOptions::new().read(true).write(writable).open(path)?;
+        let size = file.metadata()?.len();
+        Ok(Self { file, size })
+    }
+}
+
+impl Disk for ImageFile {
+    fn size(&self) -> u64 {
+        self.size
+    }
+
+    fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> io::Result<()> {
+        self.file.seek(SeekFrom::Start(offset))?;
+        self.file.read_exact(buf)
+    }
+
+    fn write_at(&mut self, offset: u64, buf: &[u8]) -> io::Result<()> {
+        self.file.seek(SeekFrom::Start(offset))?;
+        self.file.write_all(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+
+    fn format_name(&self) -> &'static str {
+        RAW_IMAGE
+    }
+}
+
+// Writes go to disk offsets, so only a raw image can take them; in any container
+// format they would land on its headers and tables instead.
+pub fn open_disk(target: &str, writable: bool) -> io::Result<Box<dyn Disk>> {
+    match target.parse::<usize>() {
+        Ok(index) => Ok(Box::new(PhysicalDrive::open(index, writable)?)),
+        Err(_) if writable => {
+            let format = crate::vdisk::open_virtual_disk(target)?.format_name();
+            if format != RAW_IMAGE {
+                return Err(io::Error::new(
+                    io::ErrorKind::Unsupported,
+                    format!("{} is a {} image; only raw images can be modified, so convert it to raw first", target, format),
+                ));
+            }
+            Ok(Box::new(ImageFile::open(target, true)?))
+        }
+        Err(_) => crate::vdisk::open_virtual_disk(target),
+    }
+}
+
+// Lets zero ranges that are never written become holes instead of allocated zeroes.
+pub fn mark_sparse(file: &File) -> io::Result<()> {
+    let mut returned = 0;
+    let ok = unsafe {
+        DeviceIoControl(
+            file.as_raw_handle() as _,
+            FSCTL_SET_SPARSE,
+            null_mut(),
+            0,
+            null_mut(),
+            0,
+            &mut returned,
+            null_mut(),
+        )
+    };
+    if ok == 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+// Physical drive numbers under the volume holding `path`; a spanned or mirrored
+// volume lies on several. The path must exist.
+pub fn physical_drives_of(path: &Path) -> io::Result<Vec<u32>> {
+    let path_utf16 = U16CString::from_os_str(path.as_os_str()).map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "path contains a NUL"))?;
+    let mut mount_point = [0u16; 1024];
+    let mut volume_name = [0u16; 64];
+    unsafe {
+        if GetVolumePathNameW(path_utf16.as_ptr(), mount_point.as_mut_ptr(), mount_point.len() as u32) == 0
+            || GetVolumeNameForVolumeMountPointW(mount_point.as_ptr(), volume_name.as_mut_ptr(), volume_name.len() as u32) == 0
+        {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    // "\\?\Volume{GUID}\" opens the volume itself once the trailing backslash is gone.
+    let length = volume_name.iter().position(|c| *c == 0).unwrap_or(volume_name.len());
+    let volume = String::from_utf16_lossy(&volume_name[..length]);
+    let volume_utf16 = U16CString::from_str(volume.trim_end_matches('\\')).unwrap();
+    let handle = unsafe {
+        CreateFileW(
+            volume_utf16.as_ptr(),
+            0,
+            FILE_SHARE_READ | FILE_SHARE_WRITE,
+            null_mut(),
+            OPEN_EXISTING,
+            FILE_ATTRIBUTE_NORMAL,
+            null_mut(),
+        )
+    };
+    if handle == INVALID_HANDLE_VALUE {
+        return Err(io::Error::last_os_error());
+    }
+    // VOLUME_DISK_EXTENTS: a count, padding, then 24-byte DISK_EXTENTs led by the disk number.
+    let mut extents = vec![0u8; 8 + 24 * 32];
+    let mut bytes_returned: u32 = 0;
+    let ok = unsafe {
+        DeviceIoControl(
+            handle,
+            IOCTL_VOLUME_GET_VOLUME_DISK_EXTENTS,
+            null_mut(),
+            0,
+            extents.as_mut_ptr() as *mut _,
+            extents.len() as u32,
+            &mut bytes_returned,
+            null_mut(),
+        )
+    };
+    let error = io::Error::last_os_error();
+    unsafe { CloseHandle(handle) };
+    if ok == 0 {
+        return Err(error);
+    }
+    let count = u32::from_le_bytes(extents[0..4].try_into().unwrap()) as usize;
+    let mut drives: Vec<u32> = (0..count.min(32))
+        .map(|i| u32::from_le_bytes(extents[8 + i * 24..12 + i * 24].try_into().unwrap()))
+        .collect();
+    drives.dedup();
+    Ok(drives)
+}
@@ -0,0 +1,376 @@
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Condvar, Mutex};
+use std::thread;
+
+const MAX_SCAN_THREADS: usize = 16;
+const NO_EXTENSION: &str = "(none)";
+
+pub struct SizeNode {
+    pub name: String,
+    // For a folder, the total of everything beneath it.
+    pub size: u64,
+    pub files: u64,
+    pub directory: bool,
+    pub parent: Option<usize>,
+    pub children: Vec<usize>,
+    pub error: Option<String>,
+}
+
+#[derive(Default)]
+pub struct ScanProgress {
+    pub files: AtomicU64,
+    pub directories: AtomicU64,
+    pub bytes: AtomicU64,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SortKey {
+    Size,
+    Files,
+    Name,
+}
+
+impl SortKey {
+    pub const ALL: [SortKey; 3] = [SortKey::Size, SortKey::Files, SortKey::Name];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            SortKey::Size => "Size",
+            SortKey::Files => "Files",
+            SortKey::Name => "Name",
+        }
+    }
+}
+
+pub struct ExtensionTotal {
+    pub extension: String,
+    pub bytes: u64,
+    pub files: u64,
+}
+
+// Sizes are file lengths as the directory listing reports them; hard links count once
+// per name, and symbolic links and junctions are not followed.
+pub struct SizeTree {
+    pub root: PathBuf,
+    pub nodes: Vec<SizeNode>,
+    pub unreadable: u64,
+    pub skipped_links: u64,
+    pub cancelled: bool,
+}
+
+struct ScanQueue {
+    nodes: Vec<SizeNode>,
+    pending: Vec<(usize, PathBuf)>,
+    busy: usize,
+    unreadable: u64,
+    skipped_links: u64,
+}
+
+struct Listing {
+    entries: Vec<(String, u64, bool)>,
+    links: u64,
+}
+
+fn list_directory(path: &Path, progress: &ScanProgress) -> io::Result<Listing> {
+    let mut listing = Listing {
+        entries: Vec::new(),
+        links: 0,
+    };
+    for entry in fs::read_dir(path)? {
+        let entry = entry?;
+        let file_type = entry.file_type()?;
+        if file_type.is_symlink() {
+            listing.links += 1;
+            continue;
+        }
+        let name = entry.file_name().to_string_lossy().into_owned();
+        if file_type.is_dir() {
+            progress.directories.fetch_add(1, Ordering::Relaxed);
+            listing.entries.push((name, 0, true));
+        } else {
+            let size = entry.metadata().map_or(0, |metadata| metadata.len());
+            progress.files.fetch_add(1, Ordering::Relaxed);
+            progress.bytes.fetch_add(size, Ordering::Relaxed);
+            listing.entries.push((name, size, false));
+        }
+    }
+    Ok(listing)
+}
+
+impl SizeTree {
+    // Folders are listed by a pool of threads taking them from a shared queue, so a
+    // slow folder does not hold up its siblings. A cancelled scan keeps what was
+    // listed so far.
+    pub fn scan(root: &Path, cancel: &AtomicBool, progress: &ScanProgress) -> io::Result<Self> {
+        let root_listing = list_directory(root, progress)?;
+        let queue = Mutex::new(ScanQueue {
+            nodes: vec![SizeNode {
+                name: root.display().to_string(),
+                size: 0,
+                files: 0,
+                directory: true,
+                parent: None,
+                children: Vec::new(),
+                error: None,
+            }],
+            pending: Vec::new(),
+            busy: 0,
+            unreadable: 0,
+            skipped_links: 0,
+        });
+        let changed = Condvar::new();
+        add_listing(&mut queue.lock().unwrap(), 0, root, Ok(root_listing));
+
+        let threads = thread::available_parallelism().map_or(4, |count| count.get()).clamp(2, MAX_SCAN_THREADS);
+        thread::scope(|scope| {
+            for _ in 0..threads {
+                scope.spawn(|| loop {
+                    let (parent, path) = {
+                        let mut state = queue.lock().unwrap();
+                        loop {
+                            if cancel.load(Ordering::Relaxed) {
+                                state.pending.clear();
+                            }
+                            if let Some(job) = state.pending.pop() {
+                                state.busy += 1;
+                                break job;
+                            }
+                            if state.busy == 0 {
+                                changed.notify_all();
+                                return;
+                            }
+                            state = changed.wait(state).unwrap();
+                        }
+                    };
+                    let listing = list_directory(&path, progress);
+                    let mut state = queue.lock().unwrap();
+                    add_listing(&mut state, parent, &path, listing);
+                    state.busy -= 1;
+                    changed.notify_all();
+                });
+            }
+        });
+
+        let state = queue.into_inner().unwrap();
+        let mut tree = Self {
+            root: root.to_path_buf(),
+            nodes: state.nodes,
+            unreadable: state.unreadable,
+            skipped_links: state.skipped_links,
+            cancelled: cancel.load(Ordering::Relaxed),
+        };
+        // Every node comes after its parent, so one backward pass adds up the folders.
+        for index in (1..tree.nodes.len()).rev() {
+            let (size, files) = (tree.nodes[index].size, tree.nodes[index].files);
+            if let Some(parent) = tree.nodes[index].parent {
+                tree.nodes[parent].size += size;
+                tree.nodes[parent].files += files;
+            }
+        }
+        for index in 0..tree.nodes.len() {
+            let mut children = std::mem::take(&mut tree.nodes[index].children);
+            children.sort_by_key(|child| Reverse(tree.nodes[*child].size));
+            tree.nodes[index].children = children;
+        }
+        Ok(tree)
+    }
+
+    pub fn path(&self, node: usize) -> PathBuf {
+        let mut names = Vec::new();
+        let mut current = node;
+        while let Some(parent) = self.nodes[current].parent {
+            names.push(self.nodes[current].name.as_str());
+            current = parent;
+        }
+        names.iter().rev().fold(self.root.clone(), |path, name| path.join(name))
+    }
+
+    // Children are kept largest first; other orders are sorted on request.
+    pub fn sorted_children(&self, node: usize, key: SortKey, descending: bool) -> Vec<usize> {
+        let mut children = self.nodes[node].children.clone();
+        match key {
+            SortKey::Size => children.sort_by_key(|child| self.nodes[*child].size),
+            SortKey::Files => children.sort_by_key(|child| self.nodes[*child].files),
+            SortKey::Name => children.sort_by_key(|child| self.nodes[*child].name.to_lowercase()),
+        }
+        if descending {
+            children.reverse();
+        }
+        children
+    }
+
+    fn descendants(&self, node: usize) -> impl Iterator<Item = usize> + '_ {
+        let mut stack = vec![node];
+        std::iter::from_fn(move || {
+            let next = stack.pop()?;
+            stack.extend(&self.nodes[next].children);
+            Some(next)
+        })
+    }
+
+    pub fn largest_files(&self, node: usize, count: usize) -> Vec<usize> {
+        let mut heap = BinaryHeap::new();
+        for index in self.descendants(node).filter(|index| !self.nodes[*index].directory) {
+            heap.push(Reverse((self.nodes[index].size, index)));
+            if heap.len() > count {
+                heap.pop();
+            }
+        }
+        let mut files: Vec<(u64, usize)> = heap.into_iter().map(|Reverse(file)| file).collect();
+        files.sort_by_key(|(size, _)| Reverse(*size));
+        files.into_iter().map(|(_, index)| index).collect()
+    }
+
+    pub fn extensions(&self, node: usize) -> Vec<ExtensionTotal> {
+        let mut totals: HashMap<String, (u64, u64)> = HashMap::new();
+        for index in self.descendants(node).filter(|index| !self.nodes[*index].directory) {
+            let total = totals.entry(extension_of(&self.nodes[index].name)).or_default();
+            total.0 += self.nodes[index].size;
+            total.1 += 1;
+        }
+        let mut extensions: Vec<ExtensionTotal> = totals
+            .into_iter()
+            .map(|(extension, (bytes, files))| ExtensionTotal { extension, bytes, files })
+            .collect();
+        extensions.sort_by(|a, b| b.bytes.cmp(&a.bytes).then_with(|| a.extension.cmp(&b.extension)));
+        extensions
+    }
+}
+
+fn add_listing(state: &mut ScanQueue, parent: usize, path: &Path, listing: io::Result<Listing>) {
+    let listing = match listing {
+        Ok(listing) => listing,
+        Err(err) => {
+            state.nodes[parent].error = Some(err.to_string());
+            state.unreadable += 1;
+            return;
+        }
+    };
+    state.skipped_links += listing.links;
+    for (name, size, directory) in listing.entries {
+        let index = state.nodes.len();
+        if directory {
+            state.pending.push((index, path.join(&name)));
+        }
+        state.nodes.push(SizeNode {
+            name,
+            size,
+            files: u64::from(!directory),
+            directory,
+            parent: Some(parent),
+            children: Vec::new(),
+            error: None,
+        });
+        state.nodes[parent].children.push(index);
+    }
+}
+
+pub fn extension_of(name: &str) -> String {
+    match name.rsplit_once('.') {
+        Some((stem, extension)) if !stem.is_empty() && !extension.is_empty() => extension.to_lowercase(),
+        _ => NO_EXTENSION.to_string(),
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct TreemapRect {
+    pub x: f32,
+    pub y: f32,
+    pub width: f32,
+    pub height: f32,
+}
+
+// Squarified treemap (Bruls, Huizing and van Wijk): items are laid out in rows along
+// the shorter side of what is left, and a row takes another item only while that keeps
+// its worst aspect ratio from getting worse. `sizes` must be largest first; the
+// rectangles come back in the same order.
+pub fn squarify(sizes: &[u64], area: TreemapRect) -> Vec<TreemapRect> {
+    let total: u64 = sizes.iter().sum();
+    let empty = TreemapRect {
+        width: 0.0,
+        height: 0.0,
+        ..area
+    };
+    if total == 0 || area.width <= 0.0 || area.height <= 0.0 {
+        return vec![empty; sizes.len()];
+    }
+    let scale = f64::from(area.width) * f64::from(area.height) / total as f64;
+    let areas: Vec<f64> = sizes.iter().map(|size| *size as f64 * scale).collect();
+    let (mut x, mut y) = (f64::from(area.x), f64::from(area.y));
+    let (mut width, mut height) = (f64::from(area.width), f64::from(area.height));
+    let mut rects = Vec::with_capacity(sizes.len());
+    let mut start = 0;
+    while start < areas.len() && areas[start] > 0.0 {
+        let side = width.min(height);
+        let mut end = start + 1;
+        let mut sum = areas[start];
+        let mut ratio = worst_ratio(areas[start], areas[start], sum, side);
+        while end < areas.len() && areas[end] > 0.0 {
+            let next = worst_ratio(areas[start], areas[end], sum + areas[end], side);
+            if next > ratio {
+                break;
+            }
+            ratio = next;
+            sum += areas[end];
+            end += 1;
+        }
+        let thickness = sum / side;
+        let mut offset = 0.0;
+        for item in &areas[start..end] {
+            let length = item / thickness;
+            rects.push(if width >= height {
+                TreemapRect {
+                    x: x as f32,
+                    y: (y + offset) as f32,
+                    width: thickness as f32,
+                    height: length as f32,
+                }
+            } else {
+                TreemapRect {
+                    x: (x + offset) as f32,
+                    y: y as f32,
+                    width: length as f32,
+                    height: thickness as f32,
+                }
+            });
+            offset += length;
+        }
+        if width >= height {
+            x += thickness;
+            width = (width - thickness).max(0.0);
+        } else {
+            y += thickness;
+            height = (height - thickness).max(0.0);
+        }
+        start = end;
+    }
+    rects.resize(sizes.len(), empty);
+    rects
+}
+
+// The worst aspect ratio in a row of total area `sum` laid along a side of length
+// `side`, from its largest and smallest items.
+fn worst_ratio(largest: f64, smallest: f64, sum: f64, side: f64) -> f64 {
+    let sum_squared = sum * sum;
+    let side_squared = side * side;
+    (side_squared * largest / sum_squared).max(sum_squared / (side_squared * smallest))
+}
+
+pub fn format_size(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["bytes", "KB", "MB", "GB", "TB"];
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit + 1 < UNITS.len() {
+        value /= 1024.0;
+        unit += 1;
+    }
+    match unit {
+        0 => format!("{} bytes", bytes),
+        _ => format!("{:.1} {}", value, UNITS[unit]),
+    }
+}
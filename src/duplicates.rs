@@ -0,0 +1,368 @@
+use crate::disk_usage::{format_size, ScanProgress, SizeTree};
+use crate::vdisk::to_hex;
+use sha2::{Digest, Sha256};
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Component, Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+const PARTIAL_HASH_SIZE: u64 = 64 * 1024;
+const HASH_BUFFER_SIZE: usize = 1024 * 1024;
+pub const MANIFEST_NAME: &str = "quarantine-manifest.txt";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DuplicateStage {
+    Listing,
+    PartialHash,
+    FullHash,
+}
+
+impl DuplicateStage {
+    pub fn name(self) -> &'static str {
+        match self {
+            DuplicateStage::Listing => "Listing files",
+            DuplicateStage::PartialHash => "Hashing the start of same-sized files",
+            DuplicateStage::FullHash => "Hashing whole files",
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct DuplicateGroup {
+    pub size: u64,
+    pub hash: String,
+    pub files: Vec<PathBuf>,
+}
+
+impl DuplicateGroup {
+    // Keeping one copy frees the space of all the others.
+    pub fn reclaimable(&self) -> u64 {
+        self.size * (self.files.len() as u64 - 1)
+    }
+}
+
+pub struct DuplicateReport {
+    pub groups: Vec<DuplicateGroup>,
+    pub files: u64,
+    pub unreadable: u64,
+    pub cancelled: bool,
+}
+
+impl DuplicateReport {
+    pub fn reclaimable(&self) -> u64 {
+        self.groups.iter().map(DuplicateGroup::reclaimable).sum()
+    }
+
+    pub fn describe(&self) -> String {
+        format!(
+            "{} group(s) of duplicates among {} file(s), {} reclaimable{}",
+            self.groups.len(),
+            self.files,
+            format_size(self.reclaimable()),
+            match self.unreadable {
+                0 => String::new(),
+                unreadable => format!(", {} file(s) or folder(s) could not be read", unreadable),
+            }
+        )
+    }
+}
+
+// Hashes the first `limit` bytes of a file, or all of it. Returns `None` when cancelled.
+fn hash_file(path: &Path, limit: u64, cancel: &AtomicBool, hashed: &mut dyn FnMut(u64)) -> io::Result<Option<String>> {
+    let mut file = File::open(path)?.take(limit);
+    let mut hasher = Sha256::new();
+    let mut buffer = vec![0u8; HASH_BUFFER_SIZE];
+    loop {
+        if cancel.load(Ordering::Relaxed) {
+            return Ok(None);
+        }
+        let read = file.read(&mut buffer)?;
+        if read == 0 {
+            return Ok(Some(to_hex(&hasher.finalize())));
+        }
+        hasher.update(&buffer[..read]);
+        hashed(read as u64);
+    }
+}
+
+// Files are compared by size first, then by a hash of their first 64 KiB, and only
+// files still alike after that are read in full, so most files are never read at all.
+// `progress` receives the stage with how far it is out of how much: roots for the
+// listing, files for the partial hash and bytes for the full one.
+pub fn find_duplicates(
+    roots: &[PathBuf],
+    min_size: u64,
+    cancel: &AtomicBool,
+    progress: &mut dyn FnMut(DuplicateStage, u64, u64),
+) -> io::Result<DuplicateReport> {
+    let mut report = DuplicateReport {
+        groups: Vec::new(),
+        files: 0,
+        unreadable: 0,
+        cancelled: false,
+    };
+    // Empty files are all alike and take no space, so they never count.
+    let min_size = min_size.max(1);
+    let mut seen = HashSet::new();
+    let mut by_size: HashMap<u64, Vec<PathBuf>> = HashMap::new();
+    for (index, root) in roots.iter().enumerate() {
+        progress(DuplicateStage::Listing, index as u64, roots.len() as u64);
+        let tree = SizeTree::scan(root, cancel, &ScanProgress::default())?;
+        report.unreadable += tree.unreadable;
+        for (node, entry) in tree.nodes.iter().enumerate() {
+            if entry.directory {
+                continue;
+            }
+            // Overlapping roots would otherwise make every file a duplicate of itself.
+            let path = tree.path(node);
+            if !seen.insert(path.clone()) {
+                continue;
+            }
+            report.files += 1;
+            if entry.size >= min_size {
+                by_size.entry(entry.size).or_default().push(path);
+            }
+        }
+    }
+    if cancel.load(Ordering::Relaxed) {
+        report.cancelled = true;
+        return Ok(report);
+    }
+
+    let candidates: Vec<(u64, Vec<PathBuf>)> = by_size.into_iter().filter(|(_, files)| files.len() > 1).collect();
+    let total = candidates.iter().map(|(_, files)| files.len() as u64).sum();
+    let mut done = 0;
+    let mut by_start: HashMap<(u64, String), Vec<PathBuf>> = HashMap::new();
+    for (size, files) in candidates {
+        for path in files {
+            progress(DuplicateStage::PartialHash, done, total);
+            done += 1;
+            match hash_file(&path, PARTIAL_HASH_SIZE, cancel, &mut |_| {}) {
+                Ok(Some(hash)) => by_start.entry((size, hash)).or_default().push(path),
+                Ok(None) => {
+                    report.cancelled = true;
+                    return Ok(report);
+                }
+                Err(_) => report.unreadable += 1,
+            }
+        }
+    }
+
+    // For files no larger than the partial hash, it already covered all of them.
+    let alike: Vec<((u64, String), Vec<PathBuf>)> = by_start.into_iter().filter(|(_, files)| files.len() > 1).collect();
+    let total = alike
+        .iter()
+        .filter(|((size, _), _)| *size > PARTIAL_HASH_SIZE)
+        .map(|((size, _), files)| size * files.len() as u64)
+        .sum();
+    let mut done = 0;
+    let mut by_content: HashMap<(u64, String), Vec<PathBuf>> = HashMap::new();
+    for ((size, start_hash), files) in alike {
+        if size <= PARTIAL_HASH_SIZE {
+            by_content.insert((size, start_hash), files);
+            continue;
+        }
+        for path in files {
+            let result = hash_file(&path, u64::MAX, cancel, &mut |hashed| {
+                done += hashed;
+                progress(DuplicateStage::FullHash, done, total);
+            });
+            match result {
+                Ok(Some(hash)) => by_content.entry((size, hash)).or_default().push(path),
+                Ok(None) => {
+                    report.cancelled = true;
+                    return Ok(report);
+                }
+                Err(_) => report.unreadable += 1,
+            }
+        }
+    }
+
+    report.groups = by_content
+        .into_iter()
+        .filter(|(_, files)| files.len() > 1)
+        .map(|((size, hash), mut files)| {
+            files.sort();
+            DuplicateGroup { size, hash, files }
+        })
+        .collect();
+    report.groups.sort_by(|a, b| b.reclaimable().cmp(&a.reclaimable()).then_with(|| a.files.cmp(&b.files)));
+    Ok(report)
+}
+
+fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\n']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+pub fn write_csv(report: &DuplicateReport, path: &Path) -> io::Result<()> {
+    let mut output = io::BufWriter::new(OpenOptions::new().write(true).create_new(true).open(path)?);
+    writeln!(output, "group,size,sha256,path")?;
+    for (number, group) in report.groups.iter().enumerate() {
+        for file in &group.files {
+            writeln!(output, "{},{},{},{}", number + 1, group.size, group.hash, csv_field(&file.display().to_string()))?;
+        }
+    }
+    output.flush()
+}
+
+#[derive(Debug, Default)]
+pub struct MoveSummary {
+    pub moved: u64,
+    pub bytes: u64,
+    pub failed: Vec<(PathBuf, String)>,
+}
+
+impl MoveSummary {
+    pub fn describe(&self) -> String {
+        let mut text = format!("{} file(s), {}", self.moved, format_size(self.bytes));
+        if !self.failed.is_empty() {
+            text.push_str(&format!(", {} failed", self.failed.len()));
+        }
+        text
+    }
+}
+
+// Renaming is instant on the same volume; elsewhere the file is copied and the original
+// removed only once the copy is complete.
+fn move_file(from: &Path, to: &Path) -> io::Result<()> {
+    if fs::rename(from, to).is_ok() {
+        return Ok(());
+    }
+    fs::copy(from, to)?;
+    if let Err(err) = fs::remove_file(from) {
+        let _ = fs::remove_file(to);
+        return Err(err);
+    }
+    Ok(())
+}
+
+// Mirrors the original location under `destination`, with the drive letter as the first
+// folder, so files from different folders and drives cannot collide.
+fn quarantine_path(destination: &Path, original: &Path) -> PathBuf {
+    let mut path = destination.to_path_buf();
+    for component in original.components() {
+        match component {
+            Component::Prefix(prefix) => path.push(prefix.as_os_str().to_string_lossy().replace(|c: char| !c.is_alphanumeric(), "")),
+            Component::Normal(name) => path.push(name),
+            _ => {}
+        }
+    }
+    if !path.exists() {
+        return path;
+    }
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy().into_owned();
+    let extension = path.extension().map(|extension| format!(".{}", extension.to_string_lossy())).unwrap_or_default();
+    (2..)
+        .map(|number| path.with_file_name(format!("{} ({}){}", stem, number, extension)))
+        .find(|candidate| !candidate.exists())
+        .unwrap()
+}
+
+fn same_size(path: &Path, size: u64) -> bool {
+    fs::metadata(path).is_ok_and(|metadata| metadata.is_file() && metadata.len() == size)
+}
+
+// The copy to keep is read in full again, since an edit need not change its size and
+// its duplicates are about to go.
+fn still_matches(path: &Path, group: &DuplicateGroup) -> bool {
+    same_size(path, group.size)
+        && hash_file(path, u64::MAX, &AtomicBool::new(false), &mut |_| {}).is_ok_and(|hash| hash.as_deref() == Some(group.hash.as_str()))
+}
+
+// Moves every file of each group except `keep[group]` into `destination`. Each move is
+// recorded in the manifest before it is made and the entry taken out again if the move
+// fails, so `undo_quarantine` can put the files back even after an interrupted run.
+// Groups whose kept copy has changed since the scan are left alone.
+pub fn quarantine(groups: &[DuplicateGroup], keep: &[usize], destination: &Path, progress: &mut dyn FnMut(&Path)) -> io::Result<MoveSummary> {
+    fs::create_dir_all(destination)?;
+    // Opened for writing rather than appending, since a failed move truncates its entry away.
+    let mut manifest = OpenOptions::new().create(true).truncate(false).write(true).open(destination.join(MANIFEST_NAME))?;
+    let mut summary = MoveSummary::default();
+    for (group, keep) in groups.iter().zip(keep) {
+        let kept_intact = group.files.get(*keep).is_some_and(|kept| still_matches(kept, group));
+        for file in group.files.iter().enumerate().filter(|(index, _)| index != keep).map(|(_, file)| file) {
+            progress(file);
+            if !kept_intact {
+                summary.failed.push((file.clone(), "the copy to keep is gone or has changed since the scan".to_string()));
+                continue;
+            }
+            if !same_size(file, group.size) {
+                summary.failed.push((file.clone(), "the file is gone or has changed since the scan".to_string()));
+                continue;
+            }
+            let target = quarantine_path(destination, file);
+            let recorded = manifest.seek(SeekFrom::End(0))?;
+            let result = target
+                .parent()
+                .map_or(Ok(()), fs::create_dir_all)
+                .and_then(|_| {
+                    writeln!(manifest, "{}\t{}", target.display(), file.display())?;
+                    manifest.sync_data()?;
+                    move_file(file, &target)
+                })
+                .inspect_err(|_| {
+                    let _ = manifest.set_len(recorded);
+                });
+            match result {
+                Ok(()) => {
+                    summary.moved += 1;
+                    summary.bytes += group.size;
+                }
+                Err(err) => summary.failed.push((file.clone(), err.to_string())),
+            }
+        }
+    }
+    Ok(summary)
+}
+
+// Puts quarantined files back where they came from. A file whose original location has
+// been taken by another file stays in quarantine, and in the manifest.
+pub fn undo_quarantine(destination: &Path, progress: &mut dyn FnMut(&Path)) -> io::Result<MoveSummary> {
+    let manifest_path = destination.join(MANIFEST_NAME);
+    let manifest = fs::read_to_string(&manifest_path)?;
+    let mut summary = MoveSummary::default();
+    let mut remaining = Vec::new();
+    for line in manifest.lines().filter(|line| !line.is_empty()) {
+        let Some((quarantined, original)) = line.split_once('\t') else {
+            remaining.push(line);
+            continue;
+        };
+        let (quarantined, original) = (Path::new(quarantined), Path::new(original));
+        progress(original);
+        // An entry whose move never happened, from a run interrupted in between.
+        if !quarantined.exists() && original.exists() {
+            continue;
+        }
+        let result = if original.exists() {
+            Err(io::Error::new(io::ErrorKind::AlreadyExists, "another file is at the original location"))
+        } else {
+            let size = fs::metadata(quarantined).map(|metadata| metadata.len());
+            original
+                .parent()
+                .map_or(Ok(()), fs::create_dir_all)
+                .and(size)
+                .and_then(|size| move_file(quarantined, original).map(|_| size))
+        };
+        match result {
+            Ok(size) => {
+                summary.moved += 1;
+                summary.bytes += size;
+            }
+            Err(err) => {
+                summary.failed.push((original.to_path_buf(), err.to_string()));
+                remaining.push(line);
+            }
+        }
+    }
+    if remaining.is_empty() {
+        fs::remove_file(&manifest_path)?;
+    } else {
+        fs::write(&manifest_path, remaining.iter().map(|line| format!("{}\n", line)).collect::<String>())?;
+    }
+    Ok(summary)
+}
@@ -0,0 +1,182 @@
+use crate::disk::Disk;
+use crate::filesystem::{walk_allocations, ClusterBitmap, Extent, FileSystem};
+use std::collections::HashSet;
+use std::io;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ClusterState {
+    Free,
+    Used,
+    Metadata,
+    Fragmented,
+    Bad,
+}
+
+impl ClusterState {
+    pub const ALL: [ClusterState; 5] = [
+        ClusterState::Free,
+        ClusterState::Used,
+        ClusterState::Metadata,
+        ClusterState::Fragmented,
+        ClusterState::Bad,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            ClusterState::Free => "Free",
+            ClusterState::Used => "Used",
+            ClusterState::Metadata => "Metadata",
+            ClusterState::Fragmented => "Fragmented file",
+            ClusterState::Bad => "Bad cluster",
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct StateCounts {
+    counts: [u64; 5],
+}
+
+impl StateCounts {
+    pub fn get(&self, state: ClusterState) -> u64 {
+        self.counts[state as usize]
+    }
+
+    fn add(&mut self, state: ClusterState, count: u64) {
+        self.counts[state as usize] += count;
+    }
+
+    // A block of the map stands for many clusters: a single bad cluster shows, and
+    // otherwise the most common kind of allocated cluster wins over free space.
+    pub fn dominant(&self) -> ClusterState {
+        if self.get(ClusterState::Bad) > 0 {
+            return ClusterState::Bad;
+        }
+        [ClusterState::Metadata, ClusterState::Used, ClusterState::Fragmented]
+            .into_iter()
+            .filter(|state| self.get(*state) > 0)
+            .max_by_key(|state| self.get(*state))
+            .unwrap_or(ClusterState::Free)
+    }
+}
+
+struct Owner {
+    name: String,
+    state: ClusterState,
+}
+
+// Clusters `start..start + count` belong to `owners[owner]`.
+struct OwnedRun {
+    start: u64,
+    count: u64,
+    owner: usize,
+}
+
+// Who owns each cluster of a volume, from a walk of the whole directory tree plus the
+// areas the filesystem reserves for itself.
+pub struct ClusterMap {
+    bitmap: ClusterBitmap,
+    owners: Vec<Owner>,
+    runs: Vec<OwnedRun>,
+    pub files: u64,
+    pub unreadable: u64,
+}
+
+impl ClusterMap {
+    // `progress` receives the number of files and folders walked so far.
+    pub fn build(filesystem: &mut dyn FileSystem, disk: &mut dyn Disk, progress: &mut dyn FnMut(u64)) -> io::Result<Self> {
+        let mut map = Self {
+            bitmap: filesystem.cluster_bitmap(disk)?,
+            owners: Vec::new(),
+            runs: Vec::new(),
+            files: 0,
+            unreadable: 0,
+        };
+        for area in filesystem.reserved_areas(disk)? {
+            let state = if area.bad { ClusterState::Bad } else { ClusterState::Metadata };
+            map.add_owner(area.name, state, &area.extents);
+        }
+        walk_allocations(filesystem, disk, &mut |entry, allocation| match allocation {
+            Ok(extents) => {
+                map.files += 1;
+                progress(map.files);
+                let state = if !entry.is_dir && extents.len() > 1 { ClusterState::Fragmented } else { ClusterState::Used };
+                let name = if entry.path.is_empty() { "/".to_string() } else { entry.path.clone() };
+                map.add_owner(name, state, &extents);
+            }
+            Err(_) => map.unreadable += 1,
+        });
+        // Cross-linked clusters go to the first claimant, which puts reserved areas
+        // ahead of the files that share them (NTFS lists its system files in the root).
+        map.runs.sort_by_key(|run| (run.start, run.owner));
+        let mut end = 0;
+        map.runs.retain_mut(|run| {
+            let run_end = run.start + run.count;
+            if run_end <= end {
+                return false;
+            }
+            if run.start < end {
+                run.count = run_end - end;
+                run.start = end;
+            }
+            end = run_end;
+            true
+        });
+        Ok(map)
+    }
+
+    fn add_owner(&mut self, name: String, state: ClusterState, extents: &[Extent]) {
+        if extents.is_empty() {
+            return;
+        }
+        let owner = self.owners.len();
+        self.owners.push(Owner { name, state });
+        self.runs.extend(extents.iter().map(|extent| OwnedRun {
+            start: extent.start,
+            count: extent.count,
+            owner,
+        }));
+    }
+
+    pub fn first(&self) -> u64 {
+        self.bitmap.first
+    }
+
+    pub fn count(&self) -> u64 {
+        self.bitmap.count
+    }
+
+    fn overlapping(&self, start: u64, count: u64) -> impl Iterator<Item = (&OwnedRun, u64)> {
+        let end = start + count;
+        let index = self.runs.partition_point(|run| run.start + run.count <= start);
+        self.runs[index..]
+            .iter()
+            .take_while(move |run| run.start < end)
+            .map(move |run| (run, (run.start + run.count).min(end) - run.start.max(start)))
+    }
+
+    // Allocated clusters that nothing claims are structures the filesystem does not
+    // name, such as extent tree or index blocks, or orphans; both count as metadata.
+    pub fn counts(&self, start: u64, count: u64) -> StateCounts {
+        let mut counts = StateCounts::default();
+        let mut owned = 0;
+        for (run, overlap) in self.overlapping(start, count) {
+            counts.add(self.owners[run.owner].state, overlap);
+            owned += overlap;
+        }
+        let unclaimed = self.bitmap.used_in(start, count).saturating_sub(owned);
+        counts.add(ClusterState::Metadata, unclaimed);
+        counts.add(ClusterState::Free, count - owned - unclaimed);
+        counts
+    }
+
+    // Names of the first `limit` owners of clusters in the range, in disk order.
+    pub fn owners(&self, start: u64, count: u64, limit: usize) -> Vec<(&str, ClusterState)> {
+        let mut seen = HashSet::new();
+        self.overlapping(start, count)
+            .filter(|(run, _)| seen.insert(run.owner))
+            .take(limit)
+            .map(|(run, _)| (self.owners[run.owner].name.as_str(), self.owners[run.owner].state))
+            .collect()
+    }
+}
@@ -0,0 +1,468 @@
+use crate::disk::Disk;
+use crate::filesystem::fat::format_dos_time;
+use crate::filesystem::{push_extent, ClusterBitmap, Extent, FileEntry, FileSystem, ReservedArea};
+use crate::partition_table::{read_u16, read_u32, read_u64};
+use crate::vdisk::invalid_data;
+use std::collections::HashMap;
+use std::io::{self, Write};
+
+const BOOT_REGION_SECTORS: u64 = 12;
+const ENTRY_SIZE: usize = 32;
+const READ_CHUNK_CLUSTERS: usize = 64;
+
+const ENTRY_END: u8 = 0x00;
+const ENTRY_ALLOCATION_BITMAP: u8 = 0x81;
+const ENTRY_UPCASE_TABLE: u8 = 0x82;
+const ENTRY_VOLUME_LABEL: u8 = 0x83;
+const ENTRY_FILE: u8 = 0x85;
+const ENTRY_STREAM_EXTENSION: u8 = 0xC0;
+const ENTRY_FILE_NAME: u8 = 0xC1;
+
+const ATTR_DIRECTORY: u16 = 0x10;
+const FLAG_NO_FAT_CHAIN: u8 = 0x02;
+const BAD_CLUSTER: u32 = 0xFFFF_FFF7;
+const END_OF_CHAIN: u32 = 0xFFFF_FFFF;
+
+// FileEntry nodes carry the first cluster, plus this bit when the data is contiguous
+// and the FAT must not be consulted.
+const NODE_NO_FAT_CHAIN: u64 = 1 << 32;
+
+// Sum over the first 11 sectors of a boot region, skipping VolumeFlags and PercentInUse.
+fn boot_checksum(region: &[u8]) -> u32 {
+    region
+        .iter()
+        .enumerate()
+        .filter(|(index, _)| !matches!(index, 106 | 107 | 112))
+        .fold(0u32, |sum, (_, byte)| sum.rotate_right(1).wrapping_add(*byte as u32))
+}
+
+fn entry_set_checksum(set: &[u8]) -> u16 {
+    set.iter()
+        .enumerate()
+        .filter(|(index, _)| !matches!(index, 2 | 3))
+        .fold(0u16, |sum, (_, byte)| sum.rotate_right(1).wrapping_add(*byte as u16))
+}
+
+fn table_checksum(table: &[u8]) -> u32 {
+    table.iter().fold(0u32, |sum, byte| sum.rotate_right(1).wrapping_add(*byte as u32))
+}
+
+fn utf16_units(bytes: &[u8]) -> Vec<u16> {
+    bytes.chunks_exact(2).map(|unit| u16::from_le_bytes([unit[0], unit[1]])).collect()
+}
+
+// DOS-style timestamp with the 10 ms increment ignored and the UTC offset appended when valid.
+fn format_timestamp(timestamp: u32, utc_offset: u8) -> Option<String> {
+    let local = format_dos_time((timestamp >> 16) as u16, timestamp as u16)?;
+    if utc_offset & 0x80 == 0 {
+        return Some(local);
+    }
+    let minutes = (((utc_offset << 1) as i8) >> 1) as i32 * 15;
+    let sign = if minutes < 0 { '-' } else { '+' };
+    Some(format!("{} {}{:02}:{:02}", local, sign, minutes.abs() / 60, minutes.abs() % 60))
+}
+
+struct EntrySet {
+    name: String,
+    attributes: u16,
+    modified: Option<String>,
+    first_cluster: u32,
+    no_fat_chain: bool,
+    valid_length: u64,
+    length: u64,
+}
+
+// Parses the file, stream extension and file name entries of one in-use entry set.
+fn parse_entry_set(entries: &[u8]) -> Option<EntrySet> {
+    let stream = entries.get(ENTRY_SIZE..2 * ENTRY_SIZE)?;
+    if stream[0] != ENTRY_STREAM_EXTENSION {
+        return None;
+    }
+    let name_length = stream[3] as usize;
+    let mut units = Vec::new();
+    for name in entries[2 * ENTRY_SIZE..].chunks_exact(ENTRY_SIZE).filter(|entry| entry[0] == ENTRY_FILE_NAME) {
+        units.extend(utf16_units(&name[2..32]));
+    }
+    if units.len() < name_length {
+        return None;
+    }
+    Some(EntrySet {
+        name: String::from_utf16_lossy(&units[..name_length]),
+        attributes: read_u16(entries, 4),
+        modified: format_timestamp(read_u32(entries, 12), entries[23]),
+        first_cluster: read_u32(stream, 20),
+        no_fat_chain: stream[1] & FLAG_NO_FAT_CHAIN != 0,
+        valid_length: read_u64(stream, 8),
+        length: read_u64(stream, 24),
+    })
+}
+
+pub struct ExfatFileSystem {
+    offset: u64,
+    bytes_per_sector: u64,
+    cluster_size: u64,
+    cluster_count: u32,
+    fat_offset: u64,
+    fat_length: u64,
+    fat_count: u8,
+    active_fat: u64,
+    heap_offset: u64,
+    root_cluster: u32,
+    serial: u32,
+    revision: u16,
+    volume_flags: u16,
+    boot_region: &'static str,
+    label: String,
+    // First cluster and length of the allocation bitmap, and its count of set bits.
+    bitmap: Option<(u64, u64)>,
+    clusters_in_use: Option<u64>,
+    // First cluster and length of the up-case table, its entry count and checksum result.
+    upcase_table: Option<(u64, u64)>,
+    upcase: Option<(usize, bool)>,
+    // Preallocated files whose valid data length is below their size, by node.
+    valid_lengths: HashMap<u64, u64>,
+    fat_cache: Option<(u64, Vec<u8>)>,
+}
+
+impl ExfatFileSystem {
+    pub fn open(disk: &mut dyn Disk, offset: u64) -> io::Result<Self> {
+        let first = disk.read_bytes(offset, 512)?;
+        if &first[3..11] != b"EXFAT   " {
+            return Err(invalid_data("missing exFAT boot sector signature"));
+        }
+        let sector_shift = first[108] as u32;
+        let cluster_shift = first[109] as u32;
+        if !(9..=12).contains(&sector_shift) || sector_shift + cluster_shift > 25 {
+            return Err(invalid_data("invalid exFAT sector or cluster size"));
+        }
+        let bytes_per_sector = 1u64 << sector_shift;
+        // Fall back to the backup boot region when the main one fails its checksum.
+        let mut boot = None;
+        for (start, region) in [(0, "main"), (BOOT_REGION_SECTORS, "backup")] {
+            let bytes = disk.read_bytes(offset + start * bytes_per_sector, (BOOT_REGION_SECTORS * bytes_per_sector) as usize)?;
+            let checksum_sector = &bytes[(11 * bytes_per_sector) as usize..];
+            let checksum = boot_checksum(&bytes[..(11 * bytes_per_sector) as usize]);
+            if &bytes[3..11] == b"EXFAT   " && checksum_sector.chunks_exact(4).all(|value| read_u32(value, 0) == checksum) {
+                boot = Some((bytes, region));
+                break;
+            }
+        }
+        let (boot, boot_region) = boot.ok_or_else(|| invalid_data("both exFAT boot regions fail their checksum"))?;
+        let volume_flags = read_u16(&boot, 106);
+        let mut filesystem = Self {
+            offset,
+            bytes_per_sector,
+            cluster_size: bytes_per_sector << cluster_shift,
+            cluster_count: read_u32(&boot, 92),
+            fat_offset: read_u32(&boot, 80) as u64 * bytes_per_sector,
+            fat_length: read_u32(&boot, 84) as u64 * bytes_per_sector,
+            fat_count: boot[110],
+            active_fat: if boot[110] > 1 { (volume_flags & 1) as u64 } else { 0 },
+            heap_offset: read_u32(&boot, 88) as u64 * bytes_per_sector,
+            root_cluster: read_u32(&boot, 96),
+            serial: read_u32(&boot, 100),
+            revision: read_u16(&boot, 104),
+            volume_flags,
+            boot_region,
+            label: String::new(),
+            bitmap: None,
+            clusters_in_use: None,
+            upcase_table: None,
+            upcase: None,
+            valid_lengths: HashMap::new(),
+            fat_cache: None,
+        };
+        filesystem.read_root_metadata(disk)?;
+        Ok(filesystem)
+    }
+
+    // The root directory holds the volume label, allocation bitmap and up-case table.
+    fn read_root_metadata(&mut self, disk: &mut dyn Disk) -> io::Result<()> {
+        let root = self.directory_bytes(disk, self.root_cluster as u64, 0)?;
+        for entry in root.chunks_exact(ENTRY_SIZE) {
+            match entry[0] {
+                ENTRY_END => break,
+                ENTRY_VOLUME_LABEL => {
+                    let length = (entry[1] as usize).min(11);
+                    self.label = String::from_utf16_lossy(&utf16_units(&entry[2..2 + length * 2]));
+                }
+                ENTRY_ALLOCATION_BITMAP if entry[1] & 1 == 0 => {
+                    self.bitmap = Some((read_u32(entry, 20) as u64, read_u64(entry, 24)));
+                    self.clusters_in_use = Some(self.cluster_bitmap(disk)?.used());
+                }
+                ENTRY_UPCASE_TABLE => {
+                    self.upcase_table = Some((read_u32(entry, 20) as u64, read_u64(entry, 24)));
+                    let table = self.read_data(disk, read_u32(entry, 20) as u64, read_u64(entry, 24))?;
+                    self.upcase = Some((table.len() / 2, table_checksum(&table) == read_u32(entry, 4)));
+                }
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    fn cluster_offset(&self, cluster: u32) -> u64 {
+        self.offset + self.heap_offset + (cluster as u64 - 2) * self.cluster_size
+    }
+
+    fn fat_entry(&mut self, disk: &mut dyn Disk, cluster: u32) -> io::Result<u32> {
+        const CACHE_SIZE: u64 = 64 * 1024;
+        let index = cluster as u64 * 4;
+        let chunk = index / CACHE_SIZE;
+        if self.fat_cache.as_ref().is_none_or(|(cached, _)| *cached != chunk) {
+            let start = chunk * CACHE_SIZE;
+            let len = CACHE_SIZE.min(self.fat_length.saturating_sub(start)) as usize;
+            let fat_start = self.offset + self.fat_offset + self.active_fat * self.fat_length;
+            self.fat_cache = Some((chunk, disk.read_bytes(fat_start + start, len)?));
+        }
+        let (_, bytes) = self.fat_cache.as_ref().unwrap();
+        let position = (index % CACHE_SIZE) as usize;
+        bytes
+            .get(position..position + 4)
+            .map(|entry| read_u32(entry, 0))
+            .ok_or_else(|| invalid_data(format!("FAT entry {} is beyond the end of the FAT", cluster)))
+    }
+
+    // Clusters of a file: consecutive when NoFatChain is set, otherwise a FAT chain.
+    fn clusters(&mut self, disk: &mut dyn Disk, node: u64, length: u64) -> io::Result<Vec<u32>> {
+        let first = node as u32;
+        if first == 0 {
+            return Ok(Vec::new());
+        }
+        let last_cluster = self.cluster_count + 1;
+        if node & NODE_NO_FAT_CHAIN != 0 {
+            let count = length.div_ceil(self.cluster_size);
+            if first < 2 || first as u64 + count > last_cluster as u64 + 1 {
+                return Err(invalid_data(format!("contiguous run at cluster {} extends past the cluster heap", first)));
+            }
+            return Ok((first..first + count as u32).collect());
+        }
+        let mut chain = Vec::new();
+        let mut cluster = first;
+        loop {
+            if cluster < 2 || cluster > last_cluster {
+                return Err(invalid_data(format!("cluster chain starting at {} points to invalid cluster {}", first, cluster)));
+            }
+            if chain.len() > self.cluster_count as usize {
+                return Err(invalid_data(format!("cluster chain starting at {} contains a loop", first)));
+            }
+            chain.push(cluster);
+            match self.fat_entry(disk, cluster)? {
+                END_OF_CHAIN => return Ok(chain),
+                BAD_CLUSTER => return Err(invalid_data(format!("cluster chain starting at {} runs into bad cluster {}", first, cluster))),
+                next => cluster = next,
+            }
+        }
+    }
+
+    fn read_data(&mut self, disk: &mut dyn Disk, node: u64, length: u64) -> io::Result<Vec<u8>> {
+        let mut data = Vec::new();
+        self.write_data(disk, node, length, length, &mut data)?;
+        Ok(data)
+    }
+
+    // Writes `length` bytes, of which only the first `valid_length` were ever written.
+    fn write_data(&mut self, disk: &mut dyn Disk, node: u64, length: u64, valid_length: u64, out: &mut dyn Write) -> io::Result<()> {
+        let clusters = self.clusters(disk, node, length)?;
+        if (clusters.len() as u64) < length.div_ceil(self.cluster_size) {
+            return Err(invalid_data(format!(
+                "data at cluster {} has {} cluster(s) but needs {} for {} bytes",
+                node as u32,
+                clusters.len(),
+                length.div_ceil(self.cluster_size),
+                length
+            )));
+        }
+        let mut position = 0;
+        for group in clusters.chunk_by(|a, b| a + 1 == *b).flat_map(|run| run.chunks(READ_CHUNK_CLUSTERS)) {
+            if position >= length {
+                break;
+            }
+            let len = (group.len() as u64 * self.cluster_size).min(length - position);
+            let valid = valid_length.saturating_sub(position).min(len);
+            let mut chunk = disk.read_bytes(self.cluster_offset(group[0]), valid as usize)?;
+            chunk.resize(len as usize, 0);
+            out.write_all(&chunk)?;
+            position += len;
+        }
+        Ok(())
+    }
+
+    fn directory_bytes(&mut self, disk: &mut dyn Disk, node: u64, length: u64) -> io::Result<Vec<u8>> {
+        // Directories in a FAT chain carry no reliable length; read the whole chain.
+        let length = match node & NODE_NO_FAT_CHAIN {
+            0 => self.clusters(disk, node, 0)?.len() as u64 * self.cluster_size,
+            _ => length,
+        };
+        self.read_data(disk, node, length)
+    }
+
+    // In-use entry sets of a directory; sets failing their checksum are invalid and skipped.
+    fn entry_sets(&mut self, disk: &mut dyn Disk, node: u64, length: u64) -> io::Result<Vec<EntrySet>> {
+        let bytes = self.directory_bytes(disk, node, length)?;
+        let mut sets = Vec::new();
+        let mut position = 0;
+        while position + ENTRY_SIZE <= bytes.len() {
+            let entry_type = bytes[position];
+            if entry_type == ENTRY_END {
+                break;
+            }
+            if entry_type != ENTRY_FILE {
+                position += ENTRY_SIZE;
+                continue;
+            }
+            let end = position + (1 + bytes[position + 1] as usize) * ENTRY_SIZE;
+            let set = &bytes[position..end.min(bytes.len())];
+            if end <= bytes.len() && entry_set_checksum(set) == read_u16(set, 2) {
+                if let Some(parsed) = parse_entry_set(set) {
+                    sets.push(parsed);
+                }
+                position = end;
+            } else {
+                position += ENTRY_SIZE;
+            }
+        }
+        Ok(sets)
+    }
+}
+
+impl FileSystem for ExfatFileSystem {
+    fn name(&self) -> &'static str {
+        "exFAT"
+    }
+
+    fn details(&self) -> Vec<(String, String)> {
+        let mut details = vec![
+            ("Type".to_string(), "exFAT".to_string()),
+            ("Revision".to_string(), format!("{}.{:02}", self.revision >> 8, self.revision & 0xFF)),
+            ("Label".to_string(), self.label.clone()),
+            ("Serial number".to_string(), format!("{:04X}-{:04X}", self.serial >> 16, self.serial & 0xFFFF)),
+            ("Boot region".to_string(), format!("{} (checksum verified)", self.boot_region)),
+            ("Bytes per sector".to_string(), self.bytes_per_sector.to_string()),
+            ("Cluster size".to_string(), format!("{} bytes", self.cluster_size)),
+            ("Clusters".to_string(), self.cluster_count.to_string()),
+            ("FATs".to_string(), format!("{} of {} bytes, active FAT {}", self.fat_count, self.fat_length, self.active_fat)),
+            ("Cluster heap offset".to_string(), format!("{} bytes", self.heap_offset)),
+            ("Root directory cluster".to_string(), self.root_cluster.to_string()),
+        ];
+        if let Some(used) = self.clusters_in_use {
+            details.push(("Clusters in use".to_string(), format!("{} (allocation bitmap)", used)));
+        }
+        if let Some((entries, valid)) = self.upcase {
+            let status = if valid { "checksum OK" } else { "checksum MISMATCH" };
+            details.push(("Up-case table".to_string(), format!("{} entries, {}", entries, status)));
+        }
+        let mut flags = Vec::new();
+        if self.volume_flags & 2 != 0 {
+            flags.push("dirty");
+        }
+        if self.volume_flags & 4 != 0 {
+            flags.push("media failure");
+        }
+        details.push(("Volume flags".to_string(), if flags.is_empty() { "clean".to_string() } else { flags.join(", ") }));
+        details
+    }
+
+    fn cluster_size(&self) -> u64 {
+        self.cluster_size
+    }
+
+    fn root(&self) -> FileEntry {
+        FileEntry {
+            name: String::new(),
+            path: "/".to_string(),
+            is_dir: true,
+            size: 0,
+            modified: None,
+            node: self.root_cluster as u64,
+            stream: None,
+            symlink: None,
+        }
+    }
+
+    fn read_dir(&mut self, disk: &mut dyn Disk, dir: &FileEntry) -> io::Result<Vec<FileEntry>> {
+        let sets = self.entry_sets(disk, dir.node, dir.size)?;
+        let mut files = Vec::new();
+        for set in sets {
+            let node = set.first_cluster as u64 | if set.no_fat_chain { NODE_NO_FAT_CHAIN } else { 0 };
+            if set.valid_length < set.length {
+                self.valid_lengths.insert(node, set.valid_length);
+            }
+            files.push(FileEntry {
+                path: format!("{}/{}", dir.path.trim_end_matches('/'), set.name),
+                name: set.name,
+                is_dir: set.attributes & ATTR_DIRECTORY != 0,
+                // Directories keep their length here; contiguous ones cannot be read without it.
+                size: set.length,
+                modified: set.modified,
+                node,
+                stream: None,
+                symlink: None,
+            });
+        }
+        Ok(files)
+    }
+
+    fn read_file(&mut self, disk: &mut dyn Disk, file: &FileEntry, out: &mut dyn Write) -> io::Result<()> {
+        let valid_length = self.valid_lengths.get(&file.node).copied().unwrap_or(file.size);
+        self.write_data(disk, file.node, file.size, valid_length, out)
+    }
+
+    fn allocation(&mut self, disk: &mut dyn Disk, entry: &FileEntry) -> io::Result<Vec<Extent>> {
+        let mut extents: Vec<Extent> = Vec::new();
+        for cluster in self.clusters(disk, entry.node, entry.size)? {
+            match extents.last_mut() {
+                Some(extent) if extent.start + extent.count == cluster as u64 => extent.count += 1,
+                _ => extents.push(Extent {
+                    start: cluster as u64,
+                    count: 1,
+                }),
+            }
+        }
+        Ok(extents)
+    }
+
+    fn cluster_bitmap(&mut self, disk: &mut dyn Disk) -> io::Result<ClusterBitmap> {
+        let (node, length) = self.bitmap.ok_or_else(|| invalid_data("exFAT volume has no allocation bitmap"))?;
+        let bits = self.read_data(disk, node, length)?;
+        Ok(ClusterBitmap::new(2, self.cluster_count as u64, bits))
+    }
+
+    fn cluster_position(&self, cluster: u64) -> u64 {
+        self.cluster_offset(cluster as u32)
+    }
+
+    fn reserved_areas(&mut self, disk: &mut dyn Disk) -> io::Result<Vec<ReservedArea>> {
+        let mut areas = Vec::new();
+        for (name, location) in [("Allocation bitmap", self.bitmap), ("Up-case table", self.upcase_table)] {
+            let Some((node, length)) = location else {
+                continue;
+            };
+            let mut extents = Vec::new();
+            for cluster in self.clusters(disk, node, length)? {
+                push_extent(&mut extents, cluster as u64, 1);
+            }
+            areas.push(ReservedArea {
+                name: name.to_string(),
+                bad: false,
+                extents,
+            });
+        }
+        let mut bad = Vec::new();
+        for cluster in 2..self.cluster_count + 2 {
+            if self.fat_entry(disk, cluster)? == BAD_CLUSTER {
+                push_extent(&mut bad, cluster as u64, 1);
+            }
+        }
+        areas.push(ReservedArea {
+            name: "Bad clusters".to_string(),
+            bad: true,
+            extents: bad,
+        });
+        Ok(areas)
+    }
+
+    fn deleted_files(&mut self, _disk: &mut dyn Disk) -> io::Result<Vec<FileEntry>> {
+        Err(io::Error::new(io::ErrorKind::Unsupported, "deleted files can only be recovered from FAT and NTFS volumes"))
+    }
+}
@@ -0,0 +1,788 @@
+use crate::disk::Disk;
+use crate::filesystem::{push_extent, ClusterBitmap, Extent, FileEntry, FileSystem, ReservedArea};
+use crate::partition_table::{read_u16, read_u32};
+use crate::vdisk::{format_unix_time, invalid_data};
+use std::io::{self, Write};
+
+const SUPERBLOCK_OFFSET: u64 = 1024;
+const MAGIC: u16 = 0xEF53;
+const BAD_BLOCKS_INODE: u64 = 1;
+const ROOT_INODE: u64 = 2;
+const READ_CHUNK_BLOCKS: u64 = 256;
+const MAX_EXTENT_DEPTH: u16 = 5;
+const EXTENT_MAGIC: u16 = 0xF30A;
+const INLINE_SIZE: usize = 60;
+
+const COMPAT_HAS_JOURNAL: u32 = 0x0004;
+const INCOMPAT_COMPRESSION: u32 = 0x0001;
+const INCOMPAT_JOURNAL_DEV: u32 = 0x0008;
+const INCOMPAT_META_BG: u32 = 0x0010;
+const INCOMPAT_64BIT: u32 = 0x0080;
+const RO_COMPAT_SPARSE_SUPER: u32 = 0x0001;
+const GROUP_BLOCK_UNINIT: u16 = 0x0002;
+
+const INODE_FLAG_INDEX: u32 = 0x0000_1000;
+const INODE_FLAG_EXTENTS: u32 = 0x0008_0000;
+const INODE_FLAG_INLINE_DATA: u32 = 0x1000_0000;
+
+const MODE_TYPE: u16 = 0xF000;
+const MODE_DIRECTORY: u16 = 0x4000;
+const MODE_REGULAR: u16 = 0x8000;
+const MODE_SYMLINK: u16 = 0xA000;
+
+const XATTR_MAGIC: u32 = 0xEA02_0000;
+const XATTR_INDEX_SYSTEM: u8 = 7;
+
+const COMPAT_FEATURES: &[(u32, &str)] = &[
+    (0x0001, "dir_prealloc"),
+    (0x0002, "imagic_inodes"),
+    (0x0004, "has_journal"),
+    (0x0008, "ext_attr"),
+    (0x0010, "resize_inode"),
+    (0x0020, "dir_index"),
+    (0x0200, "sparse_super2"),
+    (0x0400, "fast_commit"),
+    (0x1000, "orphan_file"),
+];
+
+const INCOMPAT_FEATURES: &[(u32, &str)] = &[
+    (0x0001, "compression"),
+    (0x0002, "filetype"),
+    (0x0004, "needs_recovery"),
+    (0x0008, "journal_dev"),
+    (0x0010, "meta_bg"),
+    (0x0040, "extent"),
+    (0x0080, "64bit"),
+    (0x0100, "mmp"),
+    (0x0200, "flex_bg"),
+    (0x0400, "ea_inode"),
+    (0x1000, "dirdata"),
+    (0x2000, "metadata_csum_seed"),
+    (0x4000, "large_dir"),
+    (0x8000, "inline_data"),
+    (0x10000, "encrypt"),
+    (0x20000, "casefold"),
+];
+
+const RO_COMPAT_FEATURES: &[(u32, &str)] = &[
+    (0x0001, "sparse_super"),
+    (0x0002, "large_file"),
+    (0x0004, "btree_dir"),
+    (0x0008, "huge_file"),
+    (0x0010, "gdt_csum"),
+    (0x0020, "dir_nlink"),
+    (0x0040, "extra_isize"),
+    (0x0100, "quota"),
+    (0x0200, "bigalloc"),
+    (0x0400, "metadata_csum"),
+    (0x0800, "replica"),
+    (0x1000, "readonly"),
+    (0x2000, "project"),
+    (0x8000, "verity"),
+    (0x10000, "orphan_present"),
+];
+
+// Names of the set flags; unknown bits are shown in hex so nothing is hidden.
+pub fn feature_names(flags: u32, names: &[(u32, &str)]) -> String {
+    let known = names.iter().fold(0, |known, (bit, _)| known | bit);
+    let mut list: Vec<String> = names.iter().filter(|(bit, _)| flags & bit != 0).map(|(_, name)| name.to_string()).collect();
+    if flags & !known != 0 {
+        list.push(format!("0x{:X}", flags & !known));
+    }
+    if list.is_empty() { "none".to_string() } else { list.join(" ") }
+}
+
+fn text(bytes: &[u8]) -> String {
+    String::from_utf8_lossy(bytes.split(|byte| *byte == 0).next().unwrap_or_default()).to_string()
+}
+
+fn format_uuid(bytes: &[u8]) -> String {
+    let hex: String = bytes.iter().map(|byte| format!("{:02x}", byte)).collect();
+    format!("{}-{}-{}-{}-{}", &hex[0..8], &hex[8..12], &hex[12..16], &hex[16..20], &hex[20..32])
+}
+
+fn format_time(seconds: u32) -> Option<String> {
+    (seconds != 0).then(|| format_unix_time(seconds as u64))
+}
+
+// A run of logical file blocks stored at consecutive physical blocks; uninitialized
+// extents are allocated but read as zeroes.
+#[derive(Clone, Copy, Debug)]
+struct BlockRun {
+    logical: u64,
+    physical: u64,
+    count: u64,
+    initialized: bool,
+}
+
+fn push_run(runs: &mut Vec<BlockRun>, run: BlockRun) {
+    match runs.last_mut() {
+        Some(last)
+            if last.initialized == run.initialized
+                && last.logical + last.count == run.logical
+                && last.physical + last.count == run.physical =>
+        {
+            last.count += run.count
+        }
+        _ => runs.push(run),
+    }
+}
+
+pub struct Inode {
+    pub number: u64,
+    pub mode: u16,
+    pub size: u64,
+    pub flags: u32,
+    raw: Vec<u8>,
+}
+
+impl Inode {
+    pub fn file_type(&self) -> u16 {
+        self.mode & MODE_TYPE
+    }
+
+    fn block(&self) -> &[u8] {
+        &self.raw[40..40 + INLINE_SIZE]
+    }
+
+    // i_mtime plus the two epoch bits of i_mtime_extra on large inodes, so times
+    // after 2038 come out right.
+    pub fn modified(&self) -> Option<String> {
+        let seconds = read_u32(&self.raw, 16) as i32 as i64;
+        let epoch = if self.raw.len() >= 0x8C && self.extra_size() >= 0x0C { (read_u32(&self.raw, 0x88) & 3) as i64 } else { 0 };
+        let seconds = seconds + (epoch << 32);
+        (seconds > 0).then(|| format_unix_time(seconds as u64))
+    }
+
+    fn extra_size(&self) -> usize {
+        if self.raw.len() > 128 { read_u16(&self.raw, 128) as usize } else { 0 }
+    }
+
+    // Value of an extended attribute stored in the inode body after i_extra_isize.
+    fn inline_xattr(&self, index: u8, name: &[u8]) -> Option<Vec<u8>> {
+        let start = 128 + self.extra_size();
+        if start + 4 > self.raw.len() || read_u32(&self.raw, start) != XATTR_MAGIC {
+            return None;
+        }
+        let entries = start + 4;
+        let mut position = entries;
+        while position + 16 <= self.raw.len() && read_u32(&self.raw, position) != 0 {
+            let name_length = self.raw[position] as usize;
+            let value_offset = read_u16(&self.raw, position + 2) as usize;
+            let value_size = read_u32(&self.raw, position + 8) as usize;
+            let entry_name = self.raw.get(position + 16..position + 16 + name_length)?;
+            if self.raw[position + 1] == index && entry_name == name {
+                return self.raw.get(entries + value_offset..entries + value_offset + value_size).map(<[u8]>::to_vec);
+            }
+            position += (16 + name_length).next_multiple_of(4);
+        }
+        None
+    }
+
+    // Inline data: the first 60 bytes live in i_block, the rest in the system.data attribute.
+    fn inline_data(&self) -> Vec<u8> {
+        let mut data = self.block().to_vec();
+        data.extend(self.inline_xattr(XATTR_INDEX_SYSTEM, b"data").unwrap_or_default());
+        data
+    }
+}
+
+struct GroupDescriptor {
+    block_bitmap: u64,
+    inode_bitmap: u64,
+    inode_table: u64,
+    free_blocks: u64,
+    flags: u16,
+}
+
+pub struct ExtFileSystem {
+    offset: u64,
+    label: String,
+    uuid: String,
+    last_mounted: String,
+    revision: u32,
+    block_size: u64,
+    inode_size: u64,
+    blocks_count: u64,
+    free_blocks: u64,
+    inodes_count: u32,
+    free_inodes: u32,
+    first_data_block: u64,
+    blocks_per_group: u64,
+    inodes_per_group: u64,
+    descriptor_size: u64,
+    reserved_gdt_blocks: u64,
+    first_meta_bg: u64,
+    journal_inode: u64,
+    state: u16,
+    creator_os: u32,
+    mount_time: u32,
+    write_time: u32,
+    compat: u32,
+    incompat: u32,
+    ro_compat: u32,
+    groups: Vec<GroupDescriptor>,
+}
+
+impl ExtFileSystem {
+    pub fn open(disk: &mut dyn Disk, offset: u64) -> io::Result<Self> {
+        let superblock = disk.read_bytes(offset + SUPERBLOCK_OFFSET, 1024)?;
+        if read_u16(&superblock, 56) != MAGIC {
+            return Err(invalid_data("missing ext superblock magic"));
+        }
+        let log_block_size = read_u32(&superblock, 24);
+        if log_block_size > 6 {
+            return Err(invalid_data(format!("invalid block size exponent {}", log_block_size)));
+        }
+        let revision = read_u32(&superblock, 76);
+        let incompat = if revision >= 1 { read_u32(&superblock, 96) } else { 0 };
+        if incompat & (INCOMPAT_COMPRESSION | INCOMPAT_JOURNAL_DEV) != 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                format!("unsupported ext features: {}", feature_names(incompat & (INCOMPAT_COMPRESSION | INCOMPAT_JOURNAL_DEV), INCOMPAT_FEATURES)),
+            ));
+        }
+        let is_64bit = incompat & INCOMPAT_64BIT != 0;
+        let high = |offset: usize| if is_64bit { (read_u32(&superblock, offset) as u64) << 32 } else { 0 };
+        let inode_size = if revision >= 1 { read_u16(&superblock, 88) as u64 } else { 128 };
+        let descriptor_size = if is_64bit { read_u16(&superblock, 254) as u64 } else { 32 };
+        let mut filesystem = Self {
+            offset,
+            label: text(&superblock[120..136]),
+            uuid: format_uuid(&superblock[104..120]),
+            last_mounted: text(&superblock[136..200]),
+            revision,
+            block_size: 1024 << log_block_size,
+            inode_size,
+            blocks_count: read_u32(&superblock, 4) as u64 | high(0x150),
+            free_blocks: read_u32(&superblock, 12) as u64 | high(0x158),
+            inodes_count: read_u32(&superblock, 0),
+            free_inodes: read_u32(&superblock, 16),
+            first_data_block: read_u32(&superblock, 20) as u64,
+            blocks_per_group: read_u32(&superblock, 32) as u64,
+            inodes_per_group: read_u32(&superblock, 40) as u64,
+            descriptor_size,
+            reserved_gdt_blocks: read_u16(&superblock, 206) as u64,
+            first_meta_bg: read_u32(&superblock, 260) as u64,
+            journal_inode: read_u32(&superblock, 224) as u64,
+            state: read_u16(&superblock, 58),
+            creator_os: read_u32(&superblock, 72),
+            mount_time: read_u32(&superblock, 44),
+            write_time: read_u32(&superblock, 48),
+            compat: if revision >= 1 { read_u32(&superblock, 92) } else { 0 },
+            incompat,
+            ro_compat: if revision >= 1 { read_u32(&superblock, 100) } else { 0 },
+            groups: Vec::new(),
+        };
+        if filesystem.blocks_per_group == 0
+            || filesystem.inodes_per_group == 0
+            || filesystem.blocks_count <= filesystem.first_data_block
+            || !inode_size.is_power_of_two()
+            || !(128..=filesystem.block_size).contains(&inode_size)
+            || !(32..=filesystem.block_size).contains(&descriptor_size)
+        {
+            return Err(invalid_data("invalid ext superblock geometry"));
+        }
+        filesystem.groups = filesystem.read_group_descriptors(disk)?;
+        Ok(filesystem)
+    }
+
+    fn group_count(&self) -> u64 {
+        self.blocks_count.saturating_sub(self.first_data_block).div_ceil(self.blocks_per_group)
+    }
+
+    fn group_has_superblock(&self, group: u64) -> bool {
+        if self.ro_compat & RO_COMPAT_SPARSE_SUPER == 0 || group <= 1 {
+            return true;
+        }
+        [3, 5, 7].iter().any(|base| {
+            let mut power = *base;
+            while power < group {
+                power *= base;
+            }
+            power == group
+        })
+    }
+
+    // Descriptor blocks follow the superblock, except that with meta_bg those past
+    // s_first_meta_bg sit at the start of the first group they describe.
+    fn descriptor_block(&self, index: u64) -> u64 {
+        if self.incompat & INCOMPAT_META_BG == 0 || index < self.first_meta_bg {
+            return self.first_data_block + 1 + index;
+        }
+        let group = index * (self.block_size / self.descriptor_size);
+        self.first_data_block + group * self.blocks_per_group + self.group_has_superblock(group) as u64
+    }
+
+    fn read_group_descriptors(&self, disk: &mut dyn Disk) -> io::Result<Vec<GroupDescriptor>> {
+        let groups = self.group_count();
+        let per_block = self.block_size / self.descriptor_size;
+        // No capacity hint: the group count comes from an unchecked blocks_count.
+        let mut descriptors = Vec::new();
+        for index in 0..groups.div_ceil(per_block) {
+            let block = self.read_block(disk, self.descriptor_block(index), 1)?;
+            for descriptor in block.chunks_exact(self.descriptor_size as usize).take((groups - index * per_block) as usize) {
+                let wide = self.descriptor_size >= 64;
+                let high = |offset: usize| if wide { (read_u32(descriptor, offset) as u64) << 32 } else { 0 };
+                let free_high = if wide { (read_u16(descriptor, 44) as u64) << 16 } else { 0 };
+                descriptors.push(GroupDescriptor {
+                    block_bitmap: read_u32(descriptor, 0) as u64 | high(32),
+                    inode_bitmap: read_u32(descriptor, 4) as u64 | high(36),
+                    inode_table: read_u32(descriptor, 8) as u64 | high(40),
+                    free_blocks: read_u16(descriptor, 12) as u64 | free_high,
+                    flags: read_u16(descriptor, 18),
+                });
+            }
+        }
+        Ok(descriptors)
+    }
+
+    fn read_block(&self, disk: &mut dyn Disk, block: u64, count: u64) -> io::Result<Vec<u8>> {
+        if block + count > self.blocks_count {
+            return Err(invalid_data(format!("block {} is beyond the end of the filesystem", block + count - 1)));
+        }
+        disk.read_bytes(self.offset + block * self.block_size, (count * self.block_size) as usize)
+    }
+
+    pub fn inode(&self, disk: &mut dyn Disk, number: u64) -> io::Result<Inode> {
+        if number == 0 || number > self.inodes_count as u64 {
+            return Err(invalid_data(format!("inode {} is out of range", number)));
+        }
+        let group = (number - 1) / self.inodes_per_group;
+        let index = (number - 1) % self.inodes_per_group;
+        let table = self
+            .groups
+            .get(group as usize)
+            .ok_or_else(|| invalid_data(format!("inode {} lies in missing block group {}", number, group)))?
+            .inode_table;
+        let raw = disk.read_bytes(self.offset + table * self.block_size + index * self.inode_size, self.inode_size as usize)?;
+        let size = read_u32(&raw, 4) as u64 | (read_u32(&raw, 108) as u64) << 32;
+        Ok(Inode {
+            number,
+            mode: read_u16(&raw, 0),
+            size,
+            flags: read_u32(&raw, 32),
+            raw,
+        })
+    }
+
+    fn extent_runs(&self, disk: &mut dyn Disk, node: &[u8], depth: u16, runs: &mut Vec<BlockRun>) -> io::Result<()> {
+        if node.len() < 12 || read_u16(node, 0) != EXTENT_MAGIC {
+            return Err(invalid_data("missing extent header magic"));
+        }
+        let entries = read_u16(node, 2) as usize;
+        let node_depth = read_u16(node, 6);
+        if node_depth >= MAX_EXTENT_DEPTH || (depth > 0 && node_depth + 1 != depth) || 12 + entries * 12 > node.len() {
+            return Err(invalid_data("inconsistent extent tree"));
+        }
+        for entry in node[12..12 + entries * 12].chunks_exact(12) {
+            if node_depth == 0 {
+                let length = read_u16(entry, 4) as u64;
+                // Lengths above 32768 mark uninitialized extents.
+                let (count, initialized) = if length > 32768 { (length - 32768, false) } else { (length, true) };
+                push_run(
+                    runs,
+                    BlockRun {
+                        logical: read_u32(entry, 0) as u64,
+                        physical: (read_u16(entry, 6) as u64) << 32 | read_u32(entry, 8) as u64,
+                        count,
+                        initialized,
+                    },
+                );
+            } else {
+                let child = (read_u16(entry, 8) as u64) << 32 | read_u32(entry, 4) as u64;
+                let block = self.read_block(disk, child, 1)?;
+                self.extent_runs(disk, &block, node_depth, runs)?;
+            }
+        }
+        Ok(())
+    }
+
+    // Walks direct, indirect, double and triple indirect pointers up to `limit` logical blocks.
+    fn indirect_runs(&self, disk: &mut dyn Disk, inode: &Inode, limit: u64) -> io::Result<Vec<BlockRun>> {
+        let mut runs = Vec::new();
+        let per_block = self.block_size / 4;
+        let pointers: Vec<u64> = inode.block().chunks_exact(4).map(|pointer| read_u32(pointer, 0) as u64).collect();
+        for (logical, pointer) in pointers[..12].iter().enumerate() {
+            if *pointer != 0 && (logical as u64) < limit {
+                push_run(&mut runs, BlockRun { logical: logical as u64, physical: *pointer, count: 1, initialized: true });
+            }
+        }
+        let mut start = 12;
+        for (level, pointer) in pointers[12..15].iter().enumerate() {
+            let span = per_block.pow(level as u32 + 1);
+            if start >= limit {
+                break;
+            }
+            self.indirect_level(disk, *pointer, level as u32, start, limit, &mut runs)?;
+            start += span;
+        }
+        Ok(runs)
+    }
+
+    fn indirect_level(&self, disk: &mut dyn Disk, block: u64, level: u32, start: u64, limit: u64, runs: &mut Vec<BlockRun>) -> io::Result<()> {
+        if block == 0 {
+            return Ok(());
+        }
+        let per_block = self.block_size / 4;
+        let span = per_block.pow(level);
+        let pointers = self.read_block(disk, block, 1)?;
+        for (index, pointer) in pointers.chunks_exact(4).map(|pointer| read_u32(pointer, 0) as u64).enumerate() {
+            let logical = start + index as u64 * span;
+            if logical >= limit {
+                break;
+            }
+            if level == 0 {
+                if pointer != 0 {
+                    push_run(runs, BlockRun { logical, physical: pointer, count: 1, initialized: true });
+                }
+            } else {
+                self.indirect_level(disk, pointer, level - 1, logical, limit, runs)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn block_runs(&self, disk: &mut dyn Disk, inode: &Inode) -> io::Result<Vec<BlockRun>> {
+        if inode.flags & INODE_FLAG_INLINE_DATA != 0 {
+            return Ok(Vec::new());
+        }
+        let mut runs = Vec::new();
+        if inode.flags & INODE_FLAG_EXTENTS != 0 {
+            self.extent_runs(disk, inode.block(), 0, &mut runs)?;
+            runs.sort_by_key(|run| run.logical);
+        } else {
+            runs = self.indirect_runs(disk, inode, inode.size.div_ceil(self.block_size))?;
+        }
+        Ok(runs)
+    }
+
+    // Fast symlinks keep a target shorter than 60 bytes in i_block itself and own no
+    // blocks apart from a possible extended attribute block.
+    fn is_fast_symlink(&self, inode: &Inode) -> bool {
+        let xattr_sectors = if read_u32(&inode.raw, 104) != 0 { self.block_size / 512 } else { 0 };
+        inode.file_type() == MODE_SYMLINK
+            && inode.size < INLINE_SIZE as u64
+            && inode.flags & (INODE_FLAG_EXTENTS | INODE_FLAG_INLINE_DATA) == 0
+            && read_u32(&inode.raw, 28) as u64 == xattr_sectors
+    }
+
+    fn inode_extents(&self, disk: &mut dyn Disk, number: u64) -> io::Result<Vec<Extent>> {
+        let inode = self.inode(disk, number)?;
+        if self.is_fast_symlink(&inode) {
+            return Ok(Vec::new());
+        }
+        let mut extents = Vec::new();
+        for run in self.block_runs(disk, &inode)? {
+            push_extent(&mut extents, run.physical, run.count);
+        }
+        Ok(extents)
+    }
+
+    fn symlink_target(&self, disk: &mut dyn Disk, inode: &Inode) -> io::Result<String> {
+        let mut target = Vec::new();
+        if self.is_fast_symlink(inode) {
+            target.extend_from_slice(&inode.block()[..inode.size as usize]);
+        } else {
+            self.write_data(disk, inode, &mut target)?;
+        }
+        Ok(String::from_utf8_lossy(&target).to_string())
+    }
+
+    fn write_data(&self, disk: &mut dyn Disk, inode: &Inode, out: &mut dyn Write) -> io::Result<()> {
+        if inode.flags & INODE_FLAG_INLINE_DATA != 0 {
+            let data = inode.inline_data();
+            if (data.len() as u64) < inode.size {
+                return Err(invalid_data(format!("inode {} has {} bytes of inline data, expected {}", inode.number, data.len(), inode.size)));
+            }
+            return out.write_all(&data[..inode.size as usize]);
+        }
+        let mut remaining = inode.size;
+        let mut next_logical = 0;
+        let zeroes = vec![0u8; (READ_CHUNK_BLOCKS * self.block_size) as usize];
+        let emit = |out: &mut dyn Write, data: &[u8], remaining: &mut u64| -> io::Result<()> {
+            let len = (*remaining).min(data.len() as u64);
+            *remaining -= len;
+            out.write_all(&data[..len as usize])
+        };
+        for run in self.block_runs(disk, inode)? {
+            // Holes before this run read as zeroes.
+            while next_logical < run.logical && remaining > 0 {
+                let count = (run.logical - next_logical).min(READ_CHUNK_BLOCKS);
+                emit(out, &zeroes[..(count * self.block_size) as usize], &mut remaining)?;
+                next_logical += count;
+            }
+            let mut done = 0;
+            while done < run.count && remaining > 0 {
+                let count = (run.count - done).min(READ_CHUNK_BLOCKS).min(remaining.div_ceil(self.block_size));
+                if run.initialized {
+                    let data = self.read_block(disk, run.physical + done, count)?;
+                    emit(out, &data, &mut remaining)?;
+                } else {
+                    emit(out, &zeroes[..(count * self.block_size) as usize], &mut remaining)?;
+                }
+                done += count;
+            }
+            next_logical = next_logical.max(run.logical + run.count);
+        }
+        while remaining > 0 {
+            let len = remaining.min(zeroes.len() as u64);
+            emit(out, &zeroes[..len as usize], &mut remaining)?;
+        }
+        Ok(())
+    }
+
+    // htree directories keep their index in blocks that look like empty entries to a
+    // linear reader, so reading every block sequentially lists both layouts.
+    fn directory_entries(&self, disk: &mut dyn Disk, inode: &Inode) -> io::Result<Vec<(u64, String)>> {
+        let mut entries = Vec::new();
+        if inode.flags & INODE_FLAG_INLINE_DATA != 0 {
+            // Inline directories start with the parent inode instead of "." and "..".
+            let data = inode.inline_data();
+            parse_directory_block(&data[4..INLINE_SIZE], &mut entries)?;
+            parse_directory_block(&data[INLINE_SIZE..], &mut entries)?;
+            return Ok(entries);
+        }
+        let mut bytes = Vec::new();
+        self.write_data(disk, inode, &mut bytes)?;
+        for block in bytes.chunks(self.block_size as usize) {
+            parse_directory_block(block, &mut entries)?;
+        }
+        Ok(entries)
+    }
+}
+
+fn parse_directory_block(block: &[u8], entries: &mut Vec<(u64, String)>) -> io::Result<()> {
+    let mut position = 0;
+    while position + 8 <= block.len() {
+        let inode = read_u32(block, position) as u64;
+        let record_length = read_u16(block, position + 4) as usize;
+        let name_length = block[position + 6] as usize;
+        if record_length < 8 || position + record_length > block.len() || 8 + name_length > record_length {
+            return Err(invalid_data(format!("malformed directory entry at offset {}", position)));
+        }
+        let name = &block[position + 8..position + 8 + name_length];
+        if inode != 0 && name != b"." && name != b".." {
+            entries.push((inode, String::from_utf8_lossy(name).to_string()));
+        }
+        position += record_length;
+    }
+    Ok(())
+}
+
+impl FileSystem for ExtFileSystem {
+    // Same rule as detect_filesystem: extents, 64bit or flex_bg make it ext4, a journal ext3.
+    fn name(&self) -> &'static str {
+        if self.incompat & 0x2C0 != 0 {
+            "ext4"
+        } else if self.compat & COMPAT_HAS_JOURNAL != 0 {
+            "ext3"
+        } else {
+            "ext2"
+        }
+    }
+
+    fn details(&self) -> Vec<(String, String)> {
+        let state = match self.state {
+            1 => "clean".to_string(),
+            2 => "errors detected".to_string(),
+            state => format!("0x{:X}", state),
+        };
+        let creator = match self.creator_os {
+            0 => "Linux".to_string(),
+            1 => "Hurd".to_string(),
+            3 => "FreeBSD".to_string(),
+            os => os.to_string(),
+        };
+        vec![
+            ("Type".to_string(), self.name().to_string()),
+            ("Label".to_string(), self.label.clone()),
+            ("UUID".to_string(), self.uuid.clone()),
+            ("Revision".to_string(), self.revision.to_string()),
+            ("State".to_string(), state),
+            ("Created by".to_string(), creator),
+            ("Last mounted at".to_string(), self.last_mounted.clone()),
+            ("Last mount time".to_string(), format_time(self.mount_time).unwrap_or_else(|| "never".to_string())),
+            ("Last write time".to_string(), format_time(self.write_time).unwrap_or_else(|| "never".to_string())),
+            ("Block size".to_string(), format!("{} bytes", self.block_size)),
+            ("Blocks".to_string(), format!("{} ({} free)", self.blocks_count, self.free_blocks)),
+            ("Inodes".to_string(), format!("{} ({} free)", self.inodes_count, self.free_inodes)),
+            ("Inode size".to_string(), format!("{} bytes", self.inode_size)),
+            ("Block groups".to_string(), format!("{} of {} blocks, {} inodes", self.group_count(), self.blocks_per_group, self.inodes_per_group)),
+            ("Group descriptor size".to_string(), format!("{} bytes", self.descriptor_size)),
+            ("Compatible features".to_string(), feature_names(self.compat, COMPAT_FEATURES)),
+            ("Incompatible features".to_string(), feature_names(self.incompat, INCOMPAT_FEATURES)),
+            ("Read-only compatible features".to_string(), feature_names(self.ro_compat, RO_COMPAT_FEATURES)),
+        ]
+    }
+
+    fn cluster_size(&self) -> u64 {
+        self.block_size
+    }
+
+    fn root(&self) -> FileEntry {
+        FileEntry {
+            name: String::new(),
+            path: "/".to_string(),
+            is_dir: true,
+            size: 0,
+            modified: None,
+            node: ROOT_INODE,
+            stream: None,
+            symlink: None,
+        }
+    }
+
+    fn read_dir(&mut self, disk: &mut dyn Disk, dir: &FileEntry) -> io::Result<Vec<FileEntry>> {
+        let inode = self.inode(disk, dir.node)?;
+        if inode.file_type() != MODE_DIRECTORY {
+            return Err(invalid_data(format!("inode {} is not a directory", dir.node)));
+        }
+        let mut files = Vec::new();
+        for (number, name) in self.directory_entries(disk, &inode)? {
+            let path = format!("{}/{}", dir.path.trim_end_matches('/'), name);
+            // A damaged child is still listed, as an empty file that reports the
+            // error when it is opened, instead of hiding its whole directory.
+            let Ok(child) = self.inode(disk, number) else {
+                files.push(FileEntry {
+                    path,
+                    name,
+                    is_dir: false,
+                    size: 0,
+                    modified: None,
+                    node: number,
+                    stream: None,
+                    symlink: None,
+                });
+                continue;
+            };
+            let symlink = match child.file_type() {
+                MODE_SYMLINK => self.symlink_target(disk, &child).ok(),
+                _ => None,
+            };
+            files.push(FileEntry {
+                path,
+                name,
+                is_dir: child.file_type() == MODE_DIRECTORY,
+                size: if child.file_type() == MODE_REGULAR { child.size } else { 0 },
+                modified: child.modified(),
+                node: number,
+                stream: None,
+                symlink,
+            });
+        }
+        // Hashed directories are stored in hash order.
+        if inode.flags & INODE_FLAG_INDEX != 0 {
+            files.sort_by(|a, b| a.name.cmp(&b.name));
+        }
+        Ok(files)
+    }
+
+    fn read_file(&mut self, disk: &mut dyn Disk, file: &FileEntry, out: &mut dyn Write) -> io::Result<()> {
+        let inode = self.inode(disk, file.node)?;
+        match inode.file_type() {
+            MODE_REGULAR => self.write_data(disk, &inode, out),
+            MODE_SYMLINK => out.write_all(self.symlink_target(disk, &inode)?.as_bytes()),
+            // Devices, FIFOs and sockets have no content.
+            _ => Ok(()),
+        }
+    }
+
+    fn allocation(&mut self, disk: &mut dyn Disk, entry: &FileEntry) -> io::Result<Vec<Extent>> {
+        self.inode_extents(disk, entry.node)
+    }
+
+    fn cluster_bitmap(&mut self, disk: &mut dyn Disk) -> io::Result<ClusterBitmap> {
+        let count = self.blocks_count - self.first_data_block;
+        let group_bytes = (self.blocks_per_group / 8) as usize;
+        let mut bits = Vec::new();
+        for (index, group) in self.groups.iter().enumerate() {
+            let blocks = self.blocks_per_group.min(count - index as u64 * self.blocks_per_group);
+            if group.flags & GROUP_BLOCK_UNINIT != 0 {
+                // The bitmap was never written; only the group's own metadata, at its start, is in use.
+                let mut bytes = vec![0u8; group_bytes];
+                for block in 0..blocks.saturating_sub(group.free_blocks) {
+                    bytes[(block / 8) as usize] |= 1 << (block % 8);
+                }
+                bits.extend(bytes);
+                continue;
+            }
+            let mut bytes = self.read_block(disk, group.block_bitmap, 1)?;
+            bytes.resize(group_bytes, 0);
+            bits.extend(bytes);
+        }
+        Ok(ClusterBitmap::new(self.first_data_block, count, bits))
+    }
+
+    fn cluster_position(&self, cluster: u64) -> u64 {
+        self.offset + cluster * self.block_size
+    }
+
+    // Every group has its bitmaps and inode table, wherever flex_bg moved them; the
+    // superblock and descriptor copies sit at the start of the sparse_super groups.
+    fn reserved_areas(&mut self, disk: &mut dyn Disk) -> io::Result<Vec<ReservedArea>> {
+        let per_block = self.block_size / self.descriptor_size;
+        let descriptor_blocks = self.group_count().div_ceil(per_block);
+        let mut superblocks = Vec::new();
+        for group in (0..self.group_count()).filter(|group| self.group_has_superblock(*group)) {
+            let count = match self.incompat & INCOMPAT_META_BG {
+                0 => 1 + descriptor_blocks + self.reserved_gdt_blocks,
+                _ => 1 + descriptor_blocks.min(self.first_meta_bg) + self.reserved_gdt_blocks,
+            };
+            push_extent(&mut superblocks, self.first_data_block + group * self.blocks_per_group, count);
+        }
+        if self.incompat & INCOMPAT_META_BG != 0 {
+            for index in self.first_meta_bg..descriptor_blocks {
+                push_extent(&mut superblocks, self.descriptor_block(index), 1);
+            }
+        }
+        let table_blocks = (self.inodes_per_group * self.inode_size).div_ceil(self.block_size);
+        let mut block_bitmaps = Vec::new();
+        let mut inode_bitmaps = Vec::new();
+        let mut inode_tables = Vec::new();
+        for group in &self.groups {
+            push_extent(&mut block_bitmaps, group.block_bitmap, 1);
+            push_extent(&mut inode_bitmaps, group.inode_bitmap, 1);
+            push_extent(&mut inode_tables, group.inode_table, table_blocks);
+        }
+        let mut areas = vec![
+            ReservedArea {
+                name: "Superblocks and group descriptors".to_string(),
+                bad: false,
+                extents: superblocks,
+            },
+            ReservedArea {
+                name: "Block bitmaps".to_string(),
+                bad: false,
+                extents: block_bitmaps,
+            },
+            ReservedArea {
+                name: "Inode bitmaps".to_string(),
+                bad: false,
+                extents: inode_bitmaps,
+            },
+            ReservedArea {
+                name: "Inode tables".to_string(),
+                bad: false,
+                extents: inode_tables,
+            },
+        ];
+        if self.compat & COMPAT_HAS_JOURNAL != 0 && self.journal_inode != 0 {
+            areas.push(ReservedArea {
+                name: "Journal".to_string(),
+                bad: false,
+                extents: self.inode_extents(disk, self.journal_inode)?,
+            });
+        }
+        areas.push(ReservedArea {
+            name: "Bad blocks".to_string(),
+            bad: true,
+            extents: self.inode_extents(disk, BAD_BLOCKS_INODE)?,
+        });
+        Ok(areas)
+    }
+
+    fn deleted_files(&mut self, _disk: &mut dyn Disk) -> io::Result<Vec<FileEntry>> {
+        Err(io::Error::new(io::ErrorKind::Unsupported, "deleted files can only be recovered from FAT and NTFS volumes"))
+    }
+}
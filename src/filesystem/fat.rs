@@ -0,0 +1,691 @@
+use crate::disk::Disk;
+use crate::filesystem::{push_extent, ClusterBitmap, Extent, FileEntry, FileSystem, ReservedArea};
+use crate::partition_table::{read_u16, read_u32};
+use crate::vdisk::invalid_data;
+use std::collections::HashSet;
+use std::io::{self, Write};
+
+const FAT_CACHE_SIZE: u64 = 64 * 1024;
+const DIRECTORY_ENTRY_SIZE: usize = 32;
+
+const ATTR_VOLUME_ID: u8 = 0x08;
+const ATTR_DIRECTORY: u8 = 0x10;
+const ATTR_LONG_NAME: u8 = 0x0F;
+const DELETED: u8 = 0xE5;
+const LOWERCASE_BASE: u8 = 0x08;
+const LOWERCASE_EXTENSION: u8 = 0x10;
+// Set on the node of deleted entries, whose first cluster fills the low 32 bits.
+const NODE_DELETED: u64 = 1 << 32;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FatKind {
+    Fat12,
+    Fat16,
+    Fat32,
+}
+
+impl FatKind {
+    pub fn name(self) -> &'static str {
+        match self {
+            FatKind::Fat12 => "FAT12",
+            FatKind::Fat16 => "FAT16",
+            FatKind::Fat32 => "FAT32",
+        }
+    }
+
+    fn end_of_chain(self) -> u32 {
+        match self {
+            FatKind::Fat12 => 0xFF8,
+            FatKind::Fat16 => 0xFFF8,
+            FatKind::Fat32 => 0x0FFF_FFF8,
+        }
+    }
+
+    fn bad_cluster(self) -> u32 {
+        self.end_of_chain() - 1
+    }
+}
+
+// DOS date and time fields, local time as written by the creating system.
+pub fn format_dos_time(date: u16, time: u16) -> Option<String> {
+    if date == 0 {
+        return None;
+    }
+    Some(format!(
+        "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+        1980 + (date >> 9),
+        (date >> 5) & 0x0F,
+        date & 0x1F,
+        time >> 11,
+        (time >> 5) & 0x3F,
+        (time & 0x1F) * 2
+    ))
+}
+
+fn short_name_checksum(name: &[u8]) -> u8 {
+    name.iter().fold(0u8, |sum, byte| ((sum & 1) << 7).wrapping_add(sum >> 1).wrapping_add(*byte))
+}
+
+fn short_name(entry: &[u8]) -> String {
+    let mut raw = entry[0..11].to_vec();
+    if raw[0] == 0x05 {
+        raw[0] = DELETED;
+    }
+    let decode = |bytes: &[u8], lowercase: bool| -> String {
+        let text: String = bytes.iter().map(|byte| *byte as char).collect::<String>().trim_end().to_string();
+        if lowercase { text.to_lowercase() } else { text }
+    };
+    let base = decode(&raw[0..8], entry[12] & LOWERCASE_BASE != 0);
+    let extension = decode(&raw[8..11], entry[12] & LOWERCASE_EXTENSION != 0);
+    if extension.is_empty() { base } else { format!("{}.{}", base, extension) }
+}
+
+// A raw 32-byte directory slot together with the long name that preceded it.
+pub struct DirectorySlot {
+    pub entry: [u8; DIRECTORY_ENTRY_SIZE],
+    pub long_name: Option<String>,
+}
+
+impl DirectorySlot {
+    // A deleted entry without its long name has lost the first character of its name.
+    pub fn name(&self) -> String {
+        match &self.long_name {
+            Some(name) => name.clone(),
+            None if self.entry[0] == DELETED => {
+                let mut entry = self.entry;
+                entry[0] = b'_';
+                short_name(&entry)
+            }
+            None => short_name(&self.entry),
+        }
+    }
+
+    pub fn attributes(&self) -> u8 {
+        self.entry[11]
+    }
+
+    pub fn first_cluster(&self) -> u32 {
+        ((read_u16(&self.entry, 20) as u32) << 16) | read_u16(&self.entry, 26) as u32
+    }
+
+    pub fn size(&self) -> u32 {
+        read_u32(&self.entry, 28)
+    }
+
+    pub fn modified(&self) -> Option<String> {
+        format_dos_time(read_u16(&self.entry, 24), read_u16(&self.entry, 22))
+    }
+}
+
+// Collects VFAT long name fragments, which precede their short entry in reverse order.
+#[derive(Default)]
+struct LongNameParts {
+    parts: Vec<(u8, u8, Vec<u16>)>,
+}
+
+impl LongNameParts {
+    fn push(&mut self, entry: &[u8]) {
+        let sequence = entry[0];
+        if sequence & 0x40 != 0 {
+            self.parts.clear();
+        }
+        self.parts.push((sequence & 0x1F, entry[13], Self::units(entry)));
+    }
+
+    fn units(entry: &[u8]) -> Vec<u16> {
+        [1..11, 14..26, 28..32]
+            .into_iter()
+            .flat_map(|range| entry[range].chunks_exact(2).map(|unit| u16::from_le_bytes([unit[0], unit[1]])).collect::<Vec<_>>())
+            .collect()
+    }
+
+    fn take(&mut self, short: &[u8]) -> Option<String> {
+        let parts = std::mem::take(&mut self.parts);
+        let checksum = short_name_checksum(&short[0..11]);
+        let consistent = !parts.is_empty()
+            && parts.iter().all(|(_, part_checksum, _)| *part_checksum == checksum)
+            && parts.iter().rev().enumerate().all(|(index, (sequence, _, _))| *sequence as usize == index + 1);
+        if !consistent {
+            return None;
+        }
+        Some(Self::assemble(&parts))
+    }
+
+    // Deleting overwrites the sequence byte of every fragment with 0xE5, so only the
+    // order of the fragments and the checksum of the short name tie them together.
+    // The checksum folds in the lost first byte of the short name before any other, so
+    // exactly one value of that byte matches; the long name must start with it.
+    fn take_deleted(&mut self, short: &[u8]) -> Option<String> {
+        let parts = std::mem::take(&mut self.parts);
+        let checksum = parts.first()?.1;
+        if !parts.iter().all(|(_, part_checksum, _)| *part_checksum == checksum) {
+            return None;
+        }
+        let mut name: [u8; 11] = short[0..11].try_into().unwrap();
+        let first = (1..=u8::MAX).find(|first| {
+            name[0] = *first;
+            short_name_checksum(&name) == checksum
+        })?;
+        let long_name = Self::assemble(&parts);
+        if !Self::starts_short_name(&long_name, first) {
+            return None;
+        }
+        Some(long_name)
+    }
+
+    // Short names drop leading dots and spaces, upper-case the rest and replace the
+    // characters they cannot hold with '_'. Non-ASCII characters go through the OEM
+    // code page, which is unknown here, so any high byte is accepted for them.
+    fn starts_short_name(long_name: &str, first: u8) -> bool {
+        let first = if first == 0x05 { DELETED } else { first };
+        match long_name.chars().find(|c| *c != '.' && *c != ' ') {
+            Some(c) if c.is_ascii() => {
+                let expected = match c.to_ascii_uppercase() as u8 {
+                    b'+' | b',' | b';' | b'=' | b'[' | b']' => b'_',
+                    byte => byte,
+                };
+                first == expected
+            }
+            Some(_) => first >= 0x80 || first == b'_',
+            None => false,
+        }
+    }
+
+    fn assemble(parts: &[(u8, u8, Vec<u16>)]) -> String {
+        let units: Vec<u16> = parts
+            .iter()
+            .rev()
+            .flat_map(|(_, _, units)| units.iter().copied())
+            .take_while(|unit| *unit != 0)
+            .collect();
+        String::from_utf16_lossy(&units)
+    }
+}
+
+fn parse_slots(bytes: &[u8]) -> Vec<DirectorySlot> {
+    let mut slots = Vec::new();
+    let mut long_name = LongNameParts::default();
+    let mut deleted_name = LongNameParts::default();
+    for entry in bytes.chunks_exact(DIRECTORY_ENTRY_SIZE) {
+        if entry[0] == 0 {
+            break;
+        }
+        if entry[11] & 0x3F == ATTR_LONG_NAME {
+            if entry[0] == DELETED {
+                long_name.parts.clear();
+                deleted_name.parts.push((0, entry[13], LongNameParts::units(entry)));
+            } else {
+                deleted_name.parts.clear();
+                long_name.push(entry);
+            }
+            continue;
+        }
+        let resolved = if entry[0] == DELETED { deleted_name.take_deleted(entry) } else { long_name.take(entry) };
+        long_name.parts.clear();
+        deleted_name.parts.clear();
+        slots.push(DirectorySlot {
+            entry: entry.try_into().unwrap(),
+            long_name: resolved,
+        });
+    }
+    slots
+}
+
+pub struct FatFileSystem {
+    offset: u64,
+    kind: FatKind,
+    oem_name: String,
+    label: String,
+    serial: u32,
+    media: u8,
+    bytes_per_sector: u64,
+    cluster_size: u64,
+    reserved_sectors: u64,
+    fat_count: u64,
+    fat_sectors: u64,
+    active_fat: u64,
+    root_entries: u64,
+    root_cluster: u32,
+    root_offset: u64,
+    data_offset: u64,
+    cluster_count: u32,
+    fat_cache: Option<(u64, Vec<u8>)>,
+}
+
+impl FatFileSystem {
+    pub fn open(disk: &mut dyn Disk, offset: u64) -> io::Result<Self> {
+        let boot = disk.read_bytes(offset, 512)?;
+        let bytes_per_sector = read_u16(&boot, 11) as u64;
+        let sectors_per_cluster = boot[13] as u64;
+        let reserved_sectors = read_u16(&boot, 14) as u64;
+        let fat_count = boot[16] as u64;
+        let root_entries = read_u16(&boot, 17) as u64;
+        let total_sectors = match read_u16(&boot, 19) {
+            0 => read_u32(&boot, 32) as u64,
+            sectors => sectors as u64,
+        };
+        let fat_sectors = match read_u16(&boot, 22) {
+            0 => read_u32(&boot, 36) as u64,
+            sectors => sectors as u64,
+        };
+        if !bytes_per_sector.is_power_of_two()
+            || !(512..=4096).contains(&bytes_per_sector)
+            || !sectors_per_cluster.is_power_of_two()
+            || fat_count == 0
+            || fat_sectors == 0
+        {
+            return Err(invalid_data("invalid FAT BIOS parameter block"));
+        }
+        let root_sectors = (root_entries * 32).div_ceil(bytes_per_sector);
+        let first_data_sector = reserved_sectors + fat_count * fat_sectors + root_sectors;
+        let data_sectors = total_sectors
+            .checked_sub(first_data_sector)
+            .ok_or_else(|| invalid_data("FAT metadata is larger than the volume"))?;
+        let cluster_count = (data_sectors / sectors_per_cluster) as u32;
+        let kind = match cluster_count {
+            0..=4084 => FatKind::Fat12,
+            4085..=65524 => FatKind::Fat16,
+            _ => FatKind::Fat32,
+        };
+        // FAT32 moves the extended boot record fields and can disable FAT mirroring.
+        let extended = if kind == FatKind::Fat32 { 64 } else { 36 };
+        let flags = read_u16(&boot, 40);
+        let active_fat = if kind == FatKind::Fat32 && flags & 0x80 != 0 { (flags & 0x0F) as u64 } else { 0 };
+        let has_extended = boot[extended + 2] == 0x29;
+        let text = |bytes: &[u8]| String::from_utf8_lossy(bytes).trim_end().to_string();
+        Ok(Self {
+            offset,
+            kind,
+            oem_name: text(&boot[3..11]),
+            label: if has_extended { text(&boot[extended + 7..extended + 18]) } else { String::new() },
+            serial: if has_extended { read_u32(&boot, extended + 3) } else { 0 },
+            media: boot[21],
+            bytes_per_sector,
+            cluster_size: bytes_per_sector * sectors_per_cluster,
+            reserved_sectors,
+            fat_count,
+            fat_sectors,
+            active_fat,
+            root_entries,
+            root_cluster: if kind == FatKind::Fat32 { read_u32(&boot, 44) } else { 0 },
+            root_offset: (reserved_sectors + fat_count * fat_sectors) * bytes_per_sector,
+            data_offset: first_data_sector * bytes_per_sector,
+            cluster_count,
+            fat_cache: None,
+        })
+    }
+
+    // Clusters 0 and 1 have no data; they still turn up in corrupt entries and chains.
+    pub fn cluster_offset(&self, cluster: u32) -> io::Result<u64> {
+        if cluster < 2 || cluster as u64 >= self.cluster_count as u64 + 2 {
+            return Err(invalid_data(format!("cluster {} lies outside the data area", cluster)));
+        }
+        Ok(self.offset + self.data_offset + (cluster as u64 - 2) * self.cluster_size)
+    }
+
+    fn fat_byte(&mut self, disk: &mut dyn Disk, index: u64) -> io::Result<u8> {
+        let chunk = index / FAT_CACHE_SIZE;
+        if self.fat_cache.as_ref().is_none_or(|(cached, _)| *cached != chunk) {
+            let fat_bytes = self.fat_sectors * self.bytes_per_sector;
+            let start = chunk * FAT_CACHE_SIZE;
+            let len = FAT_CACHE_SIZE.min(fat_bytes.saturating_sub(start)) as usize;
+            let fat_start = self.offset + (self.reserved_sectors + self.active_fat * self.fat_sectors) * self.bytes_per_sector;
+            self.fat_cache = Some((chunk, disk.read_bytes(fat_start + start, len)?));
+        }
+        let (_, bytes) = self.fat_cache.as_ref().unwrap();
+        bytes
+            .get((index % FAT_CACHE_SIZE) as usize)
+            .copied()
+            .ok_or_else(|| invalid_data(format!("FAT entry byte {} is beyond the end of the FAT", index)))
+    }
+
+    pub fn fat_entry(&mut self, disk: &mut dyn Disk, cluster: u32) -> io::Result<u32> {
+        let cluster = cluster as u64;
+        match self.kind {
+            FatKind::Fat12 => {
+                let index = cluster + cluster / 2;
+                let value = u16::from_le_bytes([self.fat_byte(disk, index)?, self.fat_byte(disk, index + 1)?]);
+                let entry = if cluster & 1 == 0 { value & 0x0FFF } else { value >> 4 };
+                Ok(entry as u32)
+            }
+            FatKind::Fat16 => Ok(u16::from_le_bytes([self.fat_byte(disk, cluster * 2)?, self.fat_byte(disk, cluster * 2 + 1)?]) as u32),
+            FatKind::Fat32 => {
+                let mut bytes = [0u8; 4];
+                for (index, byte) in bytes.iter_mut().enumerate() {
+                    *byte = self.fat_byte(disk, cluster * 4 + index as u64)?;
+                }
+                Ok(u32::from_le_bytes(bytes) & 0x0FFF_FFFF)
+            }
+        }
+    }
+
+    // Follows a cluster chain, rejecting loops, free or bad clusters and clusters
+    // outside the data area instead of reading garbage.
+    pub fn cluster_chain(&mut self, disk: &mut dyn Disk, first: u32) -> io::Result<Vec<u32>> {
+        let mut chain = Vec::new();
+        if first == 0 {
+            return Ok(chain);
+        }
+        let last_cluster = self.cluster_count + 1;
+        let mut cluster = first;
+        loop {
+            if cluster < 2 || cluster > last_cluster {
+                return Err(invalid_data(format!("cluster chain starting at {} points to invalid cluster {}", first, cluster)));
+            }
+            if chain.len() > self.cluster_count as usize {
+                return Err(invalid_data(format!("cluster chain starting at {} contains a loop", first)));
+            }
+            chain.push(cluster);
+            let next = self.fat_entry(disk, cluster)?;
+            if next >= self.kind.end_of_chain() {
+                return Ok(chain);
+            }
+            if next == self.kind.bad_cluster() {
+                return Err(invalid_data(format!("cluster chain starting at {} runs into bad cluster {}", first, cluster)));
+            }
+            if next == 0 {
+                return Err(invalid_data(format!("cluster chain starting at {} runs into free cluster {}", first, cluster)));
+            }
+            cluster = next;
+        }
+    }
+
+    fn directory_bytes(&mut self, disk: &mut dyn Disk, first_cluster: u32) -> io::Result<Vec<u8>> {
+        // FAT12/16 keep the root directory in a fixed region before the data area.
+        if first_cluster == 0 && self.kind != FatKind::Fat32 {
+            return disk.read_bytes(self.offset + self.root_offset, (self.root_entries * 32) as usize);
+        }
+        let first = if first_cluster == 0 { self.root_cluster } else { first_cluster };
+        let mut bytes = Vec::new();
+        for cluster in self.cluster_chain(disk, first)? {
+            bytes.extend(disk.read_bytes(self.cluster_offset(cluster)?, self.cluster_size as usize)?);
+        }
+        Ok(bytes)
+    }
+
+    // Every used slot of a directory, including deleted ones, with long names resolved.
+    pub fn directory_slots(&mut self, disk: &mut dyn Disk, first_cluster: u32) -> io::Result<Vec<DirectorySlot>> {
+        Ok(parse_slots(&self.directory_bytes(disk, first_cluster)?))
+    }
+
+    // Deleted files and folders keep their first cluster but their chains are zeroed in
+    // the FAT, so their data is taken as the contiguous run FAT allocates when it can.
+    fn clusters_of(&mut self, disk: &mut dyn Disk, node: u64, size: u64) -> io::Result<Vec<u32>> {
+        let first = node as u32;
+        if node & NODE_DELETED == 0 {
+            return self.cluster_chain(disk, first);
+        }
+        let count = size.div_ceil(self.cluster_size);
+        if count == 0 {
+            return Ok(Vec::new());
+        }
+        if first < 2 {
+            return Err(invalid_data("the deleted entry no longer records its first cluster"));
+        }
+        if first as u64 + count > self.cluster_count as u64 + 2 {
+            return Err(invalid_data(format!("deleted data at cluster {} would run past the end of the volume", first)));
+        }
+        Ok((first..first + count as u32).collect())
+    }
+
+    fn entry_from_slot(parent: &FileEntry, slot: &DirectorySlot) -> FileEntry {
+        let name = slot.name();
+        FileEntry {
+            path: format!("{}/{}", parent.path.trim_end_matches('/'), name),
+            name,
+            is_dir: slot.attributes() & ATTR_DIRECTORY != 0,
+            size: slot.size() as u64,
+            modified: slot.modified(),
+            node: slot.first_cluster() as u64,
+            stream: None,
+            symlink: None,
+        }
+    }
+}
+
+impl FileSystem for FatFileSystem {
+    fn name(&self) -> &'static str {
+        self.kind.name()
+    }
+
+    fn details(&self) -> Vec<(String, String)> {
+        let mut details = vec![
+            ("Type".to_string(), self.kind.name().to_string()),
+            ("OEM name".to_string(), self.oem_name.clone()),
+            ("Label".to_string(), self.label.clone()),
+            ("Serial number".to_string(), format!("{:04X}-{:04X}", self.serial >> 16, self.serial & 0xFFFF)),
+            ("Bytes per sector".to_string(), self.bytes_per_sector.to_string()),
+            ("Cluster size".to_string(), format!("{} bytes", self.cluster_size)),
+            ("Clusters".to_string(), self.cluster_count.to_string()),
+            ("Reserved sectors".to_string(), self.reserved_sectors.to_string()),
+            ("FATs".to_string(), format!("{} of {} sectors", self.fat_count, self.fat_sectors)),
+            ("Media descriptor".to_string(), format!("0x{:02X}", self.media)),
+        ];
+        if self.kind == FatKind::Fat32 {
+            details.push(("Root directory cluster".to_string(), self.root_cluster.to_string()));
+            if self.active_fat != 0 {
+                details.push(("Active FAT".to_string(), format!("{} (mirroring disabled)", self.active_fat)));
+            }
+        } else {
+            details.push(("Root directory entries".to_string(), self.root_entries.to_string()));
+        }
+        details
+    }
+
+    fn cluster_size(&self) -> u64 {
+        self.cluster_size
+    }
+
+    fn root(&self) -> FileEntry {
+        FileEntry {
+            name: String::new(),
+            path: "/".to_string(),
+            is_dir: true,
+            size: 0,
+            modified: None,
+            node: 0,
+            stream: None,
+            symlink: None,
+        }
+    }
+
+    fn read_dir(&mut self, disk: &mut dyn Disk, dir: &FileEntry) -> io::Result<Vec<FileEntry>> {
+        let slots = self.directory_slots(disk, dir.node as u32)?;
+        Ok(slots
+            .iter()
+            .filter(|slot| slot.entry[0] != DELETED && slot.attributes() & ATTR_VOLUME_ID == 0)
+            .filter(|slot| &slot.entry[0..2] != b". " && &slot.entry[0..2] != b"..")
+            .map(|slot| Self::entry_from_slot(dir, slot))
+            .collect())
+    }
+
+    fn read_file(&mut self, disk: &mut dyn Disk, file: &FileEntry, out: &mut dyn Write) -> io::Result<()> {
+        let chain = self.clusters_of(disk, file.node, file.size)?;
+        if (chain.len() as u64) < file.size.div_ceil(self.cluster_size) {
+            return Err(invalid_data(format!(
+                "{} has {} cluster(s) but needs {} for {} bytes",
+                file.path,
+                chain.len(),
+                file.size.div_ceil(self.cluster_size),
+                file.size
+            )));
+        }
+        let mut remaining = file.size;
+        for cluster in chain {
+            if remaining == 0 {
+                break;
+            }
+            let len = remaining.min(self.cluster_size);
+            out.write_all(&disk.read_bytes(self.cluster_offset(cluster)?, len as usize)?)?;
+            remaining -= len;
+        }
+        Ok(())
+    }
+
+    fn allocation(&mut self, disk: &mut dyn Disk, entry: &FileEntry) -> io::Result<Vec<Extent>> {
+        let node = if entry.node == 0 && entry.is_dir { self.root_cluster as u64 } else { entry.node };
+        let mut extents: Vec<Extent> = Vec::new();
+        for cluster in self.clusters_of(disk, node, entry.size)? {
+            match extents.last_mut() {
+                Some(extent) if extent.start + extent.count == cluster as u64 => extent.count += 1,
+                _ => extents.push(Extent {
+                    start: cluster as u64,
+                    count: 1,
+                }),
+            }
+        }
+        Ok(extents)
+    }
+
+    fn cluster_bitmap(&mut self, disk: &mut dyn Disk) -> io::Result<ClusterBitmap> {
+        let mut bitmap = ClusterBitmap::new(2, self.cluster_count as u64, Vec::new());
+        for index in 0..self.cluster_count {
+            if self.fat_entry(disk, index + 2)? != 0 {
+                bitmap.set(index as u64);
+            }
+        }
+        Ok(bitmap)
+    }
+
+    fn cluster_position(&self, cluster: u64) -> u64 {
+        self.offset + self.data_offset + cluster.saturating_sub(2) * self.cluster_size
+    }
+
+    // The reserved sectors, FATs and FAT12/16 root directory lie before the cluster
+    // heap, so only bad clusters are left to report.
+    fn reserved_areas(&mut self, disk: &mut dyn Disk) -> io::Result<Vec<ReservedArea>> {
+        let mut extents = Vec::new();
+        for cluster in 2..self.cluster_count + 2 {
+            if self.fat_entry(disk, cluster)? == self.kind.bad_cluster() {
+                push_extent(&mut extents, cluster as u64, 1);
+            }
+        }
+        Ok(vec![ReservedArea {
+            name: "Bad clusters".to_string(),
+            bad: true,
+            extents,
+        }])
+    }
+
+    // Deleted folders are searched too while their first cluster is still free and
+    // still starts with the "." entry; the rest of their chain is lost.
+    fn deleted_files(&mut self, disk: &mut dyn Disk) -> io::Result<Vec<FileEntry>> {
+        let mut found = Vec::new();
+        let mut visited = HashSet::new();
+        let mut pending = vec![self.root()];
+        while let Some(dir) = pending.pop() {
+            if !visited.insert(dir.node) {
+                continue;
+            }
+            let in_deleted = dir.node & NODE_DELETED != 0;
+            let bytes = if in_deleted {
+                let first = dir.node as u32;
+                if first < 2 || first > self.cluster_count + 1 || self.fat_entry(disk, first)? != 0 {
+                    continue;
+                }
+                let bytes = disk.read_bytes(self.cluster_offset(first)?, self.cluster_size as usize)?;
+                if &bytes[0..11] != b".          " {
+                    continue;
+                }
+                bytes
+            } else {
+                match self.directory_bytes(disk, dir.node as u32) {
+                    Ok(bytes) => bytes,
+                    Err(_) => continue,
+                }
+            };
+            for slot in parse_slots(&bytes) {
+                if slot.attributes() & ATTR_VOLUME_ID != 0 || &slot.entry[0..2] == b". " || &slot.entry[0..2] == b".." {
+                    continue;
+                }
+                let deleted = in_deleted || slot.entry[0] == DELETED;
+                let mut entry = Self::entry_from_slot(&dir, &slot);
+                if deleted {
+                    entry.node |= NODE_DELETED;
+                }
+                if entry.is_dir {
+                    if entry.node as u32 != 0 {
+                        pending.push(entry);
+                    }
+                } else if deleted {
+                    found.push(entry);
+                }
+            }
+        }
+        Ok(found)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn long_name_entry(sequence: u8, checksum: u8, name: &str) -> [u8; DIRECTORY_ENTRY_SIZE] {
+        let mut units: Vec<u16> = name.encode_utf16().collect();
+        units.push(0);
+        units.resize(13, 0xFFFF);
+        let mut entry = [0u8; DIRECTORY_ENTRY_SIZE];
+        entry[0] = sequence;
+        entry[11] = ATTR_LONG_NAME;
+        entry[13] = checksum;
+        let offsets = (1..11).step_by(2).chain((14..26).step_by(2)).chain((28..32).step_by(2));
+        for (offset, unit) in offsets.zip(units) {
+            entry[offset..offset + 2].copy_from_slice(&unit.to_le_bytes());
+        }
+        entry
+    }
+
+    fn short_entry(name: &[u8; 11]) -> [u8; DIRECTORY_ENTRY_SIZE] {
+        let mut entry = [0u8; DIRECTORY_ENTRY_SIZE];
+        entry[0..11].copy_from_slice(name);
+        entry[11] = 0x20;
+        entry
+    }
+
+    // Deletes a long name entry set the way DOS does, by overwriting every first byte.
+    fn deleted_slots(long_name: &str, short: &[u8; 11]) -> Vec<DirectorySlot> {
+        let checksum = short_name_checksum(short);
+        let mut bytes = long_name_entry(0x41, checksum, long_name).to_vec();
+        bytes.extend_from_slice(&short_entry(short));
+        bytes[0] = DELETED;
+        bytes[DIRECTORY_ENTRY_SIZE] = DELETED;
+        parse_slots(&bytes)
+    }
+
+    #[test]
+    fn live_long_name_is_attached() {
+        let short = b"REPORT~1TXT";
+        let mut bytes = long_name_entry(0x41, short_name_checksum(short), "report 24.txt").to_vec();
+        bytes.extend_from_slice(&short_entry(short));
+        let slots = parse_slots(&bytes);
+        assert_eq!(slots.len(), 1);
+        assert_eq!(slots[0].name(), "report 24.txt");
+    }
+
+    #[test]
+    fn deleted_long_name_matching_first_byte_is_recovered() {
+        let slots = deleted_slots("report 24.txt", b"REPORT~1TXT");
+        assert_eq!(slots.len(), 1);
+        assert_eq!(slots[0].long_name.as_deref(), Some("report 24.txt"));
+    }
+
+    #[test]
+    fn deleted_long_name_skips_leading_dots_and_maps_invalid_characters() {
+        assert_eq!(deleted_slots(".profile", b"PROFILE    ")[0].long_name.as_deref(), Some(".profile"));
+        assert_eq!(deleted_slots("[draft].doc", b"_DRAFT_ DOC")[0].long_name.as_deref(), Some("[draft].doc"));
+    }
+
+    #[test]
+    fn deleted_long_name_with_other_first_byte_is_dropped() {
+        // The checksum belongs to "REPORT~1TXT", but the fragments say "notes.txt".
+        let checksum = short_name_checksum(b"REPORT~1TXT");
+        let mut bytes = long_name_entry(0x41, checksum, "notes.txt").to_vec();
+        bytes.extend_from_slice(&short_entry(b"REPORT~1TXT"));
+        bytes[0] = DELETED;
+        bytes[DIRECTORY_ENTRY_SIZE] = DELETED;
+        let slots = parse_slots(&bytes);
+        assert_eq!(slots.len(), 1);
+        assert_eq!(slots[0].long_name, None);
+        assert_eq!(slots[0].name(), "_EPORT~1.TXT");
+    }
+}
@@ -0,0 +1,193 @@
+use crate::disk::Disk;
+use crate::filesystem::{walk_allocations, Extent, FileSystem};
+use std::io;
+
+pub struct FragmentedFile {
+    pub path: String,
+    pub size: u64,
+    pub clusters: u64,
+    pub fragments: u64,
+}
+
+// Fragmentation of the files of a volume, from their data runs, extents or cluster
+// chains, and of its free space, from the allocation bitmap.
+pub struct FragmentationReport {
+    pub filesystem: &'static str,
+    pub cluster_size: u64,
+    pub files: u64,
+    pub fragmented_files: u64,
+    pub fragments: u64,
+    pub file_clusters: u64,
+    pub fragmented_clusters: u64,
+    pub unreadable: u64,
+    pub most_fragmented: Vec<FragmentedFile>,
+    pub free_clusters: u64,
+    pub free_extents: u64,
+    pub largest_free_extent: Option<Extent>,
+}
+
+impl FragmentationReport {
+    // Keeps the `top` files with the most fragments; `progress` receives the number
+    // of files and folders walked so far.
+    pub fn analyze(filesystem: &mut dyn FileSystem, disk: &mut dyn Disk, top: usize, progress: &mut dyn FnMut(u64)) -> io::Result<Self> {
+        let free = filesystem.cluster_bitmap(disk)?.free_extents();
+        let mut report = Self {
+            filesystem: filesystem.name(),
+            cluster_size: filesystem.cluster_size(),
+            files: 0,
+            fragmented_files: 0,
+            fragments: 0,
+            file_clusters: 0,
+            fragmented_clusters: 0,
+            unreadable: 0,
+            most_fragmented: Vec::new(),
+            free_clusters: free.iter().map(|extent| extent.count).sum(),
+            free_extents: free.len() as u64,
+            largest_free_extent: free.iter().copied().max_by_key(|extent| (extent.count, std::cmp::Reverse(extent.start))),
+        };
+        let mut walked = 0;
+        let mut fragmented = Vec::new();
+        walk_allocations(filesystem, disk, &mut |entry, allocation| {
+            walked += 1;
+            progress(walked);
+            let extents = match allocation {
+                Ok(extents) => extents,
+                Err(_) => {
+                    report.unreadable += 1;
+                    return;
+                }
+            };
+            // Folders and empty or resident files have nothing to defragment.
+            if entry.is_dir || extents.is_empty() {
+                return;
+            }
+            let clusters: u64 = extents.iter().map(|extent| extent.count).sum();
+            report.files += 1;
+            report.fragments += extents.len() as u64;
+            report.file_clusters += clusters;
+            if extents.len() > 1 {
+                report.fragmented_files += 1;
+                report.fragmented_clusters += clusters;
+                fragmented.push(FragmentedFile {
+                    path: entry.path.clone(),
+                    size: entry.size,
+                    clusters,
+                    fragments: extents.len() as u64,
+                });
+            }
+        });
+        fragmented.sort_by(|a, b| b.fragments.cmp(&a.fragments).then_with(|| a.path.cmp(&b.path)));
+        fragmented.truncate(top);
+        report.most_fragmented = fragmented;
+        Ok(report)
+    }
+
+    // Share of file data stored in fragmented files, as Windows' defragmenter reports it.
+    pub fn fragmented_percent(&self) -> f64 {
+        percent(self.fragmented_clusters, self.file_clusters)
+    }
+
+    // Free space outside the largest free extent; 0% when all of it is one run.
+    pub fn free_space_fragmentation(&self) -> f64 {
+        let largest = self.largest_free_extent.map_or(0, |extent| extent.count);
+        percent(self.free_clusters - largest, self.free_clusters)
+    }
+
+    pub fn describe(&self) -> String {
+        let mut text = format!("{}, {}-byte clusters\n", self.filesystem, self.cluster_size);
+        text.push_str(&format!(
+            "Files: {} with data, {} fragmented ({:.1}%), {} fragments in all\n",
+            self.files,
+            self.fragmented_files,
+            percent(self.fragmented_files, self.files),
+            self.fragments
+        ));
+        text.push_str(&format!("Fragmentation: {:.1}% of file clusters are in fragmented files\n", self.fragmented_percent()));
+        match self.largest_free_extent {
+            Some(largest) => text.push_str(&format!(
+                "Free space: {} clusters in {} extent(s), largest {} clusters at {} ({:.1}% fragmented)\n",
+                self.free_clusters,
+                self.free_extents,
+                largest.count,
+                largest.start,
+                self.free_space_fragmentation()
+            )),
+            None => text.push_str("Free space: none\n"),
+        }
+        if self.unreadable > 0 {
+            text.push_str(&format!("{} files or folders could not be read\n", self.unreadable));
+        }
+        if !self.most_fragmented.is_empty() {
+            text.push_str("Most fragmented files:\n");
+            for file in &self.most_fragmented {
+                text.push_str(&format!("{:>8} fragments {:>14} bytes  {}\n", file.fragments, file.size, file.path));
+            }
+        }
+        text
+    }
+
+    pub fn to_json(&self) -> String {
+        let files: Vec<String> = self
+            .most_fragmented
+            .iter()
+            .map(|file| {
+                format!(
+                    "{{\"path\": {}, \"size\": {}, \"clusters\": {}, \"fragments\": {}}}",
+                    json_string(&file.path),
+                    file.size,
+                    file.clusters,
+                    file.fragments
+                )
+            })
+            .collect();
+        let largest = match self.largest_free_extent {
+            Some(extent) => format!("{{\"start\": {}, \"clusters\": {}}}", extent.start, extent.count),
+            None => "null".to_string(),
+        };
+        format!(
+            "{{\"filesystem\": {}, \"cluster_size\": {}, \"files\": {}, \"fragmented_files\": {}, \"fragments\": {}, \
+             \"file_clusters\": {}, \"fragmented_clusters\": {}, \"fragmented_percent\": {:.2}, \"unreadable\": {}, \
+             \"free_clusters\": {}, \"free_extents\": {}, \"largest_free_extent\": {}, \"free_space_fragmentation\": {:.2}, \
+             \"most_fragmented\": [{}]}}",
+            json_string(self.filesystem),
+            self.cluster_size,
+            self.files,
+            self.fragmented_files,
+            self.fragments,
+            self.file_clusters,
+            self.fragmented_clusters,
+            self.fragmented_percent(),
+            self.unreadable,
+            self.free_clusters,
+            self.free_extents,
+            largest,
+            self.free_space_fragmentation(),
+            files.join(", ")
+        )
+    }
+}
+
+fn percent(part: u64, total: u64) -> f64 {
+    match total {
+        0 => 0.0,
+        total => part as f64 * 100.0 / total as f64,
+    }
+}
+
+pub fn json_string(text: &str) -> String {
+    let mut quoted = String::with_capacity(text.len() + 2);
+    quoted.push('"');
+    for c in text.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            '\t' => quoted.push_str("\\t"),
+            c if (c as u32) < 0x20 => quoted.push_str(&format!("\\u{:04x}", c as u32)),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
@@ -0,0 +1,735 @@
+use crate::disk::Disk;
+use crate::filesystem::{push_extent, ClusterBitmap, Extent, FileEntry, FileSystem, ReservedArea};
+use crate::partition_table::{read_u16, read_u32};
+use crate::vdisk::invalid_data;
+use std::collections::{HashMap, HashSet};
+use std::io::{self, Write};
+
+const SECTOR_SIZE: u64 = 2048;
+const FIRST_DESCRIPTOR: u64 = 16;
+const MAX_DESCRIPTORS: u64 = 64;
+const READ_CHUNK: u64 = 1024 * 1024;
+const MAX_CONTINUATIONS: usize = 16;
+const MAX_PATH_TABLE: u32 = 16 * 1024 * 1024;
+
+const DESCRIPTOR_BOOT_RECORD: u8 = 0;
+const DESCRIPTOR_PRIMARY: u8 = 1;
+const DESCRIPTOR_SUPPLEMENTARY: u8 = 2;
+const DESCRIPTOR_TERMINATOR: u8 = 255;
+
+const FLAG_DIRECTORY: u8 = 0x02;
+const FLAG_ASSOCIATED: u8 = 0x04;
+const FLAG_MULTI_EXTENT: u8 = 0x80;
+
+// Nodes of the virtual [BOOT] folder and the El Torito images in it; real nodes are
+// block numbers and never reach this bit.
+const BOOT_NODE: u64 = 1 << 63;
+
+pub fn format_utc_offset(minutes: i32) -> String {
+    let sign = if minutes < 0 { '-' } else { '+' };
+    format!("{}{:02}:{:02}", sign, minutes.abs() / 60, minutes.abs() % 60)
+}
+
+// Seven byte directory record date: years since 1900, then month to second and a
+// signed offset from UTC in 15 minute units.
+fn format_recording_date(date: &[u8]) -> Option<String> {
+    if date.len() < 7 || date[1] == 0 {
+        return None;
+    }
+    Some(format!(
+        "{:04}-{:02}-{:02} {:02}:{:02}:{:02} {}",
+        1900 + date[0] as u32,
+        date[1],
+        date[2],
+        date[3],
+        date[4],
+        date[5],
+        format_utc_offset(date[6] as i8 as i32 * 15)
+    ))
+}
+
+// Seventeen byte volume descriptor date: sixteen ASCII digits and the UTC offset.
+fn format_descriptor_date(date: &[u8]) -> Option<String> {
+    let digits = std::str::from_utf8(date.get(..16)?).ok()?;
+    if !digits.bytes().all(|digit| digit.is_ascii_digit()) || &digits[..4] == "0000" {
+        return None;
+    }
+    Some(format!(
+        "{}-{}-{} {}:{}:{} {}",
+        &digits[0..4],
+        &digits[4..6],
+        &digits[6..8],
+        &digits[8..10],
+        &digits[10..12],
+        &digits[12..14],
+        format_utc_offset(date[16] as i8 as i32 * 15)
+    ))
+}
+
+fn text(bytes: &[u8]) -> String {
+    String::from_utf8_lossy(bytes).trim_end_matches([' ', '\0']).to_string()
+}
+
+fn joliet_level(descriptor: &[u8]) -> Option<u8> {
+    match &descriptor[88..91] {
+        b"%/@" => Some(1),
+        b"%/C" => Some(2),
+        b"%/E" => Some(3),
+        _ => None,
+    }
+}
+
+pub fn copy_range(disk: &mut dyn Disk, start: u64, len: u64, out: &mut dyn Write) -> io::Result<()> {
+    let mut done = 0;
+    while done < len {
+        let chunk = READ_CHUNK.min(len - done);
+        out.write_all(&disk.read_bytes(start + done, chunk as usize)?)?;
+        done += chunk;
+    }
+    Ok(())
+}
+
+struct BootImage {
+    name: String,
+    sector: u32,
+    size: u64,
+}
+
+fn platform_name(platform: u8) -> String {
+    match platform {
+        0x00 => "x86".to_string(),
+        0x01 => "PowerPC".to_string(),
+        0x02 => "Mac".to_string(),
+        0xEF => "UEFI".to_string(),
+        other => format!("platform{:02X}", other),
+    }
+}
+
+// El Torito boot catalog, presented as a virtual [BOOT] folder in the root.
+pub struct BootCatalog {
+    sector: u32,
+    images: Vec<BootImage>,
+}
+
+impl BootCatalog {
+    // Looks for the El Torito boot record among the volume descriptors.
+    pub fn find(disk: &mut dyn Disk, offset: u64) -> io::Result<Option<Self>> {
+        for index in FIRST_DESCRIPTOR..FIRST_DESCRIPTOR + MAX_DESCRIPTORS {
+            let descriptor = disk.read_bytes(offset + index * SECTOR_SIZE, SECTOR_SIZE as usize)?;
+            if &descriptor[1..6] != b"CD001" || descriptor[0] == DESCRIPTOR_TERMINATOR {
+                break;
+            }
+            if descriptor[0] == DESCRIPTOR_BOOT_RECORD && descriptor[7..30] == *b"EL TORITO SPECIFICATION" {
+                return Self::read(disk, offset, read_u32(&descriptor, 71)).map(Some);
+            }
+        }
+        Ok(None)
+    }
+
+    fn read(disk: &mut dyn Disk, offset: u64, sector: u32) -> io::Result<Self> {
+        let catalog = disk.read_bytes(offset + sector as u64 * SECTOR_SIZE, SECTOR_SIZE as usize)?;
+        let checksum = (0..32).step_by(2).fold(0u16, |sum, position| sum.wrapping_add(read_u16(&catalog, position)));
+        if catalog[0] != 1 || catalog[30..32] != [0x55, 0xAA] || checksum != 0 {
+            return Err(invalid_data(format!("invalid El Torito validation entry at sector {}", sector)));
+        }
+        let mut entries = vec![(catalog[1], &catalog[32..64])];
+        let mut position = 64;
+        while position + 32 <= catalog.len() && matches!(catalog[position], 0x90 | 0x91) {
+            let last = catalog[position] == 0x91;
+            let platform = catalog[position + 1];
+            let count = read_u16(&catalog, position + 2) as usize;
+            position += 32;
+            for _ in 0..count {
+                if position + 32 > catalog.len() {
+                    break;
+                }
+                entries.push((platform, &catalog[position..position + 32]));
+                position += 32;
+                // Extension entries continue the selection criteria of the entry before them.
+                while position + 32 <= catalog.len() && catalog[position] == 0x44 {
+                    position += 32;
+                }
+            }
+            if last {
+                break;
+            }
+        }
+        let mut images = Vec::new();
+        for (platform, entry) in entries {
+            if !matches!(entry[0], 0x00 | 0x88) {
+                continue;
+            }
+            let image_sector = read_u32(entry, 8);
+            let start = offset + image_sector as u64 * SECTOR_SIZE;
+            let (media, size) = match entry[1] & 0x0F {
+                1 => ("1.2M", 1_228_800),
+                2 => ("1.44M", 1_474_560),
+                3 => ("2.88M", 2_949_120),
+                4 => ("HardDisk", Self::hard_disk_size(disk, start)?.unwrap_or(read_u16(entry, 6) as u64 * 512)),
+                _ => ("NoEmulation", Self::no_emulation_size(disk, start, read_u16(entry, 6) as u64 * 512)?),
+            };
+            let size = size.min(disk.size().saturating_sub(start));
+            images.push(BootImage {
+                name: format!("{}-{}-{}.img", images.len() + 1, platform_name(platform), media),
+                sector: image_sector,
+                size,
+            });
+        }
+        Ok(Self { sector, images })
+    }
+
+    // A hard disk emulation image ends where its last MBR partition ends.
+    fn hard_disk_size(disk: &mut dyn Disk, start: u64) -> io::Result<Option<u64>> {
+        let mbr = disk.read_bytes(start, 512)?;
+        if mbr[510..512] != [0x55, 0xAA] {
+            return Ok(None);
+        }
+        let end = (0..4)
+            .map(|index| 446 + index * 16)
+            .map(|entry| read_u32(&mbr, entry + 8) as u64 + read_u32(&mbr, entry + 12) as u64)
+            .max()
+            .unwrap_or(0);
+        Ok((end > 0).then_some(end * 512))
+    }
+
+    // The sector count of a no emulation entry only covers what the BIOS loads; UEFI
+    // images are FAT volumes whose boot sector gives the real size.
+    fn no_emulation_size(disk: &mut dyn Disk, start: u64, loaded: u64) -> io::Result<u64> {
+        let boot = disk.read_bytes(start, 512)?;
+        let bytes_per_sector = read_u16(&boot, 11) as u64;
+        if boot[510..512] != [0x55, 0xAA] || !matches!(bytes_per_sector, 512 | 1024 | 2048 | 4096) {
+            return Ok(loaded);
+        }
+        let sectors = match read_u16(&boot, 19) {
+            0 => read_u32(&boot, 32) as u64,
+            sectors => sectors as u64,
+        };
+        Ok(loaded.max(sectors * bytes_per_sector))
+    }
+
+    pub fn describe(&self) -> String {
+        format!("{} boot image(s) in the catalog at sector {}", self.images.len(), self.sector)
+    }
+
+    pub fn is_boot_node(node: u64) -> bool {
+        node & BOOT_NODE != 0
+    }
+
+    pub fn directory(&self) -> FileEntry {
+        FileEntry {
+            name: "[BOOT]".to_string(),
+            path: "/[BOOT]".to_string(),
+            is_dir: true,
+            size: 0,
+            modified: None,
+            node: BOOT_NODE,
+            stream: None,
+            symlink: None,
+        }
+    }
+
+    pub fn entries(&self) -> Vec<FileEntry> {
+        self.images
+            .iter()
+            .enumerate()
+            .map(|(index, image)| FileEntry {
+                name: image.name.clone(),
+                path: format!("/[BOOT]/{}", image.name),
+                is_dir: false,
+                size: image.size,
+                modified: None,
+                node: BOOT_NODE | (index as u64 + 1),
+                stream: None,
+                symlink: None,
+            })
+            .collect()
+    }
+
+    fn image(&self, node: u64) -> io::Result<&BootImage> {
+        ((node & !BOOT_NODE) as usize)
+            .checked_sub(1)
+            .and_then(|index| self.images.get(index))
+            .ok_or_else(|| invalid_data(format!("no boot image {}", node & !BOOT_NODE)))
+    }
+
+    pub fn read_image(&self, disk: &mut dyn Disk, offset: u64, node: u64, out: &mut dyn Write) -> io::Result<()> {
+        let image = self.image(node)?;
+        copy_range(disk, offset + image.sector as u64 * SECTOR_SIZE, image.size, out)
+    }
+
+    // Boot images are addressed in 2048 byte sectors whatever the filesystem block size.
+    pub fn allocation(&self, node: u64) -> io::Result<Vec<Extent>> {
+        if node == BOOT_NODE {
+            return Ok(Vec::new());
+        }
+        let image = self.image(node)?;
+        Ok(vec![Extent {
+            start: image.sector as u64,
+            count: image.size.div_ceil(SECTOR_SIZE),
+        }])
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Tree {
+    RockRidge { skip: usize },
+    Joliet { level: u8 },
+    Plain,
+}
+
+#[derive(Default)]
+struct RockRidge {
+    name: Option<String>,
+    symlink: Option<String>,
+    modified: Option<String>,
+    child_link: Option<u32>,
+    relocated: bool,
+}
+
+pub struct IsoFileSystem {
+    offset: u64,
+    block_size: u64,
+    tree: Tree,
+    volume_id: String,
+    system_id: String,
+    publisher: String,
+    application: String,
+    created: Option<String>,
+    modified: Option<String>,
+    volume_blocks: u32,
+    root_extent: u32,
+    root_length: u64,
+    path_table: Option<(usize, bool)>,
+    // Length of the system area and volume descriptor set, and where the path tables are.
+    descriptor_blocks: u64,
+    path_tables: Vec<Extent>,
+    boot: Result<Option<BootCatalog>, String>,
+    // Files stored in more than one extent, and interleaved files, by node.
+    multi_extents: HashMap<u64, Vec<(u32, u64)>>,
+    interleaved: HashSet<u64>,
+}
+
+impl IsoFileSystem {
+    pub fn open(disk: &mut dyn Disk, offset: u64) -> io::Result<Self> {
+        let mut primary = None;
+        let mut joliet = None;
+        let mut descriptor_end = FIRST_DESCRIPTOR;
+        for index in FIRST_DESCRIPTOR..FIRST_DESCRIPTOR + MAX_DESCRIPTORS {
+            let descriptor = disk.read_bytes(offset + index * SECTOR_SIZE, SECTOR_SIZE as usize)?;
+            if &descriptor[1..6] != b"CD001" {
+                break;
+            }
+            descriptor_end = index + 1;
+            if descriptor[0] == DESCRIPTOR_TERMINATOR {
+                break;
+            }
+            match descriptor[0] {
+                DESCRIPTOR_PRIMARY if primary.is_none() => primary = Some(descriptor),
+                DESCRIPTOR_SUPPLEMENTARY if joliet.is_none() && joliet_level(&descriptor).is_some() => joliet = Some(descriptor),
+                _ => {}
+            }
+        }
+        let primary = primary.ok_or_else(|| invalid_data("no ISO 9660 primary volume descriptor"))?;
+        let block_size = read_u16(&primary, 128) as u64;
+        if !matches!(block_size, 512 | 1024 | 2048) {
+            return Err(invalid_data(format!("unsupported ISO 9660 block size {}", block_size)));
+        }
+        let mut filesystem = Self {
+            offset,
+            block_size,
+            tree: Tree::Plain,
+            volume_id: text(&primary[40..72]),
+            system_id: text(&primary[8..40]),
+            publisher: text(&primary[318..446]),
+            application: text(&primary[574..702]),
+            created: format_descriptor_date(&primary[813..830]),
+            modified: format_descriptor_date(&primary[830..847]),
+            volume_blocks: read_u32(&primary, 80),
+            root_extent: read_u32(&primary, 158),
+            root_length: read_u32(&primary, 166) as u64,
+            path_table: None,
+            descriptor_blocks: (descriptor_end * SECTOR_SIZE).div_ceil(block_size),
+            path_tables: Vec::new(),
+            boot: BootCatalog::find(disk, offset).map_err(|err| err.to_string()),
+            multi_extents: HashMap::new(),
+            interleaved: HashSet::new(),
+        };
+        // Rock Ridge announces itself with a SUSP "SP" entry in the root's "." record,
+        // and is preferred over Joliet since it keeps POSIX names and symlinks.
+        let root = disk.read_bytes(offset + filesystem.root_extent as u64 * block_size, block_size as usize)?;
+        let dot_length = root[0] as usize;
+        if dot_length >= 41 && &root[34..36] == b"SP" && root[38..40] == [0xBE, 0xEF] {
+            filesystem.tree = Tree::RockRidge { skip: root[40] as usize };
+        } else if let Some(joliet) = &joliet {
+            filesystem.tree = Tree::Joliet { level: joliet_level(joliet).unwrap_or(1) };
+            filesystem.volume_id = String::from_utf16_lossy(&utf16_be(&joliet[40..72])).trim_end_matches([' ', '\0']).to_string();
+            filesystem.root_extent = read_u32(joliet, 158);
+            filesystem.root_length = read_u32(joliet, 166) as u64;
+        }
+        // Type L and M tables, each with an optional copy.
+        for descriptor in [Some(&primary), joliet.as_ref()].into_iter().flatten() {
+            let blocks = (read_u32(descriptor, 132) as u64).div_ceil(block_size);
+            for location in [140, 144, 148, 152] {
+                match read_u32(descriptor, location) {
+                    0 => {}
+                    location => push_extent(&mut filesystem.path_tables, location as u64, blocks),
+                }
+            }
+        }
+        let descriptor = match (&filesystem.tree, &joliet) {
+            (Tree::Joliet { .. }, Some(joliet)) => joliet,
+            _ => &primary,
+        };
+        filesystem.path_table = filesystem.read_path_table(disk, read_u32(descriptor, 140), read_u32(descriptor, 132)).ok();
+        Ok(filesystem)
+    }
+
+    // Counts the directories in the L path table and checks that its first entry is the root.
+    fn read_path_table(&self, disk: &mut dyn Disk, location: u32, size: u32) -> io::Result<(usize, bool)> {
+        let table = disk.read_bytes(self.offset + location as u64 * self.block_size, size.min(MAX_PATH_TABLE) as usize)?;
+        let mut directories = 0;
+        let mut root_matches = false;
+        let mut position = 0;
+        while position + 8 <= table.len() && table[position] != 0 {
+            let name_length = table[position] as usize;
+            if directories == 0 {
+                root_matches = read_u32(&table, position + 2) == self.root_extent;
+            }
+            directories += 1;
+            position += 8 + name_length + name_length % 2;
+        }
+        Ok((directories, root_matches))
+    }
+
+    // Directory records never cross a sector; a zero length byte pads to the next one.
+    fn directory_records(&self, disk: &mut dyn Disk, extent: u32, length: u64) -> io::Result<Vec<Vec<u8>>> {
+        let volume_size = self.volume_blocks as u64 * self.block_size;
+        if extent as u64 * self.block_size + length > volume_size {
+            return Err(invalid_data(format!("directory at block {} ({} bytes) extends past the end of the volume", extent, length)));
+        }
+        let bytes = disk.read_bytes(self.offset + extent as u64 * self.block_size, length as usize)?;
+        let mut records = Vec::new();
+        let mut position = 0;
+        while position < bytes.len() {
+            let record_length = bytes[position] as usize;
+            let sector_end = (position / SECTOR_SIZE as usize + 1) * SECTOR_SIZE as usize;
+            if record_length < 34 || position + record_length > sector_end.min(bytes.len()) {
+                position = sector_end;
+                continue;
+            }
+            let record = &bytes[position..position + record_length];
+            if 33 + record[32] as usize <= record_length {
+                records.push(record.to_vec());
+            }
+            position += record_length;
+        }
+        Ok(records)
+    }
+
+    // Length of a relocated directory, from the "." record at its start.
+    fn directory_length(&self, disk: &mut dyn Disk, extent: u32) -> io::Result<u64> {
+        let record = disk.read_bytes(self.offset + extent as u64 * self.block_size, 34)?;
+        Ok(read_u32(&record, 10) as u64)
+    }
+
+    fn rock_ridge(&self, disk: &mut dyn Disk, record: &[u8], skip: usize) -> io::Result<RockRidge> {
+        let name_length = record[32] as usize;
+        let start = 33 + name_length + (1 - name_length % 2) + skip;
+        let mut area = record.get(start..).unwrap_or(&[]).to_vec();
+        let mut result = RockRidge::default();
+        let mut name = None::<String>;
+        let mut components = Vec::<String>::new();
+        let mut component_continues = false;
+        for _ in 0..MAX_CONTINUATIONS {
+            let mut continuation = None;
+            let mut position = 0;
+            while position + 4 <= area.len() {
+                let length = area[position + 2] as usize;
+                if length < 4 || position + length > area.len() {
+                    break;
+                }
+                let entry = &area[position..position + length];
+                match &entry[..2] {
+                    b"NM" if length >= 5 && entry[4] & 0x06 == 0 => {
+                        name.get_or_insert_with(String::new).push_str(&String::from_utf8_lossy(&entry[5..]));
+                    }
+                    b"SL" if length >= 5 => {
+                        let mut component = 5;
+                        while component + 2 <= entry.len() {
+                            let flags = entry[component];
+                            let content = entry.get(component + 2..component + 2 + entry[component + 1] as usize).unwrap_or(&[]);
+                            let part = match flags {
+                                _ if flags & 0x02 != 0 => ".".to_string(),
+                                _ if flags & 0x04 != 0 => "..".to_string(),
+                                _ if flags & 0x08 != 0 => String::new(),
+                                _ => String::from_utf8_lossy(content).to_string(),
+                            };
+                            match components.last_mut() {
+                                Some(last) if component_continues => last.push_str(&part),
+                                _ => components.push(part),
+                            }
+                            component_continues = flags & 0x01 != 0;
+                            component += 2 + content.len();
+                        }
+                    }
+                    b"TF" if length >= 5 && entry[4] & 0x02 != 0 => {
+                        let flags = entry[4];
+                        let size = if flags & 0x80 != 0 { 17 } else { 7 };
+                        let date = entry.get(5 + (flags & 0x01) as usize * size..).unwrap_or(&[]);
+                        result.modified = match size {
+                            17 if date.len() >= 17 => format_descriptor_date(date),
+                            _ => format_recording_date(date),
+                        };
+                    }
+                    b"CL" if length >= 12 => result.child_link = Some(read_u32(entry, 4)),
+                    b"RE" => result.relocated = true,
+                    b"CE" if length >= 28 => continuation = Some((read_u32(entry, 4), read_u32(entry, 12), read_u32(entry, 20))),
+                    b"ST" => break,
+                    _ => {}
+                }
+                position += length;
+            }
+            let Some((block, offset, length)) = continuation else {
+                break;
+            };
+            let start = self.offset + block as u64 * self.block_size + offset as u64;
+            area = disk.read_bytes(start, (length as u64).min(SECTOR_SIZE) as usize)?;
+        }
+        result.name = name;
+        if !components.is_empty() {
+            let target = components.join("/");
+            result.symlink = Some(if target.is_empty() { "/".to_string() } else { target });
+        }
+        Ok(result)
+    }
+
+    fn record_name(&self, record: &[u8]) -> String {
+        let raw = &record[33..33 + record[32] as usize];
+        let mut name = match self.tree {
+            Tree::Joliet { .. } => String::from_utf16_lossy(&utf16_be(raw)),
+            _ => raw.iter().map(|byte| *byte as char).collect(),
+        };
+        if let Some(version) = name.rfind(';') {
+            name.truncate(version);
+        }
+        if record[25] & FLAG_DIRECTORY == 0 && !matches!(self.tree, Tree::Joliet { .. }) {
+            name.truncate(name.trim_end_matches('.').len());
+        }
+        name
+    }
+
+    fn catalog(&self) -> io::Result<&BootCatalog> {
+        self.boot.as_ref().ok().and_then(Option::as_ref).ok_or_else(|| invalid_data("no El Torito boot catalog"))
+    }
+
+    fn extents(&self, entry: &FileEntry) -> Vec<(u32, u64)> {
+        match self.multi_extents.get(&entry.node) {
+            Some(extents) => extents.clone(),
+            None => vec![(entry.node as u32, entry.size)],
+        }
+    }
+}
+
+fn utf16_be(bytes: &[u8]) -> Vec<u16> {
+    bytes.chunks_exact(2).map(|unit| u16::from_be_bytes([unit[0], unit[1]])).collect()
+}
+
+impl FileSystem for IsoFileSystem {
+    fn name(&self) -> &'static str {
+        "ISO 9660"
+    }
+
+    fn details(&self) -> Vec<(String, String)> {
+        let tree = match self.tree {
+            Tree::RockRidge { .. } => "Rock Ridge".to_string(),
+            Tree::Joliet { level } => format!("Joliet level {}", level),
+            Tree::Plain => "ISO 9660 names".to_string(),
+        };
+        let mut details = vec![
+            ("Type".to_string(), "ISO 9660".to_string()),
+            ("Volume identifier".to_string(), self.volume_id.clone()),
+            ("System identifier".to_string(), self.system_id.clone()),
+            ("Directory tree".to_string(), tree),
+            ("Block size".to_string(), format!("{} bytes", self.block_size)),
+            ("Volume size".to_string(), format!("{} blocks", self.volume_blocks)),
+        ];
+        for (label, value) in [("Publisher", &self.publisher), ("Application", &self.application)] {
+            if !value.is_empty() {
+                details.push((label.to_string(), value.clone()));
+            }
+        }
+        for (label, value) in [("Created", &self.created), ("Modified", &self.modified)] {
+            if let Some(value) = value {
+                details.push((label.to_string(), value.clone()));
+            }
+        }
+        if let Some((directories, root_matches)) = self.path_table {
+            let status = if root_matches { "" } else { ", first entry is not the root directory" };
+            details.push(("Path table".to_string(), format!("{} directories{}", directories, status)));
+        }
+        match &self.boot {
+            Ok(Some(catalog)) => details.push(("El Torito".to_string(), catalog.describe())),
+            Ok(None) => {}
+            Err(err) => details.push(("El Torito".to_string(), err.clone())),
+        }
+        details
+    }
+
+    fn cluster_size(&self) -> u64 {
+        self.block_size
+    }
+
+    fn root(&self) -> FileEntry {
+        FileEntry {
+            name: String::new(),
+            path: "/".to_string(),
+            is_dir: true,
+            size: self.root_length,
+            modified: None,
+            node: self.root_extent as u64,
+            stream: None,
+            symlink: None,
+        }
+    }
+
+    fn read_dir(&mut self, disk: &mut dyn Disk, dir: &FileEntry) -> io::Result<Vec<FileEntry>> {
+        if BootCatalog::is_boot_node(dir.node) {
+            return Ok(self.catalog()?.entries());
+        }
+        let mut files: Vec<FileEntry> = Vec::new();
+        let mut multi_extent: Option<Vec<(u32, u64)>> = None;
+        for record in self.directory_records(disk, dir.node as u32, dir.size)? {
+            let flags = record[25];
+            if record[32] == 1 && matches!(record[33], 0 | 1) || flags & FLAG_ASSOCIATED != 0 {
+                continue;
+            }
+            let rock = match self.tree {
+                Tree::RockRidge { skip } => self.rock_ridge(disk, &record, skip)?,
+                _ => RockRidge::default(),
+            };
+            if rock.relocated {
+                continue;
+            }
+            let name = rock.name.unwrap_or_else(|| self.record_name(&record));
+            // Data starts after the extended attribute record, if any.
+            let mut extent = read_u32(&record, 2)
+                .checked_add(record[1] as u32)
+                .ok_or_else(|| invalid_data(format!("extent of {} is out of range", name)))?;
+            let mut length = read_u32(&record, 10) as u64;
+            if let Some(child) = rock.child_link {
+                extent = child;
+                length = self.directory_length(disk, child)?;
+            }
+            // Later sections of a multi-extent file repeat its name.
+            if let Some(extents) = multi_extent.as_mut() {
+                if files.last().is_some_and(|last| last.name == name) {
+                    extents.push((extent, length));
+                    let last = files.last_mut().unwrap();
+                    last.size += length;
+                    if flags & FLAG_MULTI_EXTENT == 0 {
+                        self.multi_extents.insert(last.node, multi_extent.take().unwrap());
+                    }
+                    continue;
+                }
+                multi_extent = None;
+            }
+            if flags & FLAG_MULTI_EXTENT != 0 {
+                multi_extent = Some(vec![(extent, length)]);
+            }
+            if record[26] != 0 {
+                self.interleaved.insert(extent as u64);
+            }
+            files.push(FileEntry {
+                path: format!("{}/{}", dir.path.trim_end_matches('/'), name),
+                name,
+                is_dir: flags & FLAG_DIRECTORY != 0 || rock.child_link.is_some(),
+                size: length,
+                modified: rock.modified.or_else(|| format_recording_date(&record[18..25])),
+                node: extent as u64,
+                stream: None,
+                symlink: rock.symlink,
+            });
+        }
+        if dir.path == "/" {
+            if let Ok(Some(catalog)) = &self.boot {
+                files.push(catalog.directory());
+            }
+        }
+        Ok(files)
+    }
+
+    fn read_file(&mut self, disk: &mut dyn Disk, file: &FileEntry, out: &mut dyn Write) -> io::Result<()> {
+        if BootCatalog::is_boot_node(file.node) {
+            return self.catalog()?.read_image(disk, self.offset, file.node, out);
+        }
+        if self.interleaved.contains(&file.node) {
+            return Err(io::Error::new(io::ErrorKind::Unsupported, "interleaved ISO 9660 files are not supported"));
+        }
+        for (extent, length) in self.extents(file) {
+            copy_range(disk, self.offset + extent as u64 * self.block_size, length, out)?;
+        }
+        Ok(())
+    }
+
+    fn allocation(&mut self, _disk: &mut dyn Disk, entry: &FileEntry) -> io::Result<Vec<Extent>> {
+        if BootCatalog::is_boot_node(entry.node) {
+            return self.catalog()?.allocation(entry.node);
+        }
+        let mut extents: Vec<Extent> = Vec::new();
+        for (extent, length) in self.extents(entry) {
+            let count = length.div_ceil(self.block_size);
+            match extents.last_mut() {
+                Some(last) if last.start + last.count == extent as u64 => last.count += count,
+                _ if count > 0 => extents.push(Extent {
+                    start: extent as u64,
+                    count,
+                }),
+                _ => {}
+            }
+        }
+        Ok(extents)
+    }
+
+    // Mastered discs have no free space; every block of the volume counts as used.
+    fn cluster_bitmap(&mut self, _disk: &mut dyn Disk) -> io::Result<ClusterBitmap> {
+        let count = self.volume_blocks as u64;
+        Ok(ClusterBitmap::new(0, count, vec![0xFF; count.div_ceil(8) as usize]))
+    }
+
+    fn cluster_position(&self, cluster: u64) -> u64 {
+        self.offset + cluster * self.block_size
+    }
+
+    fn reserved_areas(&mut self, _disk: &mut dyn Disk) -> io::Result<Vec<ReservedArea>> {
+        let mut areas = vec![
+            ReservedArea {
+                name: "System area and volume descriptors".to_string(),
+                bad: false,
+                extents: vec![Extent {
+                    start: 0,
+                    count: self.descriptor_blocks,
+                }],
+            },
+            ReservedArea {
+                name: "Path tables".to_string(),
+                bad: false,
+                extents: self.path_tables.clone(),
+            },
+        ];
+        if let Ok(catalog) = self.catalog() {
+            areas.push(ReservedArea {
+                name: "El Torito boot catalog".to_string(),
+                bad: false,
+                extents: vec![Extent {
+                    start: catalog.sector as u64,
+                    count: 1,
+                }],
+            });
+        }
+        Ok(areas)
+    }
+
+    fn deleted_files(&mut self, _disk: &mut dyn Disk) -> io::Result<Vec<FileEntry>> {
+        Err(io::Error::new(io::ErrorKind::Unsupported, "deleted files can only be recovered from FAT and NTFS volumes"))
+    }
+}
@@ -0,0 +1,451 @@
+use crate::disk::Disk;
+use crate::partition_table::{read_partition_table, Partition};
+use std::collections::HashSet;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+pub mod cluster_map;
+pub mod exfat;
+pub mod ext;
+pub mod fat;
+pub mod fragmentation;
+pub mod iso9660;
+pub mod ntfs;
+pub mod recovery;
+pub mod udf;
+
+#[derive(Clone, Debug)]
+pub struct Volume {
+    pub partition: Partition,
+    pub filesystem: Option<&'static str>,
+}
+
+impl Volume {
+    pub fn describe(&self) -> String {
+        match self.filesystem {
+            Some(filesystem) => format!("{} [{}]", self.partition.label, filesystem),
+            None => format!("{} [{}]", self.partition.label, self.partition.type_name),
+        }
+    }
+}
+
+fn matches_at(boot: &[u8], offset: usize, signature: &[u8]) -> bool {
+    boot.get(offset..offset + signature.len()) == Some(signature)
+}
+
+fn detect_fat(boot: &[u8]) -> Option<&'static str> {
+    let bytes_per_sector = u16::from_le_bytes([boot[11], boot[12]]) as u32;
+    let sectors_per_cluster = boot[13] as u32;
+    let reserved_sectors = u16::from_le_bytes([boot[14], boot[15]]) as u32;
+    let fat_count = boot[16] as u32;
+    let root_entries = u16::from_le_bytes([boot[17], boot[18]]) as u32;
+    if !bytes_per_sector.is_power_of_two()
+        || !(512..=4096).contains(&bytes_per_sector)
+        || !sectors_per_cluster.is_power_of_two()
+        || reserved_sectors == 0
+        || fat_count == 0
+        || boot[510] != 0x55
+        || boot[511] != 0xAA
+    {
+        return None;
+    }
+    let total_sectors = match u16::from_le_bytes([boot[19], boot[20]]) as u32 {
+        0 => u32::from_le_bytes([boot[32], boot[33], boot[34], boot[35]]),
+        sectors => sectors,
+    };
+    let fat_size = match u16::from_le_bytes([boot[22], boot[23]]) as u32 {
+        0 => u32::from_le_bytes([boot[36], boot[37], boot[38], boot[39]]),
+        sectors => sectors,
+    };
+    let root_sectors = (root_entries * 32).div_ceil(bytes_per_sector);
+    let metadata_sectors = fat_count.checked_mul(fat_size)?.checked_add(reserved_sectors)?.checked_add(root_sectors)?;
+    let data_sectors = total_sectors.checked_sub(metadata_sectors)?;
+    // The FAT type is decided by the cluster count alone, as in the Microsoft specification.
+    match data_sectors / sectors_per_cluster {
+        0..=4084 => Some("FAT12"),
+        4085..=65524 => Some("FAT16"),
+        _ => Some("FAT32"),
+    }
+}
+
+pub fn detect_filesystem(disk: &mut dyn Disk, offset: u64) -> Option<&'static str> {
+    let boot = disk.read_bytes(offset, 4096).ok()?;
+    if matches_at(&boot, 3, b"NTFS    ") {
+        return Some("NTFS");
+    }
+    if matches_at(&boot, 3, b"EXFAT   ") {
+        return Some("exFAT");
+    }
+    if matches_at(&boot, 3, b"-FVE-FS-") {
+        return Some("BitLocker");
+    }
+    if matches_at(&boot, 3, b"ReFS") {
+        return Some("ReFS");
+    }
+    if matches_at(&boot, 0, b"XFSB") {
+        return Some("XFS");
+    }
+    if matches_at(&boot, 1080, &[0x53, 0xEF]) {
+        let compat = u32::from_le_bytes(boot[1116..1120].try_into().unwrap());
+        let incompat = u32::from_le_bytes(boot[1120..1124].try_into().unwrap());
+        return Some(if incompat & 0x2C0 != 0 {
+            "ext4"
+        } else if compat & 0x4 != 0 {
+            "ext3"
+        } else {
+            "ext2"
+        });
+    }
+    if matches_at(&boot, 1024, b"H+") || matches_at(&boot, 1024, b"HX") {
+        return Some("HFS+");
+    }
+    if matches_at(&boot, 32, b"NXSB") {
+        return Some("APFS");
+    }
+    if matches_at(&boot, 4086, b"SWAPSPACE2") {
+        return Some("Linux swap");
+    }
+    if let Some(fat) = detect_fat(&boot) {
+        return Some(fat);
+    }
+    if disk.read_bytes(offset + 0x10040, 8).is_ok_and(|magic| magic == b"_BHRfS_M") {
+        return Some("Btrfs");
+    }
+    // UDF bridge discs also carry an ISO 9660 tree; the UDF one is complete on DVDs and
+    // Windows media, so it wins.
+    let udf = (0..16).any(|index| {
+        disk.read_bytes(offset + 32769 + index * 2048, 5).is_ok_and(|magic| magic == b"NSR02" || magic == b"NSR03")
+    });
+    if udf {
+        return Some("UDF");
+    }
+    if disk.read_bytes(offset + 32769, 5).is_ok_and(|magic| magic == b"CD001") {
+        return Some("ISO 9660");
+    }
+    None
+}
+
+// A disk without a GPT but with a recognisable filesystem at offset 0 (a volume
+// image or an optical disc) is shown as a single volume; FAT boot sectors carry
+// the same 0x55AA signature as an MBR.
+pub fn list_volumes(disk: &mut dyn Disk) -> io::Result<Vec<Volume>> {
+    let table = read_partition_table(disk)?;
+    let whole_disk = if table.has_gpt() { None } else { detect_filesystem(disk, 0) };
+    let partitions = match whole_disk {
+        Some(filesystem) => vec![Partition {
+            start: 0,
+            size: disk.size(),
+            type_name: filesystem.to_string(),
+            label: "Whole disk".to_string(),
+        }],
+        None => table.partitions(),
+    };
+    Ok(partitions
+        .into_iter()
+        .map(|partition| Volume {
+            filesystem: detect_filesystem(disk, partition.start),
+            partition,
+        })
+        .collect())
+}
+
+#[derive(Clone, Debug)]
+pub struct FileEntry {
+    pub name: String,
+    pub path: String,
+    pub is_dir: bool,
+    pub size: u64,
+    pub modified: Option<String>,
+    // Filesystem specific handle: first cluster, MFT record or inode number.
+    pub node: u64,
+    // Named data stream of `node` (an NTFS alternate data stream); None for the main data.
+    pub stream: Option<String>,
+    // Target of a symbolic link.
+    pub symlink: Option<String>,
+}
+
+// A run of consecutive allocation units (clusters or blocks).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Extent {
+    pub start: u64,
+    pub count: u64,
+}
+
+// Appends a run of units to an extent list, merging it with the previous run when contiguous.
+pub fn push_extent(extents: &mut Vec<Extent>, start: u64, count: u64) {
+    match extents.last_mut() {
+        Some(extent) if extent.start + extent.count == start => extent.count += count,
+        _ if count > 0 => extents.push(Extent { start, count }),
+        _ => {}
+    }
+}
+
+// Units the filesystem keeps for itself rather than for a file, such as the MFT or
+// ext inode tables, or units it has marked bad.
+pub struct ReservedArea {
+    pub name: String,
+    pub bad: bool,
+    pub extents: Vec<Extent>,
+}
+
+// One bit per allocation unit, set when the unit is in use, as the filesystem's own
+// allocation structures record it. Bit 0 is unit `first`: FAT numbers its clusters
+// from 2 and ext its blocks from s_first_data_block.
+pub struct ClusterBitmap {
+    pub first: u64,
+    pub count: u64,
+    bits: Vec<u8>,
+}
+
+impl ClusterBitmap {
+    pub fn new(first: u64, count: u64, mut bits: Vec<u8>) -> Self {
+        bits.resize(count.div_ceil(8) as usize, 0);
+        // Bitmaps are stored in whole bytes or blocks; bits past the last unit are not units.
+        if !count.is_multiple_of(8) {
+            bits[(count / 8) as usize] &= (1u8 << (count % 8)) - 1;
+        }
+        Self { first, count, bits }
+    }
+
+    pub fn set(&mut self, index: u64) {
+        if index < self.count {
+            self.bits[(index / 8) as usize] |= 1 << (index % 8);
+        }
+    }
+
+    fn bit(&self, index: u64) -> bool {
+        self.bits[(index / 8) as usize] & (1 << (index % 8)) != 0
+    }
+
+    pub fn used(&self) -> u64 {
+        self.bits.iter().map(|byte| byte.count_ones() as u64).sum()
+    }
+
+    // Runs of free units, in unit numbers.
+    pub fn free_extents(&self) -> Vec<Extent> {
+        let mut extents = Vec::new();
+        let mut index = 0;
+        while index < self.count {
+            // Skip whole bytes of used units; most of a busy volume is.
+            if index.is_multiple_of(8) && self.bits[(index / 8) as usize] == 0xFF {
+                index += 8;
+                continue;
+            }
+            if !self.bit(index) {
+                push_extent(&mut extents, self.first + index, 1);
+            }
+            index += 1;
+        }
+        extents
+    }
+
+    // Used units among units `start..start + count`; units outside the bitmap count as free.
+    pub fn used_in(&self, start: u64, count: u64) -> u64 {
+        let end = (start + count).saturating_sub(self.first).min(self.count);
+        let mut index = start.saturating_sub(self.first).min(end);
+        let mut used = 0;
+        while index < end && !index.is_multiple_of(8) {
+            used += self.bit(index) as u64;
+            index += 1;
+        }
+        let whole = (end - index) / 8;
+        let bytes = &self.bits[(index / 8) as usize..(index / 8 + whole) as usize];
+        used += bytes.iter().map(|byte| byte.count_ones() as u64).sum::<u64>();
+        index += whole * 8;
+        while index < end {
+            used += self.bit(index) as u64;
+            index += 1;
+        }
+        used
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct SpaceUsage {
+    pub total: u64,
+    pub used: u64,
+}
+
+impl SpaceUsage {
+    pub fn from_bitmap(bitmap: &ClusterBitmap, unit_size: u64) -> Self {
+        Self {
+            total: bitmap.count * unit_size,
+            used: bitmap.used() * unit_size,
+        }
+    }
+
+    pub fn free(&self) -> u64 {
+        self.total - self.used
+    }
+}
+
+// Read-only access to a filesystem at a fixed offset of `disk`; the disk is passed to
+// every call so that the GUI can keep owning it.
+pub trait FileSystem {
+    fn name(&self) -> &'static str;
+    fn details(&self) -> Vec<(String, String)>;
+    fn cluster_size(&self) -> u64;
+    fn root(&self) -> FileEntry;
+    fn read_dir(&mut self, disk: &mut dyn Disk, dir: &FileEntry) -> io::Result<Vec<FileEntry>>;
+    fn read_file(&mut self, disk: &mut dyn Disk, file: &FileEntry, out: &mut dyn Write) -> io::Result<()>;
+    fn allocation(&mut self, disk: &mut dyn Disk, entry: &FileEntry) -> io::Result<Vec<Extent>>;
+    fn cluster_bitmap(&mut self, disk: &mut dyn Disk) -> io::Result<ClusterBitmap>;
+    // Disk offset of a cluster numbered as in the cluster bitmap.
+    fn cluster_position(&self, cluster: u64) -> u64;
+    fn reserved_areas(&mut self, disk: &mut dyn Disk) -> io::Result<Vec<ReservedArea>>;
+    // Deleted files whose directory entry or file record is still on disk.
+    fn deleted_files(&mut self, disk: &mut dyn Disk) -> io::Result<Vec<FileEntry>>;
+}
+
+pub fn open_filesystem(disk: &mut dyn Disk, volume: &Volume) -> io::Result<Box<dyn FileSystem>> {
+    let offset = volume.partition.start;
+    match volume.filesystem {
+        Some("FAT12") | Some("FAT16") | Some("FAT32") => Ok(Box::new(fat::FatFileSystem::open(disk, offset)?)),
+        Some("NTFS") => Ok(Box::new(ntfs::NtfsFileSystem::open(disk, offset)?)),
+        Some("exFAT") => Ok(Box::new(exfat::ExfatFileSystem::open(disk, offset)?)),
+        Some("ext2") | Some("ext3") | Some("ext4") => Ok(Box::new(ext::ExtFileSystem::open(disk, offset)?)),
+        Some("ISO 9660") => Ok(Box::new(iso9660::IsoFileSystem::open(disk, offset)?)),
+        Some("UDF") => Ok(Box::new(udf::UdfFileSystem::open(disk, offset)?)),
+        Some(filesystem) => Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            format!("browsing {} volumes is not supported", filesystem),
+        )),
+        None => Err(io::Error::new(io::ErrorKind::Unsupported, "no recognised filesystem on this volume")),
+    }
+}
+
+// Visits every file and folder of the volume with its allocation, depth first; a folder
+// that cannot be listed is passed to `visit` a second time with the error.
+pub fn walk_allocations(filesystem: &mut dyn FileSystem, disk: &mut dyn Disk, visit: &mut dyn FnMut(&FileEntry, io::Result<Vec<Extent>>)) {
+    let mut pending = vec![filesystem.root()];
+    let mut visited = HashSet::new();
+    while let Some(entry) = pending.pop() {
+        visit(&entry, filesystem.allocation(disk, &entry));
+        // A corrupted volume can link a directory into its own subtree.
+        if entry.is_dir && entry.symlink.is_none() && visited.insert(entry.node) {
+            match filesystem.read_dir(disk, &entry) {
+                Ok(children) => pending.extend(children),
+                Err(err) => visit(&entry, Err(err)),
+            }
+        }
+    }
+}
+
+// Exact matches win over case-insensitive ones, so case-sensitive filesystems still
+// resolve names that differ only in case.
+pub fn find_entry(filesystem: &mut dyn FileSystem, disk: &mut dyn Disk, path: &str) -> io::Result<FileEntry> {
+    let mut entry = filesystem.root();
+    for component in path.split(['/', '\\']).filter(|component| !component.is_empty()) {
+        if !entry.is_dir {
+            return Err(io::Error::new(io::ErrorKind::NotFound, format!("{} is not a directory", entry.path)));
+        }
+        let children = filesystem.read_dir(disk, &entry)?;
+        entry = children
+            .iter()
+            .find(|child| child.name == component)
+            .or_else(|| children.iter().find(|child| child.name.eq_ignore_ascii_case(component)))
+            .cloned()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("{} not found in {}", component, entry.path)))?;
+    }
+    Ok(entry)
+}
+
+pub fn format_extents(extents: &[Extent]) -> String {
+    if extents.is_empty() {
+        return "none".to_string();
+    }
+    extents
+        .iter()
+        .map(|extent| match extent.count {
+            1 => extent.start.to_string(),
+            count => format!("{}-{}", extent.start, extent.start + count - 1),
+        })
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+// Names come from an untrusted filesystem: never let them escape the export folder
+// or use characters Windows rejects.
+fn safe_file_name(name: &str) -> String {
+    let cleaned: String = name
+        .chars()
+        .map(|c| if c.is_control() || "<>:\"/\\|?*".contains(c) { '_' } else { c })
+        .collect();
+    let trimmed = cleaned.trim_end_matches(['.', ' ']);
+    let stem = trimmed.split('.').next().unwrap_or_default().to_ascii_uppercase();
+    let reserved = matches!(stem.as_str(), "CON" | "PRN" | "AUX" | "NUL")
+        || (stem.len() == 4 && (stem.starts_with("COM") || stem.starts_with("LPT")) && stem.ends_with(|c: char| c.is_ascii_digit()));
+    if trimmed.is_empty() {
+        "_".to_string()
+    } else if reserved {
+        format!("_{}", trimmed)
+    } else {
+        trimmed.to_string()
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct ExportSummary {
+    pub files: u64,
+    pub directories: u64,
+    pub bytes: u64,
+    pub skipped_links: u64,
+}
+
+impl ExportSummary {
+    pub fn describe(&self) -> String {
+        let mut text = format!("{} file(s) and {} folder(s), {} bytes", self.files, self.directories, self.bytes);
+        if self.skipped_links > 0 {
+            text.push_str(&format!(", {} symbolic link(s) skipped", self.skipped_links));
+        }
+        text
+    }
+}
+
+// Copies a file or a whole directory tree into `destination`; existing files are
+// never overwritten. `progress` receives the path being exported.
+pub fn export(
+    filesystem: &mut dyn FileSystem,
+    disk: &mut dyn Disk,
+    entry: &FileEntry,
+    destination: &Path,
+    progress: &mut dyn FnMut(&str, &ExportSummary),
+) -> io::Result<ExportSummary> {
+    let mut summary = ExportSummary::default();
+    let name = if entry.name.is_empty() { "root".to_string() } else { safe_file_name(&entry.name) };
+    export_entry(filesystem, disk, entry, &destination.join(name), &mut summary, progress)?;
+    Ok(summary)
+}
+
+fn export_entry(
+    filesystem: &mut dyn FileSystem,
+    disk: &mut dyn Disk,
+    entry: &FileEntry,
+    target: &PathBuf,
+    summary: &mut ExportSummary,
+    progress: &mut dyn FnMut(&str, &ExportSummary),
+) -> io::Result<()> {
+    progress(&entry.path, summary);
+    let context = |err: io::Error| io::Error::new(err.kind(), format!("{}: {}", entry.path, err));
+    // Links may point anywhere on the source system; recreating them is not our business.
+    if entry.symlink.is_some() {
+        summary.skipped_links += 1;
+        return Ok(());
+    }
+    if entry.is_dir {
+        fs::create_dir_all(target).map_err(context)?;
+        summary.directories += 1;
+        for child in filesystem.read_dir(disk, entry).map_err(context)? {
+            export_entry(filesystem, disk, &child, &target.join(safe_file_name(&child.name)), summary, progress)?;
+        }
+        return Ok(());
+    }
+    let file = File::options().write(true).create_new(true).open(target).map_err(context)?;
+    let mut out = BufWriter::new(file);
+    filesystem.read_file(disk, entry, &mut out).map_err(context)?;
+    out.flush().map_err(context)?;
+    summary.files += 1;
+    summary.bytes += entry.size;
+    Ok(())
+}
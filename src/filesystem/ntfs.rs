@@ -0,0 +1,784 @@
+use crate::disk::Disk;
+use crate::filesystem::{push_extent, ClusterBitmap, Extent, FileEntry, FileSystem, ReservedArea};
+use crate::partition_table::{read_u16, read_u32, read_u64};
+use crate::vdisk::{format_unix_time, invalid_data};
+use std::collections::{HashMap, HashSet};
+use std::io::{self, Write};
+
+const MFT_RECORD: u64 = 0;
+const VOLUME_RECORD: u64 = 3;
+const ROOT_RECORD: u64 = 5;
+const BITMAP_RECORD: u64 = 6;
+const BAD_CLUSTERS_RECORD: u64 = 8;
+const BAD_CLUSTERS_STREAM: &str = "$Bad";
+const FIRST_USER_RECORD: u64 = 16;
+const ORPHAN_DIRECTORY: &str = "?";
+const UPDATE_SEQUENCE_STRIDE: usize = 512;
+const READ_CHUNK_SIZE: u64 = 1024 * 1024;
+const MAX_INDEX_DEPTH: usize = 32;
+const MAX_CLUSTER_SIZE: u64 = 2 * 1024 * 1024;
+const MAX_INDEX_BLOCK_SIZE: u64 = 64 * 1024;
+
+const ATTR_STANDARD_INFORMATION: u32 = 0x10;
+const ATTR_ATTRIBUTE_LIST: u32 = 0x20;
+const ATTR_FILE_NAME: u32 = 0x30;
+const ATTR_VOLUME_NAME: u32 = 0x60;
+const ATTR_VOLUME_INFORMATION: u32 = 0x70;
+const ATTR_DATA: u32 = 0x80;
+const ATTR_INDEX_ROOT: u32 = 0x90;
+const ATTR_INDEX_ALLOCATION: u32 = 0xA0;
+const ATTR_END: u32 = 0xFFFF_FFFF;
+
+const RECORD_IN_USE: u16 = 0x01;
+const RECORD_IS_DIRECTORY: u16 = 0x02;
+const ATTRIBUTE_COMPRESSED: u16 = 0x0001;
+const ATTRIBUTE_ENCRYPTED: u16 = 0x4000;
+const INDEX_ENTRY_SUBNODE: u16 = 0x01;
+const INDEX_ENTRY_LAST: u16 = 0x02;
+const FILE_NAME_IS_DIRECTORY: u32 = 0x1000_0000;
+const NAMESPACE_DOS: u8 = 2;
+const DIRECTORY_INDEX: &str = "$I30";
+
+// The fixed system files; record 5 is the root directory, which is browsed like any other.
+const SYSTEM_FILES: &[(u64, &str)] = &[
+    (0, "$MFT"),
+    (1, "$MFTMirr"),
+    (2, "$LogFile"),
+    (3, "$Volume"),
+    (4, "$AttrDef"),
+    (6, "$Bitmap"),
+    (7, "$Boot"),
+    (8, "$BadClus"),
+    (9, "$Secure"),
+    (10, "$UpCase"),
+    (11, "$Extend"),
+];
+
+// Seconds between the FILETIME epoch (1601-01-01) and the Unix epoch.
+const FILETIME_UNIX_OFFSET: u64 = 11_644_473_600;
+
+pub fn format_filetime(filetime: u64) -> Option<String> {
+    let seconds = (filetime / 10_000_000).checked_sub(FILETIME_UNIX_OFFSET)?;
+    Some(format_unix_time(seconds))
+}
+
+fn utf16_string(bytes: &[u8]) -> String {
+    let units: Vec<u16> = bytes.chunks_exact(2).map(|unit| u16::from_le_bytes([unit[0], unit[1]])).collect();
+    String::from_utf16_lossy(&units)
+}
+
+// Low 48 bits of a file reference; the high 16 are the sequence number.
+fn record_number(reference: u64) -> u64 {
+    reference & 0xFFFF_FFFF_FFFF
+}
+
+// Multi-sector structures store the update sequence number in the last two bytes of
+// every 512-byte stride; the bytes that belong there live in the update sequence array.
+fn apply_fixups(record: &mut [u8], magic: &[u8; 4]) -> io::Result<()> {
+    let kind = String::from_utf8_lossy(magic);
+    if record.len() < 8 || &record[0..4] != magic {
+        return Err(invalid_data(format!("missing {} signature", kind)));
+    }
+    let array = read_u16(record, 4) as usize;
+    let count = read_u16(record, 6) as usize;
+    if count == 0 || array + count * 2 > record.len() || (count - 1) * UPDATE_SEQUENCE_STRIDE > record.len() {
+        return Err(invalid_data(format!("{} record has an invalid update sequence array", kind)));
+    }
+    let sequence = [record[array], record[array + 1]];
+    for index in 1..count {
+        let end = index * UPDATE_SEQUENCE_STRIDE;
+        if record[end - 2..end] != sequence {
+            return Err(invalid_data(format!("{} record fails the update sequence check in sector {}", kind, index - 1)));
+        }
+        record[end - 2] = record[array + index * 2];
+        record[end - 1] = record[array + index * 2 + 1];
+    }
+    Ok(())
+}
+
+fn read_varint(bytes: &[u8], signed: bool) -> i64 {
+    let mut value = bytes.iter().enumerate().fold(0u64, |value, (index, byte)| value | (*byte as u64) << (8 * index));
+    if signed && bytes.len() < 8 && bytes.last().is_some_and(|byte| byte & 0x80 != 0) {
+        value |= u64::MAX << (8 * bytes.len());
+    }
+    value as i64
+}
+
+// A run without a cluster is sparse and reads as zeroes.
+#[derive(Clone, Copy, Debug)]
+pub struct DataRun {
+    pub lcn: Option<u64>,
+    pub length: u64,
+}
+
+pub fn parse_data_runs(bytes: &[u8]) -> io::Result<Vec<DataRun>> {
+    let mut runs = Vec::new();
+    let mut position = 0;
+    let mut lcn: i64 = 0;
+    while position < bytes.len() && bytes[position] != 0 {
+        let length_size = (bytes[position] & 0x0F) as usize;
+        let offset_size = (bytes[position] >> 4) as usize;
+        let start = position + 1;
+        if length_size == 0 || length_size > 8 || offset_size > 8 || start + length_size + offset_size > bytes.len() {
+            return Err(invalid_data(format!("malformed data run at offset {}", position)));
+        }
+        let length = read_varint(&bytes[start..start + length_size], false) as u64;
+        let run_lcn = if offset_size == 0 {
+            None
+        } else {
+            let delta = read_varint(&bytes[start + length_size..start + length_size + offset_size], true);
+            lcn = lcn.checked_add(delta).filter(|lcn| *lcn >= 0).ok_or_else(|| invalid_data("data run points before the start of the volume"))?;
+            Some(lcn as u64)
+        };
+        runs.push(DataRun { lcn: run_lcn, length });
+        position = start + length_size + offset_size;
+    }
+    Ok(runs)
+}
+
+#[derive(Clone, Debug)]
+pub struct Attribute {
+    pub kind: u32,
+    pub name: String,
+    pub flags: u16,
+    pub resident: Option<Vec<u8>>,
+    pub start_vcn: u64,
+    pub runs: Vec<DataRun>,
+    pub data_size: u64,
+    pub initialized_size: u64,
+}
+
+impl Attribute {
+    pub fn size(&self) -> u64 {
+        match &self.resident {
+            Some(value) => value.len() as u64,
+            None => self.data_size,
+        }
+    }
+}
+
+fn parse_attribute(bytes: &[u8]) -> io::Result<Attribute> {
+    let kind = read_u32(bytes, 0);
+    let malformed = || invalid_data(format!("malformed attribute 0x{:X}", kind));
+    let name_length = bytes[9] as usize;
+    let name_offset = read_u16(bytes, 10) as usize;
+    let name = utf16_string(bytes.get(name_offset..name_offset + name_length * 2).ok_or_else(malformed)?);
+    let flags = read_u16(bytes, 12);
+    if bytes[8] == 0 {
+        if bytes.len() < 24 {
+            return Err(malformed());
+        }
+        let value_length = read_u32(bytes, 16) as usize;
+        let value_offset = read_u16(bytes, 20) as usize;
+        let value = bytes.get(value_offset..value_offset + value_length).ok_or_else(malformed)?.to_vec();
+        return Ok(Attribute {
+            kind,
+            name,
+            flags,
+            data_size: value.len() as u64,
+            initialized_size: value.len() as u64,
+            resident: Some(value),
+            start_vcn: 0,
+            runs: Vec::new(),
+        });
+    }
+    if bytes.len() < 64 {
+        return Err(malformed());
+    }
+    let runs_offset = read_u16(bytes, 32) as usize;
+    Ok(Attribute {
+        kind,
+        name,
+        flags,
+        resident: None,
+        start_vcn: read_u64(bytes, 16),
+        runs: parse_data_runs(bytes.get(runs_offset..).ok_or_else(malformed)?)?,
+        data_size: read_u64(bytes, 48),
+        initialized_size: read_u64(bytes, 56),
+    })
+}
+
+fn parse_attributes(record: &[u8]) -> io::Result<Vec<Attribute>> {
+    let mut attributes = Vec::new();
+    let used = (read_u32(record, 24) as usize).min(record.len());
+    let mut position = read_u16(record, 20) as usize;
+    while position + 16 <= used {
+        let kind = read_u32(record, position);
+        if kind == ATTR_END {
+            break;
+        }
+        let length = read_u32(record, position + 4) as usize;
+        if length < 16 || position + length > used {
+            return Err(invalid_data(format!("attribute 0x{:X} at offset {} overruns the record", kind, position)));
+        }
+        attributes.push(parse_attribute(&record[position..position + length])?);
+        position += length;
+    }
+    Ok(attributes)
+}
+
+// Non-resident attributes that did not fit one record are split by VCN across
+// extension records; glue the run lists back onto the first piece.
+fn merge_fragments(mut attributes: Vec<Attribute>) -> Vec<Attribute> {
+    attributes.sort_by_key(|attribute| attribute.start_vcn);
+    let mut merged: Vec<Attribute> = Vec::new();
+    for attribute in attributes {
+        if attribute.start_vcn > 0 {
+            if let Some(first) = merged
+                .iter_mut()
+                .find(|first| first.kind == attribute.kind && first.name == attribute.name && first.resident.is_none())
+            {
+                first.runs.extend(attribute.runs);
+                continue;
+            }
+        }
+        merged.push(attribute);
+    }
+    merged
+}
+
+pub struct FileRecord {
+    pub number: u64,
+    pub sequence: u16,
+    pub flags: u16,
+    pub attributes: Vec<Attribute>,
+}
+
+impl FileRecord {
+    pub fn in_use(&self) -> bool {
+        self.flags & RECORD_IN_USE != 0
+    }
+
+    pub fn is_directory(&self) -> bool {
+        self.flags & RECORD_IS_DIRECTORY != 0
+    }
+
+    pub fn attribute(&self, kind: u32, name: &str) -> Option<&Attribute> {
+        self.attributes.iter().find(|attribute| attribute.kind == kind && attribute.name == name)
+    }
+
+    pub fn modified(&self) -> Option<String> {
+        let info = self.attribute(ATTR_STANDARD_INFORMATION, "")?.resident.as_ref()?;
+        format_filetime(read_u64(info.get(0..16)?, 8))
+    }
+
+    // The record's own name and parent directory, preferring the long name to the DOS one.
+    fn file_name(&self) -> Option<(u64, String)> {
+        let parsed: Vec<(u64, IndexEntry)> = self
+            .attributes
+            .iter()
+            .filter(|attribute| attribute.kind == ATTR_FILE_NAME)
+            .filter_map(|attribute| attribute.resident.as_ref())
+            .filter_map(|value| Some((record_number(read_u64(value.get(0..8)?, 0)), parse_index_key(0, value)?)))
+            .collect();
+        let (parent, entry) = parsed.iter().find(|(_, entry)| entry.namespace != NAMESPACE_DOS).or(parsed.first())?;
+        Some((*parent, entry.name.clone()))
+    }
+}
+
+struct IndexEntry {
+    reference: u64,
+    name: String,
+    namespace: u8,
+    size: u64,
+    modified: u64,
+    is_dir: bool,
+}
+
+fn parse_index_key(reference: u64, key: &[u8]) -> Option<IndexEntry> {
+    let name_length = *key.get(64)? as usize;
+    let name = utf16_string(key.get(66..66 + name_length * 2)?);
+    Some(IndexEntry {
+        reference,
+        name,
+        namespace: key[65],
+        size: read_u64(key, 48),
+        modified: read_u64(key, 16),
+        is_dir: read_u32(key, 56) & FILE_NAME_IS_DIRECTORY != 0,
+    })
+}
+
+pub struct NtfsFileSystem {
+    offset: u64,
+    oem_name: String,
+    bytes_per_sector: u64,
+    cluster_size: u64,
+    total_sectors: u64,
+    mft_cluster: u64,
+    mft_mirror_cluster: u64,
+    record_size: u64,
+    index_record_size: u64,
+    serial: u64,
+    label: String,
+    version: Option<(u8, u8)>,
+    mft_runs: Vec<DataRun>,
+    mft_size: u64,
+}
+
+impl NtfsFileSystem {
+    pub fn open(disk: &mut dyn Disk, offset: u64) -> io::Result<Self> {
+        let boot = disk.read_bytes(offset, 512)?;
+        if &boot[3..11] != b"NTFS    " {
+            return Err(invalid_data("missing NTFS boot sector signature"));
+        }
+        let bytes_per_sector = read_u16(&boot, 11) as u64;
+        // Larger clusters store sectors per cluster as a negative power of two.
+        let cluster_size = match boot[13] {
+            sectors @ 0..=0x80 => Some(sectors as u64 * bytes_per_sector),
+            exponent => bytes_per_sector.checked_shl(256 - exponent as u32),
+        };
+        let cluster_size = cluster_size
+            .filter(|size| size.is_power_of_two() && (bytes_per_sector..=MAX_CLUSTER_SIZE).contains(size))
+            .filter(|_| bytes_per_sector.is_power_of_two() && (512..=4096).contains(&bytes_per_sector))
+            .ok_or_else(|| invalid_data("invalid NTFS BIOS parameter block"))?;
+        let structure_size = |value: u8| match value as i8 {
+            clusters @ 1.. => Some(clusters as u64 * cluster_size),
+            exponent @ -31..=-1 => Some(1u64 << -exponent),
+            _ => None,
+        };
+        let record_size = structure_size(boot[64]).filter(|size| *size >= 512).ok_or_else(|| invalid_data("invalid MFT record size"))?;
+        let index_record_size = structure_size(boot[68]).ok_or_else(|| invalid_data("invalid index record size"))?;
+        let mft_cluster = read_u64(&boot, 48);
+        let mut filesystem = Self {
+            offset,
+            oem_name: String::from_utf8_lossy(&boot[3..11]).trim_end().to_string(),
+            bytes_per_sector,
+            cluster_size,
+            total_sectors: read_u64(&boot, 40),
+            mft_cluster,
+            mft_mirror_cluster: read_u64(&boot, 56),
+            record_size,
+            index_record_size,
+            serial: read_u64(&boot, 72),
+            label: String::new(),
+            version: None,
+            // Enough of $MFT to read its own first record; replaced by the real runs below.
+            mft_runs: vec![DataRun {
+                lcn: Some(mft_cluster),
+                length: record_size.div_ceil(cluster_size),
+            }],
+            mft_size: record_size,
+        };
+        let mft = filesystem.file_record(disk, MFT_RECORD)?;
+        let data = mft.attribute(ATTR_DATA, "").ok_or_else(|| invalid_data("$MFT has no data attribute"))?;
+        filesystem.mft_runs = data.runs.clone();
+        filesystem.mft_size = data.data_size;
+        if let Ok(volume) = filesystem.file_record(disk, VOLUME_RECORD) {
+            if let Some(name) = volume.attribute(ATTR_VOLUME_NAME, "").and_then(|name| name.resident.as_ref()) {
+                filesystem.label = utf16_string(name);
+            }
+            if let Some(info) = volume.attribute(ATTR_VOLUME_INFORMATION, "").and_then(|info| info.resident.as_ref()) {
+                filesystem.version = info.get(8..10).map(|version| (version[0], version[1]));
+            }
+        }
+        Ok(filesystem)
+    }
+
+    fn volume_size(&self) -> u64 {
+        self.total_sectors.saturating_mul(self.bytes_per_sector)
+    }
+
+    // Reads `len` bytes at byte `position` of a non-resident stream. Runs and sizes come
+    // from possibly corrupt records, so nothing larger than the volume is allocated.
+    fn read_runs(&self, disk: &mut dyn Disk, runs: &[DataRun], position: u64, len: usize) -> io::Result<Vec<u8>> {
+        if len as u64 > self.volume_size() {
+            return Err(invalid_data(format!("stream of {} bytes is larger than the volume", len)));
+        }
+        let mut out = vec![0u8; len];
+        let mut done = 0;
+        let mut run_start = 0u64;
+        for run in runs {
+            if done == len {
+                break;
+            }
+            let run_end = run
+                .length
+                .checked_mul(self.cluster_size)
+                .and_then(|run_bytes| run_start.checked_add(run_bytes))
+                .ok_or_else(|| invalid_data("data run length overflows"))?;
+            let wanted = position + done as u64;
+            if (run_start..run_end).contains(&wanted) {
+                let piece = ((run_end - wanted) as usize).min(len - done);
+                if let Some(lcn) = run.lcn {
+                    let disk_offset = lcn
+                        .checked_mul(self.cluster_size)
+                        .filter(|start| *start < self.volume_size())
+                        .ok_or_else(|| invalid_data(format!("data run at cluster {} lies outside the volume", lcn)))?;
+                    disk.read_at(self.offset + disk_offset + wanted - run_start, &mut out[done..done + piece])?;
+                }
+                done += piece;
+            }
+            run_start = run_end;
+        }
+        if done < len {
+            return Err(invalid_data(format!("byte {} lies beyond the end of the data runs", position + done as u64)));
+        }
+        Ok(out)
+    }
+
+    fn raw_record(&self, disk: &mut dyn Disk, number: u64) -> io::Result<Vec<u8>> {
+        let position = number * self.record_size;
+        if position + self.record_size > self.mft_size {
+            return Err(invalid_data(format!("MFT record {} is beyond the end of $MFT", number)));
+        }
+        let mut record = self.read_runs(disk, &self.mft_runs, position, self.record_size as usize)?;
+        apply_fixups(&mut record, b"FILE").map_err(|err| invalid_data(format!("MFT record {}: {}", number, err)))?;
+        Ok(record)
+    }
+
+    // A file record with the attributes of its extension records folded in.
+    pub fn file_record(&self, disk: &mut dyn Disk, number: u64) -> io::Result<FileRecord> {
+        let raw = self.raw_record(disk, number)?;
+        let mut attributes = parse_attributes(&raw).map_err(|err| invalid_data(format!("MFT record {}: {}", number, err)))?;
+        if let Some(list) = attributes.iter().find(|attribute| attribute.kind == ATTR_ATTRIBUTE_LIST).cloned() {
+            let list = match list.resident {
+                Some(value) => value,
+                None => self.read_runs(disk, &list.runs, 0, list.data_size as usize)?,
+            };
+            let mut extensions = HashSet::new();
+            let mut position = 0;
+            while position + 26 <= list.len() {
+                let length = read_u16(&list, position + 4) as usize;
+                if length == 0 {
+                    break;
+                }
+                let extension = record_number(read_u64(&list, position + 16));
+                if extension != number && extensions.insert(extension) {
+                    let raw = self.raw_record(disk, extension)?;
+                    if record_number(read_u64(&raw, 32)) == number {
+                        attributes.extend(parse_attributes(&raw)?);
+                    }
+                }
+                position += length;
+            }
+            attributes = merge_fragments(attributes);
+        }
+        Ok(FileRecord {
+            number,
+            sequence: read_u16(&raw, 16),
+            flags: read_u16(&raw, 22),
+            attributes,
+        })
+    }
+
+    // Rebuilds a folder's path from the parent links of the $FILE_NAME attributes, which
+    // deleted folders keep as well; a broken chain leaves an orphan "?" folder.
+    fn directory_path(&self, disk: &mut dyn Disk, number: u64, paths: &mut HashMap<u64, String>, depth: usize) -> String {
+        if let Some(path) = paths.get(&number) {
+            return path.clone();
+        }
+        let parent = self.file_record(disk, number).ok().and_then(|record| record.file_name());
+        let path = match parent {
+            Some((parent, name)) if parent != number && depth < MAX_INDEX_DEPTH => {
+                format!("{}/{}", self.directory_path(disk, parent, paths, depth + 1), name)
+            }
+            _ => format!("/{}", ORPHAN_DIRECTORY),
+        };
+        paths.insert(number, path.clone());
+        path
+    }
+
+    fn index_block(&self, disk: &mut dyn Disk, allocation: &Attribute, vcn: u64, block_size: u64) -> io::Result<Vec<u8>> {
+        // Index VCNs count clusters, or 512-byte units when index records are smaller than a cluster.
+        let unit = if block_size >= self.cluster_size { self.cluster_size } else { 512 };
+        let position = vcn.checked_mul(unit).ok_or_else(|| invalid_data(format!("index VCN {} is out of range", vcn)))?;
+        let mut block = self.read_runs(disk, &allocation.runs, position, block_size as usize)?;
+        apply_fixups(&mut block, b"INDX").map_err(|err| invalid_data(format!("index block at VCN {}: {}", vcn, err)))?;
+        Ok(block)
+    }
+
+    // Walks the B+ tree in order: an entry's subnode holds the names sorting before it.
+    #[allow(clippy::too_many_arguments)]
+    fn walk_index_node(
+        &self,
+        disk: &mut dyn Disk,
+        node: &[u8],
+        allocation: Option<&Attribute>,
+        block_size: u64,
+        depth: usize,
+        visited: &mut HashSet<u64>,
+        entries: &mut Vec<IndexEntry>,
+    ) -> io::Result<()> {
+        if node.len() < 16 {
+            return Err(invalid_data("truncated index node header"));
+        }
+        let end = (read_u32(node, 4) as usize).min(node.len());
+        let mut position = read_u32(node, 0) as usize;
+        while position + 16 <= end {
+            let entry = &node[position..end];
+            let length = read_u16(entry, 8) as usize;
+            let key_length = read_u16(entry, 10) as usize;
+            let flags = read_u16(entry, 12);
+            if length < 16 || length > entry.len() || 16 + key_length > length {
+                return Err(invalid_data(format!("malformed index entry at offset {}", position)));
+            }
+            if flags & INDEX_ENTRY_SUBNODE != 0 {
+                let allocation = allocation.ok_or_else(|| invalid_data("index entry has a subnode but the directory has no $INDEX_ALLOCATION"))?;
+                let vcn = read_u64(entry, length - 8);
+                if depth >= MAX_INDEX_DEPTH || !visited.insert(vcn) {
+                    return Err(invalid_data(format!("directory index loops at VCN {}", vcn)));
+                }
+                let block = self.index_block(disk, allocation, vcn, block_size)?;
+                self.walk_index_node(disk, &block[24..], Some(allocation), block_size, depth + 1, visited, entries)?;
+            }
+            if flags & INDEX_ENTRY_LAST != 0 {
+                break;
+            }
+            if let Some(parsed) = parse_index_key(read_u64(entry, 0), &entry[16..16 + key_length]) {
+                entries.push(parsed);
+            }
+            position += length;
+        }
+        Ok(())
+    }
+
+    fn directory_entries(&self, disk: &mut dyn Disk, record: &FileRecord) -> io::Result<Vec<IndexEntry>> {
+        let root = record
+            .attribute(ATTR_INDEX_ROOT, DIRECTORY_INDEX)
+            .and_then(|root| root.resident.as_ref())
+            .filter(|root| root.len() >= 32)
+            .ok_or_else(|| invalid_data(format!("MFT record {} has no directory index", record.number)))?;
+        let block_size = read_u32(root, 8) as u64;
+        if !block_size.is_power_of_two() || !(512..=MAX_INDEX_BLOCK_SIZE).contains(&block_size) {
+            return Err(invalid_data(format!("MFT record {} has an invalid index block size {}", record.number, block_size)));
+        }
+        let allocation = record.attribute(ATTR_INDEX_ALLOCATION, DIRECTORY_INDEX);
+        let mut entries = Vec::new();
+        self.walk_index_node(disk, &root[16..], allocation, block_size, 0, &mut HashSet::new(), &mut entries)?;
+        Ok(entries)
+    }
+
+    fn stream<'a>(&self, record: &'a FileRecord, entry: &FileEntry) -> io::Result<&'a Attribute> {
+        let name = entry.stream.as_deref().unwrap_or("");
+        record
+            .attribute(ATTR_DATA, name)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("{} has no data stream '{}'", entry.path, name)))
+    }
+}
+
+impl FileSystem for NtfsFileSystem {
+    fn name(&self) -> &'static str {
+        "NTFS"
+    }
+
+    fn details(&self) -> Vec<(String, String)> {
+        let mut details = vec![
+            ("Type".to_string(), "NTFS".to_string()),
+            ("OEM name".to_string(), self.oem_name.clone()),
+            ("Label".to_string(), self.label.clone()),
+            ("Serial number".to_string(), format!("{:016X}", self.serial)),
+            ("Bytes per sector".to_string(), self.bytes_per_sector.to_string()),
+            ("Cluster size".to_string(), format!("{} bytes", self.cluster_size)),
+            ("Clusters".to_string(), (self.volume_size() / self.cluster_size).to_string()),
+            ("MFT cluster".to_string(), self.mft_cluster.to_string()),
+            ("MFT mirror cluster".to_string(), self.mft_mirror_cluster.to_string()),
+            ("MFT record size".to_string(), format!("{} bytes", self.record_size)),
+            ("MFT records".to_string(), (self.mft_size / self.record_size).to_string()),
+            ("MFT fragments".to_string(), self.mft_runs.len().to_string()),
+            ("Index record size".to_string(), format!("{} bytes", self.index_record_size)),
+        ];
+        if let Some((major, minor)) = self.version {
+            details.insert(1, ("Version".to_string(), format!("{}.{}", major, minor)));
+        }
+        details
+    }
+
+    fn cluster_size(&self) -> u64 {
+        self.cluster_size
+    }
+
+    fn root(&self) -> FileEntry {
+        FileEntry {
+            name: String::new(),
+            path: "/".to_string(),
+            is_dir: true,
+            size: 0,
+            modified: None,
+            node: ROOT_RECORD,
+            stream: None,
+            symlink: None,
+        }
+    }
+
+    // Sizes and times come from each file's own record; the copies in the index are
+    // only updated lazily by Windows. Alternate data streams follow their file as
+    // "name:stream" entries.
+    fn read_dir(&mut self, disk: &mut dyn Disk, dir: &FileEntry) -> io::Result<Vec<FileEntry>> {
+        let record = self.file_record(disk, dir.node)?;
+        let mut files = Vec::new();
+        let mut seen = HashSet::new();
+        for entry in self.directory_entries(disk, &record)? {
+            let number = record_number(entry.reference);
+            if entry.namespace == NAMESPACE_DOS || number == dir.node || !seen.insert((number, entry.name.clone())) {
+                continue;
+            }
+            let path = format!("{}/{}", dir.path.trim_end_matches('/'), entry.name);
+            let child = match self.file_record(disk, number) {
+                Ok(child) if child.in_use() && child.sequence == (entry.reference >> 48) as u16 => child,
+                Ok(_) => continue,
+                Err(_) => {
+                    // Keep the index data when the record itself is unreadable.
+                    files.push(FileEntry {
+                        name: entry.name,
+                        path,
+                        is_dir: entry.is_dir,
+                        size: entry.size,
+                        modified: format_filetime(entry.modified),
+                        node: number,
+                        stream: None,
+                        symlink: None,
+                    });
+                    continue;
+                }
+            };
+            let modified = child.modified().or_else(|| format_filetime(entry.modified));
+            files.push(FileEntry {
+                name: entry.name.clone(),
+                path: path.clone(),
+                is_dir: child.is_directory(),
+                size: child.attribute(ATTR_DATA, "").map_or(0, Attribute::size),
+                modified: modified.clone(),
+                node: number,
+                stream: None,
+                symlink: None,
+            });
+            for stream in child.attributes.iter().filter(|attribute| attribute.kind == ATTR_DATA && !attribute.name.is_empty()) {
+                files.push(FileEntry {
+                    name: format!("{}:{}", entry.name, stream.name),
+                    path: format!("{}:{}", path, stream.name),
+                    is_dir: false,
+                    size: stream.size(),
+                    modified: modified.clone(),
+                    node: number,
+                    stream: Some(stream.name.clone()),
+                    symlink: None,
+                });
+            }
+        }
+        Ok(files)
+    }
+
+    fn read_file(&mut self, disk: &mut dyn Disk, file: &FileEntry, out: &mut dyn Write) -> io::Result<()> {
+        let record = self.file_record(disk, file.node)?;
+        let stream = self.stream(&record, file)?;
+        if stream.flags & (ATTRIBUTE_COMPRESSED | ATTRIBUTE_ENCRYPTED) != 0 {
+            let kind = if stream.flags & ATTRIBUTE_ENCRYPTED != 0 { "encrypted" } else { "compressed" };
+            return Err(io::Error::new(io::ErrorKind::Unsupported, format!("{} is {}", file.path, kind)));
+        }
+        if let Some(value) = &stream.resident {
+            return out.write_all(value);
+        }
+        // Bytes past the initialized size were never written and read as zeroes.
+        let mut position = 0;
+        while position < stream.data_size {
+            let len = READ_CHUNK_SIZE.min(stream.data_size - position);
+            let initialized = stream.initialized_size.saturating_sub(position).min(len);
+            let mut chunk = self.read_runs(disk, &stream.runs, position, initialized as usize)?;
+            chunk.resize(len as usize, 0);
+            out.write_all(&chunk)?;
+            position += len;
+        }
+        Ok(())
+    }
+
+    fn allocation(&mut self, disk: &mut dyn Disk, entry: &FileEntry) -> io::Result<Vec<Extent>> {
+        let record = self.file_record(disk, entry.node)?;
+        let attribute = match entry.is_dir {
+            true => record.attribute(ATTR_INDEX_ALLOCATION, DIRECTORY_INDEX),
+            false => Some(self.stream(&record, entry)?),
+        };
+        let mut extents: Vec<Extent> = Vec::new();
+        for run in attribute.map_or(&[][..], |attribute| &attribute.runs) {
+            let Some(lcn) = run.lcn else {
+                continue;
+            };
+            match extents.last_mut() {
+                Some(extent) if extent.start + extent.count == lcn => extent.count += run.length,
+                _ => extents.push(Extent {
+                    start: lcn,
+                    count: run.length,
+                }),
+            }
+        }
+        Ok(extents)
+    }
+
+    fn cluster_bitmap(&mut self, disk: &mut dyn Disk) -> io::Result<ClusterBitmap> {
+        let record = self.file_record(disk, BITMAP_RECORD)?;
+        let data = record.attribute(ATTR_DATA, "").ok_or_else(|| invalid_data("$Bitmap has no data stream"))?;
+        let bits = match &data.resident {
+            Some(value) => value.clone(),
+            None => self.read_runs(disk, &data.runs, 0, data.data_size as usize)?,
+        };
+        Ok(ClusterBitmap::new(0, self.volume_size() / self.cluster_size, bits))
+    }
+
+    fn cluster_position(&self, cluster: u64) -> u64 {
+        self.offset + cluster * self.cluster_size
+    }
+
+    // $BadClus:$Bad is a sparse stream as long as the volume whose only allocated runs
+    // are the bad clusters.
+    fn reserved_areas(&mut self, disk: &mut dyn Disk) -> io::Result<Vec<ReservedArea>> {
+        let mut areas = Vec::new();
+        for (number, name) in SYSTEM_FILES {
+            let record = self.file_record(disk, *number)?;
+            let mut metadata = Vec::new();
+            let mut bad = Vec::new();
+            for attribute in &record.attributes {
+                let extents = match *number == BAD_CLUSTERS_RECORD && attribute.kind == ATTR_DATA && attribute.name == BAD_CLUSTERS_STREAM {
+                    true => &mut bad,
+                    false => &mut metadata,
+                };
+                for run in &attribute.runs {
+                    if let Some(lcn) = run.lcn {
+                        push_extent(extents, lcn, run.length);
+                    }
+                }
+            }
+            areas.push(ReservedArea {
+                name: name.to_string(),
+                bad: false,
+                extents: metadata,
+            });
+            if !bad.is_empty() {
+                areas.push(ReservedArea {
+                    name: format!("{}:{}", name, BAD_CLUSTERS_STREAM),
+                    bad: true,
+                    extents: bad,
+                });
+            }
+        }
+        Ok(areas)
+    }
+
+    // Records freed by a delete keep their attributes, data runs included, until the
+    // record is reused for a new file.
+    fn deleted_files(&mut self, disk: &mut dyn Disk) -> io::Result<Vec<FileEntry>> {
+        let mut paths = HashMap::from([(ROOT_RECORD, String::new())]);
+        let mut found = Vec::new();
+        for number in FIRST_USER_RECORD..self.mft_size / self.record_size {
+            // Records that were never used have no "FILE" signature.
+            let Ok(record) = self.file_record(disk, number) else {
+                continue;
+            };
+            if record.in_use() || record.is_directory() {
+                continue;
+            }
+            let Some((parent, name)) = record.file_name() else {
+                continue;
+            };
+            found.push(FileEntry {
+                path: format!("{}/{}", self.directory_path(disk, parent, &mut paths, 0), name),
+                name,
+                is_dir: false,
+                size: record.attribute(ATTR_DATA, "").map_or(0, Attribute::size),
+                modified: record.modified(),
+                node: number,
+                stream: None,
+                symlink: None,
+            });
+        }
+        Ok(found)
+    }
+}
@@ -0,0 +1,147 @@
+use crate::disk::{physical_drives_of, Disk};
+use crate::filesystem::{export, safe_file_name, ExportSummary, FileEntry, FileSystem};
+use std::fs;
+use std::io;
+use std::path::Path;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Recoverability {
+    Intact,
+    Resident,
+    Partial,
+    Overwritten,
+    Unknown,
+}
+
+impl Recoverability {
+    pub fn name(self) -> &'static str {
+        match self {
+            Recoverability::Intact => "Intact",
+            Recoverability::Resident => "Intact (stored in its file record)",
+            Recoverability::Partial => "Partly overwritten",
+            Recoverability::Overwritten => "Overwritten",
+            Recoverability::Unknown => "Unknown",
+        }
+    }
+}
+
+pub struct DeletedFile {
+    pub entry: FileEntry,
+    pub clusters: u64,
+    pub reused: u64,
+    pub recoverability: Recoverability,
+    pub error: Option<String>,
+}
+
+impl DeletedFile {
+    pub fn describe_recoverability(&self) -> String {
+        match self.recoverability {
+            Recoverability::Partial => format!("{} ({} of {} clusters reused)", self.recoverability.name(), self.reused, self.clusters),
+            recoverability => recoverability.name().to_string(),
+        }
+    }
+}
+
+// Deleted files with how many of their clusters have been allocated again since. A
+// cluster that is free now may still have been reused and freed in between, so
+// "intact" is the best case rather than a promise.
+pub fn scan_deleted(filesystem: &mut dyn FileSystem, disk: &mut dyn Disk) -> io::Result<Vec<DeletedFile>> {
+    let entries = filesystem.deleted_files(disk)?;
+    let bitmap = filesystem.cluster_bitmap(disk)?;
+    let mut files = Vec::new();
+    for entry in entries {
+        let (clusters, reused, error) = match filesystem.allocation(disk, &entry) {
+            Ok(extents) => (
+                extents.iter().map(|extent| extent.count).sum(),
+                extents.iter().map(|extent| bitmap.used_in(extent.start, extent.count)).sum(),
+                None,
+            ),
+            Err(err) => (0, 0, Some(err.to_string())),
+        };
+        let recoverability = match clusters {
+            _ if error.is_some() => Recoverability::Unknown,
+            0 if entry.size > 0 => Recoverability::Resident,
+            _ if reused == 0 => Recoverability::Intact,
+            _ if reused < clusters => Recoverability::Partial,
+            _ => Recoverability::Overwritten,
+        };
+        files.push(DeletedFile {
+            entry,
+            clusters,
+            reused,
+            recoverability,
+            error,
+        });
+    }
+    files.sort_by(|a, b| a.entry.path.cmp(&b.entry.path));
+    Ok(files)
+}
+
+#[derive(Debug, Default)]
+pub struct RestoreSummary {
+    pub restored: ExportSummary,
+    pub failed: Vec<(String, String)>,
+}
+
+impl RestoreSummary {
+    pub fn describe(&self) -> String {
+        let mut text = format!("{} file(s), {} bytes", self.restored.files, self.restored.bytes);
+        if !self.failed.is_empty() {
+            text.push_str(&format!(", {} failed", self.failed.len()));
+        }
+        text
+    }
+}
+
+// Writing to the drive being recovered could overwrite the clusters of the very files
+// still waiting to be restored. Disk images are only ever read, so they may be
+// restored anywhere.
+pub fn check_destination(target: &str, destination: &Path) -> io::Result<()> {
+    let Ok(index) = target.parse::<u32>() else {
+        return Ok(());
+    };
+    // Look at the closest existing folder; creating the destination first would
+    // already write to the drive it is on.
+    let absolute = std::env::current_dir()?.join(destination);
+    let existing = absolute.ancestors().find(|path| path.exists()).unwrap_or(&absolute);
+    if physical_drives_of(existing)?.contains(&index) {
+        return Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            format!("{} is on physical drive {}, the drive being recovered; choose a folder on another disk", destination.display(), index),
+        ));
+    }
+    Ok(())
+}
+
+// Each file goes to its original folder under `destination`. Failures, such as two
+// deleted files with the same path, are collected instead of ending the restore.
+pub fn restore(
+    filesystem: &mut dyn FileSystem,
+    disk: &mut dyn Disk,
+    target: &str,
+    files: &[FileEntry],
+    destination: &Path,
+    progress: &mut dyn FnMut(&str),
+) -> io::Result<RestoreSummary> {
+    check_destination(target, destination)?;
+    let mut summary = RestoreSummary::default();
+    for file in files {
+        progress(&file.path);
+        let folder = file
+            .path
+            .rsplit_once('/')
+            .map_or("", |(folder, _)| folder)
+            .split('/')
+            .filter(|component| !component.is_empty())
+            .fold(destination.to_path_buf(), |path, component| path.join(safe_file_name(component)));
+        let result = fs::create_dir_all(&folder).and_then(|_| export(filesystem, disk, file, &folder, &mut |_, _| {}));
+        match result {
+            Ok(restored) => {
+                summary.restored.files += restored.files;
+                summary.restored.bytes += restored.bytes;
+            }
+            Err(err) => summary.failed.push((file.path.clone(), err.to_string())),
+        }
+    }
+    Ok(summary)
+}
@@ -0,0 +1,594 @@
+use crate::disk::Disk;
+use crate::filesystem::iso9660::{copy_range, format_utc_offset, BootCatalog};
+use crate::filesystem::{ClusterBitmap, Extent, FileEntry, FileSystem, ReservedArea};
+use crate::partition_table::{read_u16, read_u32, read_u64};
+use crate::vdisk::invalid_data;
+use std::collections::HashMap;
+use std::io::{self, Write};
+
+const ANCHOR_BLOCK: u64 = 256;
+const MAX_SEQUENCE_BLOCKS: u64 = 64;
+const MAX_ALLOCATION_EXTENTS: usize = 256;
+const MAX_DIRECTORY_SIZE: u64 = 64 * 1024 * 1024;
+const ZERO_CHUNK: usize = 64 * 1024;
+
+const TAG_PRIMARY_VOLUME: u16 = 1;
+const TAG_ANCHOR: u16 = 2;
+const TAG_PARTITION: u16 = 5;
+const TAG_LOGICAL_VOLUME: u16 = 6;
+const TAG_TERMINATING: u16 = 8;
+const TAG_FILE_SET: u16 = 256;
+const TAG_FILE_IDENTIFIER: u16 = 257;
+const TAG_ALLOCATION_EXTENT: u16 = 258;
+const TAG_FILE_ENTRY: u16 = 261;
+const TAG_EXTENDED_FILE_ENTRY: u16 = 266;
+
+const FILE_TYPE_DIRECTORY: u8 = 4;
+const FILE_TYPE_SYMLINK: u8 = 12;
+const CHARACTERISTIC_DIRECTORY: u8 = 0x02;
+const CHARACTERISTIC_DELETED: u8 = 0x04;
+const CHARACTERISTIC_PARENT: u8 = 0x08;
+const EXTENT_RECORDED: u32 = 0;
+const EXTENT_CONTINUATION: u32 = 3;
+const ALLOCATION_EMBEDDED: u16 = 3;
+
+fn check_tag(descriptor: &[u8], id: u16) -> io::Result<()> {
+    if descriptor.len() < 16 {
+        return Err(invalid_data("truncated UDF descriptor tag"));
+    }
+    let checksum = descriptor[..16]
+        .iter()
+        .enumerate()
+        .filter(|(index, _)| *index != 4)
+        .fold(0u8, |sum, (_, byte)| sum.wrapping_add(*byte));
+    if read_u16(descriptor, 0) != id || checksum != descriptor[4] {
+        return Err(invalid_data(format!("expected UDF descriptor {}, found tag {}", id, read_u16(descriptor, 0))));
+    }
+    Ok(())
+}
+
+// OSTA compressed unicode: a compression id of 8 or 16 bits per character.
+fn decode_identifier(bytes: &[u8]) -> String {
+    match bytes.split_first() {
+        Some((8, rest)) => rest.iter().map(|byte| *byte as char).collect(),
+        Some((16, rest)) => {
+            let units: Vec<u16> = rest.chunks_exact(2).map(|unit| u16::from_be_bytes([unit[0], unit[1]])).collect();
+            String::from_utf16_lossy(&units)
+        }
+        _ => String::new(),
+    }
+}
+
+// Fixed-size identifier field whose last byte holds the used length.
+fn dstring(field: &[u8]) -> String {
+    let length = field.last().map_or(0, |length| *length as usize).min(field.len() - 1);
+    decode_identifier(&field[..length]).trim_end_matches('\0').to_string()
+}
+
+fn format_timestamp(timestamp: &[u8]) -> Option<String> {
+    let type_and_zone = read_u16(timestamp, 0);
+    let year = read_u16(timestamp, 2) as i16;
+    if year <= 0 || timestamp[4] == 0 {
+        return None;
+    }
+    let local = format!(
+        "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+        year, timestamp[4], timestamp[5], timestamp[6], timestamp[7], timestamp[8]
+    );
+    // The zone is a 12-bit signed minute count; -2047 means it was not recorded.
+    let zone = ((type_and_zone << 4) as i16) >> 4;
+    if type_and_zone >> 12 != 1 || zone == -2047 {
+        return Some(local);
+    }
+    Some(format!("{} {}", local, format_utc_offset(zone as i32)))
+}
+
+fn symlink_target(data: &[u8]) -> String {
+    let mut parts = Vec::new();
+    let mut position = 0;
+    while position + 4 <= data.len() {
+        let length = data[position + 1] as usize;
+        let identifier = data.get(position + 4..position + 4 + length).unwrap_or(&[]);
+        match data[position] {
+            1 | 2 => {
+                parts.clear();
+                parts.push(String::new());
+            }
+            3 => parts.push("..".to_string()),
+            4 => parts.push(".".to_string()),
+            5 => parts.push(decode_identifier(identifier)),
+            _ => {}
+        }
+        position += 4 + length;
+    }
+    match parts.join("/") {
+        target if target.is_empty() && !parts.is_empty() => "/".to_string(),
+        target => target,
+    }
+}
+
+#[derive(Clone, Copy)]
+struct Location {
+    partition: u16,
+    block: u32,
+}
+
+impl Location {
+    fn from_long_ad(bytes: &[u8]) -> Self {
+        Self {
+            block: read_u32(bytes, 4),
+            partition: read_u16(bytes, 8),
+        }
+    }
+
+    // FileEntry nodes hold the partition reference above the block number.
+    fn node(self) -> u64 {
+        (self.partition as u64) << 32 | self.block as u64
+    }
+
+    fn from_node(node: u64) -> Self {
+        Self {
+            partition: (node >> 32) as u16,
+            block: node as u32,
+        }
+    }
+}
+
+enum PartitionMap {
+    Physical { start: u64, length: u64 },
+    // UDF 2.50 metadata partition: blocks of the metadata file inside a physical partition.
+    Metadata { start: u64, extents: Vec<(u64, u64)> },
+}
+
+struct AllocatedExtent {
+    location: Location,
+    length: u64,
+    recorded: bool,
+}
+
+enum Data {
+    Embedded(Vec<u8>),
+    Extents(Vec<AllocatedExtent>),
+}
+
+struct Node {
+    file_type: u8,
+    length: u64,
+    modified: Option<String>,
+    data: Data,
+}
+
+pub struct UdfFileSystem {
+    offset: u64,
+    block_size: u64,
+    volume_id: String,
+    logical_volume_id: String,
+    revision: u16,
+    maps: Vec<PartitionMap>,
+    map_names: Vec<String>,
+    root: Location,
+    boot: Result<Option<BootCatalog>, String>,
+}
+
+impl UdfFileSystem {
+    pub fn open(disk: &mut dyn Disk, offset: u64) -> io::Result<Self> {
+        let (block_size, anchor) = [2048u64, 512, 4096, 1024]
+            .into_iter()
+            .find_map(|block_size| {
+                let anchor = disk.read_bytes(offset + ANCHOR_BLOCK * block_size, block_size as usize).ok()?;
+                let valid = check_tag(&anchor, TAG_ANCHOR).is_ok() && read_u32(&anchor, 12) as u64 == ANCHOR_BLOCK;
+                valid.then_some((block_size, anchor))
+            })
+            .ok_or_else(|| invalid_data("no UDF anchor volume descriptor pointer at block 256"))?;
+        let mut volume_id = String::new();
+        let mut partitions = HashMap::new();
+        let mut logical_volume = None;
+        // The reserve volume descriptor sequence is only consulted if the main one is unusable.
+        for sequence in [16, 24] {
+            let length = read_u32(&anchor, sequence) as u64;
+            let location = read_u32(&anchor, sequence + 4) as u64;
+            for index in 0..length.div_ceil(block_size).min(MAX_SEQUENCE_BLOCKS) {
+                let descriptor = disk.read_bytes(offset + (location + index) * block_size, block_size as usize)?;
+                let tag = read_u16(&descriptor, 0);
+                if check_tag(&descriptor, tag).is_err() {
+                    continue;
+                }
+                match tag {
+                    TAG_PRIMARY_VOLUME => volume_id = dstring(&descriptor[24..56]),
+                    TAG_PARTITION => {
+                        let range = (read_u32(&descriptor, 188) as u64, read_u32(&descriptor, 192) as u64);
+                        partitions.entry(read_u16(&descriptor, 22)).or_insert(range);
+                    }
+                    TAG_LOGICAL_VOLUME if logical_volume.is_none() => logical_volume = Some(descriptor),
+                    TAG_TERMINATING => break,
+                    _ => {}
+                }
+            }
+            if logical_volume.is_some() {
+                break;
+            }
+        }
+        let logical_volume = logical_volume.ok_or_else(|| invalid_data("no UDF logical volume descriptor"))?;
+        if read_u32(&logical_volume, 212) as u64 != block_size {
+            return Err(invalid_data("UDF logical block size differs from the anchor block size"));
+        }
+        let mut maps = Vec::new();
+        let mut map_names = Vec::new();
+        let mut metadata_files = Vec::new();
+        let mut position = 440;
+        for _ in 0..read_u32(&logical_volume, 268) {
+            let map = logical_volume.get(position..).filter(|map| map.len() >= 6 && map[1] as usize <= map.len());
+            let Some(map) = map.map(|map| &map[..map[1] as usize]) else {
+                return Err(invalid_data("truncated UDF partition map table"));
+            };
+            let (number, kind) = match map[0] {
+                1 => (read_u16(map, 4), "Physical"),
+                2 if map.len() >= 64 => {
+                    let kind = match &map[5..28] {
+                        identifier if identifier.starts_with(b"*UDF Metadata Partition") => "Metadata",
+                        identifier if identifier.starts_with(b"*UDF Sparable Partition") => "Sparable",
+                        _ => "Virtual",
+                    };
+                    (read_u16(map, 38), kind)
+                }
+                _ => return Err(invalid_data(format!("unknown UDF partition map type {}", map[0]))),
+            };
+            let &(start, length) = partitions
+                .get(&number)
+                .ok_or_else(|| invalid_data(format!("no descriptor for UDF partition {}", number)))?;
+            match kind {
+                "Virtual" => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::Unsupported,
+                        "UDF virtual partitions (write-once media) are not supported",
+                    ))
+                }
+                "Metadata" => {
+                    metadata_files.push((maps.len(), read_u32(map, 40)));
+                    maps.push(PartitionMap::Metadata { start, extents: Vec::new() });
+                }
+                // Sparing tables only matter on the original media; images are read directly.
+                _ => maps.push(PartitionMap::Physical { start, length }),
+            }
+            map_names.push(format!("{} partition {} at block {}", kind, number, start));
+            position += map[1] as usize;
+        }
+        let mut filesystem = Self {
+            offset,
+            block_size,
+            volume_id,
+            logical_volume_id: dstring(&logical_volume[84..212]),
+            revision: read_u16(&logical_volume, 216 + 24),
+            maps,
+            map_names,
+            root: Location { partition: 0, block: 0 },
+            boot: BootCatalog::find(disk, offset).map_err(|err| err.to_string()),
+        };
+        for (index, block) in metadata_files {
+            filesystem.resolve_metadata_partition(disk, index, block)?;
+        }
+        let file_set = filesystem.read_block(disk, Location::from_long_ad(&logical_volume[248..]))?;
+        check_tag(&file_set, TAG_FILE_SET)?;
+        filesystem.root = Location::from_long_ad(&file_set[400..]);
+        Ok(filesystem)
+    }
+
+    // Maps the metadata partition onto the extents of its metadata file, which is
+    // recorded in the physical partition that shares its partition number.
+    fn resolve_metadata_partition(&mut self, disk: &mut dyn Disk, index: usize, block: u32) -> io::Result<()> {
+        let PartitionMap::Metadata { start, .. } = self.maps[index] else {
+            return Ok(());
+        };
+        let physical = self
+            .maps
+            .iter()
+            .position(|map| matches!(map, PartitionMap::Physical { start: physical, .. } if *physical == start))
+            .ok_or_else(|| invalid_data("UDF metadata partition has no physical partition"))?;
+        let node = self.read_node(disk, Location { partition: physical as u16, block })?;
+        let Data::Extents(extents) = node.data else {
+            return Err(invalid_data("UDF metadata file has no extents"));
+        };
+        let extents = extents
+            .iter()
+            .map(|extent| (extent.location.block as u64, extent.length.div_ceil(self.block_size)))
+            .collect();
+        self.maps[index] = PartitionMap::Metadata { start, extents };
+        Ok(())
+    }
+
+    // Runs of absolute blocks (relative to the volume) covering `count` logical blocks.
+    fn physical_blocks(&self, location: Location, count: u64) -> io::Result<Vec<Extent>> {
+        match self.maps.get(location.partition as usize) {
+            Some(PartitionMap::Physical { start, length }) => {
+                if location.block as u64 + count > *length {
+                    return Err(invalid_data(format!("UDF block {} is outside its partition", location.block)));
+                }
+                Ok(vec![Extent {
+                    start: start + location.block as u64,
+                    count,
+                }])
+            }
+            Some(PartitionMap::Metadata { start, extents }) => {
+                let mut runs: Vec<Extent> = Vec::new();
+                for block in location.block as u64..location.block as u64 + count {
+                    let mut remaining = block;
+                    let mut physical = None;
+                    for (extent_start, extent_count) in extents {
+                        if remaining < *extent_count {
+                            physical = Some(start + extent_start + remaining);
+                            break;
+                        }
+                        remaining -= extent_count;
+                    }
+                    let physical = physical.ok_or_else(|| invalid_data(format!("UDF metadata block {} is not mapped", block)))?;
+                    match runs.last_mut() {
+                        Some(run) if run.start + run.count == physical => run.count += 1,
+                        _ => runs.push(Extent { start: physical, count: 1 }),
+                    }
+                }
+                Ok(runs)
+            }
+            None => Err(invalid_data(format!("no UDF partition map {}", location.partition))),
+        }
+    }
+
+    fn read_block(&self, disk: &mut dyn Disk, location: Location) -> io::Result<Vec<u8>> {
+        let block = self.physical_blocks(location, 1)?[0].start;
+        disk.read_bytes(self.offset + block * self.block_size, self.block_size as usize)
+    }
+
+    fn read_node(&self, disk: &mut dyn Disk, location: Location) -> io::Result<Node> {
+        let block = self.read_block(disk, location)?;
+        let tag = read_u16(&block, 0);
+        let (modified_at, lengths_at) = match tag {
+            TAG_FILE_ENTRY => (84, 168),
+            TAG_EXTENDED_FILE_ENTRY => (92, 208),
+            _ => return Err(invalid_data(format!("UDF block {} is not a file entry", location.block))),
+        };
+        check_tag(&block, tag)?;
+        let start = lengths_at + 8 + read_u32(&block, lengths_at) as usize;
+        let descriptors = block
+            .get(start..start + read_u32(&block, lengths_at + 4) as usize)
+            .ok_or_else(|| invalid_data(format!("UDF file entry {} overflows its block", location.block)))?;
+        let data = match read_u16(&block, 16 + 18) & 7 {
+            ALLOCATION_EMBEDDED => Data::Embedded(descriptors.to_vec()),
+            kind => Data::Extents(self.allocation_descriptors(disk, descriptors, kind, location.partition)?),
+        };
+        Ok(Node {
+            file_type: block[16 + 11],
+            length: read_u64(&block, 56),
+            modified: format_timestamp(&block[modified_at..modified_at + 12]),
+            data,
+        })
+    }
+
+    fn allocation_descriptors(&self, disk: &mut dyn Disk, descriptors: &[u8], kind: u16, partition: u16) -> io::Result<Vec<AllocatedExtent>> {
+        let size = match kind {
+            0 => 8,
+            1 => 16,
+            2 => 20,
+            _ => return Err(invalid_data(format!("unknown UDF allocation descriptor type {}", kind))),
+        };
+        let mut extents = Vec::new();
+        let mut current = descriptors.to_vec();
+        for _ in 0..MAX_ALLOCATION_EXTENTS {
+            let mut next = None;
+            for descriptor in current.chunks_exact(size) {
+                let length = read_u32(descriptor, 0);
+                if length & 0x3FFF_FFFF == 0 {
+                    break;
+                }
+                let location = match kind {
+                    0 => Location {
+                        partition,
+                        block: read_u32(descriptor, 4),
+                    },
+                    1 => Location::from_long_ad(descriptor),
+                    _ => Location {
+                        block: read_u32(descriptor, 12),
+                        partition: read_u16(descriptor, 16),
+                    },
+                };
+                if length >> 30 == EXTENT_CONTINUATION {
+                    next = Some(location);
+                    break;
+                }
+                extents.push(AllocatedExtent {
+                    location,
+                    length: (length & 0x3FFF_FFFF) as u64,
+                    recorded: length >> 30 == EXTENT_RECORDED,
+                });
+            }
+            let Some(location) = next else {
+                return Ok(extents);
+            };
+            let block = self.read_block(disk, location)?;
+            check_tag(&block, TAG_ALLOCATION_EXTENT)?;
+            current = block
+                .get(24..24 + read_u32(&block, 20) as usize)
+                .ok_or_else(|| invalid_data("UDF allocation extent descriptor overflows its block"))?
+                .to_vec();
+        }
+        Err(invalid_data("too many UDF allocation extent descriptors"))
+    }
+
+    fn write_node(&self, disk: &mut dyn Disk, node: &Node, out: &mut dyn Write) -> io::Result<()> {
+        let mut remaining = node.length;
+        match &node.data {
+            Data::Embedded(bytes) => {
+                let bytes = bytes.get(..remaining as usize).ok_or_else(|| invalid_data("embedded UDF data is shorter than the file"))?;
+                out.write_all(bytes)?;
+                remaining = 0;
+            }
+            Data::Extents(extents) => {
+                for extent in extents {
+                    let length = extent.length.min(remaining);
+                    if extent.recorded {
+                        let mut left = length;
+                        for run in self.physical_blocks(extent.location, length.div_ceil(self.block_size))? {
+                            let run_length = (run.count * self.block_size).min(left);
+                            copy_range(disk, self.offset + run.start * self.block_size, run_length, out)?;
+                            left -= run_length;
+                        }
+                    } else {
+                        let zeros = vec![0u8; ZERO_CHUNK];
+                        let mut left = length;
+                        while left > 0 {
+                            let chunk = left.min(ZERO_CHUNK as u64);
+                            out.write_all(&zeros[..chunk as usize])?;
+                            left -= chunk;
+                        }
+                    }
+                    remaining -= length;
+                }
+            }
+        }
+        if remaining > 0 {
+            return Err(invalid_data(format!("UDF file extents are {} bytes short of its length", remaining)));
+        }
+        Ok(())
+    }
+
+    fn node_data(&self, disk: &mut dyn Disk, node: &Node) -> io::Result<Vec<u8>> {
+        if node.length > MAX_DIRECTORY_SIZE {
+            return Err(invalid_data(format!("UDF directory of {} bytes is too large", node.length)));
+        }
+        let mut data = Vec::new();
+        self.write_node(disk, node, &mut data)?;
+        Ok(data)
+    }
+
+    fn catalog(&self) -> io::Result<&BootCatalog> {
+        self.boot.as_ref().ok().and_then(Option::as_ref).ok_or_else(|| invalid_data("no El Torito boot catalog"))
+    }
+}
+
+impl FileSystem for UdfFileSystem {
+    fn name(&self) -> &'static str {
+        "UDF"
+    }
+
+    fn details(&self) -> Vec<(String, String)> {
+        let mut details = vec![
+            ("Type".to_string(), "UDF".to_string()),
+            ("Revision".to_string(), format!("{:x}.{:02x}", self.revision >> 8, self.revision & 0xFF)),
+            ("Volume identifier".to_string(), self.volume_id.clone()),
+            ("Logical volume".to_string(), self.logical_volume_id.clone()),
+            ("Block size".to_string(), format!("{} bytes", self.block_size)),
+            ("Partition maps".to_string(), self.map_names.join("; ")),
+        ];
+        match &self.boot {
+            Ok(Some(catalog)) => details.push(("El Torito".to_string(), catalog.describe())),
+            Ok(None) => {}
+            Err(err) => details.push(("El Torito".to_string(), err.clone())),
+        }
+        details
+    }
+
+    fn cluster_size(&self) -> u64 {
+        self.block_size
+    }
+
+    fn root(&self) -> FileEntry {
+        FileEntry {
+            name: String::new(),
+            path: "/".to_string(),
+            is_dir: true,
+            size: 0,
+            modified: None,
+            node: self.root.node(),
+            stream: None,
+            symlink: None,
+        }
+    }
+
+    fn read_dir(&mut self, disk: &mut dyn Disk, dir: &FileEntry) -> io::Result<Vec<FileEntry>> {
+        if BootCatalog::is_boot_node(dir.node) {
+            return Ok(self.catalog()?.entries());
+        }
+        let directory = self.read_node(disk, Location::from_node(dir.node))?;
+        let data = self.node_data(disk, &directory)?;
+        let mut files = Vec::new();
+        let mut position = 0;
+        while position + 38 <= data.len() {
+            let identifier = &data[position..];
+            check_tag(identifier, TAG_FILE_IDENTIFIER)?;
+            let characteristics = identifier[18];
+            let name_start = 38 + read_u16(identifier, 36) as usize;
+            let name_end = name_start + identifier[19] as usize;
+            let name = identifier
+                .get(name_start..name_end)
+                .map(decode_identifier)
+                .ok_or_else(|| invalid_data("UDF file identifier overflows its directory"))?;
+            let location = Location::from_long_ad(&identifier[20..36]);
+            position += (name_end + 3) & !3;
+            if characteristics & (CHARACTERISTIC_DELETED | CHARACTERISTIC_PARENT) != 0 {
+                continue;
+            }
+            let node = self.read_node(disk, location)?;
+            let symlink = match node.file_type {
+                FILE_TYPE_SYMLINK => Some(symlink_target(&self.node_data(disk, &node)?)),
+                _ => None,
+            };
+            files.push(FileEntry {
+                path: format!("{}/{}", dir.path.trim_end_matches('/'), name),
+                name,
+                is_dir: node.file_type == FILE_TYPE_DIRECTORY || characteristics & CHARACTERISTIC_DIRECTORY != 0,
+                size: node.length,
+                modified: node.modified,
+                node: location.node(),
+                stream: None,
+                symlink,
+            });
+        }
+        if dir.path == "/" {
+            if let Ok(Some(catalog)) = &self.boot {
+                files.push(catalog.directory());
+            }
+        }
+        Ok(files)
+    }
+
+    fn read_file(&mut self, disk: &mut dyn Disk, file: &FileEntry, out: &mut dyn Write) -> io::Result<()> {
+        if BootCatalog::is_boot_node(file.node) {
+            return self.catalog()?.read_image(disk, self.offset, file.node, out);
+        }
+        let node = self.read_node(disk, Location::from_node(file.node))?;
+        self.write_node(disk, &node, out)
+    }
+
+    fn allocation(&mut self, disk: &mut dyn Disk, entry: &FileEntry) -> io::Result<Vec<Extent>> {
+        if BootCatalog::is_boot_node(entry.node) {
+            return self.catalog()?.allocation(entry.node);
+        }
+        let node = self.read_node(disk, Location::from_node(entry.node))?;
+        let mut blocks: Vec<Extent> = Vec::new();
+        if let Data::Extents(extents) = &node.data {
+            for extent in extents.iter().filter(|extent| extent.recorded) {
+                for run in self.physical_blocks(extent.location, extent.length.div_ceil(self.block_size))? {
+                    match blocks.last_mut() {
+                        Some(last) if last.start + last.count == run.start => last.count += run.count,
+                        _ => blocks.push(run),
+                    }
+                }
+            }
+        }
+        Ok(blocks)
+    }
+
+    fn cluster_bitmap(&mut self, _disk: &mut dyn Disk) -> io::Result<ClusterBitmap> {
+        Err(io::Error::new(io::ErrorKind::Unsupported, "UDF space bitmaps are not read"))
+    }
+
+    fn cluster_position(&self, cluster: u64) -> u64 {
+        self.offset + cluster * self.block_size
+    }
+
+    fn reserved_areas(&mut self, _disk: &mut dyn Disk) -> io::Result<Vec<ReservedArea>> {
+        Err(io::Error::new(io::ErrorKind::Unsupported, "UDF space bitmaps are not read"))
+    }
+
+    fn deleted_files(&mut self, _disk: &mut dyn Disk) -> io::Result<Vec<FileEntry>> {
+        Err(io::Error::new(io::ErrorKind::Unsupported, "deleted files can only be recovered from FAT and NTFS volumes"))
+    }
+}
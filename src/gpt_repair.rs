@@ -0,0 +1,418 @@
+use crate::disk::Disk;
+use crate::partition_table::{gpt_header_crc, read_partition_table, Gpt, GptHeader, PartitionTable, MBR_PROTECTIVE};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum GptRepair {
+    RebuildPrimary,
+    RebuildBackup,
+    FixCrcs,
+    RelocateBackup,
+}
+
+impl GptRepair {
+    pub const ALL: [GptRepair; 4] = [
+        GptRepair::RebuildPrimary,
+        GptRepair::RebuildBackup,
+        GptRepair::FixCrcs,
+        GptRepair::RelocateBackup,
+    ];
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.iter().copied().find(|action| action.name() == name)
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            GptRepair::RebuildPrimary => "rebuild-primary",
+            GptRepair::RebuildBackup => "rebuild-backup",
+            GptRepair::FixCrcs => "fix-crc",
+            GptRepair::RelocateBackup => "relocate-backup",
+        }
+    }
+
+    pub fn description(&self) -> &'static str {
+        match self {
+            GptRepair::RebuildPrimary => "Rebuild primary GPT from backup",
+            GptRepair::RebuildBackup => "Rebuild backup GPT from primary",
+            GptRepair::FixCrcs => "Fix GPT header CRCs",
+            GptRepair::RelocateBackup => "Move backup GPT to the end of the disk",
+        }
+    }
+}
+
+fn is_valid(gpt: &Gpt) -> bool {
+    gpt.header.header_crc_valid && gpt.header.entries_crc_valid
+}
+
+fn entries_sectors(header: &GptHeader, sector_size: u64) -> u64 {
+    (header.entry_count as u64 * header.entry_size as u64).div_ceil(sector_size)
+}
+
+fn build_header(
+    template: &GptHeader,
+    current_lba: u64,
+    backup_lba: u64,
+    last_usable_lba: u64,
+    entries_lba: u64,
+    entries_crc: u32,
+    sector_size: usize,
+) -> Vec<u8> {
+    let mut header = template.raw.clone();
+    header[24..32].copy_from_slice(&current_lba.to_le_bytes());
+    header[32..40].copy_from_slice(&backup_lba.to_le_bytes());
+    header[48..56].copy_from_slice(&last_usable_lba.to_le_bytes());
+    header[72..80].copy_from_slice(&entries_lba.to_le_bytes());
+    header[88..92].copy_from_slice(&entries_crc.to_le_bytes());
+    let crc = gpt_header_crc(&header);
+    header[16..20].copy_from_slice(&crc.to_le_bytes());
+    header.resize(sector_size, 0);
+    header
+}
+
+fn write_lba(disk: &mut dyn Disk, lba: u64, data: &[u8], sector_size: u64) -> Result<(), String> {
+    disk.write_at(lba * sector_size, data)
+        .map_err(|err| format!("failed to write LBA {}: {}", lba, err))
+}
+
+// Copies `source` to a new location, keeping everything but the self-describing fields.
+fn write_copy(
+    disk: &mut dyn Disk,
+    source: &Gpt,
+    current_lba: u64,
+    backup_lba: u64,
+    last_usable_lba: u64,
+    entries_lba: u64,
+    sector_size: u64,
+) -> Result<(), String> {
+    let entries_crc = crc32fast::hash(&source.raw_entries);
+    write_lba(disk, entries_lba, &source.raw_entries, sector_size)?;
+    let header = build_header(
+        &source.header,
+        current_lba,
+        backup_lba,
+        last_usable_lba,
+        entries_lba,
+        entries_crc,
+        sector_size as usize,
+    );
+    write_lba(disk, current_lba, &header, sector_size)
+}
+
+fn rebuild_primary(disk: &mut dyn Disk, table: &PartitionTable) -> Result<String, String> {
+    let backup = table.backup_gpt.as_ref().map_err(|err| format!("backup GPT is unreadable: {}", err))?;
+    if !is_valid(backup) {
+        return Err("backup GPT has CRC errors and cannot be used as a source".to_string());
+    }
+    let sector_size = table.sector_size as u64;
+    let entries_lba = 2;
+    if entries_lba + entries_sectors(&backup.header, sector_size) > backup.header.first_usable_lba {
+        return Err("partition entry array does not fit before the first usable LBA".to_string());
+    }
+    write_copy(
+        disk,
+        backup,
+        1,
+        backup.header.current_lba,
+        backup.header.last_usable_lba,
+        entries_lba,
+        sector_size,
+    )?;
+    Ok(format!("Primary GPT rebuilt from the backup at LBA {}.", backup.header.current_lba))
+}
+
+fn rebuild_backup(disk: &mut dyn Disk, table: &PartitionTable) -> Result<String, String> {
+    let primary = table.primary_gpt.as_ref().map_err(|err| format!("primary GPT is unreadable: {}", err))?;
+    if !is_valid(primary) {
+        return Err("primary GPT has CRC errors and cannot be used as a source".to_string());
+    }
+    let sector_size = table.sector_size as u64;
+    let backup_lba = primary.header.backup_lba;
+    let entries_lba = backup_lba.saturating_sub(entries_sectors(&primary.header, sector_size));
+    if backup_lba >= table.total_sectors || entries_lba <= primary.header.last_usable_lba {
+        return Err(format!(
+            "primary GPT points to an invalid backup location (LBA {}); move the backup to the end of the disk instead",
+            backup_lba
+        ));
+    }
+    write_copy(
+        disk,
+        primary,
+        backup_lba,
+        primary.header.current_lba,
+        primary.header.last_usable_lba,
+        entries_lba,
+        sector_size,
+    )?;
+    Ok(format!("Backup GPT rebuilt at LBA {} from the primary.", backup_lba))
+}
+
+// A header that fails its CRC may have any field corrupted, so its CRC is only
+// recomputed when the fields are self-consistent and agree with the other copy.
+fn header_fields_valid(header: &GptHeader, other: Option<&GptHeader>, is_primary: bool, table: &PartitionTable) -> bool {
+    let total_sectors = table.total_sectors;
+    let entries_end = header.entries_lba.saturating_add(entries_sectors(header, table.sector_size as u64));
+    let location_valid = if is_primary {
+        header.current_lba == 1 && header.backup_lba > header.last_usable_lba && header.backup_lba < total_sectors
+    } else {
+        header.current_lba > header.last_usable_lba && header.current_lba < total_sectors && header.backup_lba == 1
+    };
+    let entries_valid = header.entries_lba > 1
+        && entries_end <= total_sectors
+        && (entries_end <= header.first_usable_lba || header.entries_lba > header.last_usable_lba)
+        && !(header.entries_lba..entries_end).contains(&header.current_lba);
+    let agrees = other.is_none_or(|other| {
+        other.disk_guid == header.disk_guid
+            && other.first_usable_lba == header.first_usable_lba
+            && other.last_usable_lba == header.last_usable_lba
+            && other.entry_count == header.entry_count
+            && other.entry_size == header.entry_size
+    });
+    location_valid
+        && entries_valid
+        && header.first_usable_lba <= header.last_usable_lba
+        && header.last_usable_lba < total_sectors
+        && agrees
+}
+
+// An entry array CRC is only recomputed when both arrays agree, so a corrupted entry
+// array is never silently blessed with a fresh checksum. A header whose fields do not
+// validate is restored from the intact copy instead.
+fn fix_crcs(disk: &mut dyn Disk, table: &PartitionTable) -> Result<String, String> {
+    let sector_size = table.sector_size as u64;
+    let arrays_agree = match (&table.primary_gpt, &table.backup_gpt) {
+        (Ok(primary), Ok(backup)) => primary.raw_entries == backup.raw_entries,
+        _ => false,
+    };
+    // Which array is the damaged one cannot be told apart from a deliberate edit, so
+    // the choice is left to a rebuild from the copy the user trusts.
+    for (name, gpt, other_name) in [("primary", &table.primary_gpt, "backup"), ("backup", &table.backup_gpt, "primary")] {
+        if gpt.as_ref().is_ok_and(|gpt| !gpt.header.entries_crc_valid) && !arrays_agree {
+            return Err(format!(
+                "the {} partition entry array fails its CRC and differs from the {} copy; rebuild the {} GPT from the {} instead",
+                name, other_name, name, other_name
+            ));
+        }
+    }
+    let mut fixed = Vec::new();
+    let mut restored = Vec::new();
+    for (name, gpt, other) in [
+        ("primary", &table.primary_gpt, &table.backup_gpt),
+        ("backup", &table.backup_gpt, &table.primary_gpt),
+    ] {
+        let gpt = match gpt {
+            Ok(gpt) => gpt,
+            Err(_) => continue,
+        };
+        let header = &gpt.header;
+        let entries_crc = crc32fast::hash(&gpt.raw_entries);
+        if header.header_crc_valid && entries_crc == header.entries_crc {
+            continue;
+        }
+        let other = other.as_ref().ok();
+        let is_primary = name == "primary";
+        if !header_fields_valid(header, other.map(|other| &other.header), is_primary, table) {
+            let intact = other.filter(|other| {
+                is_valid(other) && header_fields_valid(&other.header, None, !is_primary, table)
+            });
+            if intact.is_none() {
+                return Err(format!(
+                    "{} GPT header fields are inconsistent and no intact copy exists to restore it from",
+                    name
+                ));
+            }
+            if is_primary {
+                rebuild_primary(disk, table)?;
+            } else {
+                rebuild_backup(disk, table)?;
+            }
+            restored.push(name);
+            continue;
+        }
+        let rebuilt = build_header(
+            header,
+            header.current_lba,
+            header.backup_lba,
+            header.last_usable_lba,
+            header.entries_lba,
+            entries_crc,
+            sector_size as usize,
+        );
+        write_lba(disk, header.current_lba, &rebuilt, sector_size)?;
+        fixed.push(name);
+    }
+    let mut messages = Vec::new();
+    if !fixed.is_empty() {
+        messages.push(format!("Recomputed CRCs of the {} GPT header(s).", fixed.join(" and ")));
+    }
+    if !restored.is_empty() {
+        messages.push(format!("Restored the {} GPT header(s) from the intact copy.", restored.join(" and ")));
+    }
+    if messages.is_empty() {
+        Ok("All readable GPT headers already have valid CRCs.".to_string())
+    } else {
+        Ok(messages.join(" "))
+    }
+}
+
+fn relocate_backup(disk: &mut dyn Disk, table: &PartitionTable) -> Result<String, String> {
+    let source = match (&table.primary_gpt, &table.backup_gpt) {
+        (Ok(primary), _) if is_valid(primary) => primary,
+        (_, Ok(backup)) if is_valid(backup) => backup,
+        _ => return Err("neither GPT copy is valid; rebuild one first".to_string()),
+    };
+    let sector_size = table.sector_size as u64;
+    let too_small = || format!("a disk of {} sectors is too small for a backup GPT", table.total_sectors);
+    let new_backup_lba = table.total_sectors.checked_sub(1).ok_or_else(too_small)?;
+    let new_entries_lba = new_backup_lba
+        .checked_sub(entries_sectors(&source.header, sector_size))
+        .ok_or_else(too_small)?;
+    let new_last_usable = new_entries_lba.checked_sub(1).ok_or_else(too_small)?;
+    if new_last_usable < source.header.first_usable_lba {
+        return Err(too_small());
+    }
+    let old_backup_lba = table.backup_gpt.as_ref().map(|gpt| gpt.header.current_lba).unwrap_or(source.header.backup_lba);
+    if let Some(last_lba) = source.entries.iter().map(|entry| entry.last_lba).max() {
+        if last_lba > new_last_usable {
+            return Err(format!("partition ending at LBA {} would overlap the relocated backup GPT", last_lba));
+        }
+    }
+
+    write_copy(disk, source, new_backup_lba, 1, new_last_usable, new_entries_lba, sector_size)?;
+    write_copy(disk, source, 1, new_backup_lba, new_last_usable, 2, sector_size)?;
+    if old_backup_lba != new_backup_lba
+        && old_backup_lba > source.header.last_usable_lba
+        && old_backup_lba < table.total_sectors
+    {
+        write_lba(disk, old_backup_lba, &vec![0u8; sector_size as usize], sector_size)?;
+    }
+
+    let mut message = format!("Backup GPT moved to LBA {}, last usable LBA is now {}.", new_backup_lba, new_last_usable);
+    // A hybrid MBR deliberately covers only part of the disk with its 0xEE entry.
+    match table.mbr.as_ref().map(|mbr| mbr.primary.as_slice()) {
+        Some([protective]) if protective.partition_type == MBR_PROTECTIVE => {
+            let sector_count = new_backup_lba.min(u32::MAX as u64) as u32;
+            let offset = 446 + (protective.slot as u64 - 1) * 16 + 12;
+            disk.write_at(offset, &sector_count.to_le_bytes())
+                .map_err(|err| format!("failed to update the protective MBR: {}", err))?;
+            message.push_str(" Protective MBR resized to cover the disk.");
+        }
+        Some(entries) if entries.iter().any(|entry| entry.partition_type == MBR_PROTECTIVE) => {
+            message.push_str(" Hybrid MBR left unchanged.");
+        }
+        _ => {}
+    }
+    Ok(message)
+}
+
+pub fn repair_gpt(disk: &mut dyn Disk, action: GptRepair) -> Result<String, String> {
+    let table = read_partition_table(disk).map_err(|err| format!("failed to read the partition table: {}", err))?;
+    if !table.has_gpt() {
+        return Err("no GPT found on this disk".to_string());
+    }
+    let message = match action {
+        GptRepair::RebuildPrimary => rebuild_primary(disk, &table)?,
+        GptRepair::RebuildBackup => rebuild_backup(disk, &table)?,
+        GptRepair::FixCrcs => fix_crcs(disk, &table)?,
+        GptRepair::RelocateBackup => relocate_backup(disk, &table)?,
+    };
+    disk.flush().map_err(|err| format!("failed to flush changes: {}", err))?;
+    Ok(message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::layout_check::check_disk_layout;
+    use crate::partition_table::tests::{gpt_disk, MemoryDisk, SECTOR_SIZE};
+    use crate::partition_table::read_u32;
+
+    fn assert_clean(disk: &mut MemoryDisk) {
+        let problems: Vec<String> = check_disk_layout(disk).unwrap().iter().map(ToString::to_string).collect();
+        assert_eq!(problems, Vec::<String>::new());
+    }
+
+    // Sets a header field and recomputes the header CRC, so only the field is wrong.
+    fn set_header_field(disk: &mut MemoryDisk, lba: u64, offset: usize, value: &[u8]) {
+        let sector = disk.sector_mut(lba);
+        sector[offset..offset + value.len()].copy_from_slice(value);
+        let crc = gpt_header_crc(&sector[..92]);
+        sector[16..20].copy_from_slice(&crc.to_le_bytes());
+    }
+
+    #[test]
+    fn rebuilds_corrupt_primary_from_backup() {
+        let mut disk = gpt_disk(8192, &[(2048, 8158)]);
+        let original = disk.0.clone();
+        disk.sector_mut(1)[24..40].fill(0xFF);
+        disk.sector_mut(2)[0..16].fill(0);
+        let message = repair_gpt(&mut disk, GptRepair::RebuildPrimary).unwrap();
+        assert_eq!(message, "Primary GPT rebuilt from the backup at LBA 8191.");
+        assert_eq!(disk.0, original);
+        assert_clean(&mut disk);
+    }
+
+    #[test]
+    fn fix_crc_restores_header_with_inconsistent_fields() {
+        let mut disk = gpt_disk(8192, &[(2048, 8158)]);
+        let original = disk.0.clone();
+        // A corrupted backup LBA with a stale CRC must not be blessed with a new one.
+        disk.sector_mut(1)[32..40].copy_from_slice(&123u64.to_le_bytes());
+        let message = repair_gpt(&mut disk, GptRepair::FixCrcs).unwrap();
+        assert_eq!(message, "Restored the primary GPT header(s) from the intact copy.");
+        assert_eq!(disk.0, original);
+    }
+
+    #[test]
+    fn fix_crc_recomputes_entry_crc_when_arrays_agree() {
+        let mut disk = gpt_disk(8192, &[(2048, 8158)]);
+        let original = disk.0.clone();
+        set_header_field(&mut disk, 1, 88, &0xDEAD_BEEFu32.to_le_bytes());
+        let message = repair_gpt(&mut disk, GptRepair::FixCrcs).unwrap();
+        assert_eq!(message, "Recomputed CRCs of the primary GPT header(s).");
+        assert_eq!(disk.0, original);
+        assert_eq!(repair_gpt(&mut disk, GptRepair::FixCrcs).unwrap(), "All readable GPT headers already have valid CRCs.");
+    }
+
+    #[test]
+    fn fix_crc_refuses_disagreeing_entry_arrays() {
+        let mut disk = gpt_disk(8192, &[(2048, 8158)]);
+        disk.write_at(2 * SECTOR_SIZE + 40, &4095u64.to_le_bytes()).unwrap();
+        let before = disk.0.clone();
+        let error = repair_gpt(&mut disk, GptRepair::FixCrcs).unwrap_err();
+        assert_eq!(
+            error,
+            "the primary partition entry array fails its CRC and differs from the backup copy; rebuild the primary GPT from the backup instead"
+        );
+        assert_eq!(disk.0, before);
+        repair_gpt(&mut disk, GptRepair::RebuildPrimary).unwrap();
+        assert_clean(&mut disk);
+    }
+
+    #[test]
+    fn relocates_backup_after_growing_the_disk() {
+        let mut disk = gpt_disk(4096, &[(2048, 4062)]);
+        disk.0.resize(8192 * SECTOR_SIZE as usize, 0);
+        let old_backup = disk.sector(4095).to_vec();
+        assert_eq!(&old_backup[0..8], b"EFI PART");
+
+        let message = repair_gpt(&mut disk, GptRepair::RelocateBackup).unwrap();
+        assert_eq!(message, "Backup GPT moved to LBA 8191, last usable LBA is now 8158. Protective MBR resized to cover the disk.");
+        assert!(disk.sector(4095).iter().all(|byte| *byte == 0));
+        assert_eq!(read_u32(disk.sector(0), 446 + 12), 8191);
+
+        let table = read_partition_table(&mut disk).unwrap();
+        let (primary, backup) = (table.primary_gpt.as_ref().unwrap(), table.backup_gpt.as_ref().unwrap());
+        assert_eq!((primary.header.backup_lba, primary.header.last_usable_lba), (8191, 8158));
+        assert_eq!((backup.header.current_lba, backup.header.entries_lba), (8191, 8159));
+        assert_eq!(primary.entries.len(), 1);
+        assert_clean(&mut disk);
+    }
+
+    #[test]
+    fn relocation_refuses_partitions_past_the_new_backup() {
+        let mut disk = gpt_disk(8192, &[(2048, 8158)]);
+        disk.0.truncate(8000 * SECTOR_SIZE as usize);
+        let error = repair_gpt(&mut disk, GptRepair::RelocateBackup).unwrap_err();
+        assert_eq!(error, "partition ending at LBA 8158 would overlap the relocated backup GPT");
+    }
+}
@@ -0,0 +1,388 @@
+use crate::alignment::{analyze_alignment, format_offset, SectorInfo};
+use crate::disk::Disk;
+use crate::partition_table::{is_extended_type, read_partition_table, Gpt, PartitionTable, MBR_PROTECTIVE};
+use std::collections::HashMap;
+use std::fmt;
+use std::io;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Severity {
+    Error,
+    Warning,
+    Info,
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Severity::Error => write!(f, "ERROR"),
+            Severity::Warning => write!(f, "WARNING"),
+            Severity::Info => write!(f, "INFO"),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct LayoutProblem {
+    pub severity: Severity,
+    pub partition: Option<String>,
+    pub message: String,
+}
+
+impl fmt::Display for LayoutProblem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.partition {
+            Some(partition) => write!(f, "[{}] {}: {}", self.severity, partition, self.message),
+            None => write!(f, "[{}] {}", self.severity, self.message),
+        }
+    }
+}
+
+struct Checker {
+    problems: Vec<LayoutProblem>,
+}
+
+impl Checker {
+    fn report(&mut self, severity: Severity, partition: Option<String>, message: String) {
+        self.problems.push(LayoutProblem { severity, partition, message });
+    }
+
+    fn check_overlaps(&mut self, ranges: &[(String, u64, u64)]) {
+        for (i, (label_a, start_a, end_a)) in ranges.iter().enumerate() {
+            for (label_b, start_b, end_b) in &ranges[i + 1..] {
+                if start_a <= end_b && start_b <= end_a {
+                    self.report(
+                        Severity::Error,
+                        Some(label_a.clone()),
+                        format!("overlaps {} (LBA {}-{} and {}-{})", label_b, start_a, end_a, start_b, end_b),
+                    );
+                }
+            }
+        }
+    }
+
+    fn check_disk_end(&mut self, ranges: &[(String, u64, u64)], total_sectors: u64) {
+        for (label, _, end) in ranges {
+            if *end >= total_sectors {
+                self.report(
+                    Severity::Error,
+                    Some(label.clone()),
+                    format!("ends at LBA {} but the disk has only {} sectors", end, total_sectors),
+                );
+            }
+        }
+    }
+
+    fn check_mbr(&mut self, table: &PartitionTable) {
+        let mbr = match &table.mbr {
+            Some(mbr) => mbr,
+            None => {
+                if !table.has_gpt() {
+                    self.report(Severity::Info, None, "no MBR boot signature and no GPT found".to_string());
+                }
+                return;
+            }
+        };
+
+        if let Some(lba) = mbr.ebr_loop_at {
+            self.report(Severity::Error, None, format!("EBR chain loops back to LBA {}", lba));
+        }
+        for error in &mbr.chain_errors {
+            self.report(Severity::Error, None, error.clone());
+        }
+        if mbr.primary.iter().filter(|entry| is_extended_type(entry.partition_type)).count() > 1 {
+            self.report(Severity::Error, None, "more than one extended partition in the MBR".to_string());
+        }
+
+        if !mbr.is_protective() {
+            let mut ranges: Vec<(String, u64, u64)> = mbr
+                .primary
+                .iter()
+                .chain(&mbr.logical)
+                .filter(|entry| !is_extended_type(entry.partition_type))
+                .map(|entry| (entry.label(), entry.start_lba, entry.end_lba()))
+                .collect();
+            self.check_overlaps(&ranges);
+            ranges.extend(mbr.extended().map(|entry| (entry.label(), entry.start_lba, entry.end_lba())));
+            self.check_disk_end(&ranges, table.total_sectors);
+
+            if let Some(extended) = mbr.extended() {
+                for logical in &mbr.logical {
+                    if logical.start_lba < extended.start_lba || logical.end_lba() > extended.end_lba() {
+                        self.report(Severity::Error, Some(logical.label()), "lies outside the extended partition".to_string());
+                    }
+                }
+            }
+        }
+    }
+
+    fn check_protective_mbr(&mut self, table: &PartitionTable, gpt: &Gpt) {
+        let mbr = match &table.mbr {
+            Some(mbr) => mbr,
+            None => {
+                self.report(Severity::Warning, None, "GPT disk has no protective MBR".to_string());
+                return;
+            }
+        };
+        let protective = match mbr.primary.iter().find(|entry| entry.partition_type == MBR_PROTECTIVE) {
+            Some(entry) => entry,
+            None => {
+                self.report(Severity::Error, None, "GPT disk has an MBR without a 0xEE protective partition".to_string());
+                return;
+            }
+        };
+        let others: Vec<_> = mbr.primary.iter().filter(|entry| entry.partition_type != MBR_PROTECTIVE).collect();
+        let expected = table.total_sectors.saturating_sub(1).min(u32::MAX as u64);
+        if protective.start_lba != 1 {
+            self.report(
+                Severity::Error,
+                Some(protective.label()),
+                format!("protective partition starts at LBA {} instead of 1", protective.start_lba),
+            );
+        }
+        // A hybrid MBR shrinks the 0xEE entry to make room for the mirrored partitions.
+        if protective.sector_count < expected && others.is_empty() {
+            self.report(
+                Severity::Warning,
+                Some(protective.label()),
+                format!("protective partition covers {} sectors, disk needs {}", protective.sector_count, expected),
+            );
+        } else if protective.sector_count > expected && protective.sector_count != u32::MAX as u64 {
+            self.report(
+                Severity::Error,
+                Some(protective.label()),
+                format!("protective partition covers {} sectors, beyond the disk end", protective.sector_count),
+            );
+        }
+
+        if others.is_empty() {
+            return;
+        }
+        self.report(Severity::Info, None, format!("hybrid MBR layout with {} mirrored partition(s)", others.len()));
+        for entry in others {
+            let matching = gpt
+                .entries
+                .iter()
+                .any(|gpt_entry| gpt_entry.first_lba == entry.start_lba && gpt_entry.sector_count() == entry.sector_count);
+            if !matching {
+                self.report(
+                    Severity::Warning,
+                    Some(entry.label()),
+                    format!(
+                        "hybrid MBR entry (LBA {}-{}) does not mirror any GPT partition",
+                        entry.start_lba,
+                        entry.end_lba()
+                    ),
+                );
+            }
+            if entry.start_lba < gpt.header.first_usable_lba {
+                self.report(Severity::Error, Some(entry.label()), "hybrid MBR entry overlaps the GPT structures".to_string());
+            }
+        }
+    }
+
+    fn check_gpt(&mut self, table: &PartitionTable, gpt: &Gpt, which: &str) {
+        let header = &gpt.header;
+        if !header.header_crc_valid {
+            self.report(Severity::Error, None, format!("{} GPT header CRC mismatch", which));
+        }
+        if !header.entries_crc_valid {
+            self.report(Severity::Error, None, format!("{} GPT partition entry array CRC mismatch", which));
+        }
+        if header.first_usable_lba > header.last_usable_lba || header.last_usable_lba >= table.total_sectors {
+            self.report(
+                Severity::Error,
+                None,
+                format!(
+                    "{} GPT usable range LBA {}-{} is invalid for a disk of {} sectors",
+                    which, header.first_usable_lba, header.last_usable_lba, table.total_sectors
+                ),
+            );
+        }
+    }
+
+    fn check_gpt_entries(&mut self, table: &PartitionTable, gpt: &Gpt) {
+        let header = &gpt.header;
+        let ranges: Vec<(String, u64, u64)> =
+            gpt.entries.iter().map(|entry| (entry.label(), entry.first_lba, entry.last_lba)).collect();
+        self.check_overlaps(&ranges);
+        self.check_disk_end(&ranges, table.total_sectors);
+
+        for entry in &gpt.entries {
+            if entry.first_lba > entry.last_lba {
+                self.report(
+                    Severity::Error,
+                    Some(entry.label()),
+                    format!("first LBA {} is after last LBA {}", entry.first_lba, entry.last_lba),
+                );
+            }
+            if entry.first_lba < header.first_usable_lba || entry.last_lba > header.last_usable_lba {
+                self.report(
+                    Severity::Error,
+                    Some(entry.label()),
+                    format!(
+                        "LBA {}-{} is outside the usable range {}-{}",
+                        entry.first_lba, entry.last_lba, header.first_usable_lba, header.last_usable_lba
+                    ),
+                );
+            }
+        }
+
+        let mut seen: HashMap<_, String> = HashMap::new();
+        for entry in &gpt.entries {
+            if entry.unique_guid.is_zero() {
+                self.report(Severity::Warning, Some(entry.label()), "unique partition GUID is all zeros".to_string());
+                continue;
+            }
+            if entry.unique_guid == header.disk_guid {
+                self.report(Severity::Error, Some(entry.label()), "unique partition GUID equals the disk GUID".to_string());
+            }
+            if let Some(other) = seen.insert(entry.unique_guid, entry.label()) {
+                self.report(
+                    Severity::Error,
+                    Some(entry.label()),
+                    format!("unique partition GUID {} duplicates {}", entry.unique_guid, other),
+                );
+            }
+        }
+    }
+
+    // The table only tells the logical sector size, so this checks the 1 MiB boundary;
+    // the drive view adds the physical sector and optimal I/O size.
+    fn check_alignment(&mut self, table: &PartitionTable) {
+        let sector_info = SectorInfo::from_logical(table.sector_size);
+        for partition in table.partitions() {
+            let alignment = analyze_alignment(partition.start, &sector_info);
+            if alignment.is_misaligned() {
+                self.report(
+                    Severity::Warning,
+                    Some(partition.label),
+                    format!(
+                        "starts at {}, which is not aligned; {} would be",
+                        format_offset(partition.start),
+                        format_offset(alignment.suggested_start)
+                    ),
+                );
+            }
+        }
+    }
+
+    fn compare_gpts(&mut self, table: &PartitionTable, primary: &Gpt, backup: &Gpt) {
+        let (p, b) = (&primary.header, &backup.header);
+        let last_lba = table.total_sectors.saturating_sub(1);
+        if p.disk_guid != b.disk_guid {
+            self.report(Severity::Error, None, format!("disk GUID differs: primary {}, backup {}", p.disk_guid, b.disk_guid));
+        }
+        if p.first_usable_lba != b.first_usable_lba || p.last_usable_lba != b.last_usable_lba {
+            self.report(Severity::Error, None, "primary and backup GPT usable ranges differ".to_string());
+        }
+        if p.entry_count != b.entry_count || p.entry_size != b.entry_size || primary.raw_entries != backup.raw_entries {
+            self.report(Severity::Error, None, "primary and backup GPT partition entries differ".to_string());
+        }
+        if p.backup_lba != b.current_lba || b.backup_lba != p.current_lba {
+            self.report(
+                Severity::Error,
+                None,
+                format!(
+                    "header cross-references are inconsistent (primary points to LBA {}, backup is at LBA {})",
+                    p.backup_lba, b.current_lba
+                ),
+            );
+        }
+        if b.current_lba != last_lba {
+            self.report(
+                Severity::Warning,
+                None,
+                format!("backup GPT header is at LBA {}, not at the last LBA {}", b.current_lba, last_lba),
+            );
+        }
+    }
+}
+
+pub fn check_partition_table(table: &PartitionTable) -> Vec<LayoutProblem> {
+    let mut checker = Checker { problems: Vec::new() };
+    checker.check_mbr(table);
+
+    match (&table.primary_gpt, &table.backup_gpt) {
+        (Ok(primary), Ok(backup)) => {
+            checker.check_gpt(table, primary, "primary");
+            checker.check_gpt(table, backup, "backup");
+            checker.compare_gpts(table, primary, backup);
+        }
+        (Ok(primary), Err(err)) => {
+            checker.check_gpt(table, primary, "primary");
+            checker.report(Severity::Error, None, format!("backup GPT is unreadable: {}", err));
+        }
+        (Err(err), Ok(backup)) => {
+            checker.report(Severity::Error, None, format!("primary GPT is unreadable: {}", err));
+            checker.check_gpt(table, backup, "backup");
+        }
+        (Err(_), Err(_)) => {
+            if table.mbr.as_ref().is_some_and(|mbr| mbr.is_protective()) {
+                checker.report(Severity::Error, None, "protective MBR present but no readable GPT".to_string());
+            }
+        }
+    }
+    if let Some(gpt) = table.gpt() {
+        checker.check_protective_mbr(table, gpt);
+        checker.check_gpt_entries(table, gpt);
+    }
+    checker.check_alignment(table);
+
+    checker.problems.sort_by_key(|problem| problem.severity);
+    checker.problems
+}
+
+pub fn check_disk_layout(disk: &mut dyn Disk) -> io::Result<Vec<LayoutProblem>> {
+    let table = read_partition_table(disk)?;
+    Ok(check_partition_table(&table))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::partition_table::tests::{gpt_disk, MemoryDisk, SECTOR_SIZE};
+
+    fn problems(disk: &mut MemoryDisk) -> Vec<String> {
+        check_disk_layout(disk).unwrap().iter().map(ToString::to_string).collect()
+    }
+
+    #[test]
+    fn clean_layout_has_no_problems() {
+        assert_eq!(problems(&mut gpt_disk(8192, &[(2048, 4095), (4096, 8158)])), Vec::<String>::new());
+    }
+
+    #[test]
+    fn flags_overlapping_partitions() {
+        let found = problems(&mut gpt_disk(8192, &[(2048, 6143), (4096, 8158)]));
+        assert_eq!(found, ["[ERROR] GPT partition 1: overlaps GPT partition 2 (LBA 2048-6143 and 4096-8158)"]);
+    }
+
+    #[test]
+    fn flags_misaligned_partitions() {
+        let found = problems(&mut gpt_disk(8192, &[(2048, 4095), (4159, 8158)]));
+        assert_eq!(found, ["[WARNING] GPT partition 2: starts at 2129408 B, which is not aligned; 2 MiB would be"]);
+    }
+
+    #[test]
+    fn flags_partitions_outside_the_usable_range() {
+        let found = problems(&mut gpt_disk(8192, &[(2048, 8170)]));
+        assert_eq!(found, ["[ERROR] GPT partition 1: LBA 2048-8170 is outside the usable range 34-8158"]);
+    }
+
+    #[test]
+    fn flags_differing_gpt_copies() {
+        let mut disk = gpt_disk(8192, &[(2048, 4095)]);
+        // Changes the last LBA of the first backup entry without fixing its CRC.
+        let backup_entries = 8192 - 1 - 32;
+        disk.write_at(backup_entries * SECTOR_SIZE + 40, &4000u64.to_le_bytes()).unwrap();
+        let found = problems(&mut disk);
+        assert_eq!(
+            found,
+            [
+                "[ERROR] backup GPT partition entry array CRC mismatch",
+                "[ERROR] primary and backup GPT partition entries differ",
+            ]
+        );
+    }
+}
@@ -5,7 +5,9 @@ use crate::egui::Rect;
 use crate::egui::Ui;
 use core::mem::size_of;
 use winapi::um::winioctl::IOCTL_VOLUME_GET_VOLUME_DISK_EXTENTS;
+use std::collections::{HashMap, HashSet};
 use std::ptr::null_mut;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{Arc, Mutex};
 use widestring::U16CString;
 use winapi::um::fileapi::{CreateFileW, GetDiskFreeSpaceExW, GetLogicalDriveStringsW};
@@ -15,11 +17,42 @@ use winapi::um::ioapiset::DeviceIoControl;
 use winapi::um::winioctl::{IOCTL_DISK_GET_DRIVE_GEOMETRY_EX, DISK_GEOMETRY_EX};
 use winapi::um::errhandlingapi::GetLastError;
 use winapi::um::winioctl::{IOCTL_STORAGE_QUERY_PROPERTY, STORAGE_PROPERTY_QUERY, StorageDeviceProperty};
+use winapi::um::winioctl::{STORAGE_PROPERTY_ID, StorageAccessAlignmentProperty, StorageDeviceLBProvisioningProperty};
 use winapi::um::winnt::ULARGE_INTEGER;
 use eframe::{egui, NativeOptions};
 use winapi::um::winioctl::{DRIVE_LAYOUT_INFORMATION_EX, PARTITION_INFORMATION_EX, IOCTL_DISK_GET_DRIVE_LAYOUT_EX};
 use egui::Color32;
 use crate::egui::Vec2;
+use crate::alignment::{analyze_alignment, format_offset, PartitionAlignment, SectorInfo};
+use crate::carving::{builtin_signatures, carve, parse_signatures, parse_size, unallocated_ranges, CarveOptions, CarveSummary, Signature};
+use crate::device::identify::{AtaIdentity, HiddenArea};
+use crate::device::nvme::{NvmeController, NvmeHealth, NvmeNamespace};
+use crate::device::smart::SmartData;
+use crate::device::{read_identify_pages, read_nvme_pages, read_smart_pages, Verdict};
+use crate::disk::{open_disk, Disk, PhysicalDrive};
+use crate::duplicates::{find_duplicates, quarantine, undo_quarantine, write_csv, DuplicateReport, DuplicateStage, MoveSummary};
+use crate::disk_usage::{format_size, squarify, extension_of, ExtensionTotal, ScanProgress, SizeTree, SortKey, TreemapRect};
+use crate::filesystem::cluster_map::{ClusterMap, ClusterState, StateCounts};
+use crate::filesystem::recovery::{restore, scan_deleted, DeletedFile, Recoverability, RestoreSummary};
+use crate::filesystem::{export, find_entry, format_extents, list_volumes, open_filesystem, ExportSummary, Extent, FileEntry, FileSystem, SpaceUsage, Volume};
+use crate::gpt_repair::{repair_gpt, GptRepair};
+use crate::layout_check::{check_disk_layout, LayoutProblem, Severity};
+use crate::vdisk::convert::{convert_image, verify_conversion, ContentCheck, ConversionSummary, ImageFormat};
+use crate::vdisk::ewf::{self, verify_ewf, HashCheck};
+use crate::vdisk::{open_virtual_disk, VirtualDrive};
+
+mod alignment;
+mod carving;
+mod cli;
+mod device;
+mod disk;
+mod disk_usage;
+mod duplicates;
+mod filesystem;
+mod gpt_repair;
+mod layout_check;
+mod partition_table;
+mod vdisk;
 
 #[repr(C)]
 struct STORAGE_DEVICE_DESCRIPTOR {
@@ -38,6 +71,30 @@ struct STORAGE_DEVICE_DESCRIPTOR {
     RawDeviceProperties: [u8; 1],
 }
 
+#[repr(C)]
+struct STORAGE_ACCESS_ALIGNMENT_DESCRIPTOR {
+    Version: u32,
+    Size: u32,
+    BytesPerCacheLine: u32,
+    BytesOffsetForCacheAlignment: u32,
+    BytesPerLogicalSector: u32,
+    BytesPerPhysicalSector: u32,
+    BytesOffsetForSectorAlignment: u32,
+}
+
+// The bit fields after Size are packed into Flags.
+#[repr(C)]
+struct DEVICE_LB_PROVISIONING_DESCRIPTOR {
+    Version: u32,
+    Size: u32,
+    Flags: u8,
+    Reserved1: [u8; 7],
+    OptimalUnmapGranularity: u64,
+    UnmapGranularityAlignment: u64,
+    MaxUnmapLbaCount: u32,
+    MaxUnmapBlockDescriptorCount: u32,
+}
+
 #[repr(C)]
 struct VOLUME_DISK_EXTENTS {
     NumberOfDiskExtents: u32,
@@ -73,6 +130,62 @@ fn get_drive_geometry(handle: winapi::um::winnt::HANDLE) -> Option<DISK_GEOMETRY
     }
 }
 
+fn query_storage_property(handle: winapi::um::winnt::HANDLE, property_id: STORAGE_PROPERTY_ID) -> Option<Vec<u8>> {
+    let mut query = STORAGE_PROPERTY_QUERY {
+        PropertyId: property_id,
+        QueryType: 0,
+        AdditionalParameters: [0; 1],
+    };
+    let mut buffer = vec![0u8; 1024];
+    let mut bytes_returned: u32 = 0;
+    let result = unsafe {
+        DeviceIoControl(
+            handle,
+            IOCTL_STORAGE_QUERY_PROPERTY,
+            &mut query as *mut _ as *mut _,
+            std::mem::size_of::<STORAGE_PROPERTY_QUERY>() as u32,
+            buffer.as_mut_ptr() as *mut _,
+            buffer.len() as u32,
+            &mut bytes_returned,
+            null_mut(),
+        )
+    };
+    if result == 0 {
+        None
+    } else {
+        buffer.truncate(bytes_returned as usize);
+        Some(buffer)
+    }
+}
+
+fn get_drive_sector_info(handle: winapi::um::winnt::HANDLE, bytes_per_sector: u32) -> SectorInfo {
+    let mut sector_info = SectorInfo::from_logical(bytes_per_sector);
+    if let Some(buffer) = query_storage_property(handle, StorageAccessAlignmentProperty) {
+        if buffer.len() >= size_of::<STORAGE_ACCESS_ALIGNMENT_DESCRIPTOR>() {
+            let descriptor = unsafe { &*(buffer.as_ptr() as *const STORAGE_ACCESS_ALIGNMENT_DESCRIPTOR) };
+            if descriptor.BytesPerLogicalSector != 0 {
+                sector_info.logical_sector_size = descriptor.BytesPerLogicalSector;
+            }
+            if descriptor.BytesPerPhysicalSector != 0 {
+                sector_info.physical_sector_size = descriptor.BytesPerPhysicalSector;
+            }
+            sector_info.physical_sector_offset = descriptor.BytesOffsetForSectorAlignment;
+        }
+    }
+    // Thin-provisioned and flash devices free space in units of their optimal unmap
+    // granularity, in bytes; partitions starting on it avoid splitting those units.
+    // Drives that do not report one leave it at zero or the logical sector size.
+    if let Some(buffer) = query_storage_property(handle, StorageDeviceLBProvisioningProperty) {
+        if buffer.len() >= size_of::<DEVICE_LB_PROVISIONING_DESCRIPTOR>() {
+            let descriptor = unsafe { (buffer.as_ptr() as *const DEVICE_LB_PROVISIONING_DESCRIPTOR).read_unaligned() };
+            sector_info.optimal_io_size = u32::try_from(descriptor.OptimalUnmapGranularity)
+                .ok()
+                .filter(|size| size.is_power_of_two() && *size > sector_info.logical_sector_size);
+        }
+    }
+    sector_info
+}
+
 fn get_drive_model_and_type(index: usize) -> Option<(String, String)> {
     let device_path = format!("\\\\.\\PHYSICALDRIVE{}", index);
     let device_path_utf16 = U16CString::from_str(&device_path).ok()?;
@@ -170,7 +283,7 @@ fn get_logical_drives() -> Vec<String> {
     }
     let mut start = 0;
     while start < len as usize {
-        let end = buffer[start..].iter().position(|&c| c == 0).unwrap_or(buffer.len());
+        let end = buffer[start..].iter().position(|&c| c == 0).map_or(buffer.len(), |end| start + end);
         if end > start {
             let drive = unsafe { U16CString::from_vec_unchecked(buffer[start..=end].to_vec()) };
             drives.push(drive.to_string_lossy().to_string());
@@ -239,13 +352,280 @@ fn get_logical_drives_on_physical_drive(physical_drive_index: usize) -> Vec<Stri
     logical_drives
 }
 
+#[derive(Default)]
+struct HashVerification {
+    done: u64,
+    total: u64,
+    result: Option<Result<Vec<HashCheck>, String>>,
+}
+
+#[derive(Default)]
+struct ImageConversion {
+    cancel: Arc<AtomicBool>,
+    verifying: bool,
+    done: u64,
+    total: u64,
+    summary: Option<ConversionSummary>,
+    check: Option<ContentCheck>,
+    error: Option<String>,
+    finished: bool,
+}
+
+#[derive(Default)]
+struct FileExport {
+    current: String,
+    summary: ExportSummary,
+    result: Option<Result<ExportSummary, String>>,
+}
+
+#[derive(Default)]
+struct CarvingJob {
+    cancel: Arc<AtomicBool>,
+    scanned: u64,
+    total: u64,
+    files: usize,
+    result: Option<Result<CarveSummary, String>>,
+}
+
+const CARVE_SECTOR_ALIGN: u64 = 512;
+const CARVED_FILES_HEIGHT: f32 = 200.0;
+
+// Carving settings follow the drive they were made for and start over on another one.
+struct CarvingPanel {
+    target: String,
+    // The volume whose free space is carved, or the whole drive.
+    volume: Option<usize>,
+    types: Vec<(Signature, bool)>,
+    signature_file: String,
+    every_byte: bool,
+    destination: String,
+    job: Option<Arc<Mutex<CarvingJob>>>,
+}
+
+impl CarvingPanel {
+    fn new(target: &str) -> Self {
+        Self {
+            target: target.to_string(),
+            volume: None,
+            types: builtin_signatures().into_iter().map(|signature| (signature, true)).collect(),
+            signature_file: String::new(),
+            every_byte: false,
+            destination: String::new(),
+            job: None,
+        }
+    }
+}
+
+#[derive(Default)]
+struct FolderSizeScan {
+    progress: Arc<ScanProgress>,
+    cancel: Arc<AtomicBool>,
+    result: Option<Result<SizeTree, String>>,
+}
+
+struct TreemapLayout {
+    node: usize,
+    area: Rect,
+    cells: Vec<(usize, Rect)>,
+}
+
+// One scan of a mounted volume and where the user has drilled into it. The treemap
+// layout and the reports are kept for the folder shown, as redoing them for a whole
+// volume on every frame is too slow.
+struct FolderSizes {
+    root: String,
+    scan: Arc<Mutex<FolderSizeScan>>,
+    current: usize,
+    sort: SortKey,
+    descending: bool,
+    treemap: Option<TreemapLayout>,
+    reports: Option<(usize, Vec<usize>, Vec<ExtensionTotal>)>,
+    extension_colors: HashMap<String, Color32>,
+}
+
+struct DuplicateSearch {
+    cancel: Arc<AtomicBool>,
+    stage: DuplicateStage,
+    done: u64,
+    total: u64,
+    result: Option<Result<DuplicateReport, String>>,
+}
+
+#[derive(Default)]
+struct QuarantineMove {
+    current: String,
+    result: Option<Result<MoveSummary, String>>,
+}
+
+// Duplicates are searched for across mounted drives rather than one volume, so the
+// panel lives beside the drive list. `keep` holds the copy to keep of each group.
+#[derive(Default)]
+struct DuplicatePanel {
+    drives: Vec<(String, bool)>,
+    min_size: String,
+    search: Option<Arc<Mutex<DuplicateSearch>>>,
+    keep: Vec<usize>,
+    export_path: String,
+    export_result: Option<Result<String, String>>,
+    quarantine_path: String,
+    quarantine: Option<Arc<Mutex<QuarantineMove>>>,
+}
+
+// NVMe drives do not take ATA commands, so each kind has its own health data.
+enum DriveHealth {
+    Ata(SmartData),
+    Nvme(Box<(NvmeHealth, NvmeController, NvmeNamespace)>),
+}
+
+#[derive(Default)]
+struct UsageScan {
+    result: Option<Result<SpaceUsage, String>>,
+}
+
+#[derive(Default)]
+struct DeletedFileScan {
+    result: Option<Result<Vec<DeletedFile>, String>>,
+}
+
+#[derive(Default)]
+struct FileRestore {
+    current: String,
+    result: Option<Result<RestoreSummary, String>>,
+}
+
+#[derive(Default)]
+struct ClusterMapBuild {
+    files: u64,
+    result: Option<Result<ClusterMap, String>>,
+}
+
+const MAP_COLUMNS: u64 = 64;
+const MAP_ROWS: u64 = 24;
+const MAP_ZOOM_STEP: u64 = 4;
+
+// The part of the cluster map on screen: `per_block` clusters in each block, from
+// cluster `start`. Block states are cached for the view they were computed for.
+#[derive(Default)]
+struct MapView {
+    start: u64,
+    per_block: u64,
+    blocks: Vec<ClusterState>,
+    cached: Option<(u64, u64)>,
+    totals: Option<StateCounts>,
+}
+
+impl MapView {
+    // Keeps `center` in the middle of the view where the volume allows it.
+    fn zoom(&mut self, map: &ClusterMap, center: u64, per_block: u64) {
+        let span = per_block * MAP_COLUMNS * MAP_ROWS;
+        let last_start = (map.first() + map.count()).saturating_sub(span).max(map.first());
+        self.per_block = per_block;
+        self.start = center.saturating_sub(span / 2).clamp(map.first(), last_start);
+    }
+}
+
+// The browser keeps its own handle on the drive so the tree can be expanded lazily
+// without holding on to the drive list.
+struct FileBrowser {
+    target: String,
+    volume_index: usize,
+    volume_label: String,
+    disk: Box<dyn Disk>,
+    filesystem: Box<dyn FileSystem>,
+    directories: HashMap<String, Result<Vec<FileEntry>, String>>,
+    selected: Option<FileEntry>,
+    allocation: Option<Result<Vec<Extent>, String>>,
+    // Computed from the filesystem's allocation structures, so it works without a drive
+    // letter. Large bitmaps take a while to read, so this runs on its own thread.
+    usage: Arc<Mutex<UsageScan>>,
+    export_path: String,
+    export: Option<Arc<Mutex<FileExport>>>,
+    cluster_map: Option<Arc<Mutex<ClusterMapBuild>>>,
+    map_view: MapView,
+    deleted_scan: Option<Arc<Mutex<DeletedFileScan>>>,
+    deleted_selected: HashSet<usize>,
+    restore_path: String,
+    restore: Option<Arc<Mutex<FileRestore>>>,
+}
+
+impl FileBrowser {
+    fn open(target: &str, volume_index: usize, volume: &Volume) -> std::io::Result<Self> {
+        let mut disk = open_disk(target, false)?;
+        let filesystem = open_filesystem(disk.as_mut(), volume)?;
+        let usage = Arc::new(Mutex::new(UsageScan::default()));
+        let state = usage.clone();
+        let usage_target = target.to_string();
+        std::thread::spawn(move || {
+            let result = reopen_volume(&usage_target, volume_index).and_then(|(mut disk, mut filesystem)| {
+                let bitmap = filesystem.cluster_bitmap(disk.as_mut())?;
+                Ok(SpaceUsage::from_bitmap(&bitmap, filesystem.cluster_size()))
+            });
+            state.lock().unwrap().result = Some(result.map_err(|err| err.to_string()));
+        });
+        Ok(Self {
+            target: target.to_string(),
+            volume_index,
+            volume_label: volume.describe(),
+            disk,
+            filesystem,
+            directories: HashMap::new(),
+            selected: None,
+            allocation: None,
+            usage,
+            export_path: String::new(),
+            export: None,
+            cluster_map: None,
+            map_view: MapView::default(),
+            deleted_scan: None,
+            deleted_selected: HashSet::new(),
+            restore_path: String::new(),
+            restore: None,
+        })
+    }
+
+    fn children(&mut self, dir: &FileEntry) -> Result<Vec<FileEntry>, String> {
+        if !self.directories.contains_key(&dir.path) {
+            let listing = self.filesystem.read_dir(self.disk.as_mut(), dir).map_err(|err| err.to_string());
+            self.directories.insert(dir.path.clone(), listing);
+        }
+        self.directories[&dir.path].clone()
+    }
+
+    fn select(&mut self, entry: FileEntry) {
+        self.allocation = Some(self.filesystem.allocation(self.disk.as_mut(), &entry).map_err(|err| err.to_string()));
+        self.selected = Some(entry);
+    }
+}
+
 struct HDDApp {
     drives: Arc<Mutex<Vec<(String, String)>>>,
     selected_drive: Option<usize>,
     geometry: Option<DISK_GEOMETRY_EX>,
+    sector_info: Option<SectorInfo>,
     logical_drives_on_physical: Vec<String>,
     selected_logical_drive: Option<String>,
     drive_space_info: Option<(f64, f64, f64)>,
+    layout_problems: Option<Result<Vec<LayoutProblem>, String>>,
+    allow_disk_writes: bool,
+    pending_repair: Option<GptRepair>,
+    repair_result: Option<Result<String, String>>,
+    image_path: String,
+    open_image_error: Option<String>,
+    virtual_drives: Vec<VirtualDrive>,
+    selected_virtual_drive: Option<usize>,
+    hash_verification: Option<Arc<Mutex<HashVerification>>>,
+    conversion_path: String,
+    conversion_format: ImageFormat,
+    verify_after_conversion: bool,
+    image_conversion: Option<Arc<Mutex<ImageConversion>>>,
+    physical_volumes: Option<Result<Vec<Volume>, String>>,
+    file_browser: Option<FileBrowser>,
+    file_browser_error: Option<String>,
+    carving: CarvingPanel,
+    folder_sizes: Option<FolderSizes>,
+    duplicates: DuplicatePanel,
+    health: Option<Result<DriveHealth, String>>,
+    identity: Option<Result<(AtaIdentity, HiddenArea), String>>,
 }
 
 impl Default for HDDApp {
@@ -262,10 +642,206 @@ impl Default for HDDApp {
             drives: Arc::new(Mutex::new(drives)),
             selected_drive: None,
             geometry: None,
+            sector_info: None,
             logical_drives_on_physical: Vec::new(),
             selected_logical_drive: None,
             drive_space_info: None,
+            layout_problems: None,
+            allow_disk_writes: false,
+            pending_repair: None,
+            repair_result: None,
+            image_path: String::new(),
+            open_image_error: None,
+            virtual_drives: Vec::new(),
+            selected_virtual_drive: None,
+            hash_verification: None,
+            conversion_path: String::new(),
+            conversion_format: ImageFormat::Vhdx,
+            verify_after_conversion: true,
+            image_conversion: None,
+            physical_volumes: None,
+            file_browser: None,
+            file_browser_error: None,
+            carving: CarvingPanel::new(""),
+            folder_sizes: None,
+            duplicates: DuplicatePanel::default(),
+            health: None,
+            identity: None,
+        }
+    }
+}
+
+impl HDDApp {
+    fn show_virtual_drive_section(&mut self, ui: &mut Ui) {
+        ui.heading("Virtual drives:");
+        ui.horizontal(|ui| {
+            ui.label("Image path:");
+            ui.text_edit_singleline(&mut self.image_path);
+            if ui.button("Open image").clicked() {
+                match VirtualDrive::open(self.image_path.trim()) {
+                    Ok(drive) => {
+                        self.virtual_drives.push(drive);
+                        self.open_image_error = None;
+                    }
+                    Err(err) => self.open_image_error = Some(err.to_string()),
+                }
+            }
+        });
+        if let Some(err) = &self.open_image_error {
+            ui.colored_label(Color32::RED, format!("Failed to open image: {}", err));
+        }
+        for index in 0..self.virtual_drives.len() {
+            let drive = &self.virtual_drives[index];
+            if ui.button(format!("{}: {} [{}]", index, drive.path, drive.disk.format_name())).clicked() {
+                self.selected_virtual_drive = Some(index);
+                self.selected_drive = None;
+                self.selected_logical_drive = None;
+                self.layout_problems = None;
+                self.hash_verification = None;
+                self.file_browser = None;
+                self.file_browser_error = None;
+            }
+        }
+    }
+
+    fn show_virtual_drive(&mut self, ui: &mut Ui, index: usize) {
+        let drive = &mut self.virtual_drives[index];
+        ui.separator();
+        ui.heading(format!("Virtual drive {} information:", index));
+        ui.label(format!("Image: {}", drive.path));
+        ui.label(format!("Format: {}", drive.disk.format_name()));
+        for (key, value) in drive.disk.details() {
+            ui.label(format!("{}: {}", key, value));
+        }
+        let sector_info = SectorInfo::from_logical(drive.disk.sector_size());
+        ui.label(format!("Sector format: {}", sector_info.describe()));
+        ui.separator();
+
+        ui.heading("Partitions on this virtual drive:");
+        match &drive.volumes {
+            Ok(volumes) => {
+                let alignments: Vec<PartitionAlignment> = volumes
+                    .iter()
+                    .map(|volume| analyze_alignment(volume.partition.start, &sector_info))
+                    .collect();
+                let partition_data: Vec<(u64, Color32, String, bool)> = volumes
+                    .iter()
+                    .zip(&alignments)
+                    .map(|(volume, alignment)| {
+                        let misaligned = volume.partition.start != 0 && alignment.is_misaligned();
+                        (volume.partition.size, get_filesystem_color(volume.filesystem), volume.describe(), misaligned)
+                    })
+                    .collect();
+                draw_partitions_bar(ui, &partition_data, drive.disk.size());
+                ui.collapsing("Partition alignment", |ui| {
+                    for (volume, alignment) in volumes.iter().zip(&alignments) {
+                        draw_partition_alignment(ui, &volume.partition.label, volume.partition.start, alignment);
+                    }
+                });
+            }
+            Err(err) => {
+                ui.colored_label(Color32::RED, format!("Failed to read the partition table: {}", err));
+            }
+        }
+
+        ui.collapsing("Disk layout check", |ui| {
+            if ui.button("Check disk layout").clicked() {
+                self.layout_problems = Some(check_disk_layout(drive.disk.as_mut()).map_err(|err| err.to_string()));
+            }
+            draw_layout_problems(ui, &self.layout_problems);
+        });
+
+        if drive.disk.format_name() == ewf::FORMAT_NAME {
+            ui.collapsing("Acquisition hash verification", |ui| {
+                let running = self
+                    .hash_verification
+                    .as_ref()
+                    .is_some_and(|state| state.lock().unwrap().result.is_none());
+                if ui.add_enabled(!running, egui::Button::new("Verify MD5/SHA1")).clicked() {
+                    let state = Arc::new(Mutex::new(HashVerification::default()));
+                    self.hash_verification = Some(state.clone());
+                    let path = std::path::PathBuf::from(&drive.path);
+                    std::thread::spawn(move || {
+                        let result = verify_ewf(&path, |done, total| {
+                            let mut state = state.lock().unwrap();
+                            state.done = done;
+                            state.total = total;
+                        });
+                        state.lock().unwrap().result = Some(result.map_err(|err| err.to_string()));
+                    });
+                }
+                if let Some(state) = &self.hash_verification {
+                    draw_hash_verification(ui, &state.lock().unwrap());
+                }
+            });
+        }
+
+        ui.collapsing("Convert image", |ui| {
+            let running = self
+                .image_conversion
+                .as_ref()
+                .is_some_and(|state| !state.lock().unwrap().finished);
+            ui.horizontal(|ui| {
+                ui.label("Output file:");
+                ui.add(egui::TextEdit::singleline(&mut self.conversion_path).hint_text(format!("converted.{}", self.conversion_format.extension())));
+            });
+            egui::ComboBox::from_label("Output format")
+                .selected_text(self.conversion_format.description())
+                .show_ui(ui, |ui| {
+                    for format in ImageFormat::ALL {
+                        ui.selectable_value(&mut self.conversion_format, format, format.description());
+                    }
+                });
+            ui.checkbox(&mut self.verify_after_conversion, "Verify the converted image against the source");
+            ui.horizontal(|ui| {
+                let can_start = !running && !self.conversion_path.trim().is_empty();
+                if ui.add_enabled(can_start, egui::Button::new("Convert")).clicked() {
+                    let state = Arc::new(Mutex::new(ImageConversion::default()));
+                    self.image_conversion = Some(state.clone());
+                    let source_path = drive.path.clone();
+                    let destination = std::path::PathBuf::from(self.conversion_path.trim());
+                    let format = self.conversion_format;
+                    let verify = self.verify_after_conversion;
+                    std::thread::spawn(move || {
+                        let cancel = state.lock().unwrap().cancel.clone();
+                        let progress = |done, total| {
+                            let mut state = state.lock().unwrap();
+                            state.done = done;
+                            state.total = total;
+                        };
+                        let result = open_virtual_disk(&source_path).and_then(|mut source| {
+                            let summary = convert_image(source.as_mut(), &destination, format, &cancel, progress)?;
+                            state.lock().unwrap().summary = Some(summary);
+                            if verify {
+                                state.lock().unwrap().verifying = true;
+                                let check = verify_conversion(source.as_mut(), &destination, &cancel, progress)?;
+                                state.lock().unwrap().check = Some(check);
+                            }
+                            Ok(())
+                        });
+                        let mut state = state.lock().unwrap();
+                        state.error = result.err().map(|err| err.to_string());
+                        state.finished = true;
+                    });
+                }
+                if let Some(state) = &self.image_conversion {
+                    if ui.add_enabled(running, egui::Button::new("Cancel")).clicked() {
+                        state.lock().unwrap().cancel.store(true, Ordering::Relaxed);
+                    }
+                }
+            });
+            if let Some(state) = &self.image_conversion {
+                draw_image_conversion(ui, &state.lock().unwrap());
+            }
+        });
+
+        if let Ok(volumes) = &drive.volumes {
+            ui.collapsing("Browse files", |ui| {
+                show_file_browser(ui, &mut self.file_browser, &mut self.file_browser_error, &drive.path, volumes);
+            });
         }
+        let volumes = drive.volumes.as_deref().unwrap_or_default();
+        ui.collapsing("File carving", |ui| show_file_carving(ui, &mut self.carving, &drive.path, volumes));
     }
 }
 
@@ -277,6 +853,20 @@ impl eframe::App for HDDApp {
             for (index, (drive, bus_type)) in drives.iter().enumerate() {
                 if ui.button(format!("{}: {} [{}]", index, drive, bus_type)).clicked() {
                     self.selected_drive = Some(index);
+                    self.selected_virtual_drive = None;
+                    self.layout_problems = None;
+                    self.allow_disk_writes = false;
+                    self.pending_repair = None;
+                    self.repair_result = None;
+                    self.file_browser = None;
+                    self.file_browser_error = None;
+                    self.health = None;
+                    self.identity = None;
+                    self.physical_volumes = Some(
+                        PhysicalDrive::open(index, false)
+                            .and_then(|mut drive| list_volumes(&mut drive))
+                            .map_err(|err| err.to_string()),
+                    );
                     let device_path = format!("\\\\.\\PHYSICALDRIVE{}", index);
                     let device_path_utf16: Vec<u16> = device_path.encode_utf16().chain(Some(0)).collect();
                     let handle = unsafe {
@@ -292,11 +882,22 @@ impl eframe::App for HDDApp {
                     };
                     if handle != INVALID_HANDLE_VALUE {
                         self.geometry = get_drive_geometry(handle);
+                        self.sector_info = self.geometry.as_ref()
+                            .map(|geometry| get_drive_sector_info(handle, geometry.Geometry.BytesPerSector));
                         unsafe { CloseHandle(handle) };
                         self.logical_drives_on_physical = get_logical_drives_on_physical_drive(index);
                     }
                 }
             }
+            drop(drives);
+
+            ui.collapsing("Duplicate files", |ui| show_duplicates(ui, &mut self.duplicates));
+
+            ui.separator();
+            self.show_virtual_drive_section(ui);
+            if let Some(index) = self.selected_virtual_drive {
+                self.show_virtual_drive(ui, index);
+            }
 
             if let Some(index) = self.selected_drive {
                 ui.separator();
@@ -306,16 +907,55 @@ impl eframe::App for HDDApp {
                     ui.label(format!("Tracks per cylinder: {}", disk_geometry.Geometry.TracksPerCylinder));
                     ui.label(format!("Sectors per track: {}", disk_geometry.Geometry.SectorsPerTrack));
                     ui.label(format!("Bytes per sector: {}", disk_geometry.Geometry.BytesPerSector));
+                    if let Some(sector_info) = &self.sector_info {
+                        ui.label(format!("Sector format: {}", sector_info.describe()));
+                        if let Some(optimal_io_size) = sector_info.optimal_io_size {
+                            ui.label(format!("Optimal I/O size: {}", format_offset(optimal_io_size as u64)));
+                        }
+                    }
                 } else {
                     ui.label("Failed to get disk geometry.");
                 }
+                ui.collapsing("Identity (ATA)", |ui| {
+                    if ui.button("Read IDENTIFY data").clicked() {
+                        self.identity = Some(read_drive_identity(index).map_err(|err| err.to_string()));
+                    }
+                    match &self.identity {
+                        Some(Ok((identity, hidden))) => draw_identity(ui, identity, hidden),
+                        Some(Err(err)) => {
+                            ui.colored_label(Color32::RED, format!("Failed to read IDENTIFY data: {}", err));
+                        }
+                        None => {}
+                    }
+                });
+                ui.collapsing("Health", |ui| {
+                    if ui.button("Read health data").clicked() {
+                        let (model, bus_type) = self.drives.lock().unwrap().get(index).cloned().unwrap_or_default();
+                        self.health = Some(read_drive_health(index, &model, &bus_type).map_err(|err| err.to_string()));
+                    }
+                    match &self.health {
+                        Some(Ok(DriveHealth::Ata(smart))) => draw_smart(ui, smart),
+                        Some(Ok(DriveHealth::Nvme(nvme))) => draw_nvme_health(ui, &nvme.0, &nvme.1, &nvme.2),
+                        Some(Err(err)) => {
+                            ui.colored_label(Color32::RED, format!("Failed to read health data: {}", err));
+                        }
+                        None => {}
+                    }
+                });
                 ui.separator();
 
                 ui.heading("Partitions on this physical drive:");
                 let partitions = get_partitions_on_physical_drive(index);
-                let partition_data: Vec<(u64, Color32, String)> = partitions
+                let alignments: Vec<Option<PartitionAlignment>> = partitions
+                    .iter()
+                    .map(|(start, _, _, _)| self.sector_info.as_ref().map(|sector_info| analyze_alignment(*start, sector_info)))
+                    .collect();
+                let partition_data: Vec<(u64, Color32, String, bool)> = partitions
                     .iter()
-                    .map(|(size, color, label)| (*size, *color, label.clone()))
+                    .zip(&alignments)
+                    .map(|((_, size, color, label), alignment)| {
+                        (*size, *color, label.clone(), alignment.is_some_and(|alignment| alignment.is_misaligned()))
+                    })
                     .collect();
 
                 if let Some(disk_geometry) = &self.geometry {
@@ -323,6 +963,93 @@ impl eframe::App for HDDApp {
                     draw_partitions_bar(ui, &partition_data, total_disk_size);
                 }
 
+                if self.sector_info.is_some() {
+                    ui.collapsing("Partition alignment", |ui| {
+                        for ((start, _, _, label), alignment) in partitions.iter().zip(&alignments) {
+                            if let Some(alignment) = alignment {
+                                draw_partition_alignment(ui, label, *start, alignment);
+                            }
+                        }
+                    });
+                }
+
+
+                ui.collapsing("Disk layout check", |ui| {
+                    if ui.button("Check disk layout").clicked() {
+                        self.layout_problems = Some(
+                            PhysicalDrive::open(index, false)
+                                .and_then(|mut drive| check_disk_layout(&mut drive))
+                                .map_err(|err| err.to_string()),
+                        );
+                    }
+                    draw_layout_problems(ui, &self.layout_problems);
+                });
+
+                ui.collapsing("GPT repair", |ui| {
+                    ui.checkbox(&mut self.allow_disk_writes, "Allow writing to this drive");
+                    ui.add_enabled_ui(self.allow_disk_writes && self.pending_repair.is_none(), |ui| {
+                        for action in GptRepair::ALL {
+                            if ui.button(action.description()).clicked() {
+                                self.pending_repair = Some(action);
+                            }
+                        }
+                    });
+                    if let Some(action) = self.pending_repair {
+                        let model = self.drives.lock().unwrap().get(index).map(|(model, _)| model.clone()).unwrap_or_default();
+                        let mut confirmed = None;
+                        egui::Window::new("Confirm GPT repair").collapsible(false).resizable(false).show(ui.ctx(), |ui| {
+                            ui.label(format!("Drive {}: {}", index, model));
+                            ui.label(format!("Planned change: {}.", action.description()));
+                            ui.colored_label(Color32::RED, "This writes partition table sectors on the drive.");
+                            ui.horizontal(|ui| {
+                                if ui.button("Write changes").clicked() {
+                                    confirmed = Some(true);
+                                }
+                                if ui.button("Cancel").clicked() {
+                                    confirmed = Some(false);
+                                }
+                            });
+                        });
+                        if let Some(confirmed) = confirmed {
+                            self.pending_repair = None;
+                            if confirmed {
+                                self.repair_result = Some(
+                                    PhysicalDrive::open(index, true)
+                                        .map_err(|err| err.to_string())
+                                        .and_then(|mut drive| repair_gpt(&mut drive, action)),
+                                );
+                                self.layout_problems = None;
+                            }
+                        }
+                    }
+                    match &self.repair_result {
+                        Some(Ok(message)) => {
+                            ui.colored_label(Color32::GREEN, message);
+                        }
+                        Some(Err(err)) => {
+                            ui.colored_label(Color32::RED, err);
+                        }
+                        None => {}
+                    }
+                });
+
+                ui.collapsing("Browse files", |ui| match &self.physical_volumes {
+                    Some(Ok(volumes)) => {
+                        show_file_browser(ui, &mut self.file_browser, &mut self.file_browser_error, &index.to_string(), volumes);
+                    }
+                    Some(Err(err)) => {
+                        ui.colored_label(Color32::RED, format!("Failed to read the volumes: {}", err));
+                    }
+                    None => {}
+                });
+
+                ui.collapsing("File carving", |ui| {
+                    let volumes = match &self.physical_volumes {
+                        Some(Ok(volumes)) => volumes.as_slice(),
+                        _ => &[],
+                    };
+                    show_file_carving(ui, &mut self.carving, &index.to_string(), volumes);
+                });
 
                 ui.separator();
                 ui.heading("Logical Drives on this physical drive:");
@@ -347,24 +1074,29 @@ impl eframe::App for HDDApp {
                 if let Some(drive) = &self.selected_logical_drive {
                     ui.separator();
                     ui.heading(format!("Logical Drive {} information:", drive));
-                    if let Some((total_gb, free_gb, used_gb)) = &self.drive_space_info {
-                        ui.label(format!("Total space: {:.1} GB", total_gb));
-                        ui.label(format!("Free space: {:.1} GB", free_gb));
-                        ui.label(format!("Used space: {:.1} GB", used_gb));
-                        ui.horizontal(|ui| {
-                            ui.label("Usage:");
-                            let used_percent = (used_gb / total_gb) * 100.0;
-                            ui.add(egui::ProgressBar::new(used_percent as f32 / 100.0).text(format!("{:.1}%", used_percent)));
-                        });
+                    if let Some((total_gb, free_gb, used_gb)) = self.drive_space_info {
+                        draw_space_usage(ui, total_gb, free_gb, used_gb);
                     } else {
                         ui.label("Failed to get disk space information.");
                     }
+                    ui.collapsing("Folder sizes", |ui| show_folder_sizes(ui, &mut self.folder_sizes, drive));
                 }
             }
         });
     }
 }
 
+fn draw_space_usage(ui: &mut Ui, total_gb: f64, free_gb: f64, used_gb: f64) {
+    ui.label(format!("Total space: {:.1} GB", total_gb));
+    ui.label(format!("Free space: {:.1} GB", free_gb));
+    ui.label(format!("Used space: {:.1} GB", used_gb));
+    ui.horizontal(|ui| {
+        ui.label("Usage:");
+        let used_percent = if total_gb > 0.0 { (used_gb / total_gb) * 100.0 } else { 0.0 };
+        ui.add(egui::ProgressBar::new(used_percent as f32 / 100.0).text(format!("{:.1}%", used_percent)));
+    });
+}
+
 fn get_free_space(drive_letter: &str) -> Option<(f64, f64, f64)> {
     let path = format!("{}:\\", drive_letter);
 
@@ -400,7 +1132,7 @@ fn get_free_space(drive_letter: &str) -> Option<(f64, f64, f64)> {
     }
 }
 
-fn get_partitions_on_physical_drive(index: usize) -> Vec<(u64, Color32, String)> {
+fn get_partitions_on_physical_drive(index: usize) -> Vec<(u64, u64, Color32, String)> {
     let mut partitions = Vec::new();
     let device_path = format!("\\\\.\\PHYSICALDRIVE{}", index);
     let device_path_utf16 = U16CString::from_str(&device_path).ok().unwrap();
@@ -440,10 +1172,11 @@ fn get_partitions_on_physical_drive(index: usize) -> Vec<(u64, Color32, String)>
         let layout_info = unsafe { &*(layout_info.as_ptr() as *const DRIVE_LAYOUT_INFORMATION_EX) };
         for i in 0..layout_info.PartitionCount {
             let partition_info = unsafe { &*(layout_info.PartitionEntry.as_ptr().add(i as usize) as *const PARTITION_INFORMATION_EX) };
+            let start = unsafe { *partition_info.StartingOffset.QuadPart() as u64 };
             let size = unsafe { *partition_info.PartitionLength.QuadPart() as u64 };
             let color = get_partition_colors(partition_info.PartitionStyle as u8);
             let label = format!("Partition {}", i + 1);
-            partitions.push((size, color, label));
+            partitions.push((start, size, color, label));
         }
     }
 
@@ -451,7 +1184,7 @@ fn get_partitions_on_physical_drive(index: usize) -> Vec<(u64, Color32, String)>
     partitions
 }
 
-fn draw_partitions_bar(ui: &mut Ui, partitions: &[(u64, Color32, String)], total_disk_size: u64) {
+fn draw_partitions_bar(ui: &mut Ui, partitions: &[(u64, Color32, String, bool)], total_disk_size: u64) {
     let min_partition_width = 60.0;
 
     let bar_height = 23.0;
@@ -464,7 +1197,7 @@ fn draw_partitions_bar(ui: &mut Ui, partitions: &[(u64, Color32, String)], total
 
         let mut start_x = rect.min.x;
 
-        for (partition_size, color, file_system_type) in partitions {
+        for (partition_size, color, _, misaligned) in partitions {
             let width_ratio = (*partition_size as f32) / (total_disk_size as f32);
             let width = rect.width() * width_ratio;
 
@@ -478,7 +1211,11 @@ fn draw_partitions_bar(ui: &mut Ui, partitions: &[(u64, Color32, String)], total
 
 
                 painter.rect_filled(partition_rect, Rounding::none(), *color);
-                painter.rect_stroke(partition_rect, Rounding::none(), Stroke::new(1.0, Color32::WHITE));
+                if *misaligned {
+                    painter.rect_stroke(partition_rect.shrink(1.0), Rounding::none(), Stroke::new(2.0, Color32::RED));
+                } else {
+                    painter.rect_stroke(partition_rect, Rounding::none(), Stroke::new(1.0, Color32::WHITE));
+                }
 
 
                 let size_text = if *partition_size < 1024 * 1024 * 1024 {
@@ -507,7 +1244,7 @@ fn draw_partitions_bar(ui: &mut Ui, partitions: &[(u64, Color32, String)], total
 
         let mut label_y = rect.min.y + bar_height + 10.0;
 
-        for (partition_size, color, file_system_type) in partitions {
+        for (partition_size, color, file_system_type, misaligned) in partitions {
             let width_ratio = (*partition_size as f32) / (total_disk_size as f32);
             let percent = width_ratio * 100.0;
 
@@ -521,7 +1258,7 @@ fn draw_partitions_bar(ui: &mut Ui, partitions: &[(u64, Color32, String)], total
             painter.text(
                 Pos2::new(rect.min.x, label_y),
                 egui::Align2::LEFT_CENTER,
-                format!("{:.1}% {} ({})", percent, file_system_type, size_text),
+                format!("{:.1}% {} ({}){}", percent, file_system_type, size_text, if *misaligned { " - misaligned" } else { "" }),
                 egui::FontId::proportional(12.0),
                 *color,
             );
@@ -531,6 +1268,1171 @@ fn draw_partitions_bar(ui: &mut Ui, partitions: &[(u64, Color32, String)], total
     }
 }
 
+fn draw_partition_alignment(ui: &mut Ui, label: &str, start: u64, alignment: &PartitionAlignment) {
+    let mark = |aligned: bool| if aligned { "yes" } else { "NO" };
+    let optimal = alignment.optimal_io_aligned.map_or("n/a", mark);
+    let color = if alignment.is_misaligned() { Color32::RED } else { ui.visuals().text_color() };
+    ui.colored_label(
+        color,
+        format!(
+            "{}: start {} | physical sector: {} | 1 MiB: {} | optimal I/O: {}",
+            label,
+            format_offset(start),
+            mark(alignment.physical_sector_aligned),
+            mark(alignment.mib_aligned),
+            optimal,
+        ),
+    );
+    if alignment.is_misaligned() {
+        ui.label(format!(
+            "    Suggested aligned start: {} (byte offset {})",
+            format_offset(alignment.suggested_start),
+            alignment.suggested_start,
+        ));
+    }
+}
+
+fn draw_layout_problems(ui: &mut Ui, layout_problems: &Option<Result<Vec<LayoutProblem>, String>>) {
+    match layout_problems {
+        Some(Ok(problems)) if problems.is_empty() => {
+            ui.colored_label(Color32::GREEN, "No problems found.");
+        }
+        Some(Ok(problems)) => {
+            for problem in problems {
+                let color = match problem.severity {
+                    Severity::Error => Color32::RED,
+                    Severity::Warning => Color32::YELLOW,
+                    Severity::Info => ui.visuals().text_color(),
+                };
+                ui.colored_label(color, problem.to_string());
+            }
+        }
+        Some(Err(err)) => {
+            ui.colored_label(Color32::RED, format!("Failed to read the partition table: {}", err));
+        }
+        None => {}
+    }
+}
+
+fn read_drive_identity(index: usize) -> std::io::Result<(AtaIdentity, HiddenArea)> {
+    let pages = read_identify_pages(index)?;
+    let identity = AtaIdentity::parse(&pages.identify)?;
+    let hidden = HiddenArea::new(&identity, pages.native_sectors, pages.dco.as_deref());
+    Ok((identity, hidden))
+}
+
+fn draw_identity(ui: &mut Ui, identity: &AtaIdentity, hidden: &HiddenArea) {
+    for (key, value) in identity.details() {
+        ui.label(format!("{}: {}", key, value));
+    }
+    ui.separator();
+    for (key, value) in hidden.details() {
+        ui.label(format!("{}: {}", key, value));
+    }
+    if hidden.hidden_sectors() != 0 {
+        let size = format_size(hidden.hidden_sectors().saturating_mul(hidden.sector_size));
+        ui.colored_label(Color32::YELLOW, format!("{} at the end of the drive is hidden from the operating system", size));
+    } else if hidden.native.is_some() || hidden.factory.is_some() {
+        ui.colored_label(Color32::GREEN, "No hidden area found");
+    } else {
+        ui.label("The drive did not report its native size, so hidden areas cannot be checked");
+    }
+}
+
+fn read_drive_health(index: usize, model: &str, bus_type: &str) -> std::io::Result<DriveHealth> {
+    if bus_type == "NVMe" {
+        let pages = read_nvme_pages(index)?;
+        return Ok(DriveHealth::Nvme(Box::new((
+            NvmeHealth::parse(&pages.health)?,
+            NvmeController::parse(&pages.controller)?,
+            NvmeNamespace::parse(&pages.namespace)?,
+        ))));
+    }
+    let (data, thresholds) = read_smart_pages(index)?;
+    Ok(DriveHealth::Ata(SmartData::parse(&data, Some(&thresholds), model)?))
+}
+
+fn draw_verdict(ui: &mut Ui, verdict: Verdict, reasons: &[String]) {
+    let color = match verdict {
+        Verdict::Passed => Color32::GREEN,
+        Verdict::Warning => Color32::YELLOW,
+        Verdict::Failing => Color32::RED,
+    };
+    ui.colored_label(color, format!("Health: {}", verdict.name()));
+    for reason in reasons {
+        ui.colored_label(color, reason);
+    }
+}
+
+fn draw_nvme_health(ui: &mut Ui, health: &NvmeHealth, controller: &NvmeController, namespace: &NvmeNamespace) {
+    let (verdict, reasons) = health.assess();
+    draw_verdict(ui, verdict, &reasons);
+    for (key, value) in health.details() {
+        ui.label(format!("{}: {}", key, value));
+    }
+    ui.collapsing("Controller", |ui| {
+        for (key, value) in controller.details() {
+            ui.label(format!("{}: {}", key, value));
+        }
+    });
+    ui.collapsing("Namespace 1", |ui| {
+        for (key, value) in namespace.details() {
+            ui.label(format!("{}: {}", key, value));
+        }
+    });
+}
+
+fn draw_smart(ui: &mut Ui, smart: &SmartData) {
+    let (verdict, reasons) = smart.assess();
+    draw_verdict(ui, verdict, &reasons);
+    ui.label(smart.describe_self_test());
+    ui.label(smart.describe_offline_collection());
+    egui::Grid::new("smart_attributes").striped(true).show(ui, |ui| {
+        for heading in ["ID", "Attribute", "Type", "Value", "Worst", "Threshold", "Raw"] {
+            ui.strong(heading);
+        }
+        ui.end_row();
+        for attribute in &smart.attributes {
+            let color = if attribute.failing_now() {
+                Color32::RED
+            } else if attribute.failed_in_past() {
+                Color32::YELLOW
+            } else {
+                ui.visuals().text_color()
+            };
+            ui.colored_label(color, attribute.id.to_string());
+            ui.colored_label(color, attribute.name);
+            ui.label(attribute.kind());
+            ui.colored_label(color, attribute.current.to_string());
+            ui.label(attribute.worst.to_string());
+            ui.label(attribute.threshold.map_or("-".to_string(), |threshold| threshold.to_string()));
+            ui.label(&attribute.raw_text);
+            ui.end_row();
+        }
+    });
+}
+
+fn draw_hash_verification(ui: &mut Ui, state: &HashVerification) {
+    match &state.result {
+        None => {
+            let fraction = if state.total == 0 { 0.0 } else { state.done as f32 / state.total as f32 };
+            ui.add(egui::ProgressBar::new(fraction).text(format!("Hashing... {:.1}%", fraction * 100.0)));
+            ui.ctx().request_repaint();
+        }
+        Some(Ok(checks)) => {
+            for check in checks {
+                if check.matches() {
+                    ui.colored_label(Color32::GREEN, format!("{} verified: {}", check.algorithm, check.computed));
+                } else {
+                    ui.colored_label(
+                        Color32::RED,
+                        format!("{} MISMATCH: stored {}, computed {}", check.algorithm, check.stored, check.computed),
+                    );
+                }
+            }
+        }
+        Some(Err(err)) => {
+            ui.colored_label(Color32::RED, format!("Verification failed: {}", err));
+        }
+    }
+}
+
+fn draw_image_conversion(ui: &mut Ui, state: &ImageConversion) {
+    if !state.finished {
+        let fraction = if state.total == 0 { 0.0 } else { state.done as f32 / state.total as f32 };
+        let stage = if state.verifying { "Verifying" } else { "Converting" };
+        ui.add(egui::ProgressBar::new(fraction).text(format!("{}... {:.1}%", stage, fraction * 100.0)));
+        ui.ctx().request_repaint();
+    }
+    if let Some(summary) = &state.summary {
+        ui.label(summary.describe());
+    }
+    if let Some(check) = &state.check {
+        if check.matches() {
+            ui.colored_label(Color32::GREEN, format!("Content verified: SHA1 {}", check.converted_sha1));
+        } else {
+            ui.colored_label(
+                Color32::RED,
+                format!("Content MISMATCH: source SHA1 {}, converted SHA1 {}", check.source_sha1, check.converted_sha1),
+            );
+        }
+    }
+    if let Some(err) = &state.error {
+        ui.colored_label(Color32::RED, format!("Conversion failed: {}", err));
+    }
+}
+
+fn show_file_carving(ui: &mut Ui, panel: &mut CarvingPanel, target: &str, volumes: &[Volume]) {
+    if panel.target != target {
+        *panel = CarvingPanel::new(target);
+    }
+    let running = panel.job.as_ref().is_some_and(|job| job.lock().unwrap().result.is_none());
+    ui.add_enabled_ui(!running, |ui| {
+        ui.label("Search:");
+        ui.radio_value(&mut panel.volume, None, "The whole drive");
+        for (index, volume) in volumes.iter().enumerate() {
+            ui.radio_value(&mut panel.volume, Some(index), format!("Free space of {}", volume.describe()));
+        }
+        ui.horizontal_wrapped(|ui| {
+            ui.label("File types:");
+            for (signature, enabled) in &mut panel.types {
+                ui.checkbox(enabled, signature.name.as_str());
+            }
+        });
+        ui.horizontal(|ui| {
+            ui.label("Extra signatures file:");
+            ui.text_edit_singleline(&mut panel.signature_file);
+        });
+        ui.checkbox(&mut panel.every_byte, "Look for headers at every byte, not just sector starts (slow)");
+        ui.horizontal(|ui| {
+            ui.label("Output folder on another disk:");
+            ui.text_edit_singleline(&mut panel.destination);
+        });
+    });
+    ui.horizontal(|ui| {
+        if ui.add_enabled(!running && !panel.destination.trim().is_empty(), egui::Button::new("Start carving")).clicked() {
+            let job = Arc::new(Mutex::new(CarvingJob::default()));
+            panel.job = Some(job.clone());
+            let target = panel.target.clone();
+            let volume = panel.volume;
+            let mut signatures: Vec<Signature> = panel.types.iter().filter(|(_, enabled)| *enabled).map(|(signature, _)| signature.clone()).collect();
+            let signature_file = panel.signature_file.trim().to_string();
+            let align = if panel.every_byte { 1 } else { CARVE_SECTOR_ALIGN };
+            let destination = std::path::PathBuf::from(panel.destination.trim());
+            std::thread::spawn(move || {
+                let cancel = job.lock().unwrap().cancel.clone();
+                let result = (|| {
+                    if !signature_file.is_empty() {
+                        signatures.extend(parse_signatures(&std::fs::read_to_string(&signature_file)?)?);
+                    }
+                    let (mut disk, ranges) = match volume {
+                        Some(volume_index) => {
+                            let (mut disk, mut filesystem) = reopen_volume(&target, volume_index)?;
+                            let ranges = unallocated_ranges(filesystem.as_mut(), disk.as_mut())?;
+                            (disk, ranges)
+                        }
+                        None => {
+                            let disk = open_disk(&target, false)?;
+                            let size = disk.size();
+                            (disk, vec![(0, size)])
+                        }
+                    };
+                    job.lock().unwrap().total = ranges.iter().map(|(start, end)| end - start).sum();
+                    let options = CarveOptions { signatures, align, destination };
+                    carve(disk.as_mut(), &target, &ranges, &options, &cancel, &mut |scanned, files| {
+                        let mut job = job.lock().unwrap();
+                        job.scanned = scanned;
+                        job.files = files;
+                    })
+                })();
+                job.lock().unwrap().result = Some(result.map_err(|err: std::io::Error| err.to_string()));
+            });
+        }
+        if let Some(job) = &panel.job {
+            if ui.add_enabled(running, egui::Button::new("Cancel")).clicked() {
+                job.lock().unwrap().cancel.store(true, Ordering::Relaxed);
+            }
+        }
+    });
+    let Some(job) = &panel.job else {
+        return;
+    };
+    let job = job.lock().unwrap();
+    match &job.result {
+        None => {
+            let fraction = if job.total == 0 { 0.0 } else { job.scanned as f32 / job.total as f32 };
+            ui.add(egui::ProgressBar::new(fraction).text(format!("Scanning... {:.1}%, {} file(s) carved", fraction * 100.0, job.files)));
+            ui.ctx().request_repaint();
+        }
+        Some(Ok(summary)) => {
+            ui.colored_label(Color32::GREEN, format!("Carved {}", summary.describe()));
+            egui::ScrollArea::vertical().id_source("carved_files").max_height(CARVED_FILES_HEIGHT).show(ui, |ui| {
+                for file in &summary.files {
+                    let note = if file.truncated { " (truncated)" } else { "" };
+                    ui.label(format!("{} at offset {:012x}, {} bytes{}: {}", file.signature, file.offset, file.length, note, file.path.display()));
+                }
+            });
+        }
+        Some(Err(err)) => {
+            ui.colored_label(Color32::RED, format!("Carving failed: {}", err));
+        }
+    }
+}
+
+const TREEMAP_HEIGHT: f32 = 400.0;
+const TREEMAP_DEPTH: usize = 4;
+const TREEMAP_PADDING: f32 = 2.0;
+const SIZE_LIST_HEIGHT: f32 = 300.0;
+const SIZE_LIST_LIMIT: usize = 200;
+const SIZES_SHOWN: usize = 20;
+const EXTENSION_COLORS: [Color32; 10] = [
+    Color32::from_rgb(0, 120, 215),
+    Color32::from_rgb(0, 170, 0),
+    Color32::from_rgb(230, 140, 0),
+    Color32::from_rgb(200, 40, 40),
+    Color32::from_rgb(140, 60, 200),
+    Color32::from_rgb(0, 170, 170),
+    Color32::from_rgb(220, 200, 0),
+    Color32::from_rgb(220, 80, 160),
+    Color32::from_rgb(120, 80, 40),
+    Color32::from_rgb(100, 140, 200),
+];
+const OTHER_FILES_COLOR: Color32 = Color32::from_rgb(170, 170, 170);
+const FOLDER_COLOR: Color32 = Color32::from_rgb(70, 70, 70);
+
+fn show_folder_sizes(ui: &mut Ui, panel: &mut Option<FolderSizes>, root: &str) {
+    if panel.as_ref().is_some_and(|panel| panel.root != root) {
+        *panel = None;
+    }
+    let running = panel.as_ref().is_some_and(|panel| panel.scan.lock().unwrap().result.is_none());
+    ui.horizontal(|ui| {
+        if ui.add_enabled(!running, egui::Button::new("Scan folder sizes")).clicked() {
+            let scan = Arc::new(Mutex::new(FolderSizeScan::default()));
+            *panel = Some(FolderSizes {
+                root: root.to_string(),
+                scan: scan.clone(),
+                current: 0,
+                sort: SortKey::Size,
+                descending: true,
+                treemap: None,
+                reports: None,
+                extension_colors: HashMap::new(),
+            });
+            let root = root.to_string();
+            std::thread::spawn(move || {
+                let (cancel, progress) = {
+                    let scan = scan.lock().unwrap();
+                    (scan.cancel.clone(), scan.progress.clone())
+                };
+                let result = SizeTree::scan(std::path::Path::new(&root), &cancel, &progress);
+                scan.lock().unwrap().result = Some(result.map_err(|err| err.to_string()));
+            });
+        }
+        if let Some(panel) = panel {
+            if ui.add_enabled(running, egui::Button::new("Cancel")).clicked() {
+                panel.scan.lock().unwrap().cancel.store(true, Ordering::Relaxed);
+            }
+        }
+    });
+    let Some(panel) = panel else {
+        return;
+    };
+    let scan = panel.scan.clone();
+    let scan = scan.lock().unwrap();
+    let tree = match &scan.result {
+        None => {
+            ui.label(format!(
+                "Scanned {} folder(s) and {} file(s), {}",
+                scan.progress.directories.load(Ordering::Relaxed),
+                scan.progress.files.load(Ordering::Relaxed),
+                format_size(scan.progress.bytes.load(Ordering::Relaxed))
+            ));
+            ui.ctx().request_repaint();
+            return;
+        }
+        Some(Err(err)) => {
+            ui.colored_label(Color32::RED, format!("Scan failed: {}", err));
+            return;
+        }
+        Some(Ok(tree)) => tree,
+    };
+    if tree.cancelled {
+        ui.colored_label(Color32::YELLOW, "Scan cancelled; sizes only cover the folders listed so far");
+    }
+    if tree.unreadable > 0 || tree.skipped_links > 0 {
+        ui.colored_label(
+            Color32::YELLOW,
+            format!("{} folder(s) could not be read; {} link(s) and junction(s) not followed", tree.unreadable, tree.skipped_links),
+        );
+    }
+    // Extensions keep their colors while drilling in, ranked by size over the whole scan.
+    if panel.extension_colors.is_empty() {
+        panel.extension_colors = tree
+            .extensions(0)
+            .into_iter()
+            .zip(EXTENSION_COLORS)
+            .map(|(extension, color)| (extension.extension, color))
+            .collect();
+    }
+
+    let current = &tree.nodes[panel.current];
+    ui.horizontal(|ui| {
+        if let Some(parent) = current.parent {
+            if ui.button("Up").clicked() {
+                panel.current = parent;
+            }
+        }
+        ui.label(format!("{}: {} in {} file(s)", tree.path(panel.current).display(), format_size(current.size), current.files));
+    });
+    if let Some(error) = &current.error {
+        ui.colored_label(Color32::RED, format!("This folder could not be read: {}", error));
+    }
+    if let Some(open) = draw_treemap(ui, panel, tree) {
+        panel.current = open;
+    }
+
+    ui.collapsing("Folders and files", |ui| {
+        ui.horizontal(|ui| {
+            ui.label("Sort by:");
+            for key in SortKey::ALL {
+                ui.radio_value(&mut panel.sort, key, key.name());
+            }
+            ui.checkbox(&mut panel.descending, "Descending");
+        });
+        let mut open = None;
+        egui::ScrollArea::vertical().id_source("size_tree").max_height(SIZE_LIST_HEIGHT).show(ui, |ui| {
+            draw_size_tree(ui, tree, panel.current, panel.sort, panel.descending, &mut open);
+        });
+        if let Some(open) = open {
+            panel.current = open;
+        }
+    });
+
+    if panel.reports.as_ref().map(|(node, _, _)| *node) != Some(panel.current) {
+        panel.reports = Some((panel.current, tree.largest_files(panel.current, SIZES_SHOWN), tree.extensions(panel.current)));
+    }
+    let Some((_, largest, extensions)) = &panel.reports else {
+        return;
+    };
+    let total = tree.nodes[panel.current].size.max(1) as f64;
+    ui.collapsing("Largest files", |ui| {
+        for file in largest {
+            ui.label(format!("{:>10}  {}", format_size(tree.nodes[*file].size), tree.path(*file).display()));
+        }
+    });
+    ui.collapsing("Extensions by total size", |ui| {
+        for extension in extensions.iter().take(SIZES_SHOWN) {
+            ui.horizontal(|ui| {
+                let (swatch, _) = ui.allocate_exact_size(Vec2::splat(12.0), egui::Sense::hover());
+                let color = panel.extension_colors.get(&extension.extension).copied().unwrap_or(OTHER_FILES_COLOR);
+                ui.painter().rect_filled(swatch, Rounding::none(), color);
+                ui.label(format!(
+                    "{}: {} ({:.1}%), {} file(s)",
+                    extension.extension,
+                    format_size(extension.bytes),
+                    extension.bytes as f64 * 100.0 / total,
+                    extension.files
+                ));
+            });
+        }
+    });
+}
+
+// Returns the folder to drill into when one is clicked.
+fn draw_treemap(ui: &mut Ui, panel: &mut FolderSizes, tree: &SizeTree) -> Option<usize> {
+    let (rect, response) = ui.allocate_exact_size(Vec2::new(ui.available_width(), TREEMAP_HEIGHT), egui::Sense::click());
+    if panel.treemap.as_ref().map(|layout| (layout.node, layout.area)) != Some((panel.current, rect)) {
+        let mut cells = Vec::new();
+        layout_treemap(tree, panel.current, rect, 1, &mut cells);
+        panel.treemap = Some(TreemapLayout {
+            node: panel.current,
+            area: rect,
+            cells,
+        });
+    }
+    let Some(TreemapLayout { cells, .. }) = &panel.treemap else {
+        return None;
+    };
+    let painter = ui.painter_at(rect);
+    painter.rect_filled(rect, Rounding::none(), FOLDER_COLOR);
+    for (node, cell) in cells {
+        let color = match tree.nodes[*node].directory {
+            true => FOLDER_COLOR,
+            false => panel.extension_colors.get(&extension_of(&tree.nodes[*node].name)).copied().unwrap_or(OTHER_FILES_COLOR),
+        };
+        painter.rect(*cell, Rounding::none(), color, Stroke::new(0.5, Color32::BLACK));
+    }
+
+    // Cells are listed parents first, so the last one under the pointer is the innermost.
+    let pointer = response.hover_pos()?;
+    let (hovered, _) = cells.iter().rev().find(|(_, cell)| cell.contains(pointer))?;
+    let hovered = *hovered;
+    let clicked = response.clicked();
+    response.on_hover_ui_at_pointer(|ui| {
+        let node = &tree.nodes[hovered];
+        ui.label(tree.path(hovered).display().to_string());
+        match node.directory {
+            true => ui.label(format!("{} in {} file(s)", format_size(node.size), node.files)),
+            false => ui.label(format_size(node.size)),
+        };
+    });
+    // A click opens the folder directly inside the one shown that holds what was clicked.
+    let mut open = hovered;
+    while tree.nodes[open].parent.is_some_and(|parent| parent != panel.current) {
+        open = tree.nodes[open].parent?;
+    }
+    (clicked && tree.nodes[open].directory).then_some(open)
+}
+
+fn layout_treemap(tree: &SizeTree, node: usize, rect: Rect, depth: usize, cells: &mut Vec<(usize, Rect)>) {
+    let children: Vec<usize> = tree.nodes[node].children.iter().copied().filter(|child| tree.nodes[*child].size > 0).collect();
+    let sizes: Vec<u64> = children.iter().map(|child| tree.nodes[*child].size).collect();
+    let area = TreemapRect {
+        x: rect.min.x,
+        y: rect.min.y,
+        width: rect.width(),
+        height: rect.height(),
+    };
+    for (child, cell) in children.into_iter().zip(squarify(&sizes, area)) {
+        // Children are largest first, so once one is too small to see the rest are too.
+        if cell.width * cell.height < 1.0 {
+            break;
+        }
+        let cell = Rect::from_min_size(Pos2::new(cell.x, cell.y), Vec2::new(cell.width, cell.height));
+        cells.push((child, cell));
+        let inner = cell.shrink(TREEMAP_PADDING);
+        if tree.nodes[child].directory && depth < TREEMAP_DEPTH && inner.width() > TREEMAP_PADDING && inner.height() > TREEMAP_PADDING {
+            layout_treemap(tree, child, inner, depth + 1, cells);
+        }
+    }
+}
+
+fn draw_size_tree(ui: &mut Ui, tree: &SizeTree, node: usize, sort: SortKey, descending: bool, open: &mut Option<usize>) {
+    let total = tree.nodes[node].size.max(1) as f64;
+    let children = tree.sorted_children(node, sort, descending);
+    for child in children.iter().take(SIZE_LIST_LIMIT) {
+        let entry = &tree.nodes[*child];
+        let label = format!(
+            "{}  {}  {:.1}%  {} file(s)",
+            entry.name,
+            format_size(entry.size),
+            entry.size as f64 * 100.0 / total,
+            entry.files
+        );
+        if entry.directory {
+            egui::CollapsingHeader::new(label).id_source(("size_tree", *child)).show(ui, |ui| {
+                if ui.small_button("Show in treemap").clicked() {
+                    *open = Some(*child);
+                }
+                if let Some(error) = &entry.error {
+                    ui.colored_label(Color32::RED, error);
+                }
+                draw_size_tree(ui, tree, *child, sort, descending, open);
+            });
+        } else {
+            ui.label(label);
+        }
+    }
+    if children.len() > SIZE_LIST_LIMIT {
+        ui.label(format!("and {} more", children.len() - SIZE_LIST_LIMIT));
+    }
+}
+
+const DUPLICATE_LIST_HEIGHT: f32 = 300.0;
+
+fn show_duplicates(ui: &mut Ui, panel: &mut DuplicatePanel) {
+    if panel.drives.is_empty() {
+        panel.drives = get_logical_drives().into_iter().map(|drive| (drive, false)).collect();
+    }
+    let searching = panel.search.as_ref().is_some_and(|search| search.lock().unwrap().result.is_none());
+    let moving = panel.quarantine.as_ref().is_some_and(|state| state.lock().unwrap().result.is_none());
+    ui.add_enabled_ui(!searching && !moving, |ui| {
+        ui.horizontal_wrapped(|ui| {
+            ui.label("Drives:");
+            for (drive, selected) in &mut panel.drives {
+                ui.checkbox(selected, drive.as_str());
+            }
+            if ui.button("Refresh").clicked() {
+                panel.drives.clear();
+            }
+        });
+        ui.horizontal(|ui| {
+            ui.label("Ignore files smaller than:");
+            ui.text_edit_singleline(&mut panel.min_size);
+        });
+    });
+    let min_size = match panel.min_size.trim() {
+        "" => Some(1),
+        text => parse_size(text),
+    };
+    ui.horizontal(|ui| {
+        let roots: Vec<std::path::PathBuf> = panel.drives.iter().filter(|(_, selected)| *selected).map(|(drive, _)| drive.into()).collect();
+        let can_start = !searching && !moving && !roots.is_empty() && min_size.is_some();
+        if ui.add_enabled(can_start, egui::Button::new("Find duplicates")).clicked() {
+            let search = Arc::new(Mutex::new(DuplicateSearch {
+                cancel: Arc::new(AtomicBool::new(false)),
+                stage: DuplicateStage::Listing,
+                done: 0,
+                total: 0,
+                result: None,
+            }));
+            panel.search = Some(search.clone());
+            panel.keep.clear();
+            panel.export_result = None;
+            panel.quarantine = None;
+            let min_size = min_size.unwrap_or(1);
+            std::thread::spawn(move || {
+                let cancel = search.lock().unwrap().cancel.clone();
+                let result = find_duplicates(&roots, min_size, &cancel, &mut |stage, done, total| {
+                    let mut search = search.lock().unwrap();
+                    search.stage = stage;
+                    search.done = done;
+                    search.total = total;
+                });
+                search.lock().unwrap().result = Some(result.map_err(|err| err.to_string()));
+            });
+        }
+        if let Some(search) = &panel.search {
+            if ui.add_enabled(searching, egui::Button::new("Cancel")).clicked() {
+                search.lock().unwrap().cancel.store(true, Ordering::Relaxed);
+            }
+        }
+    });
+    if min_size.is_none() {
+        ui.colored_label(Color32::RED, "Enter a size in bytes, or with a K, M or G suffix");
+    }
+
+    let Some(search) = panel.search.clone() else {
+        return;
+    };
+    let search = search.lock().unwrap();
+    let report = match &search.result {
+        None => {
+            let fraction = if search.total == 0 { 0.0 } else { search.done as f32 / search.total as f32 };
+            ui.add(egui::ProgressBar::new(fraction).text(format!("{}... {:.1}%", search.stage.name(), fraction * 100.0)));
+            ui.ctx().request_repaint();
+            return;
+        }
+        Some(Err(err)) => {
+            ui.colored_label(Color32::RED, format!("Duplicate search failed: {}", err));
+            return;
+        }
+        Some(Ok(report)) => report,
+    };
+    if report.cancelled {
+        ui.colored_label(Color32::YELLOW, "Search cancelled");
+        return;
+    }
+    ui.label(report.describe());
+    panel.keep.resize(report.groups.len(), 0);
+    egui::ScrollArea::vertical().id_source("duplicate_groups").max_height(DUPLICATE_LIST_HEIGHT).show(ui, |ui| {
+        for (number, group) in report.groups.iter().enumerate() {
+            let title = format!(
+                "{} copies of {}, {} reclaimable",
+                group.files.len(),
+                format_size(group.size),
+                format_size(group.reclaimable())
+            );
+            egui::CollapsingHeader::new(title).id_source(("duplicate_group", number)).show(ui, |ui| {
+                ui.label(format!("SHA-256 {}", group.hash));
+                for (index, file) in group.files.iter().enumerate() {
+                    ui.radio_value(&mut panel.keep[number], index, file.display().to_string());
+                }
+            });
+        }
+    });
+
+    ui.horizontal(|ui| {
+        ui.label("Export list to CSV file:");
+        ui.text_edit_singleline(&mut panel.export_path);
+        if ui.add_enabled(!panel.export_path.trim().is_empty(), egui::Button::new("Export")).clicked() {
+            let path = panel.export_path.trim().to_string();
+            panel.export_result = Some(write_csv(report, std::path::Path::new(&path)).map(|_| path).map_err(|err| err.to_string()));
+        }
+    });
+    match &panel.export_result {
+        Some(Ok(path)) => {
+            ui.colored_label(Color32::GREEN, format!("List exported to {}", path));
+        }
+        Some(Err(err)) => {
+            ui.colored_label(Color32::RED, format!("Export failed: {}", err));
+        }
+        None => {}
+    }
+
+    // The copy marked in each group stays; the others are moved, so the space is only
+    // freed once the quarantine folder is emptied.
+    ui.horizontal(|ui| {
+        ui.label("Quarantine folder:");
+        ui.text_edit_singleline(&mut panel.quarantine_path);
+        let can_move = !moving && !panel.quarantine_path.trim().is_empty();
+        let start = if ui.add_enabled(can_move && !report.groups.is_empty(), egui::Button::new("Move duplicates to quarantine")).clicked() {
+            Some(true)
+        } else if ui.add_enabled(can_move, egui::Button::new("Undo quarantine")).clicked() {
+            Some(false)
+        } else {
+            None
+        };
+        let Some(move_duplicates) = start else {
+            return;
+        };
+        let state = Arc::new(Mutex::new(QuarantineMove::default()));
+        panel.quarantine = Some(state.clone());
+        let destination = std::path::PathBuf::from(panel.quarantine_path.trim());
+        let groups = report.groups.clone();
+        let keep = panel.keep.clone();
+        std::thread::spawn(move || {
+            let mut progress = |path: &std::path::Path| state.lock().unwrap().current = path.display().to_string();
+            let result = match move_duplicates {
+                true => quarantine(&groups, &keep, &destination, &mut progress),
+                false => undo_quarantine(&destination, &mut progress),
+            };
+            state.lock().unwrap().result = Some(result.map_err(|err| err.to_string()));
+        });
+    });
+    if let Some(state) = &panel.quarantine {
+        let state = state.lock().unwrap();
+        match &state.result {
+            None => {
+                ui.label(format!("Moving {}", state.current));
+                ui.ctx().request_repaint();
+            }
+            Some(Ok(summary)) => {
+                let color = if summary.failed.is_empty() { Color32::GREEN } else { Color32::YELLOW };
+                ui.colored_label(color, format!("Moved {}", summary.describe()));
+                for (path, error) in &summary.failed {
+                    ui.colored_label(Color32::RED, format!("{}: {}", path.display(), error));
+                }
+            }
+            Some(Err(err)) => {
+                ui.colored_label(Color32::RED, format!("Moving failed: {}", err));
+            }
+        }
+    }
+}
+
+fn show_file_browser(ui: &mut Ui, browser: &mut Option<FileBrowser>, error: &mut Option<String>, target: &str, volumes: &[Volume]) {
+    ui.horizontal_wrapped(|ui| {
+        for (index, volume) in volumes.iter().enumerate() {
+            let is_open = browser.as_ref().is_some_and(|browser| browser.target == target && browser.volume_index == index);
+            if ui.selectable_label(is_open, volume.describe()).clicked() {
+                match FileBrowser::open(target, index, volume) {
+                    Ok(opened) => {
+                        *browser = Some(opened);
+                        *error = None;
+                    }
+                    Err(err) => {
+                        *browser = None;
+                        *error = Some(err.to_string());
+                    }
+                }
+            }
+        }
+    });
+    if let Some(err) = error {
+        ui.colored_label(Color32::RED, format!("Cannot browse this volume: {}", err));
+    }
+    let Some(browser) = browser.as_mut().filter(|browser| browser.target == target) else {
+        return;
+    };
+    ui.label(format!("{} ({})", browser.volume_label, browser.filesystem.name()));
+    match &browser.usage.lock().unwrap().result {
+        Some(Ok(usage)) => {
+            const GB: f64 = 1024.0 * 1024.0 * 1024.0;
+            draw_space_usage(ui, usage.total as f64 / GB, usage.free() as f64 / GB, usage.used as f64 / GB);
+        }
+        Some(Err(err)) => {
+            ui.label(format!("Space usage unavailable: {}", err));
+        }
+        None => {
+            ui.label("Reading the allocation bitmap...");
+            ui.ctx().request_repaint();
+        }
+    }
+    ui.collapsing("Filesystem details", |ui| {
+        for (key, value) in browser.filesystem.details() {
+            ui.label(format!("{}: {}", key, value));
+        }
+    });
+    ui.collapsing("Cluster map", |ui| draw_cluster_map(ui, browser));
+    ui.collapsing("Deleted files", |ui| draw_deleted_files(ui, browser));
+    egui::ScrollArea::vertical().id_source("file_tree").max_height(300.0).show(ui, |ui| {
+        let root = browser.filesystem.root();
+        draw_directory(ui, browser, &root);
+    });
+    draw_selected_file(ui, browser);
+}
+
+// Directory bodies only run while expanded, so each directory is read the first time it is opened.
+fn draw_directory(ui: &mut Ui, browser: &mut FileBrowser, dir: &FileEntry) {
+    let title = if dir.name.is_empty() { "/".to_string() } else { dir.name.clone() };
+    egui::CollapsingHeader::new(title).id_source(&dir.path).show(ui, |ui| match browser.children(dir) {
+        Ok(children) => {
+            for child in children {
+                if child.is_dir {
+                    draw_directory(ui, browser, &child);
+                } else {
+                    let selected = browser.selected.as_ref().is_some_and(|entry| entry.path == child.path);
+                    let label = match &child.symlink {
+                        Some(target) => format!("{} -> {}", child.name, target),
+                        None => format!("{} ({} bytes)", child.name, child.size),
+                    };
+                    if ui.selectable_label(selected, label).clicked() {
+                        browser.select(child);
+                    }
+                }
+            }
+            if ui.small_button("Select folder").clicked() {
+                browser.select(dir.clone());
+            }
+        }
+        Err(err) => {
+            ui.colored_label(Color32::RED, format!("Failed to read the directory: {}", err));
+        }
+    });
+}
+
+// Background jobs open their own handle on the volume so the browser stays usable.
+fn reopen_volume(target: &str, volume_index: usize) -> std::io::Result<(Box<dyn Disk>, Box<dyn FileSystem>)> {
+    let mut disk = open_disk(target, false)?;
+    let volume = list_volumes(disk.as_mut())?
+        .into_iter()
+        .nth(volume_index)
+        .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::NotFound, "the volume no longer exists"))?;
+    let filesystem = open_filesystem(disk.as_mut(), &volume)?;
+    Ok((disk, filesystem))
+}
+
+fn draw_selected_file(ui: &mut Ui, browser: &mut FileBrowser) {
+    let Some(entry) = browser.selected.clone() else {
+        return;
+    };
+    ui.separator();
+    ui.label(format!("Selected: {}", entry.path));
+    if let Some(target) = &entry.symlink {
+        ui.label(format!("Symbolic link to: {}", target));
+    } else if !entry.is_dir {
+        ui.label(format!("Size: {} bytes", entry.size));
+    }
+    if let Some(modified) = &entry.modified {
+        ui.label(format!("Modified: {}", modified));
+    }
+    match &browser.allocation {
+        Some(Ok(extents)) => {
+            let clusters: u64 = extents.iter().map(|extent| extent.count).sum();
+            ui.label(format!(
+                "Cluster chain: {} cluster(s) of {} bytes in {} fragment(s)",
+                clusters,
+                browser.filesystem.cluster_size(),
+                extents.len()
+            ));
+            ui.label(format_extents(extents));
+        }
+        Some(Err(err)) => {
+            ui.colored_label(Color32::RED, format!("Cluster chain: {}", err));
+        }
+        None => {}
+    }
+
+    let running = browser.export.as_ref().is_some_and(|state| state.lock().unwrap().result.is_none());
+    ui.horizontal(|ui| {
+        ui.label("Export to folder:");
+        ui.text_edit_singleline(&mut browser.export_path);
+        let can_start = !running && !browser.export_path.trim().is_empty();
+        if ui.add_enabled(can_start, egui::Button::new("Export")).clicked() {
+            let state = Arc::new(Mutex::new(FileExport::default()));
+            browser.export = Some(state.clone());
+            let target = browser.target.clone();
+            let volume_index = browser.volume_index;
+            let destination = std::path::PathBuf::from(browser.export_path.trim());
+            std::thread::spawn(move || {
+                let result = reopen_volume(&target, volume_index).and_then(|(mut disk, mut filesystem)| {
+                    let entry = find_entry(filesystem.as_mut(), disk.as_mut(), &entry.path)?;
+                    export(filesystem.as_mut(), disk.as_mut(), &entry, &destination, &mut |path, summary| {
+                        let mut state = state.lock().unwrap();
+                        state.current = path.to_string();
+                        state.summary = summary.clone();
+                    })
+                });
+                state.lock().unwrap().result = Some(result.map_err(|err| err.to_string()));
+            });
+        }
+    });
+    if let Some(state) = &browser.export {
+        let state = state.lock().unwrap();
+        match &state.result {
+            None => {
+                ui.label(format!("Exporting {} ({} file(s) so far)", state.current, state.summary.files));
+                ui.ctx().request_repaint();
+            }
+            Some(Ok(summary)) => {
+                ui.colored_label(
+                    Color32::GREEN,
+                    format!("Exported {}", summary.describe()),
+                );
+            }
+            Some(Err(err)) => {
+                ui.colored_label(Color32::RED, format!("Export failed: {}", err));
+            }
+        }
+    }
+}
+
+fn draw_cluster_map(ui: &mut Ui, browser: &mut FileBrowser) {
+    let running = browser.cluster_map.as_ref().is_some_and(|state| state.lock().unwrap().result.is_none());
+    if ui.add_enabled(!running, egui::Button::new("Build cluster map")).clicked() {
+        let state = Arc::new(Mutex::new(ClusterMapBuild::default()));
+        browser.cluster_map = Some(state.clone());
+        browser.map_view = MapView::default();
+        let target = browser.target.clone();
+        let volume_index = browser.volume_index;
+        std::thread::spawn(move || {
+            let result = reopen_volume(&target, volume_index).and_then(|(mut disk, mut filesystem)| {
+                ClusterMap::build(filesystem.as_mut(), disk.as_mut(), &mut |files| state.lock().unwrap().files = files)
+            });
+            state.lock().unwrap().result = Some(result.map_err(|err| err.to_string()));
+        });
+    }
+    let Some(state) = browser.cluster_map.clone() else {
+        return;
+    };
+    let state = state.lock().unwrap();
+    let map = match &state.result {
+        None => {
+            ui.label(format!("Walking the directory tree ({} files and folders so far)", state.files));
+            ui.ctx().request_repaint();
+            return;
+        }
+        Some(Err(err)) => {
+            ui.colored_label(Color32::RED, format!("Cluster map failed: {}", err));
+            return;
+        }
+        Some(Ok(map)) => map,
+    };
+    if map.count() == 0 {
+        ui.label("The volume reports no clusters to map");
+        return;
+    }
+    let cluster_size = browser.filesystem.cluster_size();
+    let view = &mut browser.map_view;
+    let whole_volume = map.count().div_ceil(MAP_COLUMNS * MAP_ROWS).max(1);
+    let end = map.first() + map.count();
+    if view.per_block == 0 {
+        view.zoom(map, map.first(), whole_volume);
+    }
+    let totals = *view.totals.get_or_insert_with(|| map.counts(map.first(), map.count()));
+    ui.horizontal_wrapped(|ui| {
+        for cluster_state in ClusterState::ALL {
+            let (swatch, _) = ui.allocate_exact_size(Vec2::splat(12.0), egui::Sense::hover());
+            ui.painter().rect_filled(swatch, Rounding::none(), get_cluster_color(cluster_state));
+            ui.label(format!("{}: {}", cluster_state.name(), totals.get(cluster_state)));
+        }
+    });
+    if map.unreadable > 0 {
+        ui.colored_label(
+            Color32::YELLOW,
+            format!("{} files or folders could not be read; their clusters show as metadata", map.unreadable),
+        );
+    }
+
+    let span = view.per_block * MAP_COLUMNS * MAP_ROWS;
+    let center = view.start + span / 2;
+    ui.horizontal(|ui| {
+        if ui.button("Whole volume").clicked() {
+            view.zoom(map, map.first(), whole_volume);
+        }
+        if ui.add_enabled(view.per_block > 1, egui::Button::new("Zoom in")).clicked() {
+            view.zoom(map, center, (view.per_block / MAP_ZOOM_STEP).max(1));
+        }
+        if ui.add_enabled(view.per_block < whole_volume, egui::Button::new("Zoom out")).clicked() {
+            view.zoom(map, center, (view.per_block * MAP_ZOOM_STEP).min(whole_volume));
+        }
+        if ui.add_enabled(view.start > map.first(), egui::Button::new("Previous")).clicked() {
+            view.zoom(map, center.saturating_sub(span), view.per_block);
+        }
+        if ui.add_enabled(view.start + span < end, egui::Button::new("Next")).clicked() {
+            view.zoom(map, center + span, view.per_block);
+        }
+    });
+    ui.label(format!(
+        "Clusters {}-{}, {} cluster(s) of {} bytes per block; click a block to zoom in",
+        view.start,
+        (view.start + span).min(end) - 1,
+        view.per_block,
+        cluster_size
+    ));
+
+    if view.cached != Some((view.start, view.per_block)) {
+        view.blocks = (0..MAP_COLUMNS * MAP_ROWS)
+            .map(|index| view.start + index * view.per_block)
+            .take_while(|start| *start < end)
+            .map(|start| map.counts(start, view.per_block.min(end - start)).dominant())
+            .collect();
+        view.cached = Some((view.start, view.per_block));
+    }
+    let block_size = (ui.available_width() / MAP_COLUMNS as f32).floor().max(4.0);
+    let (rect, response) = ui.allocate_exact_size(
+        Vec2::new(block_size * MAP_COLUMNS as f32, block_size * MAP_ROWS as f32),
+        egui::Sense::click(),
+    );
+    let painter = ui.painter_at(rect);
+    for (index, cluster_state) in view.blocks.iter().enumerate() {
+        let min = rect.min + Vec2::new((index as u64 % MAP_COLUMNS) as f32, (index as u64 / MAP_COLUMNS) as f32) * block_size;
+        let block = Rect::from_min_size(min, Vec2::splat(block_size)).shrink(0.5);
+        painter.rect_filled(block, Rounding::none(), get_cluster_color(*cluster_state));
+    }
+
+    let block_at = |pos: Pos2| {
+        let column = ((pos.x - rect.min.x) / block_size) as u64;
+        let row = ((pos.y - rect.min.y) / block_size) as u64;
+        let index = row * MAP_COLUMNS + column;
+        (column < MAP_COLUMNS && (index as usize) < view.blocks.len()).then(|| {
+            let start = view.start + index * view.per_block;
+            (start, view.per_block.min(end - start))
+        })
+    };
+    let hovered = response.hover_pos().and_then(block_at);
+    let clicked = response.clicked().then(|| response.interact_pointer_pos()).flatten().and_then(block_at);
+    if let Some((start, count)) = hovered {
+        response.on_hover_ui_at_pointer(|ui| {
+            match count {
+                1 => ui.label(format!("Cluster {}", start)),
+                _ => ui.label(format!("Clusters {}-{}", start, start + count - 1)),
+            };
+            let counts = map.counts(start, count);
+            for cluster_state in ClusterState::ALL.into_iter().filter(|cluster_state| counts.get(*cluster_state) > 0) {
+                ui.label(format!("{}: {}", cluster_state.name(), counts.get(cluster_state)));
+            }
+            const SHOWN_OWNERS: usize = 5;
+            let owners = map.owners(start, count, SHOWN_OWNERS + 1);
+            for (name, cluster_state) in owners.iter().take(SHOWN_OWNERS) {
+                ui.colored_label(get_cluster_color(*cluster_state), *name);
+            }
+            if owners.len() > SHOWN_OWNERS {
+                ui.label("and more");
+            }
+            if owners.is_empty() && counts.get(ClusterState::Metadata) > 0 {
+                ui.label("Allocated but not claimed by any file");
+            }
+        });
+    }
+    if let Some((start, count)) = clicked {
+        if view.per_block > 1 {
+            view.zoom(map, start + count / 2, (view.per_block / MAP_ZOOM_STEP).max(1));
+        }
+    }
+}
+
+const DELETED_LIST_HEIGHT: f32 = 250.0;
+
+fn draw_deleted_files(ui: &mut Ui, browser: &mut FileBrowser) {
+    let scanning = browser.deleted_scan.as_ref().is_some_and(|state| state.lock().unwrap().result.is_none());
+    if ui.add_enabled(!scanning, egui::Button::new("Scan for deleted files")).clicked() {
+        let state = Arc::new(Mutex::new(DeletedFileScan::default()));
+        browser.deleted_scan = Some(state.clone());
+        browser.deleted_selected.clear();
+        let target = browser.target.clone();
+        let volume_index = browser.volume_index;
+        std::thread::spawn(move || {
+            let result = reopen_volume(&target, volume_index)
+                .and_then(|(mut disk, mut filesystem)| scan_deleted(filesystem.as_mut(), disk.as_mut()));
+            state.lock().unwrap().result = Some(result.map_err(|err| err.to_string()));
+        });
+    }
+    let Some(state) = browser.deleted_scan.clone() else {
+        return;
+    };
+    let state = state.lock().unwrap();
+    let files = match &state.result {
+        None => {
+            ui.label("Scanning directories and file records...");
+            ui.ctx().request_repaint();
+            return;
+        }
+        Some(Err(err)) => {
+            ui.colored_label(Color32::RED, format!("Scan failed: {}", err));
+            return;
+        }
+        Some(Ok(files)) => files,
+    };
+    ui.label(format!("{} deleted file(s) found, {} selected", files.len(), browser.deleted_selected.len()));
+    ui.horizontal(|ui| {
+        if ui.button("Select intact files").clicked() {
+            browser.deleted_selected = files
+                .iter()
+                .enumerate()
+                .filter(|(_, file)| matches!(file.recoverability, Recoverability::Intact | Recoverability::Resident))
+                .map(|(index, _)| index)
+                .collect();
+        }
+        if ui.button("Clear selection").clicked() {
+            browser.deleted_selected.clear();
+        }
+    });
+    egui::ScrollArea::vertical().id_source("deleted_files").max_height(DELETED_LIST_HEIGHT).show(ui, |ui| {
+        for (index, file) in files.iter().enumerate() {
+            ui.horizontal(|ui| {
+                let mut selected = browser.deleted_selected.contains(&index);
+                if ui.checkbox(&mut selected, &file.entry.path).changed() {
+                    match selected {
+                        true => browser.deleted_selected.insert(index),
+                        false => browser.deleted_selected.remove(&index),
+                    };
+                }
+                ui.label(format!("{} bytes, {}", file.entry.size, file.entry.modified.as_deref().unwrap_or("-")));
+                let label = ui.colored_label(get_recoverability_color(file.recoverability), file.describe_recoverability());
+                if let Some(error) = &file.error {
+                    label.on_hover_text(error);
+                }
+            });
+        }
+    });
+
+    // The destination is checked against the source drive when the restore starts.
+    let restoring = browser.restore.as_ref().is_some_and(|state| state.lock().unwrap().result.is_none());
+    ui.horizontal(|ui| {
+        ui.label("Restore to folder on another disk:");
+        ui.text_edit_singleline(&mut browser.restore_path);
+        let can_start = !restoring && !browser.deleted_selected.is_empty() && !browser.restore_path.trim().is_empty();
+        if ui.add_enabled(can_start, egui::Button::new("Restore selected")).clicked() {
+            let restore_state = Arc::new(Mutex::new(FileRestore::default()));
+            browser.restore = Some(restore_state.clone());
+            let target = browser.target.clone();
+            let volume_index = browser.volume_index;
+            let destination = std::path::PathBuf::from(browser.restore_path.trim());
+            let mut selected: Vec<usize> = browser.deleted_selected.iter().copied().collect();
+            selected.sort_unstable();
+            let entries: Vec<FileEntry> = selected.into_iter().map(|index| files[index].entry.clone()).collect();
+            std::thread::spawn(move || {
+                let result = reopen_volume(&target, volume_index).and_then(|(mut disk, mut filesystem)| {
+                    restore(filesystem.as_mut(), disk.as_mut(), &target, &entries, &destination, &mut |path| {
+                        restore_state.lock().unwrap().current = path.to_string();
+                    })
+                });
+                restore_state.lock().unwrap().result = Some(result.map_err(|err| err.to_string()));
+            });
+        }
+    });
+    if let Some(restore_state) = &browser.restore {
+        let restore_state = restore_state.lock().unwrap();
+        match &restore_state.result {
+            None => {
+                ui.label(format!("Restoring {}", restore_state.current));
+                ui.ctx().request_repaint();
+            }
+            Some(Ok(summary)) => {
+                let color = if summary.failed.is_empty() { Color32::GREEN } else { Color32::YELLOW };
+                ui.colored_label(color, format!("Restored {}", summary.describe()));
+                for (path, error) in &summary.failed {
+                    ui.colored_label(Color32::RED, format!("{}: {}", path, error));
+                }
+            }
+            Some(Err(err)) => {
+                ui.colored_label(Color32::RED, format!("Restore failed: {}", err));
+            }
+        }
+    }
+}
+
+fn get_recoverability_color(recoverability: Recoverability) -> Color32 {
+    match recoverability {
+        Recoverability::Intact | Recoverability::Resident => Color32::GREEN,
+        Recoverability::Partial => Color32::YELLOW,
+        Recoverability::Overwritten => Color32::RED,
+        Recoverability::Unknown => Color32::GRAY,
+    }
+}
+
+fn get_cluster_color(state: ClusterState) -> Color32 {
+    match state {
+        ClusterState::Free => Color32::from_rgb(220, 220, 220),
+        ClusterState::Used => Color32::from_rgb(0, 120, 215),
+        ClusterState::Metadata => Color32::from_rgb(230, 140, 0),
+        ClusterState::Fragmented => Color32::from_rgb(220, 40, 40),
+        ClusterState::Bad => Color32::from_rgb(40, 40, 40),
+    }
+}
+
 fn get_partition_colors(partition_type: u8) -> Color32 {
     match partition_type {
         0x07 => Color32::from_rgb(0, 120, 215),
@@ -540,7 +2442,22 @@ fn get_partition_colors(partition_type: u8) -> Color32 {
     }
 }
 
+fn get_filesystem_color(filesystem: Option<&str>) -> Color32 {
+    match filesystem {
+        Some("NTFS") => Color32::from_rgb(0, 120, 215),
+        Some("FAT12") | Some("FAT16") | Some("FAT32") | Some("exFAT") => Color32::from_rgb(0, 180, 0),
+        Some("ext2") | Some("ext3") | Some("ext4") | Some("XFS") | Some("Btrfs") => Color32::from_rgb(230, 140, 0),
+        Some("ISO 9660") | Some("UDF") => Color32::from_rgb(150, 80, 200),
+        Some(_) => Color32::from_rgb(0, 160, 160),
+        None => Color32::from_rgb(128, 128, 128),
+    }
+}
+
 fn main() -> Result<(), eframe::Error> {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if !args.is_empty() {
+        std::process::exit(cli::run(&args));
+    }
     let app = HDDApp::default();
     let native_options = NativeOptions {
         initial_window_size: Some(egui::vec2(600.0, 560.0)),
@@ -0,0 +1,612 @@
+use crate::disk::Disk;
+use std::collections::hash_map::RandomState;
+use std::collections::HashSet;
+use std::fmt;
+use std::hash::{BuildHasher, Hasher};
+use std::io;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+pub const GPT_SIGNATURE: &[u8; 8] = b"EFI PART";
+pub const GPT_HEADER_SIZE: usize = 92;
+pub const MBR_PROTECTIVE: u8 = 0xEE;
+const MAX_EBR_CHAIN: usize = 1024;
+const MAX_GPT_ENTRIES: u32 = 16384;
+// Entry sizes are only bounded by a multiple of 8, so the array is capped separately.
+const MAX_GPT_ARRAY_BYTES: u64 = 16 * 1024 * 1024;
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Guid(pub [u8; 16]);
+
+impl Guid {
+    pub fn from_bytes(bytes: &[u8]) -> Self {
+        let mut guid = [0u8; 16];
+        guid.copy_from_slice(&bytes[..16]);
+        Guid(guid)
+    }
+
+    pub fn is_zero(&self) -> bool {
+        self.0 == [0; 16]
+    }
+
+    // Inverse of Display: the first three groups are stored little-endian.
+    pub fn parse(text: &str) -> Option<Self> {
+        let hex: String = text.chars().filter(|c| *c != '-').collect();
+        if hex.len() != 32 {
+            return None;
+        }
+        let mut bytes = [0u8; 16];
+        for (index, byte) in bytes.iter_mut().enumerate() {
+            *byte = u8::from_str_radix(hex.get(index * 2..index * 2 + 2)?, 16).ok()?;
+        }
+        bytes[0..4].reverse();
+        bytes[4..6].reverse();
+        bytes[6..8].reverse();
+        Some(Guid(bytes))
+    }
+
+    // Version 4 GUID; RandomState is seeded from the OS, which is enough for identifiers.
+    pub fn random() -> Self {
+        let mut bytes = [0u8; 16];
+        for half in bytes.chunks_exact_mut(8) {
+            let mut hasher = RandomState::new().build_hasher();
+            hasher.write_u128(SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |time| time.as_nanos()));
+            half.copy_from_slice(&hasher.finish().to_le_bytes());
+        }
+        bytes[7] = (bytes[7] & 0x0F) | 0x40;
+        bytes[8] = (bytes[8] & 0x3F) | 0x80;
+        Guid(bytes)
+    }
+}
+
+impl fmt::Display for Guid {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let b = &self.0;
+        write!(
+            f,
+            "{:08X}-{:04X}-{:04X}-{:02X}{:02X}-{:02X}{:02X}{:02X}{:02X}{:02X}{:02X}",
+            u32::from_le_bytes([b[0], b[1], b[2], b[3]]),
+            u16::from_le_bytes([b[4], b[5]]),
+            u16::from_le_bytes([b[6], b[7]]),
+            b[8], b[9], b[10], b[11], b[12], b[13], b[14], b[15]
+        )
+    }
+}
+
+impl fmt::Debug for Guid {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(self, f)
+    }
+}
+
+pub fn is_extended_type(partition_type: u8) -> bool {
+    matches!(partition_type, 0x05 | 0x0F | 0x85)
+}
+
+#[derive(Clone, Debug)]
+pub struct MbrEntry {
+    pub slot: usize,
+    pub partition_type: u8,
+    pub start_lba: u64,
+    pub sector_count: u64,
+    pub ebr_lba: Option<u64>,
+}
+
+impl MbrEntry {
+    pub fn end_lba(&self) -> u64 {
+        self.start_lba + self.sector_count.max(1) - 1
+    }
+
+    pub fn label(&self) -> String {
+        match self.ebr_lba {
+            Some(_) => format!("Logical partition {}", self.slot),
+            None => format!("MBR partition {}", self.slot),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Mbr {
+    pub primary: Vec<MbrEntry>,
+    pub logical: Vec<MbrEntry>,
+    pub ebr_loop_at: Option<u64>,
+    pub chain_errors: Vec<String>,
+}
+
+impl Mbr {
+    pub fn is_protective(&self) -> bool {
+        self.primary.iter().any(|entry| entry.partition_type == MBR_PROTECTIVE)
+    }
+
+    pub fn extended(&self) -> Option<&MbrEntry> {
+        self.primary.iter().find(|entry| is_extended_type(entry.partition_type))
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct GptHeader {
+    pub current_lba: u64,
+    pub backup_lba: u64,
+    pub first_usable_lba: u64,
+    pub last_usable_lba: u64,
+    pub disk_guid: Guid,
+    pub entries_lba: u64,
+    pub entry_count: u32,
+    pub entry_size: u32,
+    pub entries_crc: u32,
+    pub header_crc_valid: bool,
+    pub entries_crc_valid: bool,
+    pub raw: Vec<u8>,
+}
+
+#[derive(Clone, Debug)]
+pub struct GptEntry {
+    pub index: usize,
+    pub type_guid: Guid,
+    pub unique_guid: Guid,
+    pub first_lba: u64,
+    pub last_lba: u64,
+    pub name: String,
+}
+
+impl GptEntry {
+    pub fn label(&self) -> String {
+        if self.name.is_empty() {
+            format!("GPT partition {}", self.index + 1)
+        } else {
+            format!("GPT partition {} ({})", self.index + 1, self.name)
+        }
+    }
+
+    pub fn sector_count(&self) -> u64 {
+        self.last_lba.saturating_sub(self.first_lba) + 1
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Partition {
+    pub start: u64,
+    pub size: u64,
+    pub type_name: String,
+    pub label: String,
+}
+
+#[derive(Clone, Debug)]
+pub struct Gpt {
+    pub header: GptHeader,
+    pub entries: Vec<GptEntry>,
+    pub raw_entries: Vec<u8>,
+}
+
+#[derive(Clone, Debug)]
+pub struct PartitionTable {
+    pub sector_size: u32,
+    pub total_sectors: u64,
+    pub mbr: Option<Mbr>,
+    pub primary_gpt: Result<Gpt, String>,
+    pub backup_gpt: Result<Gpt, String>,
+}
+
+impl PartitionTable {
+    pub fn has_gpt(&self) -> bool {
+        self.primary_gpt.is_ok() || self.backup_gpt.is_ok()
+    }
+
+    pub fn gpt(&self) -> Option<&Gpt> {
+        self.primary_gpt.as_ref().ok().or(self.backup_gpt.as_ref().ok())
+    }
+
+    pub fn partitions(&self) -> Vec<Partition> {
+        let sector_size = self.sector_size as u64;
+        if let Some(gpt) = self.gpt() {
+            return gpt
+                .entries
+                .iter()
+                .map(|entry| Partition {
+                    start: entry.first_lba * sector_size,
+                    size: entry.sector_count() * sector_size,
+                    type_name: gpt_type_name(&entry.type_guid),
+                    label: entry.label(),
+                })
+                .collect();
+        }
+        let Some(mbr) = &self.mbr else {
+            return Vec::new();
+        };
+        mbr.primary
+            .iter()
+            .chain(&mbr.logical)
+            .filter(|entry| !is_extended_type(entry.partition_type) && entry.partition_type != MBR_PROTECTIVE)
+            .map(|entry| Partition {
+                start: entry.start_lba * sector_size,
+                size: entry.sector_count * sector_size,
+                type_name: mbr_type_name(entry.partition_type),
+                label: entry.label(),
+            })
+            .collect()
+    }
+}
+
+pub fn mbr_type_name(partition_type: u8) -> String {
+    let name = match partition_type {
+        0x01 => "FAT12",
+        0x04 | 0x06 | 0x0E => "FAT16",
+        0x07 => "NTFS/exFAT",
+        0x0B | 0x0C => "FAT32",
+        0x27 => "Windows recovery",
+        0x82 => "Linux swap",
+        0x83 => "Linux",
+        0x8E => "Linux LVM",
+        0xA5 => "FreeBSD",
+        0xAF => "HFS+",
+        0xEE => "GPT protective",
+        0xEF => "EFI system",
+        0xFD => "Linux RAID",
+        _ => return format!("Type 0x{:02X}", partition_type),
+    };
+    name.to_string()
+}
+
+pub fn gpt_type_name(type_guid: &Guid) -> String {
+    let guid = type_guid.to_string();
+    let name = match guid.as_str() {
+        "C12A7328-F81F-11D2-BA4B-00A0C93EC93B" => "EFI system",
+        "E3C9E316-0B5C-4DB8-817D-F92DF00215AE" => "Microsoft reserved",
+        "EBD0A0A2-B9E5-4433-87C0-68B6B72699C7" => "Basic data",
+        "DE94BBA4-06D1-4D40-A16A-BFD50179D6AC" => "Windows recovery",
+        "5808C8AA-7E8F-42E0-85D2-E1E90434CFB3" => "LDM metadata",
+        "AF9B60A0-1431-4F62-BC68-3311714A69AD" => "LDM data",
+        "E75CAF8F-F680-4CEE-AFA3-B001E56EFC2D" => "Storage Spaces",
+        "0FC63DAF-8483-4772-8E79-3D69D8477DE4" => "Linux filesystem",
+        "0657FD6D-A4AB-43C4-84E5-0933C84B4F4F" => "Linux swap",
+        "E6D6D379-F507-44C2-A23C-238F2A3DF928" => "Linux LVM",
+        "A19D880F-05FC-4D3B-A006-743F0F84911E" => "Linux RAID",
+        "4F68BCE3-E8CD-4DB1-96E7-FBCAF984B709" => "Linux root (x86-64)",
+        "21686148-6449-6E6F-744E-656564454649" => "BIOS boot",
+        "48465300-0000-11AA-AA11-00306543ECAC" => "HFS+",
+        "7C3457EF-0000-11AA-AA11-00306543ECAC" => "APFS",
+        "516E7CB4-6ECF-11D6-8FF8-00022D09712B" => "FreeBSD",
+        _ => return guid,
+    };
+    name.to_string()
+}
+
+pub fn read_u16(bytes: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([bytes[offset], bytes[offset + 1]])
+}
+
+pub fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+}
+
+pub fn read_u64(bytes: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(bytes[offset..offset + 8].try_into().unwrap())
+}
+
+fn parse_mbr_entry(sector: &[u8], slot: usize) -> Option<MbrEntry> {
+    let entry = &sector[446 + (slot - 1) * 16..446 + slot * 16];
+    let partition_type = entry[4];
+    if partition_type == 0 {
+        return None;
+    }
+    Some(MbrEntry {
+        slot,
+        partition_type,
+        start_lba: read_u32(entry, 8) as u64,
+        sector_count: read_u32(entry, 12) as u64,
+        ebr_lba: None,
+    })
+}
+
+pub fn has_boot_signature(sector: &[u8]) -> bool {
+    sector.len() >= 512 && sector[510] == 0x55 && sector[511] == 0xAA
+}
+
+fn read_mbr(disk: &mut dyn Disk, sector_size: u64, total_sectors: u64) -> io::Result<Option<Mbr>> {
+    let sector = disk.read_bytes(0, 512)?;
+    if !has_boot_signature(&sector) {
+        return Ok(None);
+    }
+    let mut mbr = Mbr {
+        primary: (1..=4).filter_map(|slot| parse_mbr_entry(&sector, slot)).collect(),
+        logical: Vec::new(),
+        ebr_loop_at: None,
+        chain_errors: Vec::new(),
+    };
+
+    // Logical partitions are relative to their EBR; the link to the next EBR is
+    // relative to the start of the extended partition.
+    if let Some(extended) = mbr.extended().cloned() {
+        let mut visited = HashSet::new();
+        let mut ebr_lba = extended.start_lba;
+        let mut slot = 5;
+        loop {
+            if !visited.insert(ebr_lba) || visited.len() > MAX_EBR_CHAIN {
+                mbr.ebr_loop_at = Some(ebr_lba);
+                break;
+            }
+            if ebr_lba >= total_sectors {
+                mbr.chain_errors.push(format!("EBR at LBA {} lies beyond the end of the disk", ebr_lba));
+                break;
+            }
+            if ebr_lba < extended.start_lba || ebr_lba > extended.end_lba() {
+                mbr.chain_errors.push(format!("EBR at LBA {} lies outside the extended partition", ebr_lba));
+            }
+            let ebr = match disk.read_bytes(ebr_lba * sector_size, 512) {
+                Ok(ebr) => ebr,
+                Err(err) => {
+                    mbr.chain_errors.push(format!("failed to read EBR at LBA {}: {}", ebr_lba, err));
+                    break;
+                }
+            };
+            if !has_boot_signature(&ebr) {
+                mbr.chain_errors.push(format!("EBR at LBA {} has no 0x55AA signature", ebr_lba));
+                break;
+            }
+            if let Some(mut logical) = parse_mbr_entry(&ebr, 1) {
+                logical.slot = slot;
+                logical.start_lba += ebr_lba;
+                logical.ebr_lba = Some(ebr_lba);
+                mbr.logical.push(logical);
+                slot += 1;
+            }
+            match parse_mbr_entry(&ebr, 2) {
+                Some(next) if is_extended_type(next.partition_type) => {
+                    ebr_lba = extended.start_lba + next.start_lba;
+                }
+                _ => break,
+            }
+        }
+    }
+    Ok(Some(mbr))
+}
+
+pub fn parse_gpt_header(sector: &[u8]) -> Result<GptHeader, String> {
+    if sector.len() < GPT_HEADER_SIZE || &sector[0..8] != GPT_SIGNATURE {
+        return Err("no EFI PART signature".to_string());
+    }
+    let header_size = read_u32(sector, 12);
+    if (header_size as usize) < GPT_HEADER_SIZE || header_size as usize > sector.len() {
+        return Err(format!("invalid header size {}", header_size));
+    }
+    let header_crc = read_u32(sector, 16);
+    let raw = sector[..header_size as usize].to_vec();
+    Ok(GptHeader {
+        current_lba: read_u64(sector, 24),
+        backup_lba: read_u64(sector, 32),
+        first_usable_lba: read_u64(sector, 40),
+        last_usable_lba: read_u64(sector, 48),
+        disk_guid: Guid::from_bytes(&sector[56..72]),
+        entries_lba: read_u64(sector, 72),
+        entry_count: read_u32(sector, 80),
+        entry_size: read_u32(sector, 84),
+        entries_crc: read_u32(sector, 88),
+        header_crc_valid: gpt_header_crc(&raw) == header_crc,
+        entries_crc_valid: false,
+        raw,
+    })
+}
+
+pub fn gpt_header_crc(raw: &[u8]) -> u32 {
+    let mut header = raw.to_vec();
+    header[16..20].fill(0);
+    crc32fast::hash(&header)
+}
+
+pub fn parse_gpt_entries(raw_entries: &[u8], entry_size: u32) -> Vec<GptEntry> {
+    raw_entries
+        .chunks_exact(entry_size as usize)
+        .enumerate()
+        .filter_map(|(index, entry)| {
+            if Guid::from_bytes(&entry[0..16]).is_zero() {
+                return None;
+            }
+            let name_units: Vec<u16> = entry[56..128]
+                .chunks_exact(2)
+                .map(|unit| u16::from_le_bytes([unit[0], unit[1]]))
+                .take_while(|unit| *unit != 0)
+                .collect();
+            Some(GptEntry {
+                index,
+                type_guid: Guid::from_bytes(&entry[0..16]),
+                unique_guid: Guid::from_bytes(&entry[16..32]),
+                first_lba: read_u64(entry, 32),
+                last_lba: read_u64(entry, 40),
+                name: String::from_utf16_lossy(&name_units),
+            })
+        })
+        .collect()
+}
+
+pub fn read_gpt_at(disk: &mut dyn Disk, lba: u64, sector_size: u64) -> Result<Gpt, String> {
+    let offset = lba.checked_mul(sector_size).ok_or_else(|| format!("LBA {} is out of range", lba))?;
+    let sector = disk
+        .read_bytes(offset, sector_size as usize)
+        .map_err(|err| format!("failed to read LBA {}: {}", lba, err))?;
+    let mut header = parse_gpt_header(&sector)?;
+    let array_len = header.entry_count as u64 * header.entry_size as u64;
+    if header.entry_size < 128
+        || header.entry_size % 8 != 0
+        || header.entry_count > MAX_GPT_ENTRIES
+        || array_len > MAX_GPT_ARRAY_BYTES
+    {
+        return Err(format!("invalid entry array ({} entries of {} bytes)", header.entry_count, header.entry_size));
+    }
+    let entries_offset = header
+        .entries_lba
+        .checked_mul(sector_size)
+        .filter(|offset| offset.checked_add(array_len).is_some_and(|end| end <= disk.size()))
+        .ok_or_else(|| format!("partition entries at LBA {} lie outside the disk", header.entries_lba))?;
+    let raw_entries = disk
+        .read_bytes(entries_offset, array_len as usize)
+        .map_err(|err| format!("failed to read partition entries at LBA {}: {}", header.entries_lba, err))?;
+    header.entries_crc_valid = crc32fast::hash(&raw_entries) == header.entries_crc;
+    let entries = parse_gpt_entries(&raw_entries, header.entry_size);
+    Ok(Gpt { header, entries, raw_entries })
+}
+
+fn detect_sector_size(disk: &mut dyn Disk) -> u32 {
+    let sector_size = disk.sector_size();
+    for candidate in [sector_size, 512, 4096] {
+        if let Ok(signature) = disk.read_bytes(candidate as u64, 8) {
+            if signature == GPT_SIGNATURE {
+                return candidate;
+            }
+        }
+    }
+    sector_size
+}
+
+pub fn read_partition_table(disk: &mut dyn Disk) -> io::Result<PartitionTable> {
+    let sector_size = detect_sector_size(disk);
+    let total_sectors = disk.size() / sector_size as u64;
+    let mbr = read_mbr(disk, sector_size as u64, total_sectors)?;
+    let primary_gpt = read_gpt_at(disk, 1, sector_size as u64);
+    let last_lba = total_sectors.saturating_sub(1);
+    let backup_lba = match &primary_gpt {
+        Ok(gpt) if gpt.header.backup_lba < total_sectors => gpt.header.backup_lba,
+        _ => last_lba,
+    };
+    let mut backup_gpt = read_gpt_at(disk, backup_lba, sector_size as u64);
+    if backup_gpt.is_err() && backup_lba != last_lba {
+        backup_gpt = read_gpt_at(disk, last_lba, sector_size as u64);
+    }
+    Ok(PartitionTable {
+        sector_size,
+        total_sectors,
+        mbr,
+        primary_gpt,
+        backup_gpt,
+    })
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+
+    pub const SECTOR_SIZE: u64 = 512;
+    const ENTRY_COUNT: u32 = 128;
+    const ENTRY_SIZE: u32 = 128;
+    const ENTRY_SECTORS: u64 = ENTRY_COUNT as u64 * ENTRY_SIZE as u64 / SECTOR_SIZE;
+    pub const BASIC_DATA: &str = "EBD0A0A2-B9E5-4433-87C0-68B99BE7C7C7";
+
+    pub struct MemoryDisk(pub Vec<u8>);
+
+    impl Disk for MemoryDisk {
+        fn size(&self) -> u64 {
+            self.0.len() as u64
+        }
+
+        fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> io::Result<()> {
+            let start = offset as usize;
+            let source = self.0.get(start..start + buf.len()).ok_or_else(|| io::Error::from(io::ErrorKind::UnexpectedEof))?;
+            buf.copy_from_slice(source);
+            Ok(())
+        }
+
+        fn write_at(&mut self, offset: u64, buf: &[u8]) -> io::Result<()> {
+            let start = offset as usize;
+            let target = self.0.get_mut(start..start + buf.len()).ok_or_else(|| io::Error::from(io::ErrorKind::UnexpectedEof))?;
+            target.copy_from_slice(buf);
+            Ok(())
+        }
+    }
+
+    impl MemoryDisk {
+        pub fn sector(&self, lba: u64) -> &[u8] {
+            &self.0[(lba * SECTOR_SIZE) as usize..((lba + 1) * SECTOR_SIZE) as usize]
+        }
+
+        pub fn sector_mut(&mut self, lba: u64) -> &mut [u8] {
+            &mut self.0[(lba * SECTOR_SIZE) as usize..((lba + 1) * SECTOR_SIZE) as usize]
+        }
+    }
+
+    fn guid(last: u8) -> [u8; 16] {
+        let mut bytes = [0x5A; 16];
+        bytes[15] = last;
+        bytes
+    }
+
+    fn header(current_lba: u64, backup_lba: u64, last_usable_lba: u64, entries_lba: u64, entries: &[u8]) -> Vec<u8> {
+        let mut header = vec![0u8; SECTOR_SIZE as usize];
+        header[0..8].copy_from_slice(GPT_SIGNATURE);
+        header[8..12].copy_from_slice(&0x0001_0000u32.to_le_bytes());
+        header[12..16].copy_from_slice(&(GPT_HEADER_SIZE as u32).to_le_bytes());
+        header[24..32].copy_from_slice(&current_lba.to_le_bytes());
+        header[32..40].copy_from_slice(&backup_lba.to_le_bytes());
+        header[40..48].copy_from_slice(&(2 + ENTRY_SECTORS).to_le_bytes());
+        header[48..56].copy_from_slice(&last_usable_lba.to_le_bytes());
+        header[56..72].copy_from_slice(&guid(0));
+        header[72..80].copy_from_slice(&entries_lba.to_le_bytes());
+        header[80..84].copy_from_slice(&ENTRY_COUNT.to_le_bytes());
+        header[84..88].copy_from_slice(&ENTRY_SIZE.to_le_bytes());
+        header[88..92].copy_from_slice(&crc32fast::hash(entries).to_le_bytes());
+        let crc = gpt_header_crc(&header[..GPT_HEADER_SIZE]);
+        header[16..20].copy_from_slice(&crc.to_le_bytes());
+        header
+    }
+
+    // A disk of `total_sectors` with a protective MBR, both GPT copies where a
+    // partitioning tool puts them, and a basic data partition for each LBA range.
+    pub fn gpt_disk(total_sectors: u64, partitions: &[(u64, u64)]) -> MemoryDisk {
+        let mut disk = MemoryDisk(vec![0u8; (total_sectors * SECTOR_SIZE) as usize]);
+        let mbr = disk.sector_mut(0);
+        mbr[446 + 4] = MBR_PROTECTIVE;
+        mbr[446 + 8..446 + 12].copy_from_slice(&1u32.to_le_bytes());
+        mbr[446 + 12..446 + 16].copy_from_slice(&((total_sectors - 1).min(u32::MAX as u64) as u32).to_le_bytes());
+        mbr[510] = 0x55;
+        mbr[511] = 0xAA;
+
+        let mut entries = vec![0u8; (ENTRY_COUNT * ENTRY_SIZE) as usize];
+        for (index, (first_lba, last_lba)) in partitions.iter().enumerate() {
+            let entry = &mut entries[index * ENTRY_SIZE as usize..(index + 1) * ENTRY_SIZE as usize];
+            entry[0..16].copy_from_slice(&Guid::parse(BASIC_DATA).unwrap().0);
+            entry[16..32].copy_from_slice(&guid(index as u8 + 1));
+            entry[32..40].copy_from_slice(&first_lba.to_le_bytes());
+            entry[40..48].copy_from_slice(&last_lba.to_le_bytes());
+        }
+        let backup_lba = total_sectors - 1;
+        let backup_entries_lba = backup_lba - ENTRY_SECTORS;
+        let last_usable_lba = backup_entries_lba - 1;
+        disk.write_at(SECTOR_SIZE, &header(1, backup_lba, last_usable_lba, 2, &entries)).unwrap();
+        disk.write_at(2 * SECTOR_SIZE, &entries).unwrap();
+        disk.write_at(backup_entries_lba * SECTOR_SIZE, &entries).unwrap();
+        disk.write_at(backup_lba * SECTOR_SIZE, &header(backup_lba, 1, last_usable_lba, backup_entries_lba, &entries)).unwrap();
+        disk
+    }
+
+    #[test]
+    fn reads_both_gpt_copies() {
+        let mut disk = gpt_disk(4096, &[(2048, 4000)]);
+        let table = read_partition_table(&mut disk).unwrap();
+        assert_eq!((table.sector_size, table.total_sectors), (512, 4096));
+        assert!(table.mbr.as_ref().unwrap().is_protective());
+        let primary = table.primary_gpt.as_ref().unwrap();
+        let backup = table.backup_gpt.as_ref().unwrap();
+        assert!(primary.header.header_crc_valid && primary.header.entries_crc_valid);
+        assert!(backup.header.header_crc_valid && backup.header.entries_crc_valid);
+        assert_eq!((backup.header.current_lba, backup.header.entries_lba), (4095, 4063));
+        assert_eq!(primary.entries.len(), 1);
+        assert_eq!((primary.entries[0].first_lba, primary.entries[0].last_lba), (2048, 4000));
+        assert_eq!(gpt_type_name(&primary.entries[0].type_guid), gpt_type_name(&Guid::parse(BASIC_DATA).unwrap()));
+    }
+
+    #[test]
+    fn falls_back_to_the_last_sector_for_the_backup() {
+        let mut disk = gpt_disk(4096, &[(2048, 4000)]);
+        // A primary that points past the disk end still finds the backup at the last LBA.
+        let mut primary = disk.sector(1).to_vec();
+        primary[32..40].copy_from_slice(&9999u64.to_le_bytes());
+        disk.sector_mut(1).copy_from_slice(&primary);
+        let table = read_partition_table(&mut disk).unwrap();
+        assert!(!table.primary_gpt.as_ref().unwrap().header.header_crc_valid);
+        assert_eq!(table.backup_gpt.as_ref().unwrap().header.current_lba, 4095);
+    }
+
+    #[test]
+    fn oversized_entry_array_is_rejected() {
+        let mut disk = gpt_disk(4096, &[]);
+        disk.sector_mut(1)[80..84].copy_from_slice(&(MAX_GPT_ENTRIES + 1).to_le_bytes());
+        assert!(read_gpt_at(&mut disk, 1, SECTOR_SIZE).is_err());
+    }
+}
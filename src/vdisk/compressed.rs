@@ -0,0 +1,291 @@
+use crate::disk::Disk;
+use crate::partition_table::{read_u32, read_u64};
+use crate::vdisk::convert::{create_output, ImageWriter};
+use crate::vdisk::{check_bounds, invalid_data, read_blocks, read_exact_at, write_all_at};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io;
+use std::path::Path;
+
+// PMT's own image format: a 64-byte header, zstd-compressed blocks in write order and
+// a zstd-compressed index at the end with one entry per virtual block. All-zero blocks
+// have no data, and with deduplication identical blocks share one stored copy.
+pub const MAGIC: &[u8; 8] = b"PMTZIMG\x1a";
+pub const BLOCK_SIZE: u64 = 1024 * 1024;
+const VERSION: u32 = 1;
+const HEADER_SIZE: u64 = 64;
+const INDEX_ENTRY_SIZE: usize = 16;
+// Readers accept larger blocks than the writer produces, but not unbounded ones.
+const MAX_BLOCK_SIZE: u64 = 64 * 1024 * 1024;
+// A zstd frame cannot expand further than this (a 4-byte RLE block per 128 KiB), so
+// an index that claims more entries than its stored length can hold is corrupt.
+const MAX_ZSTD_EXPANSION: u64 = 32 * 1024;
+const COMPRESSION_LEVEL: i32 = 3;
+
+const FLAG_DEDUPLICATED: u32 = 1;
+const ENTRY_STORED_RAW: u32 = 1;
+
+#[derive(Clone, Copy, Default)]
+struct IndexEntry {
+    offset: u64,
+    length: u32,
+    flags: u32,
+}
+
+struct Header {
+    block_size: u64,
+    virtual_size: u64,
+    index_offset: u64,
+    index_length: u64,
+    stored_blocks: u64,
+    data_blocks: u64,
+    flags: u32,
+}
+
+fn encode_header(header: &Header) -> [u8; HEADER_SIZE as usize] {
+    let mut bytes = [0u8; HEADER_SIZE as usize];
+    bytes[0..8].copy_from_slice(MAGIC);
+    bytes[8..12].copy_from_slice(&VERSION.to_le_bytes());
+    bytes[12..16].copy_from_slice(&(header.block_size as u32).to_le_bytes());
+    bytes[16..24].copy_from_slice(&header.virtual_size.to_le_bytes());
+    bytes[24..32].copy_from_slice(&header.index_offset.to_le_bytes());
+    bytes[32..40].copy_from_slice(&header.index_length.to_le_bytes());
+    bytes[40..48].copy_from_slice(&header.stored_blocks.to_le_bytes());
+    bytes[48..56].copy_from_slice(&header.data_blocks.to_le_bytes());
+    bytes[56..60].copy_from_slice(&header.flags.to_le_bytes());
+    let checksum = crc32fast::hash(&bytes[..60]);
+    bytes[60..64].copy_from_slice(&checksum.to_le_bytes());
+    bytes
+}
+
+fn decode_header(bytes: &[u8]) -> io::Result<Header> {
+    if &bytes[0..8] != MAGIC {
+        return Err(invalid_data("missing PMT compressed image magic"));
+    }
+    if crc32fast::hash(&bytes[..60]) != read_u32(bytes, 60) {
+        return Err(invalid_data("PMT compressed image header checksum mismatch"));
+    }
+    let version = read_u32(bytes, 8);
+    if version != VERSION {
+        return Err(invalid_data(format!("unsupported PMT compressed image version {}", version)));
+    }
+    Ok(Header {
+        block_size: read_u32(bytes, 12) as u64,
+        virtual_size: read_u64(bytes, 16),
+        index_offset: read_u64(bytes, 24),
+        index_length: read_u64(bytes, 32),
+        stored_blocks: read_u64(bytes, 40),
+        data_blocks: read_u64(bytes, 48),
+        flags: read_u32(bytes, 56),
+    })
+}
+
+pub struct CompressedImage {
+    file: File,
+    file_size: u64,
+    header: Header,
+    index: Vec<IndexEntry>,
+    cached_block: Option<(u64, Vec<u8>)>,
+}
+
+impl CompressedImage {
+    pub fn open(path: &Path) -> io::Result<Self> {
+        let mut file = File::open(path)?;
+        let file_size = file.metadata()?.len();
+        let mut bytes = [0u8; HEADER_SIZE as usize];
+        read_exact_at(&mut file, 0, &mut bytes)?;
+        let header = decode_header(&bytes)?;
+        // The index is written last; a zero offset means the writer never finished.
+        if header.index_offset == 0 {
+            return Err(invalid_data("PMT compressed image is incomplete (no index was written)"));
+        }
+        if header.block_size == 0 || !header.block_size.is_power_of_two() || header.block_size > MAX_BLOCK_SIZE {
+            return Err(invalid_data(format!("invalid block size {}", header.block_size)));
+        }
+        if header.index_offset.checked_add(header.index_length).is_none_or(|end| end > file_size) {
+            return Err(invalid_data("PMT compressed image index lies beyond the end of the file"));
+        }
+        let index_size = header
+            .virtual_size
+            .div_ceil(header.block_size)
+            .checked_mul(INDEX_ENTRY_SIZE as u64)
+            .filter(|size| *size <= header.index_length.saturating_mul(MAX_ZSTD_EXPANSION))
+            .ok_or_else(|| invalid_data(format!("virtual size {} does not fit the stored index", header.virtual_size)))?;
+        let mut compressed = vec![0u8; header.index_length as usize];
+        read_exact_at(&mut file, header.index_offset, &mut compressed)?;
+        let raw = zstd::bulk::decompress(&compressed, index_size as usize)?;
+        if raw.len() as u64 != index_size {
+            return Err(invalid_data(format!("index has {} bytes, expected {}", raw.len(), index_size)));
+        }
+        let index: Vec<IndexEntry> = raw
+            .chunks_exact(INDEX_ENTRY_SIZE)
+            .map(|entry| IndexEntry {
+                offset: read_u64(entry, 0),
+                length: read_u32(entry, 8),
+                flags: read_u32(entry, 12),
+            })
+            .collect();
+        if let Some(block) = index
+            .iter()
+            .position(|entry| entry.offset != 0 && entry.offset.saturating_add(entry.length as u64) > header.index_offset)
+        {
+            return Err(invalid_data(format!("data of block {} overlaps the index", block)));
+        }
+        Ok(Self {
+            file,
+            file_size,
+            header,
+            index,
+            cached_block: None,
+        })
+    }
+
+    fn read_block(&mut self, block: u64, in_block: u64, buf: &mut [u8]) -> io::Result<()> {
+        let entry = self.index[block as usize];
+        if entry.offset == 0 {
+            buf.fill(0);
+            return Ok(());
+        }
+        if self.cached_block.as_ref().is_none_or(|(cached, _)| *cached != block) {
+            let expected = self.header.block_size.min(self.header.virtual_size - block * self.header.block_size) as usize;
+            let mut stored = vec![0u8; entry.length as usize];
+            read_exact_at(&mut self.file, entry.offset, &mut stored)?;
+            let data = if entry.flags & ENTRY_STORED_RAW != 0 { stored } else { zstd::bulk::decompress(&stored, expected)? };
+            if data.len() != expected {
+                return Err(invalid_data(format!("block {} decompressed to {} bytes, expected {}", block, data.len(), expected)));
+            }
+            self.cached_block = Some((block, data));
+        }
+        let (_, data) = self.cached_block.as_ref().unwrap();
+        buf.copy_from_slice(&data[in_block as usize..in_block as usize + buf.len()]);
+        Ok(())
+    }
+}
+
+impl Disk for CompressedImage {
+    fn size(&self) -> u64 {
+        self.header.virtual_size
+    }
+
+    fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> io::Result<()> {
+        check_bounds(offset, buf.len(), self.header.virtual_size)?;
+        let block_size = self.header.block_size;
+        read_blocks(offset, buf, block_size, |block, in_block, piece| self.read_block(block, in_block, piece))
+    }
+
+    fn format_name(&self) -> &'static str {
+        "PMT compressed image"
+    }
+
+    fn details(&self) -> Vec<(String, String)> {
+        let header = &self.header;
+        let blocks = self.index.len() as u64;
+        let data_bytes = header.data_blocks * header.block_size;
+        let mut details = vec![
+            ("Virtual size".to_string(), format!("{} bytes", header.virtual_size)),
+            ("Block size".to_string(), format!("{} bytes", header.block_size)),
+            ("Data blocks".to_string(), format!("{} of {}", header.data_blocks, blocks)),
+            ("Zero blocks".to_string(), blocks.saturating_sub(header.data_blocks).to_string()),
+            ("File size".to_string(), format!("{} bytes", self.file_size)),
+        ];
+        if header.flags & FLAG_DEDUPLICATED != 0 {
+            details.push((
+                "Deduplication".to_string(),
+                format!("{} unique of {} data blocks", header.stored_blocks, header.data_blocks),
+            ));
+        }
+        if self.file_size > 0 {
+            details.push(("Compression ratio".to_string(), format!("{:.2}:1", data_bytes as f64 / self.file_size as f64)));
+        }
+        details
+    }
+}
+
+pub struct CompressedImageWriter {
+    file: File,
+    header: Header,
+    index: Vec<IndexEntry>,
+    seen: Option<HashMap<[u8; 32], IndexEntry>>,
+    next_offset: u64,
+}
+
+impl CompressedImageWriter {
+    pub fn create(path: &Path, size: u64, deduplicate: bool) -> io::Result<Self> {
+        let header = Header {
+            block_size: BLOCK_SIZE,
+            virtual_size: size,
+            index_offset: 0,
+            index_length: 0,
+            stored_blocks: 0,
+            data_blocks: 0,
+            flags: if deduplicate { FLAG_DEDUPLICATED } else { 0 },
+        };
+        let mut file = create_output(path)?;
+        write_all_at(&mut file, 0, &encode_header(&header))?;
+        Ok(Self {
+            file,
+            index: vec![IndexEntry::default(); size.div_ceil(BLOCK_SIZE) as usize],
+            header,
+            seen: deduplicate.then(HashMap::new),
+            next_offset: HEADER_SIZE,
+        })
+    }
+
+    fn store(&mut self, data: &[u8]) -> io::Result<IndexEntry> {
+        let compressed = zstd::bulk::compress(data, COMPRESSION_LEVEL)?;
+        let (stored, flags) = if compressed.len() < data.len() { (&compressed[..], 0) } else { (data, ENTRY_STORED_RAW) };
+        let entry = IndexEntry {
+            offset: self.next_offset,
+            length: stored.len() as u32,
+            flags,
+        };
+        write_all_at(&mut self.file, self.next_offset, stored)?;
+        self.next_offset += stored.len() as u64;
+        self.header.stored_blocks += 1;
+        Ok(entry)
+    }
+}
+
+impl ImageWriter for CompressedImageWriter {
+    fn write_block(&mut self, index: u64, data: &[u8]) -> io::Result<()> {
+        let hash = self.seen.is_some().then(|| <[u8; 32]>::from(Sha256::digest(data)));
+        let known = hash.and_then(|hash| self.seen.as_ref()?.get(&hash).copied());
+        let entry = match known {
+            Some(entry) => entry,
+            None => {
+                let entry = self.store(data)?;
+                if let (Some(seen), Some(hash)) = (self.seen.as_mut(), hash) {
+                    seen.insert(hash, entry);
+                }
+                entry
+            }
+        };
+        self.index[index as usize] = entry;
+        self.header.data_blocks += 1;
+        Ok(())
+    }
+
+    fn finish(mut self: Box<Self>) -> io::Result<()> {
+        let raw: Vec<u8> = self
+            .index
+            .iter()
+            .flat_map(|entry| {
+                let mut bytes = [0u8; INDEX_ENTRY_SIZE];
+                bytes[0..8].copy_from_slice(&entry.offset.to_le_bytes());
+                bytes[8..12].copy_from_slice(&entry.length.to_le_bytes());
+                bytes[12..16].copy_from_slice(&entry.flags.to_le_bytes());
+                bytes
+            })
+            .collect();
+        let compressed = zstd::bulk::compress(&raw, COMPRESSION_LEVEL)?;
+        self.header.index_offset = self.next_offset;
+        self.header.index_length = compressed.len() as u64;
+        write_all_at(&mut self.file, self.next_offset, &compressed)?;
+        self.file.set_len(self.next_offset + compressed.len() as u64)?;
+        // The header goes last so an interrupted write never looks complete.
+        self.file.sync_all()?;
+        write_all_at(&mut self.file, 0, &encode_header(&self.header))?;
+        self.file.sync_all()
+    }
+}
@@ -0,0 +1,286 @@
+use crate::disk::{mark_sparse, Disk};
+use crate::vdisk::{compressed, open_virtual_disk, qcow2, to_hex, vhd, vhdx, vmdk, write_all_at};
+use sha1::{Digest, Sha1};
+use std::fs::{self, File, OpenOptions};
+use std::io;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+pub const RAW_BLOCK_SIZE: u64 = 64 * 1024;
+const VERIFY_CHUNK_SIZE: usize = 1024 * 1024;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ImageFormat {
+    Raw,
+    SparseRaw,
+    VhdFixed,
+    VhdDynamic,
+    Vhdx,
+    Qcow2,
+    Vmdk,
+    Compressed,
+    CompressedDeduplicated,
+}
+
+impl ImageFormat {
+    pub const ALL: [ImageFormat; 9] = [
+        ImageFormat::Raw,
+        ImageFormat::SparseRaw,
+        ImageFormat::VhdFixed,
+        ImageFormat::VhdDynamic,
+        ImageFormat::Vhdx,
+        ImageFormat::Qcow2,
+        ImageFormat::Vmdk,
+        ImageFormat::Compressed,
+        ImageFormat::CompressedDeduplicated,
+    ];
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|format| format.name() == name)
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            ImageFormat::Raw => "raw",
+            ImageFormat::SparseRaw => "sparse-raw",
+            ImageFormat::VhdFixed => "vhd-fixed",
+            ImageFormat::VhdDynamic => "vhd-dynamic",
+            ImageFormat::Vhdx => "vhdx",
+            ImageFormat::Qcow2 => "qcow2",
+            ImageFormat::Vmdk => "vmdk",
+            ImageFormat::Compressed => "compressed",
+            ImageFormat::CompressedDeduplicated => "compressed-dedup",
+        }
+    }
+
+    pub fn description(self) -> &'static str {
+        match self {
+            ImageFormat::Raw => "Raw",
+            ImageFormat::SparseRaw => "Sparse raw",
+            ImageFormat::VhdFixed => "VHD (fixed)",
+            ImageFormat::VhdDynamic => "VHD (dynamic)",
+            ImageFormat::Vhdx => "VHDX (dynamic)",
+            ImageFormat::Qcow2 => "QCOW2",
+            ImageFormat::Vmdk => "VMDK (monolithic sparse)",
+            ImageFormat::Compressed => "PMT compressed (zstd)",
+            ImageFormat::CompressedDeduplicated => "PMT compressed (zstd, deduplicated)",
+        }
+    }
+
+    pub fn extension(self) -> &'static str {
+        match self {
+            ImageFormat::Raw | ImageFormat::SparseRaw => "img",
+            ImageFormat::VhdFixed | ImageFormat::VhdDynamic => "vhd",
+            ImageFormat::Vhdx => "vhdx",
+            ImageFormat::Qcow2 => "qcow2",
+            ImageFormat::Vmdk => "vmdk",
+            ImageFormat::Compressed | ImageFormat::CompressedDeduplicated => "pmtz",
+        }
+    }
+
+    // The unit in which zero detection works; matches the allocation unit of the format.
+    fn block_size(self) -> u64 {
+        match self {
+            ImageFormat::Raw | ImageFormat::SparseRaw | ImageFormat::VhdFixed => RAW_BLOCK_SIZE,
+            ImageFormat::VhdDynamic => vhd::DYNAMIC_BLOCK_SIZE,
+            ImageFormat::Vhdx => vhdx::WRITE_BLOCK_SIZE,
+            ImageFormat::Qcow2 => qcow2::WRITE_CLUSTER_SIZE,
+            ImageFormat::Vmdk => vmdk::WRITE_GRAIN_SIZE,
+            ImageFormat::Compressed | ImageFormat::CompressedDeduplicated => compressed::BLOCK_SIZE,
+        }
+    }
+
+    // Raw and compressed output keep the exact source size; the others describe whole sectors.
+    fn virtual_size(self, size: u64) -> u64 {
+        match self {
+            ImageFormat::Raw | ImageFormat::SparseRaw | ImageFormat::Compressed | ImageFormat::CompressedDeduplicated => size,
+            _ => size.next_multiple_of(512),
+        }
+    }
+}
+
+// Blocks arrive in increasing order and are never all zero; the last one may be short.
+pub trait ImageWriter {
+    fn write_block(&mut self, index: u64, data: &[u8]) -> io::Result<()>;
+    fn finish(self: Box<Self>) -> io::Result<()>;
+}
+
+// Conversions never overwrite an existing file.
+pub fn create_output(path: &Path) -> io::Result<File> {
+    OpenOptions::new().read(true).write(true).create_new(true).open(path)
+}
+
+struct RawWriter {
+    file: File,
+    size: u64,
+}
+
+impl RawWriter {
+    fn create(path: &Path, size: u64, sparse: bool) -> io::Result<Self> {
+        let file = create_output(path)?;
+        if sparse {
+            mark_sparse(&file)?;
+        }
+        Ok(Self { file, size })
+    }
+}
+
+impl ImageWriter for RawWriter {
+    fn write_block(&mut self, index: u64, data: &[u8]) -> io::Result<()> {
+        write_all_at(&mut self.file, index * RAW_BLOCK_SIZE, data)
+    }
+
+    // Skipped zero blocks become holes, or plain zeroes when the file is not sparse.
+    fn finish(self: Box<Self>) -> io::Result<()> {
+        self.file.set_len(self.size)?;
+        self.file.sync_all()
+    }
+}
+
+fn create_writer(format: ImageFormat, path: &Path, size: u64) -> io::Result<Box<dyn ImageWriter>> {
+    Ok(match format {
+        ImageFormat::Raw => Box::new(RawWriter::create(path, size, false)?),
+        ImageFormat::SparseRaw => Box::new(RawWriter::create(path, size, true)?),
+        ImageFormat::VhdFixed => Box::new(vhd::FixedVhdWriter::create(path, size)?),
+        ImageFormat::VhdDynamic => Box::new(vhd::DynamicVhdWriter::create(path, size)?),
+        ImageFormat::Vhdx => Box::new(vhdx::VhdxWriter::create(path, size)?),
+        ImageFormat::Qcow2 => Box::new(qcow2::Qcow2Writer::create(path, size)?),
+        ImageFormat::Vmdk => Box::new(vmdk::VmdkWriter::create(path, size)?),
+        ImageFormat::Compressed => Box::new(compressed::CompressedImageWriter::create(path, size, false)?),
+        ImageFormat::CompressedDeduplicated => Box::new(compressed::CompressedImageWriter::create(path, size, true)?),
+    })
+}
+
+fn cancelled() -> io::Error {
+    io::Error::new(io::ErrorKind::Interrupted, "cancelled")
+}
+
+#[derive(Clone, Debug)]
+pub struct ConversionSummary {
+    pub format: ImageFormat,
+    pub virtual_size: u64,
+    pub block_size: u64,
+    pub data_blocks: u64,
+    pub zero_blocks: u64,
+    pub output_size: u64,
+}
+
+impl ConversionSummary {
+    pub fn describe(&self) -> String {
+        format!(
+            "{} image of {} bytes: {} data block(s) written, {} zero block(s) skipped ({} KiB blocks), file size {} bytes",
+            self.format.description(),
+            self.virtual_size,
+            self.data_blocks,
+            self.zero_blocks,
+            self.block_size / 1024,
+            self.output_size
+        )
+    }
+}
+
+pub fn convert_image(
+    source: &mut dyn Disk,
+    destination: &Path,
+    format: ImageFormat,
+    cancel: &AtomicBool,
+    mut progress: impl FnMut(u64, u64),
+) -> io::Result<ConversionSummary> {
+    let size = source.size();
+    let block_size = format.block_size();
+    let mut summary = ConversionSummary {
+        format,
+        virtual_size: format.virtual_size(size),
+        block_size,
+        data_blocks: 0,
+        zero_blocks: 0,
+        output_size: 0,
+    };
+    let mut writer = create_writer(format, destination, summary.virtual_size)?;
+    let mut copy = || -> io::Result<()> {
+        let mut buf = vec![0u8; block_size as usize];
+        for index in 0..size.div_ceil(block_size) {
+            if cancel.load(Ordering::Relaxed) {
+                return Err(cancelled());
+            }
+            let offset = index * block_size;
+            let len = block_size.min(size - offset) as usize;
+            source.read_at(offset, &mut buf[..len])?;
+            if buf[..len].iter().all(|byte| *byte == 0) {
+                summary.zero_blocks += 1;
+            } else {
+                writer.write_block(index, &buf[..len])?;
+                summary.data_blocks += 1;
+            }
+            progress(offset + len as u64, size);
+        }
+        Ok(())
+    };
+    let result = copy().and_then(|_| writer.finish());
+    if let Err(err) = result {
+        // A partial image is worse than none.
+        let _ = fs::remove_file(destination);
+        return Err(err);
+    }
+    summary.output_size = fs::metadata(destination)?.len();
+    Ok(summary)
+}
+
+#[derive(Clone, Debug)]
+pub struct ContentCheck {
+    pub source_sha1: String,
+    pub converted_sha1: String,
+}
+
+impl ContentCheck {
+    pub fn matches(&self) -> bool {
+        self.source_sha1 == self.converted_sha1
+    }
+}
+
+// Reopens the converted image through the normal reader and hashes the first
+// `source.size()` bytes of both; a size rounded up to whole sectors must be zero padded.
+pub fn verify_conversion(
+    source: &mut dyn Disk,
+    destination: &Path,
+    cancel: &AtomicBool,
+    mut progress: impl FnMut(u64, u64),
+) -> io::Result<ContentCheck> {
+    let path = destination.to_str().ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "output path is not valid UTF-8"))?;
+    let mut converted = open_virtual_disk(path)?;
+    let size = source.size();
+    let converted_size = converted.size();
+    if converted_size < size {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("converted image is {} bytes, smaller than the {} byte source", converted_size, size),
+        ));
+    }
+    let mut source_hash = Sha1::new();
+    let mut converted_hash = Sha1::new();
+    let mut source_buf = vec![0u8; VERIFY_CHUNK_SIZE];
+    let mut converted_buf = vec![0u8; VERIFY_CHUNK_SIZE];
+    let mut offset = 0;
+    while offset < converted_size {
+        if cancel.load(Ordering::Relaxed) {
+            return Err(cancelled());
+        }
+        let len = (VERIFY_CHUNK_SIZE as u64).min(converted_size - offset) as usize;
+        converted.read_at(offset, &mut converted_buf[..len])?;
+        let source_len = size.saturating_sub(offset).min(len as u64) as usize;
+        if source_len > 0 {
+            source.read_at(offset, &mut source_buf[..source_len])?;
+            source_hash.update(&source_buf[..source_len]);
+            converted_hash.update(&converted_buf[..source_len]);
+        }
+        if converted_buf[source_len..len].iter().any(|byte| *byte != 0) {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "converted image has data past the end of the source"));
+        }
+        offset += len as u64;
+        progress(offset, converted_size);
+    }
+    Ok(ContentCheck {
+        source_sha1: to_hex(&source_hash.finalize()),
+        converted_sha1: to_hex(&converted_hash.finalize()),
+    })
+}
@@ -0,0 +1,458 @@
+use crate::disk::Disk;
+use crate::partition_table::{read_u32, read_u64};
+use crate::vdisk::{check_bounds, compress_bound, format_unix_time, invalid_data, read_blocks, read_exact_at, to_hex};
+use flate2::read::ZlibDecoder;
+use md5::{Digest, Md5};
+use sha1::Sha1;
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+
+pub const SIGNATURE: &[u8; 8] = b"EVF\x09\x0d\x0a\xff\x00";
+pub const FORMAT_NAME: &str = "EWF (E01)";
+const FILE_HEADER_SIZE: u64 = 13;
+const SECTION_DESCRIPTOR_SIZE: u64 = 76;
+const MAX_SEGMENTS: u32 = u16::MAX as u32;
+const MAX_HEADER_SIZE: u64 = 16 * 1024 * 1024;
+const COMPRESSED_FLAG: u32 = 0x8000_0000;
+const MAX_SECTOR_SIZE: u32 = 4096;
+const MAX_CHUNK_SIZE: u64 = 16 * 1024 * 1024;
+// Bytes hashed per read while verifying.
+const VERIFY_BLOCK_SIZE: u64 = 4 * 1024 * 1024;
+
+const HEADER_FIELDS: [(&str, &str); 11] = [
+    ("c", "Case number"),
+    ("n", "Evidence number"),
+    ("a", "Description"),
+    ("e", "Examiner"),
+    ("t", "Notes"),
+    ("md", "Model"),
+    ("sn", "Serial number"),
+    ("av", "Acquisition software"),
+    ("ov", "Acquisition OS"),
+    ("m", "Acquired"),
+    ("u", "System date"),
+];
+
+#[derive(Clone, Copy, Debug)]
+struct Chunk {
+    offset: u64,
+    size: u32,
+    segment: u16,
+    compressed: bool,
+}
+
+#[derive(Clone, Debug)]
+pub struct HashCheck {
+    pub algorithm: &'static str,
+    pub stored: String,
+    pub computed: String,
+}
+
+impl HashCheck {
+    pub fn matches(&self) -> bool {
+        self.stored.eq_ignore_ascii_case(&self.computed)
+    }
+}
+
+// Segment extensions run E01..E99, then EAA..EZZ, FAA.. and so on, keeping the
+// case of the first segment's extension.
+fn segment_path(first: &Path, number: u32) -> Option<PathBuf> {
+    let extension = first.extension()?.to_str()?;
+    let letter = extension.chars().next().filter(|letter| letter.is_ascii_alphabetic())?;
+    let suffix = if number < 100 {
+        format!("{}{:02}", letter, number)
+    } else {
+        let index = number - 100;
+        let codes = [letter.to_ascii_uppercase() as u32 + index / 676, 'A' as u32 + index / 26 % 26, 'A' as u32 + index % 26];
+        let suffix: String = codes.iter().filter_map(|code| char::from_u32(*code)).collect();
+        if letter.is_ascii_lowercase() {
+            suffix.to_ascii_lowercase()
+        } else {
+            suffix
+        }
+    };
+    Some(first.with_extension(suffix))
+}
+
+fn decompress(data: &[u8]) -> io::Result<Vec<u8>> {
+    let mut output = Vec::new();
+    ZlibDecoder::new(data).take(MAX_HEADER_SIZE).read_to_end(&mut output)?;
+    Ok(output)
+}
+
+// Header sections are tab separated tables: a category line ("main"), a line of
+// field identifiers and a line of values. header2 is the same in UTF-16.
+fn parse_header_text(text: &str) -> Vec<(String, String)> {
+    let lines: Vec<&str> = text.lines().map(|line| line.trim_end_matches('\r')).collect();
+    let Some(main) = lines.iter().position(|line| *line == "main") else {
+        return Vec::new();
+    };
+    let (Some(keys), Some(values)) = (lines.get(main + 1), lines.get(main + 2)) else {
+        return Vec::new();
+    };
+    let values: Vec<&str> = values.split('\t').collect();
+    let mut fields = Vec::new();
+    for (key, label) in HEADER_FIELDS {
+        let Some(index) = keys.split('\t').position(|name| name == key) else {
+            continue;
+        };
+        let value = values.get(index).copied().unwrap_or_default().trim();
+        if value.is_empty() {
+            continue;
+        }
+        // header2 stores dates as Unix timestamps, header as "YYYY M D h m s".
+        let value = match value.parse::<u64>() {
+            Ok(timestamp) if matches!(key, "m" | "u") => format_unix_time(timestamp),
+            _ => value.to_string(),
+        };
+        fields.push((label.to_string(), value));
+    }
+    fields
+}
+
+fn decode_header2(bytes: &[u8]) -> String {
+    let bytes = bytes.strip_prefix(&[0xFF, 0xFE]).unwrap_or(bytes);
+    let units: Vec<u16> = bytes.chunks_exact(2).map(|unit| u16::from_le_bytes([unit[0], unit[1]])).collect();
+    String::from_utf16_lossy(&units)
+}
+
+pub struct EwfDisk {
+    segments: Vec<File>,
+    segment_names: Vec<String>,
+    chunks: Vec<Chunk>,
+    chunk_size: u64,
+    size: u64,
+    bytes_per_sector: u32,
+    media_type: u8,
+    header: Vec<(String, String)>,
+    md5: Option<[u8; 16]>,
+    sha1: Option<[u8; 20]>,
+    acquisition_errors: u32,
+    cached_chunk: Option<(usize, Vec<u8>)>,
+}
+
+#[derive(Default)]
+struct SegmentScan {
+    header: Option<Vec<(String, String)>>,
+    header2: Option<Vec<(String, String)>>,
+    volume: Option<(u64, u32, u64, u8)>,
+    md5: Option<[u8; 16]>,
+    sha1: Option<[u8; 20]>,
+    acquisition_errors: u32,
+    done: bool,
+}
+
+fn read_section_data(file: &mut File, offset: u64, size: u64) -> io::Result<Vec<u8>> {
+    let len = size.saturating_sub(SECTION_DESCRIPTOR_SIZE);
+    if len > MAX_HEADER_SIZE {
+        return Err(invalid_data(format!("EWF section at {} is too large", offset)));
+    }
+    let mut data = vec![0u8; len as usize];
+    read_exact_at(file, offset + SECTION_DESCRIPTOR_SIZE, &mut data)?;
+    Ok(data)
+}
+
+fn scan_segment(file: &mut File, segment: u16, scan: &mut SegmentScan, chunks: &mut Vec<Chunk>) -> io::Result<()> {
+    let file_size = file.metadata()?.len();
+    let mut sectors_ranges: Vec<(u64, u64)> = Vec::new();
+    let mut offset = FILE_HEADER_SIZE;
+    while offset + SECTION_DESCRIPTOR_SIZE <= file_size {
+        let mut descriptor = [0u8; SECTION_DESCRIPTOR_SIZE as usize];
+        read_exact_at(file, offset, &mut descriptor)?;
+        let name_end = descriptor[..16].iter().position(|byte| *byte == 0).unwrap_or(16);
+        let section_type = String::from_utf8_lossy(&descriptor[..name_end]).to_string();
+        let next = read_u64(&descriptor, 16);
+        let size = read_u64(&descriptor, 24);
+
+        match section_type.as_str() {
+            "header" if scan.header.is_none() => {
+                let text = decompress(&read_section_data(file, offset, size)?)?;
+                scan.header = Some(parse_header_text(&String::from_utf8_lossy(&text)));
+            }
+            "header2" if scan.header2.is_none() => {
+                let text = decompress(&read_section_data(file, offset, size)?)?;
+                scan.header2 = Some(parse_header_text(&decode_header2(&text)));
+            }
+            "volume" | "disk" | "data" if scan.volume.is_none() => {
+                let data = read_section_data(file, offset, size)?;
+                if data.len() < 24 {
+                    return Err(invalid_data("EWF volume section is truncated"));
+                }
+                let sectors_per_chunk = read_u32(&data, 8) as u64;
+                let bytes_per_sector = read_u32(&data, 12);
+                scan.volume = Some((sectors_per_chunk, bytes_per_sector, read_u64(&data, 16), data[0]));
+            }
+            "sectors" => sectors_ranges.push((offset + SECTION_DESCRIPTOR_SIZE, offset + size)),
+            "table" => {
+                let data = read_section_data(file, offset, size)?;
+                if data.len() < 24 {
+                    return Err(invalid_data("EWF table section is truncated"));
+                }
+                let entry_count = read_u32(&data, 0) as usize;
+                let base_offset = read_u64(&data, 8);
+                let entries: Vec<u32> = data[24..]
+                    .chunks_exact(4)
+                    .take(entry_count)
+                    .map(|entry| read_u32(entry, 0))
+                    .collect();
+                // A chunk ends where the next one starts; the last one at the end of
+                // the sectors section holding it.
+                for (index, entry) in entries.iter().enumerate() {
+                    let start = base_offset + (entry & !COMPRESSED_FLAG) as u64;
+                    let end = match entries.get(index + 1) {
+                        Some(next) => base_offset + (next & !COMPRESSED_FLAG) as u64,
+                        None => sectors_ranges
+                            .iter()
+                            .find(|(range_start, range_end)| (*range_start..*range_end).contains(&start))
+                            .map(|(_, range_end)| *range_end)
+                            .unwrap_or(offset),
+                    };
+                    if end <= start {
+                        return Err(invalid_data(format!("EWF chunk at offset {} in segment {} has no data", start, segment)));
+                    }
+                    chunks.push(Chunk {
+                        offset: start,
+                        size: (end - start).min(u32::MAX as u64) as u32,
+                        segment,
+                        compressed: entry & COMPRESSED_FLAG != 0,
+                    });
+                }
+            }
+            "hash" => {
+                let data = read_section_data(file, offset, size)?;
+                if data.len() >= 16 {
+                    scan.md5 = Some(data[..16].try_into().unwrap());
+                }
+            }
+            "digest" => {
+                let data = read_section_data(file, offset, size)?;
+                if data.len() >= 36 {
+                    scan.md5 = Some(data[..16].try_into().unwrap());
+                    scan.sha1 = Some(data[16..36].try_into().unwrap());
+                }
+            }
+            "error2" => {
+                let data = read_section_data(file, offset, size)?;
+                if data.len() >= 4 {
+                    scan.acquisition_errors = read_u32(&data, 0);
+                }
+            }
+            "done" => {
+                scan.done = true;
+                return Ok(());
+            }
+            "next" => return Ok(()),
+            _ => {}
+        }
+        if next <= offset {
+            break;
+        }
+        offset = next;
+    }
+    Err(invalid_data(format!("EWF segment {} ends without a next or done section", segment)))
+}
+
+impl EwfDisk {
+    pub fn open(path: &Path) -> io::Result<Self> {
+        let first = segment_path(path, 1)
+            .filter(|first| first.exists())
+            .ok_or_else(|| invalid_data(format!("{} does not look like an EWF segment (.E01)", path.display())))?;
+
+        let mut segments = Vec::new();
+        let mut segment_names = Vec::new();
+        let mut chunks = Vec::new();
+        let mut scan = SegmentScan::default();
+        for number in 1..=MAX_SEGMENTS {
+            let segment_file = match segment_path(&first, number) {
+                Some(segment_file) if segment_file.exists() => segment_file,
+                _ => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::NotFound,
+                        format!("EWF segment {} is missing (expected next to {})", number, first.display()),
+                    ))
+                }
+            };
+            let mut file = File::open(&segment_file)?;
+            let mut header = [0u8; FILE_HEADER_SIZE as usize];
+            read_exact_at(&mut file, 0, &mut header)?;
+            if &header[..8] != SIGNATURE {
+                return Err(invalid_data(format!("{} is not an EWF segment", segment_file.display())));
+            }
+            let segment_number = u16::from_le_bytes([header[9], header[10]]);
+            if segment_number as u32 != number {
+                return Err(invalid_data(format!(
+                    "{} is segment {} but segment {} was expected",
+                    segment_file.display(),
+                    segment_number,
+                    number
+                )));
+            }
+            scan_segment(&mut file, segments.len() as u16, &mut scan, &mut chunks)?;
+            segments.push(file);
+            segment_names.push(segment_file.display().to_string());
+            if scan.done {
+                break;
+            }
+        }
+        if !scan.done {
+            return Err(invalid_data("EWF segment set has no done section"));
+        }
+
+        let (sectors_per_chunk, bytes_per_sector, sector_count, media_type) =
+            scan.volume.ok_or_else(|| invalid_data("EWF image has no volume section"))?;
+        if !bytes_per_sector.is_power_of_two() || !(512..=MAX_SECTOR_SIZE).contains(&bytes_per_sector) {
+            return Err(invalid_data(format!("invalid EWF sector size {}", bytes_per_sector)));
+        }
+        let chunk_size = sectors_per_chunk * bytes_per_sector as u64;
+        if sectors_per_chunk == 0 || chunk_size > MAX_CHUNK_SIZE {
+            return Err(invalid_data(format!("invalid EWF chunk size of {} sectors", sectors_per_chunk)));
+        }
+        let size = sector_count
+            .checked_mul(bytes_per_sector as u64)
+            .ok_or_else(|| invalid_data(format!("invalid EWF sector count {}", sector_count)))?;
+        if (chunks.len() as u64) < size.div_ceil(chunk_size) {
+            return Err(invalid_data(format!(
+                "EWF chunk tables list {} chunks but the media needs {}",
+                chunks.len(),
+                size.div_ceil(chunk_size)
+            )));
+        }
+        Ok(Self {
+            segments,
+            segment_names,
+            chunks,
+            chunk_size,
+            size,
+            bytes_per_sector,
+            media_type,
+            header: scan.header2.or(scan.header).unwrap_or_default(),
+            md5: scan.md5,
+            sha1: scan.sha1,
+            acquisition_errors: scan.acquisition_errors,
+            cached_chunk: None,
+        })
+    }
+
+    fn read_chunk(&mut self, index: usize, in_chunk: u64, buf: &mut [u8]) -> io::Result<()> {
+        if self.cached_chunk.as_ref().is_none_or(|(cached, _)| *cached != index) {
+            let chunk = self.chunks[index];
+            // Uncompressed chunks are followed by a 4-byte Adler-32 checksum.
+            let max_stored = if chunk.compressed { compress_bound(self.chunk_size) } else { self.chunk_size + 4 };
+            if chunk.size as u64 > max_stored {
+                return Err(invalid_data(format!(
+                    "EWF chunk {} is {} bytes, larger than a {}-byte chunk can be",
+                    index, chunk.size, self.chunk_size
+                )));
+            }
+            let mut stored = vec![0u8; chunk.size as usize];
+            read_exact_at(&mut self.segments[chunk.segment as usize], chunk.offset, &mut stored)?;
+            let data = if chunk.compressed {
+                let mut data = Vec::with_capacity(self.chunk_size as usize);
+                ZlibDecoder::new(stored.as_slice())
+                    .take(self.chunk_size)
+                    .read_to_end(&mut data)
+                    .map_err(|err| invalid_data(format!("failed to inflate EWF chunk {}: {}", index, err)))?;
+                data
+            } else {
+                stored.truncate(self.chunk_size as usize);
+                stored
+            };
+            self.cached_chunk = Some((index, data));
+        }
+        let (_, data) = self.cached_chunk.as_ref().unwrap();
+        let start = in_chunk as usize;
+        let available = data.len().saturating_sub(start).min(buf.len());
+        buf[..available].copy_from_slice(&data[start..start + available]);
+        if available < buf.len() {
+            return Err(invalid_data(format!("EWF chunk {} is shorter than expected", index)));
+        }
+        Ok(())
+    }
+}
+
+impl Disk for EwfDisk {
+    fn size(&self) -> u64 {
+        self.size
+    }
+
+    fn sector_size(&self) -> u32 {
+        self.bytes_per_sector
+    }
+
+    fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> io::Result<()> {
+        check_bounds(offset, buf.len(), self.size)?;
+        let chunk_size = self.chunk_size;
+        read_blocks(offset, buf, chunk_size, |chunk, in_chunk, piece| self.read_chunk(chunk as usize, in_chunk, piece))
+    }
+
+    fn format_name(&self) -> &'static str {
+        FORMAT_NAME
+    }
+
+    fn details(&self) -> Vec<(String, String)> {
+        let media = match self.media_type {
+            0x00 => "removable disk",
+            0x01 => "fixed disk",
+            0x03 => "optical disc",
+            0x0E => "logical evidence",
+            0x10 => "memory",
+            _ => "unknown",
+        };
+        let mut details = vec![
+            ("Media type".to_string(), media.to_string()),
+            ("Media size".to_string(), format!("{} bytes", self.size)),
+            ("Chunk size".to_string(), format!("{} bytes", self.chunk_size)),
+            ("Segments".to_string(), self.segment_names.len().to_string()),
+        ];
+        details.extend(self.header.iter().cloned());
+        if let Some(md5) = &self.md5 {
+            details.push(("Acquisition MD5".to_string(), to_hex(md5)));
+        }
+        if let Some(sha1) = &self.sha1 {
+            details.push(("Acquisition SHA1".to_string(), to_hex(sha1)));
+        }
+        if self.acquisition_errors > 0 {
+            details.push(("Acquisition read errors".to_string(), self.acquisition_errors.to_string()));
+        }
+        details
+    }
+}
+
+// Re-reads the whole media and compares it with the hashes recorded at acquisition;
+// `progress` receives the bytes hashed so far and the media size.
+pub fn verify_ewf(path: &Path, mut progress: impl FnMut(u64, u64)) -> io::Result<Vec<HashCheck>> {
+    let mut disk = EwfDisk::open(path)?;
+    if disk.md5.is_none() && disk.sha1.is_none() {
+        return Err(invalid_data("the image does not contain acquisition hashes"));
+    }
+    let mut md5 = Md5::new();
+    let mut sha1 = Sha1::new();
+    let step = (disk.chunk_size * (VERIFY_BLOCK_SIZE / disk.chunk_size).max(1)).min(disk.size.max(1));
+    let mut buf = vec![0u8; step as usize];
+    let mut offset = 0;
+    while offset < disk.size {
+        let len = step.min(disk.size - offset) as usize;
+        disk.read_at(offset, &mut buf[..len])?;
+        md5.update(&buf[..len]);
+        sha1.update(&buf[..len]);
+        offset += len as u64;
+        progress(offset, disk.size);
+    }
+    let mut checks = Vec::new();
+    if let Some(stored) = &disk.md5 {
+        checks.push(HashCheck {
+            algorithm: "MD5",
+            stored: to_hex(stored),
+            computed: to_hex(&md5.finalize()),
+        });
+    }
+    if let Some(stored) = &disk.sha1 {
+        checks.push(HashCheck {
+            algorithm: "SHA1",
+            stored: to_hex(stored),
+            computed: to_hex(&sha1.finalize()),
+        });
+    }
+    Ok(checks)
+}
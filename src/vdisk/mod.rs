@@ -0,0 +1,182 @@
+use crate::disk::{Disk, ImageFile};
+use crate::filesystem::{list_volumes, Volume};
+use crate::partition_table::read_u32;
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+pub mod compressed;
+pub mod convert;
+pub mod ewf;
+pub mod qcow2;
+pub mod split;
+pub mod vdi;
+pub mod vhd;
+pub mod vhdx;
+pub mod vmdk;
+
+pub const MAX_PARENT_DEPTH: usize = 16;
+
+pub fn invalid_data(message: impl Into<String>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.into())
+}
+
+pub fn read_exact_at(file: &mut File, offset: u64, buf: &mut [u8]) -> io::Result<()> {
+    file.seek(SeekFrom::Start(offset))?;
+    file.read_exact(buf)
+}
+
+pub fn write_all_at(file: &mut File, offset: u64, buf: &[u8]) -> io::Result<()> {
+    file.seek(SeekFrom::Start(offset))?;
+    file.write_all(buf)
+}
+
+// Splits a virtual read into per-block pieces; `read_block` gets the block index,
+// the offset inside the block and the slice to fill.
+pub fn read_blocks(
+    offset: u64,
+    buf: &mut [u8],
+    block_size: u64,
+    mut read_block: impl FnMut(u64, u64, &mut [u8]) -> io::Result<()>,
+) -> io::Result<()> {
+    let mut done = 0;
+    while done < buf.len() {
+        let position = offset + done as u64;
+        let block = position / block_size;
+        let in_block = position % block_size;
+        let len = ((block_size - in_block) as usize).min(buf.len() - done);
+        read_block(block, in_block, &mut buf[done..done + len])?;
+        done += len;
+    }
+    Ok(())
+}
+
+// Days since 1970-01-01 to a proleptic Gregorian date.
+pub fn format_unix_time(seconds: u64) -> String {
+    let days = (seconds / 86400) as i64 + 719468;
+    let era = days.div_euclid(146097);
+    let day_of_era = days.rem_euclid(146097);
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 { month_index + 3 } else { month_index - 9 };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+    let time = seconds % 86400;
+    format!(
+        "{:04}-{:02}-{:02} {:02}:{:02}:{:02} UTC",
+        year,
+        month,
+        day,
+        time / 3600,
+        time / 60 % 60,
+        time % 60
+    )
+}
+
+pub fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+pub fn check_bounds(offset: u64, len: usize, size: u64) -> io::Result<()> {
+    if offset.checked_add(len as u64).is_none_or(|end| end > size) {
+        return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "read beyond the end of the virtual disk"));
+    }
+    Ok(())
+}
+
+// Largest zlib stream deflate can produce for `len` input bytes (zlib's compressBound).
+pub fn compress_bound(len: u64) -> u64 {
+    len + (len >> 12) + (len >> 14) + (len >> 25) + 13
+}
+
+// Parent locators may be relative to the child image or absolute paths from the
+// machine that created it; the first candidate that exists wins.
+pub fn resolve_parent_path(child: &Path, candidates: &[String]) -> io::Result<PathBuf> {
+    let directory = child.parent().unwrap_or_else(|| Path::new("."));
+    for candidate in candidates.iter().filter(|candidate| !candidate.is_empty()) {
+        let trimmed = candidate.trim_start_matches(".\\").trim_start_matches("./");
+        let relative = directory.join(trimmed);
+        if relative.exists() {
+            return Ok(relative);
+        }
+        let absolute = PathBuf::from(candidate);
+        if absolute.is_absolute() && absolute.exists() {
+            return Ok(absolute);
+        }
+        if let Some(file_name) = Path::new(&candidate.replace('\\', "/")).file_name() {
+            let sibling = directory.join(file_name);
+            if sibling.exists() {
+                return Ok(sibling);
+            }
+        }
+    }
+    Err(io::Error::new(
+        io::ErrorKind::NotFound,
+        format!("parent image not found (tried: {})", candidates.join(", ")),
+    ))
+}
+
+pub struct VirtualDrive {
+    pub path: String,
+    pub disk: Box<dyn Disk>,
+    pub volumes: Result<Vec<Volume>, String>,
+}
+
+impl VirtualDrive {
+    pub fn open(path: &str) -> io::Result<Self> {
+        let mut disk = open_virtual_disk(path)?;
+        let volumes = list_volumes(disk.as_mut()).map_err(|err| err.to_string());
+        Ok(Self {
+            path: path.to_string(),
+            disk,
+            volumes,
+        })
+    }
+}
+
+pub fn open_virtual_disk(path: &str) -> io::Result<Box<dyn Disk>> {
+    open_virtual_disk_at_depth(Path::new(path), 0)
+}
+
+pub fn open_virtual_disk_at_depth(path: &Path, depth: usize) -> io::Result<Box<dyn Disk>> {
+    if depth > MAX_PARENT_DEPTH {
+        return Err(invalid_data("differencing chain is too deep"));
+    }
+    if let Some(segments) = split::find_segments(path)? {
+        return Ok(Box::new(split::SplitImage::open(&segments)?));
+    }
+    let mut file = File::open(path)?;
+    let size = file.metadata()?.len();
+    let mut magic = [0u8; 72];
+    let magic_len = size.min(magic.len() as u64) as usize;
+    read_exact_at(&mut file, 0, &mut magic[..magic_len])?;
+    let mut footer_cookie = [0u8; 8];
+    if size >= 512 {
+        read_exact_at(&mut file, size - 512, &mut footer_cookie)?;
+    }
+
+    if magic.starts_with(compressed::MAGIC) {
+        return Ok(Box::new(compressed::CompressedImage::open(path)?));
+    }
+    if magic.starts_with(ewf::SIGNATURE) {
+        return Ok(Box::new(ewf::EwfDisk::open(path)?));
+    }
+    if magic.starts_with(qcow2::MAGIC) {
+        return Ok(Box::new(qcow2::Qcow2Disk::open(path, depth)?));
+    }
+    if magic.starts_with(vhdx::FILE_SIGNATURE) {
+        return Ok(Box::new(vhdx::VhdxDisk::open(path, depth)?));
+    }
+    if magic.starts_with(vhd::COOKIE) || &footer_cookie == vhd::COOKIE {
+        return vhd::open(path, depth);
+    }
+    if magic.starts_with(vmdk::SPARSE_MAGIC) || magic.starts_with(vmdk::DESCRIPTOR_SIGNATURE) {
+        return Ok(Box::new(vmdk::VmdkDisk::open(path, depth)?));
+    }
+    if read_u32(&magic, vdi::SIGNATURE_OFFSET) == vdi::SIGNATURE {
+        return Ok(Box::new(vdi::VdiDisk::open(path, depth)?));
+    }
+    let path = path.to_str().ok_or_else(|| invalid_data("image path is not valid UTF-8"))?;
+    Ok(Box::new(ImageFile::open(path, false)?))
+}
@@ -0,0 +1,495 @@
+use crate::disk::Disk;
+use crate::vdisk::convert::{create_output, ImageWriter};
+use crate::vdisk::{
+    check_bounds, format_unix_time, invalid_data, open_virtual_disk_at_depth, read_blocks, read_exact_at, resolve_parent_path,
+    write_all_at,
+};
+use flate2::read::DeflateDecoder;
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::Path;
+
+pub const MAGIC: &[u8; 4] = b"QFI\xfb";
+const OFFSET_MASK: u64 = 0x00FF_FFFF_FFFF_FE00;
+const L2_COMPRESSED: u64 = 1 << 62;
+const L2_ZERO: u64 = 1;
+const MAX_SNAPSHOTS: u32 = 65536;
+// The specification caps the extra data of a snapshot entry; id and name lengths are
+// 16-bit fields and so bounded already.
+const MAX_SNAPSHOT_EXTRA: usize = 1024;
+const COPIED: u64 = 1 << 63;
+const WRITE_CLUSTER_BITS: u32 = 16;
+pub const WRITE_CLUSTER_SIZE: u64 = 1 << WRITE_CLUSTER_BITS;
+
+const INCOMPAT_DIRTY: u64 = 1;
+const INCOMPAT_CORRUPT: u64 = 1 << 1;
+const INCOMPAT_EXTERNAL_DATA: u64 = 1 << 2;
+const INCOMPAT_COMPRESSION_TYPE: u64 = 1 << 3;
+const INCOMPAT_EXTENDED_L2: u64 = 1 << 4;
+
+fn be_u16(bytes: &[u8], offset: usize) -> u16 {
+    u16::from_be_bytes([bytes[offset], bytes[offset + 1]])
+}
+
+fn be_u32(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_be_bytes(bytes[offset..offset + 4].try_into().unwrap())
+}
+
+fn be_u64(bytes: &[u8], offset: usize) -> u64 {
+    u64::from_be_bytes(bytes[offset..offset + 8].try_into().unwrap())
+}
+
+#[derive(Clone, Debug)]
+pub struct Snapshot {
+    pub id: String,
+    pub name: String,
+    pub created: u64,
+    pub vm_state_size: u64,
+    pub disk_size: Option<u64>,
+}
+
+#[derive(Clone, Debug, Default)]
+struct RefcountSummary {
+    refcount_bits: u32,
+    used_clusters: u64,
+    shared_clusters: u64,
+}
+
+pub struct Qcow2Disk {
+    file: File,
+    file_size: u64,
+    version: u32,
+    cluster_bits: u32,
+    size: u64,
+    l1_table: Vec<u64>,
+    incompatible_features: u64,
+    snapshots: Vec<Snapshot>,
+    refcounts: Result<RefcountSummary, String>,
+    backing: Option<Box<dyn Disk>>,
+    backing_name: String,
+    cached_l2: Option<(u64, Vec<u64>)>,
+    cached_cluster: Option<(u64, Vec<u8>)>,
+}
+
+fn read_snapshots(file: &mut File, offset: u64, count: u32) -> io::Result<Vec<Snapshot>> {
+    let mut snapshots = Vec::new();
+    let mut position = offset;
+    for _ in 0..count.min(MAX_SNAPSHOTS) {
+        let mut header = [0u8; 40];
+        read_exact_at(file, position, &mut header)?;
+        let id_size = be_u16(&header, 12) as usize;
+        let name_size = be_u16(&header, 14) as usize;
+        let extra_size = be_u32(&header, 36) as usize;
+        if extra_size > MAX_SNAPSHOT_EXTRA {
+            return Err(invalid_data(format!("snapshot has {} bytes of extra data, at most {} are allowed", extra_size, MAX_SNAPSHOT_EXTRA)));
+        }
+        let mut rest = vec![0u8; extra_size + id_size + name_size];
+        read_exact_at(file, position + 40, &mut rest)?;
+        let extra = &rest[..extra_size];
+        snapshots.push(Snapshot {
+            id: String::from_utf8_lossy(&rest[extra_size..extra_size + id_size]).to_string(),
+            name: String::from_utf8_lossy(&rest[extra_size + id_size..]).to_string(),
+            created: be_u32(&header, 16) as u64,
+            vm_state_size: if extra_size >= 8 { be_u64(extra, 0) } else { be_u32(&header, 32) as u64 },
+            disk_size: if extra_size >= 16 { Some(be_u64(extra, 8)) } else { None },
+        });
+        position += (40 + rest.len() as u64).div_ceil(8) * 8;
+    }
+    Ok(snapshots)
+}
+
+// Refcounts are not needed to read guest data, but clusters referenced more than
+// once are shared with internal snapshots, which is worth knowing before converting.
+fn summarize_refcounts(
+    file: &mut File,
+    file_size: u64,
+    table_offset: u64,
+    table_clusters: u32,
+    cluster_size: u64,
+    refcount_order: u32,
+) -> io::Result<RefcountSummary> {
+    if refcount_order > 6 {
+        return Err(invalid_data(format!("invalid refcount order {}", refcount_order)));
+    }
+    let refcount_bits = 1u32 << refcount_order;
+    let table_len = table_clusters as u64 * cluster_size;
+    if table_offset.checked_add(table_len).is_none_or(|end| end > file_size) {
+        return Err(invalid_data(format!("refcount table of {} clusters lies beyond the end of the file", table_clusters)));
+    }
+    let mut table = vec![0u8; table_len as usize];
+    read_exact_at(file, table_offset, &mut table)?;
+    let mut summary = RefcountSummary {
+        refcount_bits,
+        ..Default::default()
+    };
+    let mut block = vec![0u8; cluster_size as usize];
+    for entry in table.chunks_exact(8).map(|entry| be_u64(entry, 0) & OFFSET_MASK).filter(|offset| *offset != 0) {
+        read_exact_at(file, entry, &mut block)?;
+        for index in 0..cluster_size * 8 / refcount_bits as u64 {
+            let bit = index * refcount_bits as u64;
+            let refcount = if refcount_bits >= 8 {
+                let start = (bit / 8) as usize;
+                block[start..start + refcount_bits as usize / 8]
+                    .iter()
+                    .fold(0u64, |value, byte| (value << 8) | *byte as u64)
+            } else {
+                ((block[(bit / 8) as usize] >> (bit % 8)) & ((1u8 << refcount_bits) - 1)) as u64
+            };
+            if refcount > 0 {
+                summary.used_clusters += 1;
+            }
+            if refcount > 1 {
+                summary.shared_clusters += 1;
+            }
+        }
+    }
+    Ok(summary)
+}
+
+impl Qcow2Disk {
+    pub fn open(path: &Path, depth: usize) -> io::Result<Self> {
+        let mut file = File::open(path)?;
+        let file_size = file.metadata()?.len();
+        let mut header = [0u8; 104];
+        read_exact_at(&mut file, 0, &mut header[..72])?;
+        if &header[0..4] != MAGIC {
+            return Err(invalid_data("missing qcow2 magic"));
+        }
+        let version = be_u32(&header, 4);
+        if version != 2 && version != 3 {
+            return Err(invalid_data(format!("unsupported qcow2 version {}", version)));
+        }
+        let cluster_bits = be_u32(&header, 20);
+        if !(9..=21).contains(&cluster_bits) {
+            return Err(invalid_data(format!("invalid qcow2 cluster size 2^{}", cluster_bits)));
+        }
+        if be_u32(&header, 32) != 0 {
+            return Err(invalid_data("encrypted qcow2 images are not supported"));
+        }
+
+        let (incompatible_features, refcount_order) = if version == 3 {
+            read_exact_at(&mut file, 72, &mut header[72..104])?;
+            (be_u64(&header, 72), be_u32(&header, 96))
+        } else {
+            (0, 4)
+        };
+        let unsupported = incompatible_features & !(INCOMPAT_DIRTY | INCOMPAT_CORRUPT);
+        if unsupported & INCOMPAT_EXTERNAL_DATA != 0 {
+            return Err(invalid_data("qcow2 images with an external data file are not supported"));
+        }
+        if unsupported & INCOMPAT_COMPRESSION_TYPE != 0 {
+            return Err(invalid_data("qcow2 images with non-zlib compression are not supported"));
+        }
+        if unsupported & INCOMPAT_EXTENDED_L2 != 0 {
+            return Err(invalid_data("qcow2 images with extended L2 entries are not supported"));
+        }
+        if unsupported != 0 {
+            return Err(invalid_data(format!("unknown qcow2 incompatible features 0x{:X}", unsupported)));
+        }
+
+        let cluster_size = 1u64 << cluster_bits;
+        let size = be_u64(&header, 24);
+        let l1_size = be_u32(&header, 36) as u64;
+        let l2_entries = cluster_size / 8;
+        if l1_size < size.div_ceil(cluster_size * l2_entries) || l1_size * 8 > file_size {
+            return Err(invalid_data(format!("qcow2 L1 table of {} entries is inconsistent with the disk size", l1_size)));
+        }
+        let mut l1_bytes = vec![0u8; (l1_size * 8) as usize];
+        read_exact_at(&mut file, be_u64(&header, 40), &mut l1_bytes)?;
+        let l1_table = l1_bytes.chunks_exact(8).map(|entry| be_u64(entry, 0)).collect();
+
+        let snapshots = read_snapshots(&mut file, be_u64(&header, 64), be_u32(&header, 60))?;
+        let refcounts = summarize_refcounts(
+            &mut file,
+            file_size,
+            be_u64(&header, 48),
+            be_u32(&header, 56),
+            cluster_size,
+            refcount_order,
+        )
+        .map_err(|err| err.to_string());
+
+        let backing_offset = be_u64(&header, 8);
+        let backing_size = be_u32(&header, 16) as usize;
+        let (backing, backing_name) = if backing_offset != 0 && backing_size > 0 {
+            let mut name = vec![0u8; backing_size.min(1023)];
+            read_exact_at(&mut file, backing_offset, &mut name)?;
+            let name = String::from_utf8_lossy(&name).to_string();
+            let backing_path = resolve_parent_path(path, &[name])?;
+            let backing = open_virtual_disk_at_depth(&backing_path, depth + 1)?;
+            (Some(backing), backing_path.display().to_string())
+        } else {
+            (None, String::new())
+        };
+
+        Ok(Self {
+            file,
+            file_size,
+            version,
+            cluster_bits,
+            size,
+            l1_table,
+            incompatible_features,
+            snapshots,
+            refcounts,
+            backing,
+            backing_name,
+            cached_l2: None,
+            cached_cluster: None,
+        })
+    }
+
+    fn cluster_size(&self) -> u64 {
+        1 << self.cluster_bits
+    }
+
+    fn l2_entry(&mut self, cluster: u64) -> io::Result<u64> {
+        let l2_entries = self.cluster_size() / 8;
+        let l1_entry = self.l1_table.get((cluster / l2_entries) as usize).copied().unwrap_or(0);
+        let l2_offset = l1_entry & OFFSET_MASK;
+        if l2_offset == 0 {
+            return Ok(0);
+        }
+        if self.cached_l2.as_ref().is_none_or(|(offset, _)| *offset != l2_offset) {
+            let mut bytes = vec![0u8; self.cluster_size() as usize];
+            read_exact_at(&mut self.file, l2_offset, &mut bytes)?;
+            let table = bytes.chunks_exact(8).map(|entry| be_u64(entry, 0)).collect();
+            self.cached_l2 = Some((l2_offset, table));
+        }
+        let (_, table) = self.cached_l2.as_ref().unwrap();
+        Ok(table[(cluster % l2_entries) as usize])
+    }
+
+    fn read_compressed(&mut self, entry: u64, in_cluster: u64, buf: &mut [u8]) -> io::Result<()> {
+        let offset_bits = 62 - (self.cluster_bits - 8);
+        let host_offset = entry & ((1u64 << offset_bits) - 1);
+        if self.cached_cluster.as_ref().is_none_or(|(offset, _)| *offset != host_offset) {
+            let sectors = ((entry & !L2_COMPRESSED) >> offset_bits) + 1;
+            let compressed_size = (sectors * 512 - (host_offset & 511)).min(self.file_size.saturating_sub(host_offset));
+            let mut compressed = vec![0u8; compressed_size as usize];
+            read_exact_at(&mut self.file, host_offset, &mut compressed)?;
+            let mut cluster = vec![0u8; self.cluster_size() as usize];
+            DeflateDecoder::new(compressed.as_slice())
+                .read_exact(&mut cluster)
+                .map_err(|err| invalid_data(format!("failed to inflate compressed cluster at {}: {}", host_offset, err)))?;
+            self.cached_cluster = Some((host_offset, cluster));
+        }
+        let (_, cluster) = self.cached_cluster.as_ref().unwrap();
+        buf.copy_from_slice(&cluster[in_cluster as usize..in_cluster as usize + buf.len()]);
+        Ok(())
+    }
+
+    // Backing files may be smaller than the overlay; anything past their end reads as zeros.
+    fn read_backing(&mut self, offset: u64, buf: &mut [u8]) -> io::Result<()> {
+        buf.fill(0);
+        if let Some(backing) = &mut self.backing {
+            let available = backing.size().saturating_sub(offset).min(buf.len() as u64) as usize;
+            if available > 0 {
+                backing.read_at(offset, &mut buf[..available])?;
+            }
+        }
+        Ok(())
+    }
+
+    fn read_cluster(&mut self, cluster: u64, in_cluster: u64, buf: &mut [u8]) -> io::Result<()> {
+        let entry = self.l2_entry(cluster)?;
+        if entry & L2_COMPRESSED != 0 {
+            return self.read_compressed(entry, in_cluster, buf);
+        }
+        if self.version == 3 && entry & L2_ZERO != 0 {
+            buf.fill(0);
+            return Ok(());
+        }
+        let host_offset = entry & OFFSET_MASK;
+        if host_offset == 0 {
+            return self.read_backing(cluster * self.cluster_size() + in_cluster, buf);
+        }
+        read_exact_at(&mut self.file, host_offset + in_cluster, buf)
+    }
+}
+
+impl Disk for Qcow2Disk {
+    fn size(&self) -> u64 {
+        self.size
+    }
+
+    fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> io::Result<()> {
+        check_bounds(offset, buf.len(), self.size)?;
+        let cluster_size = self.cluster_size();
+        read_blocks(offset, buf, cluster_size, |cluster, in_cluster, piece| self.read_cluster(cluster, in_cluster, piece))
+    }
+
+    fn format_name(&self) -> &'static str {
+        if self.backing.is_some() {
+            "QCOW2 (with backing file)"
+        } else {
+            "QCOW2"
+        }
+    }
+
+    fn details(&self) -> Vec<(String, String)> {
+        let mut details = vec![
+            ("Version".to_string(), self.version.to_string()),
+            ("Virtual size".to_string(), format!("{} bytes", self.size)),
+            ("Cluster size".to_string(), format!("{} bytes", self.cluster_size())),
+        ];
+        match &self.refcounts {
+            Ok(refcounts) => {
+                details.push(("Refcount width".to_string(), format!("{} bits", refcounts.refcount_bits)));
+                details.push((
+                    "Used clusters".to_string(),
+                    format!("{} ({} shared with snapshots)", refcounts.used_clusters, refcounts.shared_clusters),
+                ));
+            }
+            Err(err) => details.push(("Refcounts".to_string(), format!("unreadable: {}", err))),
+        }
+        if self.incompatible_features & INCOMPAT_DIRTY != 0 {
+            details.push(("State".to_string(), "dirty; refcounts may be stale".to_string()));
+        }
+        if self.incompatible_features & INCOMPAT_CORRUPT != 0 {
+            details.push(("State".to_string(), "marked corrupt".to_string()));
+        }
+        details.push(("Snapshots".to_string(), self.snapshots.len().to_string()));
+        for snapshot in &self.snapshots {
+            let disk_size = snapshot.disk_size.map(|size| format!(", disk {} bytes", size)).unwrap_or_default();
+            details.push((
+                format!("Snapshot {}", snapshot.id),
+                format!(
+                    "\"{}\" created {}, VM state {} bytes{}",
+                    snapshot.name,
+                    format_unix_time(snapshot.created),
+                    snapshot.vm_state_size,
+                    disk_size
+                ),
+            ));
+        }
+        if let Some(backing) = &self.backing {
+            details.push(("Backing file".to_string(), format!("{} ({})", self.backing_name, backing.format_name())));
+            details.extend(backing.details().into_iter().map(|(key, value)| (format!("Backing {}", key.to_lowercase()), value)));
+        }
+        details
+    }
+}
+
+// Writes a version 3 image with 64 KiB clusters and 16-bit refcounts. Clusters are
+// appended as data arrives; L2 tables follow the data they map, and the L1 table
+// sits right after the header. Refcounts are laid out last, once the size is known.
+// Blocks must arrive in ascending order, since a flushed L2 table is never reopened.
+pub struct Qcow2Writer {
+    file: File,
+    size: u64,
+    l1_table: Vec<u64>,
+    l2_table: Option<(usize, Vec<u64>)>,
+    last_index: Option<u64>,
+    next_cluster: u64,
+}
+
+impl Qcow2Writer {
+    pub fn create(path: &Path, size: u64) -> io::Result<Self> {
+        let l2_entries = WRITE_CLUSTER_SIZE / 8;
+        let l1_entries = size.div_ceil(WRITE_CLUSTER_SIZE * l2_entries).max(1);
+        let l1_clusters = (l1_entries * 8).div_ceil(WRITE_CLUSTER_SIZE);
+        Ok(Self {
+            file: create_output(path)?,
+            size,
+            l1_table: vec![0; l1_entries as usize],
+            l2_table: None,
+            last_index: None,
+            next_cluster: (1 + l1_clusters) * WRITE_CLUSTER_SIZE,
+        })
+    }
+
+    fn allocate(&mut self, data: &[u8]) -> io::Result<u64> {
+        let offset = self.next_cluster;
+        write_all_at(&mut self.file, offset, data)?;
+        self.next_cluster += WRITE_CLUSTER_SIZE;
+        Ok(offset)
+    }
+
+    fn flush_l2(&mut self) -> io::Result<()> {
+        if let Some((l1_index, table)) = self.l2_table.take() {
+            let bytes: Vec<u8> = table.iter().flat_map(|entry| entry.to_be_bytes()).collect();
+            let offset = self.allocate(&bytes)?;
+            self.l1_table[l1_index] = offset | COPIED;
+        }
+        Ok(())
+    }
+
+    fn write_refcounts(&mut self) -> io::Result<(u64, u32)> {
+        let per_block = WRITE_CLUSTER_SIZE / 2;
+        let used = self.next_cluster / WRITE_CLUSTER_SIZE;
+        // The refcount structures count themselves, so grow them until they fit.
+        let (mut blocks, mut table_clusters) = (0, 0);
+        loop {
+            let total = used + table_clusters + blocks;
+            let needed_blocks = total.div_ceil(per_block);
+            let needed_table = (needed_blocks * 8).div_ceil(WRITE_CLUSTER_SIZE);
+            if needed_blocks == blocks && needed_table == table_clusters {
+                break;
+            }
+            blocks = needed_blocks;
+            table_clusters = needed_table;
+        }
+        let table_offset = self.next_cluster;
+        let first_block = table_offset + table_clusters * WRITE_CLUSTER_SIZE;
+        let total = used + table_clusters + blocks;
+        let mut table = vec![0u8; (table_clusters * WRITE_CLUSTER_SIZE) as usize];
+        for block in 0..blocks {
+            let offset = first_block + block * WRITE_CLUSTER_SIZE;
+            table[block as usize * 8..block as usize * 8 + 8].copy_from_slice(&offset.to_be_bytes());
+            let mut refcounts = vec![0u8; WRITE_CLUSTER_SIZE as usize];
+            for index in 0..per_block.min(total - block * per_block) {
+                refcounts[index as usize * 2..index as usize * 2 + 2].copy_from_slice(&1u16.to_be_bytes());
+            }
+            write_all_at(&mut self.file, offset, &refcounts)?;
+        }
+        write_all_at(&mut self.file, table_offset, &table)?;
+        self.next_cluster = first_block + blocks * WRITE_CLUSTER_SIZE;
+        Ok((table_offset, table_clusters as u32))
+    }
+}
+
+impl ImageWriter for Qcow2Writer {
+    fn write_block(&mut self, index: u64, data: &[u8]) -> io::Result<()> {
+        if self.last_index.is_some_and(|last| index <= last) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("qcow2 block {} written out of order", index),
+            ));
+        }
+        self.last_index = Some(index);
+        let l2_entries = WRITE_CLUSTER_SIZE / 8;
+        let l1_index = (index / l2_entries) as usize;
+        if self.l2_table.as_ref().is_some_and(|(current, _)| *current != l1_index) {
+            self.flush_l2()?;
+        }
+        let offset = self.allocate(data)?;
+        let (_, table) = self.l2_table.get_or_insert_with(|| (l1_index, vec![0; l2_entries as usize]));
+        table[(index % l2_entries) as usize] = offset | COPIED;
+        Ok(())
+    }
+
+    fn finish(mut self: Box<Self>) -> io::Result<()> {
+        self.flush_l2()?;
+        let (refcount_table_offset, refcount_table_clusters) = self.write_refcounts()?;
+        let l1: Vec<u8> = self.l1_table.iter().flat_map(|entry| entry.to_be_bytes()).collect();
+        write_all_at(&mut self.file, WRITE_CLUSTER_SIZE, &l1)?;
+
+        let mut header = vec![0u8; 104];
+        header[0..4].copy_from_slice(MAGIC);
+        header[4..8].copy_from_slice(&3u32.to_be_bytes());
+        header[20..24].copy_from_slice(&WRITE_CLUSTER_BITS.to_be_bytes());
+        header[24..32].copy_from_slice(&self.size.to_be_bytes());
+        header[36..40].copy_from_slice(&(self.l1_table.len() as u32).to_be_bytes());
+        header[40..48].copy_from_slice(&WRITE_CLUSTER_SIZE.to_be_bytes());
+        header[48..56].copy_from_slice(&refcount_table_offset.to_be_bytes());
+        header[56..60].copy_from_slice(&refcount_table_clusters.to_be_bytes());
+        header[96..100].copy_from_slice(&4u32.to_be_bytes());
+        header[100..104].copy_from_slice(&104u32.to_be_bytes());
+        // An all-zero header extension ends the extension list.
+        header.resize(112, 0);
+        write_all_at(&mut self.file, 0, &header)?;
+        self.file.set_len(self.next_cluster)?;
+        self.file.sync_all()
+    }
+}
@@ -0,0 +1,194 @@
+use crate::disk::Disk;
+use crate::vdisk::{check_bounds, invalid_data, read_exact_at};
+use std::fs::File;
+use std::io;
+use std::path::{Path, PathBuf};
+
+// How many names past a gap are probed to tell a missing segment from the end of the set.
+const GAP_PROBE: u32 = 16;
+const MAX_SEGMENTS: u32 = 100_000;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Naming {
+    // .000/.001, .002, ... with a fixed number of digits
+    Numeric { width: usize, first: u32 },
+    // .aa, .ab, ... as produced by split(1)
+    Letters { width: usize, uppercase: bool },
+}
+
+impl Naming {
+    fn extension(&self, index: u32) -> Option<String> {
+        match *self {
+            Naming::Numeric { width, first } => {
+                let number = first + index;
+                let extension = format!("{:0width$}", number, width = width);
+                (extension.len() == width).then_some(extension)
+            }
+            Naming::Letters { width, uppercase } => {
+                let mut remaining = index;
+                let mut letters = vec![b'a'; width];
+                for letter in letters.iter_mut().rev() {
+                    *letter = b'a' + (remaining % 26) as u8;
+                    remaining /= 26;
+                }
+                if remaining > 0 {
+                    return None;
+                }
+                let extension = String::from_utf8(letters).unwrap();
+                Some(if uppercase { extension.to_ascii_uppercase() } else { extension })
+            }
+        }
+    }
+}
+
+fn naming_of(path: &Path) -> Option<Naming> {
+    let extension = path.extension()?.to_str()?;
+    let (naming, index) = if extension.len() >= 3 && extension.bytes().all(|byte| byte.is_ascii_digit()) {
+        let width = extension.len();
+        let first = if path.with_extension(format!("{:0width$}", 0, width = width)).exists() { 0 } else { 1 };
+        (Naming::Numeric { width, first }, extension.parse::<u32>().ok()?.checked_sub(first)?)
+    } else {
+        let uppercase = extension.bytes().all(|byte| byte.is_ascii_uppercase());
+        if extension.len() != 2 || !(uppercase || extension.bytes().all(|byte| byte.is_ascii_lowercase())) {
+            return None;
+        }
+        let index = extension.to_ascii_lowercase().bytes().fold(0, |index, letter| index * 26 + (letter - b'a') as u32);
+        (Naming::Letters { width: 2, uppercase }, index)
+    };
+    // Plenty of real extensions are two letters (.gz, .js) or numbers (.2024), so a
+    // lone file is never taken for a split part; a neighbouring segment has to exist as well.
+    let sibling_exists = [index.checked_sub(1), index.checked_add(1)]
+        .into_iter()
+        .flatten()
+        .filter_map(|sibling| naming.extension(sibling))
+        .any(|extension| path.with_extension(extension).exists());
+    sibling_exists.then_some(naming)
+}
+
+// Returns the ordered segment list when `path` is one part of a split image with at
+// least two parts, None when it does not look like one.
+pub fn find_segments(path: &Path) -> io::Result<Option<Vec<PathBuf>>> {
+    let Some(naming) = naming_of(path) else {
+        return Ok(None);
+    };
+    let segment = |index: u32| naming.extension(index).map(|extension| path.with_extension(extension));
+    let mut segments = Vec::new();
+    for index in 0..MAX_SEGMENTS {
+        match segment(index) {
+            Some(candidate) if candidate.exists() => segments.push(candidate),
+            _ => break,
+        }
+    }
+    if segments.is_empty() {
+        // A .002 next to a .003, or an .ab next to an .ac, means the earlier parts are missing.
+        if let Some(first) = segment(0).filter(|first| first != path) {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("first segment {} of the split image is missing", first.display()),
+            ));
+        }
+        return Ok(None);
+    }
+    let next = segments.len() as u32;
+    if let Some(later) = (next + 1..next + GAP_PROBE).filter_map(segment).find(|candidate| candidate.exists()) {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!(
+                "segment {} of the split image is missing ({} exists)",
+                segment(next).map(|missing| missing.display().to_string()).unwrap_or_default(),
+                later.display()
+            ),
+        ));
+    }
+    // A lone .001 is just a raw image.
+    if segments.len() < 2 {
+        return Ok(None);
+    }
+    if !segments.iter().any(|candidate| candidate == path) {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("{} is not part of the contiguous segment set starting at {}", path.display(), segments[0].display()),
+        ));
+    }
+    Ok(Some(segments))
+}
+
+struct Segment {
+    file: File,
+    start: u64,
+    size: u64,
+}
+
+pub struct SplitImage {
+    segments: Vec<Segment>,
+    names: Vec<String>,
+    size: u64,
+}
+
+impl SplitImage {
+    pub fn open(paths: &[PathBuf]) -> io::Result<Self> {
+        let mut segments = Vec::new();
+        let mut start = 0;
+        for path in paths {
+            let file = File::open(path)?;
+            let size = file.metadata()?.len();
+            segments.push(Segment { file, start, size });
+            start += size;
+        }
+        // Every part but the last must have the size of the first; the last may be shorter.
+        let segment_size = segments[0].size;
+        for (index, segment) in segments.iter().enumerate() {
+            let is_last = index + 1 == segments.len();
+            let consistent = if is_last { segment.size > 0 && segment.size <= segment_size } else { segment.size == segment_size };
+            if !consistent {
+                return Err(invalid_data(format!(
+                    "segment {} is {} bytes but the segments before it are {} bytes{}",
+                    paths[index].display(),
+                    segment.size,
+                    segment_size,
+                    if is_last { "" } else { "; the set may be truncated or mixed with another image" }
+                )));
+            }
+        }
+        Ok(Self {
+            segments,
+            names: paths.iter().map(|path| path.display().to_string()).collect(),
+            size: start,
+        })
+    }
+}
+
+impl Disk for SplitImage {
+    fn size(&self) -> u64 {
+        self.size
+    }
+
+    fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> io::Result<()> {
+        check_bounds(offset, buf.len(), self.size)?;
+        let mut done = 0;
+        while done < buf.len() {
+            let position = offset + done as u64;
+            let index = self.segments.partition_point(|segment| segment.start + segment.size <= position);
+            let segment = &mut self.segments[index];
+            let in_segment = position - segment.start;
+            let len = ((segment.size - in_segment) as usize).min(buf.len() - done);
+            read_exact_at(&mut segment.file, in_segment, &mut buf[done..done + len])?;
+            done += len;
+        }
+        Ok(())
+    }
+
+    fn format_name(&self) -> &'static str {
+        "Split raw image"
+    }
+
+    fn details(&self) -> Vec<(String, String)> {
+        vec![
+            ("Total size".to_string(), format!("{} bytes", self.size)),
+            ("Segments".to_string(), self.segments.len().to_string()),
+            ("Segment size".to_string(), format!("{} bytes", self.segments[0].size)),
+            ("First segment".to_string(), self.names[0].clone()),
+            ("Last segment".to_string(), self.names[self.names.len() - 1].clone()),
+        ]
+    }
+}
@@ -0,0 +1,226 @@
+use crate::disk::Disk;
+use crate::partition_table::{read_u32, read_u64, Guid};
+use crate::vdisk::{check_bounds, invalid_data, open_virtual_disk_at_depth, read_blocks, read_exact_at};
+use std::fs::{self, File};
+use std::io;
+use std::path::{Path, PathBuf};
+
+pub const SIGNATURE: u32 = 0xBEDA_107F;
+pub const SIGNATURE_OFFSET: usize = 64;
+const BLOCK_FREE: u32 = 0xFFFF_FFFF;
+const BLOCK_ZERO: u32 = 0xFFFF_FFFE;
+// Sizes of the pre-header (signature and version) and the headers that follow it.
+const PRE_HEADER_SIZE: u64 = 72;
+const HEADER_V0_SIZE: u64 = 348;
+const HEADER_V1_SIZE: u32 = 384;
+
+const TYPE_NORMAL: u32 = 1;
+const TYPE_FIXED: u32 = 2;
+const TYPE_UNDO: u32 = 3;
+const TYPE_DIFF: u32 = 4;
+
+#[derive(Clone, Debug)]
+struct VdiHeader {
+    version: (u16, u16),
+    image_type: u32,
+    comment: String,
+    blocks_offset: u64,
+    data_offset: u64,
+    disk_size: u64,
+    block_size: u64,
+    block_extra: u64,
+    block_count: u32,
+    allocated_blocks: u32,
+    uuid: Guid,
+    parent_uuid: Guid,
+}
+
+fn read_comment(comment: &[u8]) -> String {
+    String::from_utf8_lossy(&comment[..comment.iter().position(|byte| *byte == 0).unwrap_or(comment.len())]).to_string()
+}
+
+// Version 0 headers have no header size, block offsets or extra block data; the
+// block map directly follows the header and the data follows the map.
+fn parse_header_v0(bytes: &[u8], version: (u16, u16)) -> VdiHeader {
+    let block_count = read_u32(bytes, 364);
+    let blocks_offset = PRE_HEADER_SIZE + HEADER_V0_SIZE;
+    VdiHeader {
+        version,
+        image_type: read_u32(bytes, 72),
+        comment: read_comment(&bytes[80..336]),
+        blocks_offset,
+        data_offset: blocks_offset + block_count as u64 * 4,
+        disk_size: read_u64(bytes, 352),
+        block_size: read_u32(bytes, 360) as u64,
+        block_extra: 0,
+        block_count,
+        allocated_blocks: read_u32(bytes, 368),
+        uuid: Guid::from_bytes(&bytes[372..388]),
+        parent_uuid: Guid::from_bytes(&bytes[404..420]),
+    }
+}
+
+fn parse_header_v1(bytes: &[u8], version: (u16, u16)) -> io::Result<VdiHeader> {
+    let header_size = read_u32(bytes, 72);
+    if header_size < HEADER_V1_SIZE {
+        return Err(invalid_data(format!("VDI header of {} bytes is too small", header_size)));
+    }
+    Ok(VdiHeader {
+        version,
+        image_type: read_u32(bytes, 76),
+        comment: read_comment(&bytes[84..340]),
+        blocks_offset: read_u32(bytes, 340) as u64,
+        data_offset: read_u32(bytes, 344) as u64,
+        disk_size: read_u64(bytes, 368),
+        block_size: read_u32(bytes, 376) as u64,
+        block_extra: read_u32(bytes, 380) as u64,
+        block_count: read_u32(bytes, 384),
+        allocated_blocks: read_u32(bytes, 388),
+        uuid: Guid::from_bytes(&bytes[392..408]),
+        parent_uuid: Guid::from_bytes(&bytes[424..440]),
+    })
+}
+
+fn parse_header(bytes: &[u8]) -> io::Result<VdiHeader> {
+    if read_u32(bytes, SIGNATURE_OFFSET) != SIGNATURE {
+        return Err(invalid_data("missing VDI signature"));
+    }
+    let version = read_u32(bytes, 68);
+    let version = ((version >> 16) as u16, version as u16);
+    match version.0 {
+        0 => Ok(parse_header_v0(bytes, version)),
+        1 => parse_header_v1(bytes, version),
+        _ => Err(invalid_data(format!("unsupported VDI version {}.{}", version.0, version.1))),
+    }
+}
+
+fn read_header(path: &Path) -> io::Result<VdiHeader> {
+    let mut file = File::open(path)?;
+    let mut bytes = [0u8; 512];
+    read_exact_at(&mut file, 0, &mut bytes)?;
+    parse_header(&bytes)
+}
+
+// VDI differencing images only record the parent's UUID, so the parent is looked
+// up among the other VDI files in the same directory.
+fn find_parent(child: &Path, parent_uuid: &Guid) -> io::Result<PathBuf> {
+    let directory = child.parent().unwrap_or_else(|| Path::new("."));
+    for entry in fs::read_dir(directory)? {
+        let path = entry?.path();
+        let is_vdi = path.extension().is_some_and(|extension| extension.eq_ignore_ascii_case("vdi"));
+        if is_vdi && path != child && read_header(&path).is_ok_and(|header| header.uuid == *parent_uuid) {
+            return Ok(path);
+        }
+    }
+    Err(io::Error::new(
+        io::ErrorKind::NotFound,
+        format!("parent VDI with UUID {} not found next to {}", parent_uuid, child.display()),
+    ))
+}
+
+pub struct VdiDisk {
+    file: File,
+    header: VdiHeader,
+    block_map: Vec<u32>,
+    parent: Option<Box<dyn Disk>>,
+    parent_name: String,
+}
+
+impl VdiDisk {
+    pub fn open(path: &Path, depth: usize) -> io::Result<Self> {
+        let mut file = File::open(path)?;
+        let mut bytes = [0u8; 512];
+        read_exact_at(&mut file, 0, &mut bytes)?;
+        let header = parse_header(&bytes)?;
+        if header.block_size == 0 || !header.block_size.is_power_of_two() {
+            return Err(invalid_data(format!("invalid VDI block size {}", header.block_size)));
+        }
+        if (header.block_count as u64) < header.disk_size.div_ceil(header.block_size) {
+            return Err(invalid_data("VDI block map is smaller than the disk"));
+        }
+        if header.block_count as u64 * 4 > file.metadata()?.len() {
+            return Err(invalid_data("VDI block map is larger than the file"));
+        }
+        let mut map = vec![0u8; header.block_count as usize * 4];
+        read_exact_at(&mut file, header.blocks_offset, &mut map)?;
+        let block_map = map.chunks_exact(4).map(|entry| read_u32(entry, 0)).collect();
+
+        let (parent, parent_name) = if matches!(header.image_type, TYPE_DIFF | TYPE_UNDO) && !header.parent_uuid.is_zero() {
+            let parent_path = find_parent(path, &header.parent_uuid)?;
+            let parent = open_virtual_disk_at_depth(&parent_path, depth + 1)?;
+            (Some(parent), parent_path.display().to_string())
+        } else {
+            (None, String::new())
+        };
+
+        Ok(Self {
+            file,
+            header,
+            block_map,
+            parent,
+            parent_name,
+        })
+    }
+
+    fn read_block(&mut self, block: u64, in_block: u64, buf: &mut [u8]) -> io::Result<()> {
+        match self.block_map.get(block as usize).copied().unwrap_or(BLOCK_FREE) {
+            BLOCK_FREE => match &mut self.parent {
+                Some(parent) => parent.read_at(block * self.header.block_size + in_block, buf),
+                None => {
+                    buf.fill(0);
+                    Ok(())
+                }
+            },
+            BLOCK_ZERO => {
+                buf.fill(0);
+                Ok(())
+            }
+            index => {
+                let stride = self.header.block_size + self.header.block_extra;
+                let offset = self.header.data_offset + index as u64 * stride + self.header.block_extra + in_block;
+                read_exact_at(&mut self.file, offset, buf)
+            }
+        }
+    }
+}
+
+impl Disk for VdiDisk {
+    fn size(&self) -> u64 {
+        self.header.disk_size
+    }
+
+    fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> io::Result<()> {
+        check_bounds(offset, buf.len(), self.header.disk_size)?;
+        let block_size = self.header.block_size;
+        read_blocks(offset, buf, block_size, |block, in_block, piece| self.read_block(block, in_block, piece))
+    }
+
+    fn format_name(&self) -> &'static str {
+        match self.header.image_type {
+            TYPE_FIXED => "VDI (fixed)",
+            TYPE_DIFF => "VDI (differencing)",
+            TYPE_UNDO => "VDI (undo)",
+            TYPE_NORMAL => "VDI (dynamic)",
+            _ => "VDI",
+        }
+    }
+
+    fn details(&self) -> Vec<(String, String)> {
+        let header = &self.header;
+        let mut details = vec![
+            ("Version".to_string(), format!("{}.{}", header.version.0, header.version.1)),
+            ("Virtual size".to_string(), format!("{} bytes", header.disk_size)),
+            ("Block size".to_string(), format!("{} bytes", header.block_size)),
+            ("Allocated blocks".to_string(), format!("{} of {}", header.allocated_blocks, header.block_count)),
+            ("UUID".to_string(), header.uuid.to_string()),
+        ];
+        if !header.comment.is_empty() {
+            details.push(("Comment".to_string(), header.comment.clone()));
+        }
+        if let Some(parent) = &self.parent {
+            details.push(("Parent".to_string(), format!("{} ({})", self.parent_name, parent.format_name())));
+            details.extend(parent.details().into_iter().map(|(key, value)| (format!("Parent {}", key.to_lowercase()), value)));
+        }
+        details
+    }
+}
@@ -0,0 +1,436 @@
+use crate::disk::Disk;
+use crate::partition_table::Guid;
+use crate::vdisk::convert::{create_output, ImageWriter, RAW_BLOCK_SIZE};
+use crate::vdisk::{
+    check_bounds, invalid_data, open_virtual_disk_at_depth, read_blocks, read_exact_at, resolve_parent_path, write_all_at,
+};
+use std::fs::File;
+use std::io;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+pub const COOKIE: &[u8; 8] = b"conectix";
+pub const DYNAMIC_COOKIE: &[u8; 8] = b"cxsparse";
+pub const FOOTER_SIZE: u64 = 512;
+const UNALLOCATED: u32 = 0xFFFF_FFFF;
+pub const DYNAMIC_BLOCK_SIZE: u64 = 2 * 1024 * 1024;
+// The largest size the CHS geometry field and Windows accept.
+const MAX_VHD_SIZE: u64 = 2040 * 1024 * 1024 * 1024;
+// VHD timestamps count seconds from 2000-01-01 00:00:00 UTC.
+const VHD_EPOCH: u64 = 946_684_800;
+
+pub const DISK_TYPE_FIXED: u32 = 2;
+pub const DISK_TYPE_DYNAMIC: u32 = 3;
+pub const DISK_TYPE_DIFFERENCING: u32 = 4;
+
+fn be_u32(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_be_bytes(bytes[offset..offset + 4].try_into().unwrap())
+}
+
+fn be_u64(bytes: &[u8], offset: usize) -> u64 {
+    u64::from_be_bytes(bytes[offset..offset + 8].try_into().unwrap())
+}
+
+pub fn vhd_checksum(bytes: &[u8], checksum_offset: usize) -> u32 {
+    let sum = bytes
+        .iter()
+        .enumerate()
+        .filter(|(i, _)| !(checksum_offset..checksum_offset + 4).contains(i))
+        .fold(0u32, |sum, (_, byte)| sum.wrapping_add(*byte as u32));
+    !sum
+}
+
+#[derive(Clone, Debug)]
+pub struct VhdFooter {
+    pub data_offset: u64,
+    pub current_size: u64,
+    pub disk_type: u32,
+    pub unique_id: [u8; 16],
+    pub creator_application: String,
+    pub checksum_valid: bool,
+}
+
+pub fn parse_footer(bytes: &[u8]) -> io::Result<VhdFooter> {
+    if &bytes[0..8] != COOKIE {
+        return Err(invalid_data("missing VHD footer cookie"));
+    }
+    Ok(VhdFooter {
+        data_offset: be_u64(bytes, 16),
+        current_size: be_u64(bytes, 48),
+        disk_type: be_u32(bytes, 60),
+        unique_id: bytes[68..84].try_into().unwrap(),
+        creator_application: String::from_utf8_lossy(&bytes[28..32]).trim_end_matches('\0').to_string(),
+        checksum_valid: vhd_checksum(&bytes[..FOOTER_SIZE as usize], 64) == be_u32(bytes, 64),
+    })
+}
+
+fn disk_type_name(disk_type: u32) -> &'static str {
+    match disk_type {
+        DISK_TYPE_FIXED => "fixed",
+        DISK_TYPE_DYNAMIC => "dynamic",
+        DISK_TYPE_DIFFERENCING => "differencing",
+        _ => "unknown",
+    }
+}
+
+pub struct FixedVhd {
+    file: File,
+    footer: VhdFooter,
+}
+
+impl Disk for FixedVhd {
+    fn size(&self) -> u64 {
+        self.footer.current_size
+    }
+
+    fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> io::Result<()> {
+        check_bounds(offset, buf.len(), self.footer.current_size)?;
+        read_exact_at(&mut self.file, offset, buf)
+    }
+
+    fn format_name(&self) -> &'static str {
+        "VHD (fixed)"
+    }
+
+    fn details(&self) -> Vec<(String, String)> {
+        footer_details(&self.footer)
+    }
+}
+
+fn footer_details(footer: &VhdFooter) -> Vec<(String, String)> {
+    vec![
+        ("Disk type".to_string(), disk_type_name(footer.disk_type).to_string()),
+        ("Virtual size".to_string(), format!("{} bytes", footer.current_size)),
+        ("Creator".to_string(), footer.creator_application.clone()),
+        ("Footer checksum".to_string(), if footer.checksum_valid { "valid" } else { "INVALID" }.to_string()),
+    ]
+}
+
+pub struct DynamicVhd {
+    file: File,
+    footer: VhdFooter,
+    block_size: u64,
+    bitmap_size: u64,
+    bat: Vec<u32>,
+    parent: Option<Box<dyn Disk>>,
+    parent_name: String,
+}
+
+impl DynamicVhd {
+    fn sector_in_bitmap(&mut self, block_sector: u64, sector: u64) -> io::Result<bool> {
+        let mut byte = [0u8; 1];
+        read_exact_at(&mut self.file, block_sector * 512 + sector / 8, &mut byte)?;
+        Ok(byte[0] & (0x80 >> (sector % 8)) != 0)
+    }
+
+    fn read_block(&mut self, block: u64, in_block: u64, buf: &mut [u8]) -> io::Result<()> {
+        let entry = self.bat.get(block as usize).copied().unwrap_or(UNALLOCATED);
+        let virtual_offset = block * self.block_size + in_block;
+        if entry == UNALLOCATED {
+            return match &mut self.parent {
+                Some(parent) => parent.read_at(virtual_offset, buf),
+                None => {
+                    buf.fill(0);
+                    Ok(())
+                }
+            };
+        }
+        let data_start = entry as u64 * 512 + self.bitmap_size;
+        if self.parent.is_none() {
+            return read_exact_at(&mut self.file, data_start + in_block, buf);
+        }
+
+        // Differencing disks keep a per-sector bitmap: set bits live in this file,
+        // clear bits fall through to the parent.
+        let mut done = 0;
+        while done < buf.len() {
+            let position = in_block + done as u64;
+            let sector = position / 512;
+            let len = ((512 - position % 512) as usize).min(buf.len() - done);
+            let piece = &mut buf[done..done + len];
+            if self.sector_in_bitmap(entry as u64, sector)? {
+                read_exact_at(&mut self.file, data_start + position, piece)?;
+            } else if let Some(parent) = &mut self.parent {
+                parent.read_at(block * self.block_size + position, piece)?;
+            }
+            done += len;
+        }
+        Ok(())
+    }
+}
+
+impl Disk for DynamicVhd {
+    fn size(&self) -> u64 {
+        self.footer.current_size
+    }
+
+    fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> io::Result<()> {
+        check_bounds(offset, buf.len(), self.footer.current_size)?;
+        let block_size = self.block_size;
+        read_blocks(offset, buf, block_size, |block, in_block, piece| self.read_block(block, in_block, piece))
+    }
+
+    fn format_name(&self) -> &'static str {
+        if self.parent.is_some() {
+            "VHD (differencing)"
+        } else {
+            "VHD (dynamic)"
+        }
+    }
+
+    fn details(&self) -> Vec<(String, String)> {
+        let mut details = footer_details(&self.footer);
+        details.push(("Block size".to_string(), format!("{} bytes", self.block_size)));
+        let allocated = self.bat.iter().filter(|entry| **entry != UNALLOCATED).count();
+        details.push(("Allocated blocks".to_string(), format!("{} of {}", allocated, self.bat.len())));
+        if let Some(parent) = &self.parent {
+            details.push(("Parent".to_string(), format!("{} ({})", self.parent_name, parent.format_name())));
+            details.extend(parent.details().into_iter().map(|(key, value)| (format!("Parent {}", key.to_lowercase()), value)));
+        }
+        details
+    }
+}
+
+fn parent_candidates(file: &mut File, header: &[u8]) -> io::Result<Vec<String>> {
+    let mut candidates = Vec::new();
+    for index in 0..8 {
+        let entry = &header[576 + index * 24..576 + (index + 1) * 24];
+        let platform = &entry[0..4];
+        let data_length = be_u32(entry, 8) as usize;
+        let data_offset = be_u64(entry, 16);
+        if (platform != b"W2ru" && platform != b"W2ku") || data_length == 0 || data_length > 65536 {
+            continue;
+        }
+        let mut data = vec![0u8; data_length];
+        read_exact_at(file, data_offset, &mut data)?;
+        let units: Vec<u16> = data.chunks_exact(2).map(|unit| u16::from_le_bytes([unit[0], unit[1]])).collect();
+        candidates.push(String::from_utf16_lossy(&units).trim_end_matches('\0').to_string());
+    }
+    let name_units: Vec<u16> = header[64..576]
+        .chunks_exact(2)
+        .map(|unit| u16::from_be_bytes([unit[0], unit[1]]))
+        .take_while(|unit| *unit != 0)
+        .collect();
+    candidates.push(String::from_utf16_lossy(&name_units));
+    Ok(candidates)
+}
+
+fn read_footer(file: &mut File, size: u64) -> io::Result<VhdFooter> {
+    if size < FOOTER_SIZE {
+        return Err(invalid_data("file is too small to hold a VHD footer"));
+    }
+    let mut footer_bytes = [0u8; FOOTER_SIZE as usize];
+    read_exact_at(file, size - FOOTER_SIZE, &mut footer_bytes)?;
+    if &footer_bytes[0..8] != COOKIE {
+        // Dynamic disks keep a copy of the footer at the start of the file.
+        read_exact_at(file, 0, &mut footer_bytes)?;
+    }
+    parse_footer(&footer_bytes)
+}
+
+pub fn open(path: &Path, depth: usize) -> io::Result<Box<dyn Disk>> {
+    let mut file = File::open(path)?;
+    let size = file.metadata()?.len();
+    let footer = read_footer(&mut file, size)?;
+
+    if footer.disk_type == DISK_TYPE_FIXED {
+        if footer.current_size.checked_add(FOOTER_SIZE - 1).is_none_or(|needed| size < needed) {
+            return Err(invalid_data("fixed VHD is shorter than its virtual size"));
+        }
+        return Ok(Box::new(FixedVhd { file, footer }));
+    }
+    if footer.disk_type != DISK_TYPE_DYNAMIC && footer.disk_type != DISK_TYPE_DIFFERENCING {
+        return Err(invalid_data(format!("unsupported VHD disk type {}", footer.disk_type)));
+    }
+
+    let mut header = [0u8; 1024];
+    read_exact_at(&mut file, footer.data_offset, &mut header)?;
+    if &header[0..8] != DYNAMIC_COOKIE {
+        return Err(invalid_data("missing VHD dynamic header"));
+    }
+    let table_offset = be_u64(&header, 16);
+    let max_entries = be_u32(&header, 28) as usize;
+    let block_size = be_u32(&header, 32) as u64;
+    if block_size == 0 || !block_size.is_multiple_of(512) {
+        return Err(invalid_data(format!("invalid VHD block size {}", block_size)));
+    }
+    if max_entries as u64 * 4 > size {
+        return Err(invalid_data(format!("VHD block table of {} entries is larger than the file", max_entries)));
+    }
+    let mut bat_bytes = vec![0u8; max_entries * 4];
+    read_exact_at(&mut file, table_offset, &mut bat_bytes)?;
+    let bat = bat_bytes.chunks_exact(4).map(|entry| be_u32(entry, 0)).collect();
+    let bitmap_size = (block_size / 512).div_ceil(8).div_ceil(512) * 512;
+
+    let (parent, parent_name) = if footer.disk_type == DISK_TYPE_DIFFERENCING {
+        let candidates = parent_candidates(&mut file, &header)?;
+        let parent_path = resolve_parent_path(path, &candidates)?;
+        // The child records the parent's unique id; a parent that was replaced or
+        // modified since would silently return the wrong data.
+        let parent_id = &header[40..56];
+        let mut parent_file = File::open(&parent_path)?;
+        let parent_size = parent_file.metadata()?.len();
+        let parent_footer = read_footer(&mut parent_file, parent_size)?;
+        if parent_footer.unique_id != parent_id {
+            return Err(invalid_data(format!("{} is not the parent this VHD was created from", parent_path.display())));
+        }
+        let parent = open_virtual_disk_at_depth(&parent_path, depth + 1)?;
+        (Some(parent), parent_path.display().to_string())
+    } else {
+        (None, String::new())
+    };
+
+    Ok(Box::new(DynamicVhd {
+        file,
+        footer,
+        block_size,
+        bitmap_size,
+        bat,
+        parent,
+        parent_name,
+    }))
+}
+
+// CHS geometry as specified in the VHD format appendix.
+fn chs_geometry(size: u64) -> (u16, u8, u8) {
+    let total_sectors = (size / 512).min(65535 * 16 * 255);
+    let (sectors, heads, cylinder_heads) = if total_sectors >= 65535 * 16 * 63 {
+        (255, 16, total_sectors / 255)
+    } else {
+        let mut sectors = 17;
+        let mut cylinder_heads = total_sectors / sectors;
+        let mut heads = cylinder_heads.div_ceil(1024).max(4);
+        if cylinder_heads >= heads * 1024 || heads > 16 {
+            sectors = 31;
+            heads = 16;
+            cylinder_heads = total_sectors / sectors;
+        }
+        if cylinder_heads >= heads * 1024 {
+            sectors = 63;
+            heads = 16;
+            cylinder_heads = total_sectors / sectors;
+        }
+        (sectors, heads, cylinder_heads)
+    };
+    ((cylinder_heads / heads) as u16, heads as u8, sectors as u8)
+}
+
+fn build_footer(size: u64, disk_type: u32, data_offset: u64, unique_id: &Guid) -> [u8; FOOTER_SIZE as usize] {
+    let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |time| time.as_secs()).saturating_sub(VHD_EPOCH);
+    let (cylinders, heads, sectors) = chs_geometry(size);
+    let mut footer = [0u8; FOOTER_SIZE as usize];
+    footer[0..8].copy_from_slice(COOKIE);
+    footer[8..12].copy_from_slice(&2u32.to_be_bytes());
+    footer[12..16].copy_from_slice(&0x0001_0000u32.to_be_bytes());
+    footer[16..24].copy_from_slice(&data_offset.to_be_bytes());
+    footer[24..28].copy_from_slice(&(timestamp as u32).to_be_bytes());
+    footer[28..32].copy_from_slice(b"PMT\0");
+    footer[32..36].copy_from_slice(&0x0001_0000u32.to_be_bytes());
+    footer[36..40].copy_from_slice(b"Wi2k");
+    footer[40..48].copy_from_slice(&size.to_be_bytes());
+    footer[48..56].copy_from_slice(&size.to_be_bytes());
+    footer[56..58].copy_from_slice(&cylinders.to_be_bytes());
+    footer[58] = heads;
+    footer[59] = sectors;
+    footer[60..64].copy_from_slice(&disk_type.to_be_bytes());
+    footer[68..84].copy_from_slice(&unique_id.0);
+    let checksum = vhd_checksum(&footer, 64);
+    footer[64..68].copy_from_slice(&checksum.to_be_bytes());
+    footer
+}
+
+fn check_writable_size(size: u64) -> io::Result<()> {
+    if size > MAX_VHD_SIZE {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("VHD images are limited to {} bytes; use VHDX for {} bytes", MAX_VHD_SIZE, size),
+        ));
+    }
+    Ok(())
+}
+
+pub struct FixedVhdWriter {
+    file: File,
+    size: u64,
+}
+
+impl FixedVhdWriter {
+    pub fn create(path: &Path, size: u64) -> io::Result<Self> {
+        check_writable_size(size)?;
+        Ok(Self {
+            file: create_output(path)?,
+            size,
+        })
+    }
+}
+
+impl ImageWriter for FixedVhdWriter {
+    fn write_block(&mut self, index: u64, data: &[u8]) -> io::Result<()> {
+        write_all_at(&mut self.file, index * RAW_BLOCK_SIZE, data)
+    }
+
+    fn finish(mut self: Box<Self>) -> io::Result<()> {
+        let footer = build_footer(self.size, DISK_TYPE_FIXED, u64::MAX, &Guid::random());
+        write_all_at(&mut self.file, self.size, &footer)?;
+        self.file.sync_all()
+    }
+}
+
+pub struct DynamicVhdWriter {
+    file: File,
+    size: u64,
+    bat: Vec<u32>,
+    table_offset: u64,
+    next_block: u64,
+}
+
+impl DynamicVhdWriter {
+    pub fn create(path: &Path, size: u64) -> io::Result<Self> {
+        check_writable_size(size)?;
+        let entries = size.div_ceil(DYNAMIC_BLOCK_SIZE);
+        // Footer copy, dynamic header, then the BAT; blocks follow in write order.
+        let table_offset = FOOTER_SIZE + 1024;
+        let next_block = table_offset + (entries * 4).next_multiple_of(512);
+        Ok(Self {
+            file: create_output(path)?,
+            size,
+            bat: vec![UNALLOCATED; entries as usize],
+            table_offset,
+            next_block,
+        })
+    }
+}
+
+impl ImageWriter for DynamicVhdWriter {
+    fn write_block(&mut self, index: u64, data: &[u8]) -> io::Result<()> {
+        let bitmap_size = (DYNAMIC_BLOCK_SIZE / 512 / 8).next_multiple_of(512);
+        let mut block = vec![0u8; (bitmap_size + DYNAMIC_BLOCK_SIZE) as usize];
+        block[..bitmap_size as usize].fill(0xFF);
+        block[bitmap_size as usize..bitmap_size as usize + data.len()].copy_from_slice(data);
+        write_all_at(&mut self.file, self.next_block, &block)?;
+        self.bat[index as usize] = (self.next_block / 512) as u32;
+        self.next_block += block.len() as u64;
+        Ok(())
+    }
+
+    fn finish(mut self: Box<Self>) -> io::Result<()> {
+        let footer = build_footer(self.size, DISK_TYPE_DYNAMIC, FOOTER_SIZE, &Guid::random());
+        let mut header = [0u8; 1024];
+        header[0..8].copy_from_slice(DYNAMIC_COOKIE);
+        header[8..16].copy_from_slice(&u64::MAX.to_be_bytes());
+        header[16..24].copy_from_slice(&self.table_offset.to_be_bytes());
+        header[24..28].copy_from_slice(&0x0001_0000u32.to_be_bytes());
+        header[28..32].copy_from_slice(&(self.bat.len() as u32).to_be_bytes());
+        header[32..36].copy_from_slice(&(DYNAMIC_BLOCK_SIZE as u32).to_be_bytes());
+        let checksum = vhd_checksum(&header, 36);
+        header[36..40].copy_from_slice(&checksum.to_be_bytes());
+        let mut bat: Vec<u8> = self.bat.iter().flat_map(|entry| entry.to_be_bytes()).collect();
+        bat.resize(bat.len().next_multiple_of(512), 0xFF);
+
+        write_all_at(&mut self.file, 0, &footer)?;
+        write_all_at(&mut self.file, FOOTER_SIZE, &header)?;
+        write_all_at(&mut self.file, self.table_offset, &bat)?;
+        write_all_at(&mut self.file, self.next_block, &footer)?;
+        self.file.sync_all()
+    }
+}
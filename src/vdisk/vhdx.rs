@@ -0,0 +1,524 @@
+use crate::disk::Disk;
+use crate::partition_table::{read_u32, read_u64, Guid};
+use crate::vdisk::convert::{create_output, ImageWriter};
+use crate::vdisk::{
+    check_bounds, invalid_data, open_virtual_disk_at_depth, read_blocks, read_exact_at, resolve_parent_path, write_all_at,
+};
+use std::fs::File;
+use std::io;
+use std::path::Path;
+
+pub const FILE_SIGNATURE: &[u8; 8] = b"vhdxfile";
+const HEADER_OFFSETS: [u64; 2] = [64 * 1024, 128 * 1024];
+const REGION_TABLE_OFFSETS: [u64; 2] = [192 * 1024, 256 * 1024];
+const MIB: u64 = 1024 * 1024;
+pub const WRITE_BLOCK_SIZE: u64 = 2 * MIB;
+const LOG_OFFSET: u64 = MIB;
+const LOG_LENGTH: u64 = MIB;
+const METADATA_OFFSET: u64 = 2 * MIB;
+const METADATA_LENGTH: u64 = MIB;
+const BAT_OFFSET: u64 = 3 * MIB;
+
+const METADATA_IS_VIRTUAL_DISK: u32 = 2;
+const METADATA_IS_REQUIRED: u32 = 4;
+
+const BAT_REGION: &str = "2DC27766-F623-4200-9D64-115E9BFD4A08";
+const METADATA_REGION: &str = "8B7CA206-4790-4B9A-B8FE-575F050F886E";
+const FILE_PARAMETERS: &str = "CAA16737-FA36-4D43-B3B6-33F0AA44E76B";
+const VIRTUAL_DISK_SIZE: &str = "2FA54224-CD1B-4876-B211-5DBED83BF4B8";
+const VIRTUAL_DISK_ID: &str = "BECA12AB-B2E6-4523-93EF-C309E000C746";
+const LOGICAL_SECTOR_SIZE: &str = "8141BF1D-A96F-4709-BA47-F233A8FAAB5F";
+const PHYSICAL_SECTOR_SIZE: &str = "CDA348C7-445D-4471-9CC9-E9885251C556";
+const PARENT_LOCATOR: &str = "A8D35F2D-B30B-454D-ABF7-D3D84834AB0C";
+
+const PAYLOAD_NOT_PRESENT: u64 = 0;
+const PAYLOAD_UNDEFINED: u64 = 1;
+const PAYLOAD_ZERO: u64 = 2;
+const PAYLOAD_UNMAPPED: u64 = 3;
+const PAYLOAD_FULLY_PRESENT: u64 = 6;
+const PAYLOAD_PARTIALLY_PRESENT: u64 = 7;
+
+// CRC-32C (Castagnoli), as used by VHDX headers, region tables and log entries.
+pub fn crc32c(bytes: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for byte in bytes {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0x82F6_3B78 } else { crc >> 1 };
+        }
+    }
+    !crc
+}
+
+fn checksum_valid(block: &[u8]) -> bool {
+    let mut copy = block.to_vec();
+    let stored = read_u32(&copy, 4);
+    copy[4..8].fill(0);
+    crc32c(&copy) == stored
+}
+
+struct VhdxHeader {
+    sequence_number: u64,
+    data_write_guid: Guid,
+    log_guid: Guid,
+}
+
+fn read_header(file: &mut File) -> io::Result<VhdxHeader> {
+    let mut best: Option<VhdxHeader> = None;
+    for offset in HEADER_OFFSETS {
+        let mut block = vec![0u8; 4096];
+        read_exact_at(file, offset, &mut block)?;
+        if &block[0..4] != b"head" || !checksum_valid(&block) {
+            continue;
+        }
+        let header = VhdxHeader {
+            sequence_number: read_u64(&block, 8),
+            data_write_guid: Guid::from_bytes(&block[32..48]),
+            log_guid: Guid::from_bytes(&block[48..64]),
+        };
+        if best.as_ref().is_none_or(|current| header.sequence_number > current.sequence_number) {
+            best = Some(header);
+        }
+    }
+    best.ok_or_else(|| invalid_data("no valid VHDX header"))
+}
+
+fn read_region_table(file: &mut File) -> io::Result<Vec<(String, u64, u32)>> {
+    for offset in REGION_TABLE_OFFSETS {
+        let mut block = vec![0u8; 64 * 1024];
+        read_exact_at(file, offset, &mut block)?;
+        if &block[0..4] != b"regi" || !checksum_valid(&block) {
+            continue;
+        }
+        let count = read_u32(&block, 8).min(2047) as usize;
+        return Ok((0..count)
+            .map(|index| {
+                let entry = &block[16 + index * 32..16 + (index + 1) * 32];
+                (Guid::from_bytes(&entry[0..16]).to_string(), read_u64(entry, 16), read_u32(entry, 24))
+            })
+            .collect());
+    }
+    Err(invalid_data("no valid VHDX region table"))
+}
+
+struct Metadata {
+    block_size: u64,
+    has_parent: bool,
+    virtual_size: u64,
+    logical_sector_size: u32,
+    physical_sector_size: u32,
+    disk_id: Option<Guid>,
+    parent_locator: Vec<(String, String)>,
+}
+
+fn parse_parent_locator(item: &[u8]) -> Vec<(String, String)> {
+    if item.len() < 20 {
+        return Vec::new();
+    }
+    let count = u16::from_le_bytes([item[18], item[19]]) as usize;
+    let utf16 = |offset: usize, len: usize| -> String {
+        let units: Vec<u16> = item
+            .get(offset..offset + len)
+            .unwrap_or(&[])
+            .chunks_exact(2)
+            .map(|unit| u16::from_le_bytes([unit[0], unit[1]]))
+            .collect();
+        String::from_utf16_lossy(&units)
+    };
+    (0..count)
+        .filter_map(|index| {
+            let entry = item.get(20 + index * 12..20 + (index + 1) * 12)?;
+            let key_offset = read_u32(entry, 0) as usize;
+            let value_offset = read_u32(entry, 4) as usize;
+            let key_length = u16::from_le_bytes([entry[8], entry[9]]) as usize;
+            let value_length = u16::from_le_bytes([entry[10], entry[11]]) as usize;
+            Some((utf16(key_offset, key_length), utf16(value_offset, value_length)))
+        })
+        .collect()
+}
+
+fn read_metadata(file: &mut File, offset: u64, length: u32) -> io::Result<Metadata> {
+    if length as u64 > 256 * MIB {
+        return Err(invalid_data(format!("VHDX metadata region of {} bytes is too large", length)));
+    }
+    let mut region = vec![0u8; length as usize];
+    read_exact_at(file, offset, &mut region)?;
+    if region.len() < 32 || &region[0..8] != b"metadata" {
+        return Err(invalid_data("missing VHDX metadata table"));
+    }
+    let count = (u16::from_le_bytes([region[10], region[11]]) as usize).min(2047);
+    let mut metadata = Metadata {
+        block_size: 0,
+        has_parent: false,
+        virtual_size: 0,
+        logical_sector_size: 512,
+        physical_sector_size: 512,
+        disk_id: None,
+        parent_locator: Vec::new(),
+    };
+    for index in 0..count {
+        let entry = region
+            .get(32 + index * 32..32 + (index + 1) * 32)
+            .ok_or_else(|| invalid_data("VHDX metadata table is truncated"))?;
+        let item_offset = read_u32(entry, 16) as usize;
+        let item_length = read_u32(entry, 20) as usize;
+        let item = item_offset
+            .checked_add(item_length)
+            .and_then(|item_end| region.get(item_offset..item_end))
+            .ok_or_else(|| invalid_data("VHDX metadata item lies outside the metadata region"))?;
+        match Guid::from_bytes(&entry[0..16]).to_string().as_str() {
+            FILE_PARAMETERS if item.len() >= 8 => {
+                metadata.block_size = read_u32(item, 0) as u64;
+                metadata.has_parent = read_u32(item, 4) & 2 != 0;
+            }
+            VIRTUAL_DISK_SIZE if item.len() >= 8 => metadata.virtual_size = read_u64(item, 0),
+            VIRTUAL_DISK_ID if item.len() >= 16 => metadata.disk_id = Some(Guid::from_bytes(item)),
+            LOGICAL_SECTOR_SIZE if item.len() >= 4 => metadata.logical_sector_size = read_u32(item, 0),
+            PHYSICAL_SECTOR_SIZE if item.len() >= 4 => metadata.physical_sector_size = read_u32(item, 0),
+            PARENT_LOCATOR => metadata.parent_locator = parse_parent_locator(item),
+            _ => {}
+        }
+    }
+    if metadata.block_size < MIB || !metadata.block_size.is_power_of_two() {
+        return Err(invalid_data(format!("invalid VHDX block size {}", metadata.block_size)));
+    }
+    if metadata.logical_sector_size != 512 && metadata.logical_sector_size != 4096 {
+        return Err(invalid_data(format!("invalid VHDX logical sector size {}", metadata.logical_sector_size)));
+    }
+    Ok(metadata)
+}
+
+pub struct VhdxDisk {
+    file: File,
+    metadata: Metadata,
+    bat: Vec<u64>,
+    chunk_ratio: u64,
+    log_pending: bool,
+    parent: Option<Box<dyn Disk>>,
+    parent_name: String,
+}
+
+impl VhdxDisk {
+    pub fn open(path: &Path, depth: usize) -> io::Result<Self> {
+        let mut file = File::open(path)?;
+        let header = read_header(&mut file)?;
+        let regions = read_region_table(&mut file)?;
+        let region = |guid: &str| {
+            regions
+                .iter()
+                .find(|(id, _, _)| id == guid)
+                .map(|(_, offset, length)| (*offset, *length))
+                .ok_or_else(|| invalid_data(format!("VHDX region {} missing", guid)))
+        };
+        let (metadata_offset, metadata_length) = region(METADATA_REGION)?;
+        let (bat_offset, bat_length) = region(BAT_REGION)?;
+        let metadata = read_metadata(&mut file, metadata_offset, metadata_length)?;
+
+        // Every chunk of payload blocks is followed by one sector bitmap block entry.
+        let chunk_ratio = (1u64 << 23) * metadata.logical_sector_size as u64 / metadata.block_size;
+        let payload_blocks = metadata.virtual_size.div_ceil(metadata.block_size);
+        let bat_entries = if metadata.has_parent {
+            payload_blocks.div_ceil(chunk_ratio) * (chunk_ratio + 1)
+        } else {
+            payload_blocks + payload_blocks.saturating_sub(1) / chunk_ratio
+        };
+        let bat_bytes_len = (bat_entries * 8).min(bat_length as u64) as usize;
+        let mut bat_bytes = vec![0u8; bat_bytes_len];
+        read_exact_at(&mut file, bat_offset, &mut bat_bytes)?;
+        let bat = bat_bytes.chunks_exact(8).map(|entry| read_u64(entry, 0)).collect();
+
+        let (parent, parent_name) = if metadata.has_parent {
+            let lookup = |key: &str| {
+                metadata
+                    .parent_locator
+                    .iter()
+                    .find(|(name, _)| name == key)
+                    .map(|(_, value)| value.clone())
+                    .unwrap_or_default()
+            };
+            let candidates = [lookup("relative_path"), lookup("absolute_win32_path"), lookup("volume_path")];
+            let parent_path = resolve_parent_path(path, &candidates)?;
+            // parent_linkage holds the parent's DataWriteGuid when the child was created;
+            // any write to the parent since then changes it.
+            let linkage = lookup("parent_linkage");
+            let parent_header = read_header(&mut File::open(&parent_path)?)?;
+            let expected = linkage.trim_matches(|c| c == '{' || c == '}');
+            if !expected.eq_ignore_ascii_case(&parent_header.data_write_guid.to_string()) {
+                return Err(invalid_data(format!(
+                    "{} does not match the parent linkage {} of this VHDX",
+                    parent_path.display(),
+                    linkage
+                )));
+            }
+            let parent = open_virtual_disk_at_depth(&parent_path, depth + 1)?;
+            if parent.size() != metadata.virtual_size {
+                return Err(invalid_data("parent VHDX has a different virtual size"));
+            }
+            (Some(parent), parent_path.display().to_string())
+        } else {
+            (None, String::new())
+        };
+
+        Ok(Self {
+            file,
+            metadata,
+            bat,
+            chunk_ratio,
+            log_pending: !header.log_guid.is_zero(),
+            parent,
+            parent_name,
+        })
+    }
+
+    fn payload_entry(&self, block: u64) -> u64 {
+        let index = block + block / self.chunk_ratio;
+        self.bat.get(index as usize).copied().unwrap_or(0)
+    }
+
+    fn bitmap_entry(&self, block: u64) -> u64 {
+        let chunk = block / self.chunk_ratio;
+        let index = (chunk + 1) * (self.chunk_ratio + 1) - 1;
+        self.bat.get(index as usize).copied().unwrap_or(0)
+    }
+
+    fn read_parent_or_zero(&mut self, offset: u64, buf: &mut [u8]) -> io::Result<()> {
+        match &mut self.parent {
+            Some(parent) => parent.read_at(offset, buf),
+            None => {
+                buf.fill(0);
+                Ok(())
+            }
+        }
+    }
+
+    fn read_block(&mut self, block: u64, in_block: u64, buf: &mut [u8]) -> io::Result<()> {
+        let entry = self.payload_entry(block);
+        let state = entry & 7;
+        let file_offset = (entry >> 20) * MIB;
+        let virtual_offset = block * self.metadata.block_size + in_block;
+        match state {
+            PAYLOAD_FULLY_PRESENT => read_exact_at(&mut self.file, file_offset + in_block, buf),
+            PAYLOAD_PARTIALLY_PRESENT => self.read_partial(block, file_offset, in_block, buf),
+            // Zero blocks read as zeros even in differencing disks; they hide the parent.
+            PAYLOAD_ZERO => {
+                buf.fill(0);
+                Ok(())
+            }
+            PAYLOAD_NOT_PRESENT | PAYLOAD_UNDEFINED | PAYLOAD_UNMAPPED => {
+                self.read_parent_or_zero(virtual_offset, buf)
+            }
+            _ => Err(invalid_data(format!("invalid VHDX BAT state {} for block {}", state, block))),
+        }
+    }
+
+    // Partially present blocks only exist in differencing disks; the chunk's sector
+    // bitmap says which sectors are stored here and which come from the parent.
+    fn read_partial(&mut self, block: u64, file_offset: u64, in_block: u64, buf: &mut [u8]) -> io::Result<()> {
+        let bitmap = self.bitmap_entry(block);
+        if bitmap & 7 != PAYLOAD_FULLY_PRESENT {
+            return Err(invalid_data(format!("sector bitmap for block {} is not present", block)));
+        }
+        let bitmap_offset = (bitmap >> 20) * MIB;
+        let sector_size = self.metadata.logical_sector_size as u64;
+        let sectors_per_block = self.metadata.block_size / sector_size;
+        let block_in_chunk = block % self.chunk_ratio;
+        let mut done = 0;
+        while done < buf.len() {
+            let position = in_block + done as u64;
+            let len = ((sector_size - position % sector_size) as usize).min(buf.len() - done);
+            let bit = block_in_chunk * sectors_per_block + position / sector_size;
+            let mut byte = [0u8; 1];
+            read_exact_at(&mut self.file, bitmap_offset + bit / 8, &mut byte)?;
+            let piece = &mut buf[done..done + len];
+            if byte[0] & (1 << (bit % 8)) != 0 {
+                read_exact_at(&mut self.file, file_offset + position, piece)?;
+            } else {
+                let virtual_offset = block * self.metadata.block_size + position;
+                self.read_parent_or_zero(virtual_offset, piece)?;
+            }
+            done += len;
+        }
+        Ok(())
+    }
+}
+
+impl Disk for VhdxDisk {
+    fn size(&self) -> u64 {
+        self.metadata.virtual_size
+    }
+
+    fn sector_size(&self) -> u32 {
+        self.metadata.logical_sector_size
+    }
+
+    fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> io::Result<()> {
+        check_bounds(offset, buf.len(), self.metadata.virtual_size)?;
+        let block_size = self.metadata.block_size;
+        read_blocks(offset, buf, block_size, |block, in_block, piece| self.read_block(block, in_block, piece))
+    }
+
+    fn format_name(&self) -> &'static str {
+        if self.parent.is_some() {
+            "VHDX (differencing)"
+        } else {
+            "VHDX"
+        }
+    }
+
+    fn details(&self) -> Vec<(String, String)> {
+        let metadata = &self.metadata;
+        let present = (0..metadata.virtual_size.div_ceil(metadata.block_size))
+            .filter(|block| matches!(self.payload_entry(*block) & 7, PAYLOAD_FULLY_PRESENT | PAYLOAD_PARTIALLY_PRESENT))
+            .count();
+        let mut details = vec![
+            ("Virtual size".to_string(), format!("{} bytes", metadata.virtual_size)),
+            ("Block size".to_string(), format!("{} bytes", metadata.block_size)),
+            (
+                "Sector size".to_string(),
+                format!("logical {} B, physical {} B", metadata.logical_sector_size, metadata.physical_sector_size),
+            ),
+            ("Present blocks".to_string(), present.to_string()),
+        ];
+        if let Some(disk_id) = metadata.disk_id {
+            details.push(("Virtual disk ID".to_string(), disk_id.to_string()));
+        }
+        if self.log_pending {
+            details.push(("Log".to_string(), "pending entries not replayed; data may be stale".to_string()));
+        }
+        if let Some(parent) = &self.parent {
+            details.push(("Parent".to_string(), format!("{} ({})", self.parent_name, parent.format_name())));
+            details.extend(parent.details().into_iter().map(|(key, value)| (format!("Parent {}", key.to_lowercase()), value)));
+        }
+        details
+    }
+}
+
+fn guid_bytes(guid: &str) -> [u8; 16] {
+    Guid::parse(guid).expect("valid GUID constant").0
+}
+
+fn with_checksum(mut block: Vec<u8>) -> Vec<u8> {
+    let checksum = crc32c(&block);
+    block[4..8].copy_from_slice(&checksum.to_le_bytes());
+    block
+}
+
+// Writes a dynamic VHDX with a 512-byte logical sector size. Every written block is
+// fully present; blocks that are never written stay NOT_PRESENT and read as zeroes.
+pub struct VhdxWriter {
+    file: File,
+    size: u64,
+    bat: Vec<u64>,
+    chunk_ratio: u64,
+    next_block: u64,
+}
+
+impl VhdxWriter {
+    pub fn create(path: &Path, size: u64) -> io::Result<Self> {
+        let chunk_ratio = (1u64 << 23) * 512 / WRITE_BLOCK_SIZE;
+        let payload_blocks = size.div_ceil(WRITE_BLOCK_SIZE);
+        let entries = payload_blocks + payload_blocks.saturating_sub(1) / chunk_ratio;
+        Ok(Self {
+            file: create_output(path)?,
+            size,
+            bat: vec![PAYLOAD_NOT_PRESENT; entries as usize],
+            chunk_ratio,
+            next_block: BAT_OFFSET + (entries * 8).next_multiple_of(MIB).max(MIB),
+        })
+    }
+
+    fn headers(&self) -> Vec<Vec<u8>> {
+        let file_write_guid = Guid::random();
+        let data_write_guid = Guid::random();
+        (0..2u64)
+            .map(|sequence_number| {
+                let mut header = vec![0u8; 4096];
+                header[0..4].copy_from_slice(b"head");
+                header[8..16].copy_from_slice(&sequence_number.to_le_bytes());
+                header[16..32].copy_from_slice(&file_write_guid.0);
+                header[32..48].copy_from_slice(&data_write_guid.0);
+                header[66..68].copy_from_slice(&1u16.to_le_bytes());
+                header[68..72].copy_from_slice(&(LOG_LENGTH as u32).to_le_bytes());
+                header[72..80].copy_from_slice(&LOG_OFFSET.to_le_bytes());
+                with_checksum(header)
+            })
+            .collect()
+    }
+
+    fn region_table(&self) -> Vec<u8> {
+        let mut table = vec![0u8; 64 * 1024];
+        table[0..4].copy_from_slice(b"regi");
+        table[8..12].copy_from_slice(&2u32.to_le_bytes());
+        let bat_length = (self.bat.len() as u64 * 8).next_multiple_of(MIB).max(MIB);
+        for (index, (guid, offset, length)) in
+            [(BAT_REGION, BAT_OFFSET, bat_length), (METADATA_REGION, METADATA_OFFSET, METADATA_LENGTH)].into_iter().enumerate()
+        {
+            let entry = &mut table[16 + index * 32..16 + (index + 1) * 32];
+            entry[0..16].copy_from_slice(&guid_bytes(guid));
+            entry[16..24].copy_from_slice(&offset.to_le_bytes());
+            entry[24..28].copy_from_slice(&(length as u32).to_le_bytes());
+            entry[28..32].copy_from_slice(&1u32.to_le_bytes());
+        }
+        with_checksum(table)
+    }
+
+    fn metadata(&self) -> Vec<u8> {
+        let mut file_parameters = (WRITE_BLOCK_SIZE as u32).to_le_bytes().to_vec();
+        file_parameters.extend_from_slice(&0u32.to_le_bytes());
+        let items: [(&str, Vec<u8>, u32); 5] = [
+            (FILE_PARAMETERS, file_parameters, METADATA_IS_REQUIRED),
+            (VIRTUAL_DISK_SIZE, self.size.to_le_bytes().to_vec(), METADATA_IS_VIRTUAL_DISK | METADATA_IS_REQUIRED),
+            (VIRTUAL_DISK_ID, Guid::random().0.to_vec(), METADATA_IS_VIRTUAL_DISK | METADATA_IS_REQUIRED),
+            (LOGICAL_SECTOR_SIZE, 512u32.to_le_bytes().to_vec(), METADATA_IS_VIRTUAL_DISK | METADATA_IS_REQUIRED),
+            (PHYSICAL_SECTOR_SIZE, 4096u32.to_le_bytes().to_vec(), METADATA_IS_VIRTUAL_DISK | METADATA_IS_REQUIRED),
+        ];
+        let mut region = vec![0u8; 64 * 1024];
+        region[0..8].copy_from_slice(b"metadata");
+        region[10..12].copy_from_slice(&(items.len() as u16).to_le_bytes());
+        for (index, (guid, item, flags)) in items.iter().enumerate() {
+            let offset = region.len() as u32;
+            let entry = &mut region[32 + index * 32..32 + (index + 1) * 32];
+            entry[0..16].copy_from_slice(&guid_bytes(guid));
+            entry[16..20].copy_from_slice(&offset.to_le_bytes());
+            entry[20..24].copy_from_slice(&(item.len() as u32).to_le_bytes());
+            entry[24..28].copy_from_slice(&flags.to_le_bytes());
+            region.extend_from_slice(item);
+        }
+        region
+    }
+}
+
+impl ImageWriter for VhdxWriter {
+    fn write_block(&mut self, index: u64, data: &[u8]) -> io::Result<()> {
+        let mut block = data.to_vec();
+        block.resize(WRITE_BLOCK_SIZE as usize, 0);
+        write_all_at(&mut self.file, self.next_block, &block)?;
+        self.bat[(index + index / self.chunk_ratio) as usize] = ((self.next_block / MIB) << 20) | PAYLOAD_FULLY_PRESENT;
+        self.next_block += WRITE_BLOCK_SIZE;
+        Ok(())
+    }
+
+    fn finish(mut self: Box<Self>) -> io::Result<()> {
+        let mut identifier = vec![0u8; 64 * 1024];
+        identifier[0..8].copy_from_slice(FILE_SIGNATURE);
+        for (index, unit) in "plasitol's memory tools".encode_utf16().enumerate() {
+            identifier[8 + index * 2..10 + index * 2].copy_from_slice(&unit.to_le_bytes());
+        }
+        let bat: Vec<u8> = self.bat.iter().flat_map(|entry| entry.to_le_bytes()).collect();
+        let headers = self.headers();
+        let region_table = self.region_table();
+        let metadata = self.metadata();
+
+        write_all_at(&mut self.file, 0, &identifier)?;
+        for (offset, header) in HEADER_OFFSETS.into_iter().zip(&headers) {
+            write_all_at(&mut self.file, offset, header)?;
+        }
+        for offset in REGION_TABLE_OFFSETS {
+            write_all_at(&mut self.file, offset, &region_table)?;
+        }
+        write_all_at(&mut self.file, METADATA_OFFSET, &metadata)?;
+        write_all_at(&mut self.file, BAT_OFFSET, &bat)?;
+        self.file.set_len(self.next_block)?;
+        self.file.sync_all()
+    }
+}
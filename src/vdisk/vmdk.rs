@@ -0,0 +1,507 @@
+use crate::disk::Disk;
+use crate::partition_table::{read_u32, read_u64};
+use crate::partition_table::Guid;
+use crate::vdisk::convert::{create_output, ImageWriter};
+use crate::vdisk::{check_bounds, compress_bound, invalid_data, open_virtual_disk_at_depth, read_exact_at, resolve_parent_path, write_all_at};
+use flate2::read::ZlibDecoder;
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::Path;
+
+pub const SPARSE_MAGIC: &[u8; 4] = b"KDMV";
+pub const DESCRIPTOR_SIGNATURE: &[u8] = b"# Disk DescriptorFile";
+const SECTOR: u64 = 512;
+const GD_AT_END: u64 = 0xFFFF_FFFF_FFFF_FFFF;
+const FLAG_COMPRESSED: u32 = 1 << 16;
+const MAX_DESCRIPTOR_SIZE: u64 = 1024 * 1024;
+// Grains are 64 KiB in practice; the cap keeps a corrupt header from sizing buffers.
+const MAX_GRAIN_SECTORS: u64 = 128 * 1024;
+pub const WRITE_GRAIN_SIZE: u64 = 64 * 1024;
+const WRITE_GRAIN_TABLE_ENTRIES: u64 = 512;
+const WRITE_DESCRIPTOR_SECTORS: u64 = 20;
+const FLAG_VALID_NEWLINE_DETECTION: u32 = 1;
+const FLAG_REDUNDANT_GRAIN_TABLE: u32 = 1 << 1;
+
+struct SparseExtent {
+    file: File,
+    capacity: u64,
+    grain_size: u64,
+    grain_table_entries: u64,
+    grain_directory: Vec<u32>,
+    compressed: bool,
+    cached_table: Option<(u32, Vec<u32>)>,
+    cached_grain: Option<(u32, Vec<u8>)>,
+}
+
+struct SparseHeader {
+    flags: u32,
+    capacity: u64,
+    grain_size: u64,
+    descriptor_offset: u64,
+    descriptor_size: u64,
+    grain_table_entries: u32,
+    gd_offset: u64,
+}
+
+fn parse_sparse_header(bytes: &[u8]) -> io::Result<SparseHeader> {
+    if &bytes[0..4] != SPARSE_MAGIC {
+        return Err(invalid_data("missing VMDK sparse extent magic"));
+    }
+    Ok(SparseHeader {
+        flags: read_u32(bytes, 8),
+        capacity: read_u64(bytes, 12),
+        grain_size: read_u64(bytes, 20),
+        descriptor_offset: read_u64(bytes, 28),
+        descriptor_size: read_u64(bytes, 36),
+        grain_table_entries: read_u32(bytes, 44),
+        gd_offset: read_u64(bytes, 56),
+    })
+}
+
+impl SparseExtent {
+    fn open(path: &Path) -> io::Result<(Self, Option<String>)> {
+        let mut file = File::open(path)?;
+        let file_size = file.metadata()?.len();
+        let mut header = [0u8; 512];
+        read_exact_at(&mut file, 0, &mut header)?;
+        let mut sparse = parse_sparse_header(&header)?;
+        let (descriptor_offset, descriptor_size) = (sparse.descriptor_offset, sparse.descriptor_size);
+        // Stream-optimized images write the grain directory last and repeat the
+        // header, with the real offsets, in a footer before the end-of-stream marker.
+        if sparse.gd_offset == GD_AT_END {
+            read_exact_at(&mut file, file_size.saturating_sub(1024), &mut header)?;
+            sparse = parse_sparse_header(&header)?;
+        }
+        if sparse.grain_size == 0
+            || !sparse.grain_size.is_power_of_two()
+            || sparse.grain_size > MAX_GRAIN_SECTORS
+            || sparse.grain_table_entries == 0
+        {
+            return Err(invalid_data(format!(
+                "invalid VMDK grain size {} or table size {}",
+                sparse.grain_size, sparse.grain_table_entries
+            )));
+        }
+        let grain_table_entries = sparse.grain_table_entries as u64;
+        let directory_entries = sparse.capacity.div_ceil(sparse.grain_size * grain_table_entries);
+        if directory_entries * 4 > file_size {
+            return Err(invalid_data("VMDK grain directory is larger than the extent file"));
+        }
+        let mut directory = vec![0u8; (directory_entries * 4) as usize];
+        read_exact_at(&mut file, sparse.gd_offset * SECTOR, &mut directory)?;
+
+        let descriptor = if descriptor_offset != 0 && descriptor_size != 0 {
+            let mut text = vec![0u8; (descriptor_size * SECTOR).min(MAX_DESCRIPTOR_SIZE) as usize];
+            read_exact_at(&mut file, descriptor_offset * SECTOR, &mut text)?;
+            Some(String::from_utf8_lossy(&text).trim_end_matches('\0').to_string())
+        } else {
+            None
+        };
+        let extent = Self {
+            file,
+            capacity: sparse.capacity,
+            grain_size: sparse.grain_size,
+            grain_table_entries,
+            grain_directory: directory.chunks_exact(4).map(|entry| read_u32(entry, 0)).collect(),
+            compressed: sparse.flags & FLAG_COMPRESSED != 0,
+            cached_table: None,
+            cached_grain: None,
+        };
+        Ok((extent, descriptor))
+    }
+
+    fn grain_entry(&mut self, grain: u64) -> io::Result<u32> {
+        let table_sector = self.grain_directory.get((grain / self.grain_table_entries) as usize).copied().unwrap_or(0);
+        if table_sector == 0 {
+            return Ok(0);
+        }
+        if self.cached_table.as_ref().is_none_or(|(sector, _)| *sector != table_sector) {
+            let mut bytes = vec![0u8; (self.grain_table_entries * 4) as usize];
+            read_exact_at(&mut self.file, table_sector as u64 * SECTOR, &mut bytes)?;
+            let table = bytes.chunks_exact(4).map(|entry| read_u32(entry, 0)).collect();
+            self.cached_table = Some((table_sector, table));
+        }
+        let (_, table) = self.cached_table.as_ref().unwrap();
+        Ok(table[(grain % self.grain_table_entries) as usize])
+    }
+
+    fn read_compressed_grain(&mut self, grain_sector: u32, in_grain: u64, buf: &mut [u8]) -> io::Result<()> {
+        if self.cached_grain.as_ref().is_none_or(|(sector, _)| *sector != grain_sector) {
+            let mut marker = [0u8; 12];
+            read_exact_at(&mut self.file, grain_sector as u64 * SECTOR, &mut marker)?;
+            let size = read_u32(&marker, 8) as u64;
+            if size > compress_bound(self.grain_size * SECTOR) {
+                return Err(invalid_data(format!("VMDK grain at sector {} claims {} compressed bytes", grain_sector, size)));
+            }
+            let mut compressed = vec![0u8; size as usize];
+            read_exact_at(&mut self.file, grain_sector as u64 * SECTOR + 12, &mut compressed)?;
+            let mut grain = vec![0u8; (self.grain_size * SECTOR) as usize];
+            ZlibDecoder::new(compressed.as_slice())
+                .read_exact(&mut grain)
+                .map_err(|err| invalid_data(format!("failed to inflate VMDK grain at sector {}: {}", grain_sector, err)))?;
+            self.cached_grain = Some((grain_sector, grain));
+        }
+        let (_, grain) = self.cached_grain.as_ref().unwrap();
+        buf.copy_from_slice(&grain[in_grain as usize..in_grain as usize + buf.len()]);
+        Ok(())
+    }
+
+    // Returns false for unallocated grains so that the caller can fall back to the parent.
+    fn read_grain(&mut self, grain: u64, in_grain: u64, buf: &mut [u8]) -> io::Result<bool> {
+        match self.grain_entry(grain)? {
+            0 => Ok(false),
+            1 => {
+                buf.fill(0);
+                Ok(true)
+            }
+            sector if self.compressed => self.read_compressed_grain(sector, in_grain, buf).map(|_| true),
+            sector => read_exact_at(&mut self.file, sector as u64 * SECTOR + in_grain, buf).map(|_| true),
+        }
+    }
+
+    fn allocated_grains(&mut self) -> usize {
+        let tables: Vec<u32> = self.grain_directory.iter().copied().filter(|sector| *sector != 0).collect();
+        let mut allocated = 0;
+        for table_sector in tables {
+            let mut bytes = vec![0u8; (self.grain_table_entries * 4) as usize];
+            if read_exact_at(&mut self.file, table_sector as u64 * SECTOR, &mut bytes).is_ok() {
+                allocated += bytes.chunks_exact(4).filter(|entry| read_u32(entry, 0) > 1).count();
+            }
+        }
+        allocated
+    }
+}
+
+enum ExtentData {
+    Sparse(Box<SparseExtent>),
+    Flat(File, u64),
+    Zero,
+}
+
+struct Extent {
+    start: u64,
+    sectors: u64,
+    file_name: String,
+    data: ExtentData,
+}
+
+fn descriptor_value(descriptor: &str, key: &str) -> Option<String> {
+    descriptor.lines().find_map(|line| {
+        let (name, value) = line.split_once('=')?;
+        (name.trim() == key).then(|| value.trim().trim_matches('"').to_string())
+    })
+}
+
+pub struct VmdkDisk {
+    size: u64,
+    create_type: String,
+    extents: Vec<Extent>,
+    allocated_grains: Option<usize>,
+    descriptor: String,
+    parent: Option<Box<dyn Disk>>,
+    parent_name: String,
+}
+
+impl VmdkDisk {
+    pub fn open(path: &Path, depth: usize) -> io::Result<Self> {
+        let mut file = File::open(path)?;
+        let mut magic = [0u8; 4];
+        read_exact_at(&mut file, 0, &mut magic)?;
+        let (descriptor, embedded) = if &magic == SPARSE_MAGIC {
+            let (extent, descriptor) = SparseExtent::open(path)?;
+            (descriptor.unwrap_or_default(), Some(extent))
+        } else {
+            if file.metadata()?.len() > MAX_DESCRIPTOR_SIZE {
+                return Err(invalid_data("VMDK descriptor file is too large"));
+            }
+            let mut text = String::new();
+            file.read_to_string(&mut text)?;
+            (text, None)
+        };
+        let directory = path.parent().unwrap_or_else(|| Path::new("."));
+
+        let mut extents = Vec::new();
+        let mut embedded = embedded;
+        let mut start = 0;
+        for line in descriptor.lines().map(str::trim) {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            if fields.len() < 3 || !matches!(fields[0], "RW" | "RDONLY" | "NOACCESS") {
+                continue;
+            }
+            let sectors: u64 = fields[1]
+                .parse()
+                .map_err(|_| invalid_data(format!("invalid VMDK extent line: {}", line)))?;
+            let file_name = line.split('"').nth(1).unwrap_or_default().to_string();
+            let data = match fields[2] {
+                "ZERO" => ExtentData::Zero,
+                "FLAT" | "VMFS" => {
+                    let offset: u64 = fields.last().and_then(|field| field.parse().ok()).unwrap_or(0);
+                    ExtentData::Flat(File::open(directory.join(&file_name))?, offset * SECTOR)
+                }
+                "SPARSE" => match embedded.take() {
+                    Some(extent) => ExtentData::Sparse(Box::new(extent)),
+                    None => ExtentData::Sparse(Box::new(SparseExtent::open(&directory.join(&file_name))?.0)),
+                },
+                kind => return Err(invalid_data(format!("unsupported VMDK extent type {}", kind))),
+            };
+            extents.push(Extent {
+                start,
+                sectors,
+                file_name,
+                data,
+            });
+            start += sectors * SECTOR;
+        }
+        // A sparse file without a usable descriptor still describes one extent.
+        if extents.is_empty() {
+            match embedded {
+                Some(extent) => {
+                    let sectors = extent.capacity;
+                    extents.push(Extent {
+                        start: 0,
+                        sectors,
+                        file_name: path.display().to_string(),
+                        data: ExtentData::Sparse(Box::new(extent)),
+                    });
+                    start = sectors * SECTOR;
+                }
+                None => return Err(invalid_data("VMDK descriptor lists no extents")),
+            }
+        }
+
+        let (parent, parent_name) = match descriptor_value(&descriptor, "parentFileNameHint") {
+            Some(hint) if descriptor_value(&descriptor, "parentCID").is_some_and(|cid| cid != "ffffffff") => {
+                let parent_path = resolve_parent_path(path, &[hint])?;
+                let parent = open_virtual_disk_at_depth(&parent_path, depth + 1)?;
+                (Some(parent), parent_path.display().to_string())
+            }
+            _ => (None, String::new()),
+        };
+
+        let mut allocated_grains = None;
+        for extent in &mut extents {
+            if let ExtentData::Sparse(sparse) = &mut extent.data {
+                *allocated_grains.get_or_insert(0) += sparse.allocated_grains();
+            }
+        }
+        Ok(Self {
+            size: start,
+            create_type: descriptor_value(&descriptor, "createType").unwrap_or_else(|| "monolithicSparse".to_string()),
+            extents,
+            allocated_grains,
+            descriptor,
+            parent,
+            parent_name,
+        })
+    }
+
+    fn read_extent(&mut self, index: usize, offset: u64, buf: &mut [u8]) -> io::Result<()> {
+        let virtual_offset = self.extents[index].start + offset;
+        match &mut self.extents[index].data {
+            ExtentData::Zero => buf.fill(0),
+            ExtentData::Flat(file, base) => read_exact_at(file, *base + offset, buf)?,
+            ExtentData::Sparse(sparse) => {
+                let grain_bytes = sparse.grain_size * SECTOR;
+                let mut done = 0;
+                while done < buf.len() {
+                    let position = offset + done as u64;
+                    let in_grain = position % grain_bytes;
+                    let len = ((grain_bytes - in_grain) as usize).min(buf.len() - done);
+                    let piece = &mut buf[done..done + len];
+                    if !sparse.read_grain(position / grain_bytes, in_grain, piece)? {
+                        match &mut self.parent {
+                            Some(parent) => parent.read_at(virtual_offset + done as u64, piece)?,
+                            None => piece.fill(0),
+                        }
+                    }
+                    done += len;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Disk for VmdkDisk {
+    fn size(&self) -> u64 {
+        self.size
+    }
+
+    fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> io::Result<()> {
+        check_bounds(offset, buf.len(), self.size)?;
+        let mut done = 0;
+        while done < buf.len() {
+            let position = offset + done as u64;
+            let index = self
+                .extents
+                .iter()
+                .position(|extent| position < extent.start + extent.sectors * SECTOR)
+                .ok_or_else(|| invalid_data("read outside the VMDK extents"))?;
+            let extent = &self.extents[index];
+            let in_extent = position - extent.start;
+            let len = ((extent.sectors * SECTOR - in_extent) as usize).min(buf.len() - done);
+            self.read_extent(index, in_extent, &mut buf[done..done + len])?;
+            done += len;
+        }
+        Ok(())
+    }
+
+    fn format_name(&self) -> &'static str {
+        if self.parent.is_some() {
+            "VMDK (delta)"
+        } else {
+            "VMDK"
+        }
+    }
+
+    fn details(&self) -> Vec<(String, String)> {
+        let mut details = vec![
+            ("Create type".to_string(), self.create_type.clone()),
+            ("Virtual size".to_string(), format!("{} bytes", self.size)),
+            ("Extents".to_string(), self.extents.len().to_string()),
+        ];
+        for (index, extent) in self.extents.iter().enumerate() {
+            let kind = match &extent.data {
+                ExtentData::Sparse(sparse) if sparse.compressed => "sparse, compressed",
+                ExtentData::Sparse(_) => "sparse",
+                ExtentData::Flat(..) => "flat",
+                ExtentData::Zero => "zero",
+            };
+            let description = if extent.file_name.is_empty() {
+                format!("{}, {} sectors", kind, extent.sectors)
+            } else {
+                format!("{} ({}, {} sectors)", extent.file_name, kind, extent.sectors)
+            };
+            details.push((format!("Extent {}", index + 1), description));
+        }
+        if let Some(allocated) = self.allocated_grains {
+            details.push(("Allocated grains".to_string(), allocated.to_string()));
+        }
+        if let Some(adapter) = descriptor_value(&self.descriptor, "ddb.adapterType") {
+            details.push(("Adapter type".to_string(), adapter));
+        }
+        if let Some(parent) = &self.parent {
+            details.push(("Parent".to_string(), format!("{} ({})", self.parent_name, parent.format_name())));
+            details.extend(parent.details().into_iter().map(|(key, value)| (format!("Parent {}", key.to_lowercase()), value)));
+        }
+        details
+    }
+}
+
+// Writes a monolithicSparse extent with an embedded descriptor. Both grain
+// directories and all grain tables are preallocated after the descriptor, the way
+// VMware lays them out, and filled in once every grain has been written.
+pub struct VmdkWriter {
+    file: File,
+    file_name: String,
+    capacity: u64,
+    grain_table: Vec<u32>,
+    redundant_directory: u64,
+    directory: u64,
+    overhead: u64,
+    next_grain: u64,
+}
+
+impl VmdkWriter {
+    pub fn create(path: &Path, size: u64) -> io::Result<Self> {
+        let file_name = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .filter(|name| !name.contains('"'))
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "VMDK file name must be valid UTF-8 without quotes"))?
+            .to_string();
+        let capacity = size / SECTOR;
+        let grain_sectors = WRITE_GRAIN_SIZE / SECTOR;
+        let grains = capacity.div_ceil(grain_sectors);
+        let tables = grains.div_ceil(WRITE_GRAIN_TABLE_ENTRIES);
+        let directory_sectors = (tables * 4).div_ceil(SECTOR);
+        let metadata_sectors = directory_sectors + tables * WRITE_GRAIN_TABLE_ENTRIES * 4 / SECTOR;
+        let redundant_directory = 1 + WRITE_DESCRIPTOR_SECTORS;
+        let directory = redundant_directory + metadata_sectors;
+        let overhead = (directory + metadata_sectors).next_multiple_of(grain_sectors);
+        // Grain table entries are 32-bit sector numbers.
+        if overhead + grains * grain_sectors > u32::MAX as u64 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "VMDK sparse extents are limited to 2 TiB"));
+        }
+        Ok(Self {
+            file: create_output(path)?,
+            file_name,
+            capacity,
+            grain_table: vec![0; (tables * WRITE_GRAIN_TABLE_ENTRIES) as usize],
+            redundant_directory,
+            directory,
+            overhead,
+            next_grain: overhead,
+        })
+    }
+
+    fn header(&self) -> [u8; 512] {
+        let mut header = [0u8; 512];
+        header[0..4].copy_from_slice(SPARSE_MAGIC);
+        header[4..8].copy_from_slice(&1u32.to_le_bytes());
+        header[8..12].copy_from_slice(&(FLAG_VALID_NEWLINE_DETECTION | FLAG_REDUNDANT_GRAIN_TABLE).to_le_bytes());
+        header[12..20].copy_from_slice(&self.capacity.to_le_bytes());
+        header[20..28].copy_from_slice(&(WRITE_GRAIN_SIZE / SECTOR).to_le_bytes());
+        header[28..36].copy_from_slice(&1u64.to_le_bytes());
+        header[36..44].copy_from_slice(&WRITE_DESCRIPTOR_SECTORS.to_le_bytes());
+        header[44..48].copy_from_slice(&(WRITE_GRAIN_TABLE_ENTRIES as u32).to_le_bytes());
+        header[48..56].copy_from_slice(&self.redundant_directory.to_le_bytes());
+        header[56..64].copy_from_slice(&self.directory.to_le_bytes());
+        header[64..72].copy_from_slice(&self.overhead.to_le_bytes());
+        header[73..77].copy_from_slice(b"\n \r\n");
+        header
+    }
+
+    fn descriptor(&self) -> Vec<u8> {
+        let cylinders = (self.capacity / (16 * 63)).min(16383);
+        let content_id = u32::from_le_bytes(Guid::random().0[0..4].try_into().unwrap());
+        let text = format!(
+            "# Disk DescriptorFile\nversion=1\nCID={:08x}\nparentCID=ffffffff\ncreateType=\"monolithicSparse\"\n\n\
+             # Extent description\nRW {} SPARSE \"{}\"\n\n\
+             # The Disk Data Base\n#DDB\n\n\
+             ddb.virtualHWVersion = \"4\"\nddb.geometry.cylinders = \"{}\"\nddb.geometry.heads = \"16\"\n\
+             ddb.geometry.sectors = \"63\"\nddb.adapterType = \"ide\"\n",
+            content_id, self.capacity, self.file_name, cylinders
+        );
+        let mut bytes = text.into_bytes();
+        bytes.resize((WRITE_DESCRIPTOR_SECTORS * SECTOR) as usize, 0);
+        bytes
+    }
+
+    // A grain directory followed by the grain tables it points to.
+    fn directory_and_tables(&self, directory: u64) -> Vec<u8> {
+        let tables = self.grain_table.len() as u64 / WRITE_GRAIN_TABLE_ENTRIES;
+        let directory_sectors = (tables * 4).div_ceil(SECTOR);
+        let table_sectors = WRITE_GRAIN_TABLE_ENTRIES * 4 / SECTOR;
+        let mut bytes: Vec<u8> = (0..tables)
+            .flat_map(|table| ((directory + directory_sectors + table * table_sectors) as u32).to_le_bytes())
+            .collect();
+        bytes.resize((directory_sectors * SECTOR) as usize, 0);
+        bytes.extend(self.grain_table.iter().flat_map(|entry| entry.to_le_bytes()));
+        bytes
+    }
+}
+
+impl ImageWriter for VmdkWriter {
+    fn write_block(&mut self, index: u64, data: &[u8]) -> io::Result<()> {
+        let mut grain = data.to_vec();
+        grain.resize(WRITE_GRAIN_SIZE as usize, 0);
+        write_all_at(&mut self.file, self.next_grain * SECTOR, &grain)?;
+        self.grain_table[index as usize] = self.next_grain as u32;
+        self.next_grain += WRITE_GRAIN_SIZE / SECTOR;
+        Ok(())
+    }
+
+    fn finish(mut self: Box<Self>) -> io::Result<()> {
+        let header = self.header();
+        let descriptor = self.descriptor();
+        let redundant = self.directory_and_tables(self.redundant_directory);
+        let primary = self.directory_and_tables(self.directory);
+        write_all_at(&mut self.file, 0, &header)?;
+        write_all_at(&mut self.file, SECTOR, &descriptor)?;
+        write_all_at(&mut self.file, self.redundant_directory * SECTOR, &redundant)?;
+        write_all_at(&mut self.file, self.directory * SECTOR, &primary)?;
+        self.file.set_len(self.next_grain * SECTOR)?;
+        self.file.sync_all()
+    }
+}